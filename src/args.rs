@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use lazy_static::lazy_static;
 use std::net::SocketAddr;
 
@@ -8,17 +8,128 @@ pub struct Args {
     #[arg(short = 'l', long, default_value = "[::1]:6667")]
     pub ircd_listen: SocketAddr,
 
+    /// Also accept irc clients over websocket (e.g. web clients) on this address
+    #[arg(long, default_value = None)]
+    pub ircd_ws_listen: Option<SocketAddr>,
+
+    /// Browser origins allowed to connect to --ircd-ws-listen (e.g.
+    /// https://web.example.com), besides pages served from the same host.
+    /// Can be repeated, `*` allows any origin
+    #[arg(long)]
+    pub ircd_ws_origin: Vec<String>,
+
     #[arg(long, default_value_t = false)]
     pub allow_register: bool,
 
+    /// Only let new users matching these nicks register (* matches anything).
+    /// Can be repeated; with neither this nor --register-token anyone can register
+    #[arg(long)]
+    pub register_allow: Vec<String>,
+
+    /// Let new users register with any nick if their password is suffixed
+    /// with `:<token>` (PASS mypass:token). Can be repeated
+    #[arg(long)]
+    pub register_token: Vec<String>,
+
+    /// Messages queued for each irc client before matrix sync waits for it.
+    /// Joins/parts are skipped when the queue is 3/4 full
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(4..))]
+    pub irc_queue_size: u32,
+
     #[arg(long, default_value = "/var/lib/matrirc")]
     pub state_dir: String,
 
+    /// Join invitations matching pattern without asking, e.g.
+    /// `from:@*:trusted.server` (* matches anything). Can be repeated
+    #[arg(long, value_parser = invite_pattern)]
+    pub autoaccept_invites: Vec<String>,
+
     #[arg(long, default_value = None)]
     pub media_dir: Option<String>,
 
     #[arg(long, default_value = None)]
     pub media_url: Option<String>,
+
+    /// Serve --media-dir over http on this address (links default to
+    /// http://<address>, set --media-url if it is reached differently)
+    #[arg(long, default_value = None)]
+    pub media_listen: Option<SocketAddr>,
+
+    /// With --media-listen, stop serving files older than this
+    #[arg(long, default_value = None)]
+    pub media_expiry_hours: Option<u64>,
+
+    /// Don't download encrypted attachments bigger than this (bytes), they can
+    /// be fetched on demand
+    #[arg(long, default_value = None)]
+    pub media_max_size: Option<u64>,
+
+    /// Proxy used to reach homeservers, e.g. http://proxy:3128 or socks5h://127.0.0.1:9050
+    #[arg(long, default_value = None)]
+    pub proxy: Option<String>,
+
+    /// argon2 memory cost (KiB) used to encrypt user state.
+    /// Sessions encrypted with weaker parameters are upgraded on login.
+    #[arg(long, default_value_t = argon2::Params::DEFAULT_M_COST)]
+    pub argon2_memory: u32,
+
+    /// argon2 iterations used to encrypt user state, see --argon2-memory
+    #[arg(long, default_value_t = argon2::Params::DEFAULT_T_COST)]
+    pub argon2_iterations: u32,
+
+    /// Maximum concurrent irc connections to the daemon, including ones
+    /// still logging in
+    #[arg(long, default_value = None)]
+    pub max_connections: Option<usize>,
+
+    /// Maximum concurrent irc connections logged in as the same nick
+    #[arg(long, default_value = None)]
+    pub max_connections_per_nick: Option<usize>,
+
+    /// Shell command run with a json description on stdin for highlights and
+    /// direct messages while no client is connected (or while idle, if
+    /// idle_minutes is set), e.g. to send push notifications
+    #[arg(long, default_value = None)]
+    pub hook_command: Option<String>,
+
+    /// Accept messages for rooms over http on this address: POST text to
+    /// /<nick>/<room> with the user's webhook_token as bearer token
+    #[arg(long, default_value = None)]
+    pub webhook_listen: Option<SocketAddr>,
+
+    /// How to show short message IDs used by commands (\r, \react...)
+    #[arg(long, value_enum, default_value_t = MessageIds::None)]
+    pub message_ids: MessageIds,
+
+    #[command(subcommand)]
+    pub command: Option<Tool>,
+}
+
+/// one-off actions instead of running the server
+#[derive(Subcommand, Debug)]
+pub enum Tool {
+    /// Write decrypted session and crypto store of a user to file (asks for password)
+    ExportSession { nick: String, file: String },
+    /// Create user from exported session, with the password used for export
+    ImportSession { nick: String, file: String },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MessageIds {
+    /// don't show message IDs
+    None,
+    /// append ` [id]` to messages
+    Suffix,
+    /// msgid message tag, for clients with message-tags capability
+    Tag,
+}
+
+/// only sender patterns for now
+pub fn invite_pattern(pattern: &str) -> Result<String, String> {
+    match pattern.strip_prefix("from:") {
+        Some(_) => Ok(pattern.to_string()),
+        None => Err("expected from:<user pattern>".to_string()),
+    }
 }
 
 pub fn args() -> &'static Args {
@@ -0,0 +1,247 @@
+use anyhow::{Context, Error, Result};
+use log::warn;
+use matrix_sdk::{
+    encryption::{identities::Device, recovery::RecoveryError},
+    ruma::{api::client::uiaa::AuthData, OwnedUserId},
+    RoomMemberships,
+};
+use std::sync::Arc;
+use tokio::task;
+
+use crate::commands::CommandArgs;
+use crate::matrirc::Matrirc;
+use crate::matrix::{time::ToLocal, uiaa};
+use crate::state;
+
+/// other member of origin if it is a direct chat
+async fn dm_user(matrirc: &Matrirc, origin: &str) -> Option<OwnedUserId> {
+    let room = matrirc.mappings().room(origin).await?;
+    if !room.is_direct().await.unwrap_or(false) {
+        return None;
+    }
+    let own_user = matrirc.matrix().user_id()?;
+    let members = room.members(RoomMemberships::ACTIVE).await.ok()?;
+    let mut others = members.iter().filter(|m| m.user_id() != own_user);
+    match (others.next(), others.next()) {
+        (Some(member), None) => Some(member.user_id().to_owned()),
+        _ => None,
+    }
+}
+
+/// trust [@user]: cross-signing status and unverified devices of user
+/// (other member of current direct chat, or ourselves by default)
+pub async fn trust(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let client = matrirc.matrix();
+    let user_id: OwnedUserId = match args.next() {
+        Some(user) => user.try_into().context("Invalid user id")?,
+        None => match dm_user(matrirc, origin).await {
+            Some(user_id) => user_id,
+            None => client.user_id().context("client has no user?")?.to_owned(),
+        },
+    };
+    let encryption = client.encryption();
+    // ask server in case we don't share any room with user yet
+    let identity = encryption.request_user_identity(&user_id).await?;
+    let mut lines = vec![match identity {
+        Some(identity) if identity.is_verified() => {
+            format!("{} is verified (cross-signing)", user_id)
+        }
+        Some(_) => format!("{} has a cross-signing identity, not verified", user_id),
+        None => format!("{} has no cross-signing identity", user_id),
+    }];
+    let devices = encryption.get_user_devices(&user_id).await?;
+    let mut devices: Vec<_> = devices.devices().collect();
+    devices.sort_by(|a, b| a.device_id().cmp(b.device_id()));
+    let unverified = devices.iter().filter(|d| !d.is_verified()).count();
+    lines.push(format!(
+        "{} devices, {} unverified:",
+        devices.len(),
+        unverified
+    ));
+    for device in devices {
+        lines.push(format!(
+            "{}: {} ({})",
+            device.device_id(),
+            device.display_name().unwrap_or("(no name)"),
+            device_status(&device)
+        ));
+    }
+    matrirc.mappings().matrirc_query(lines.join("\n")).await
+}
+
+fn device_status(device: &Device) -> &'static str {
+    if device.is_verified() {
+        "verified"
+    } else if device.is_cross_signed_by_owner() {
+        "signed by owner, unverified"
+    } else {
+        "unverified"
+    }
+}
+
+/// devices (or sessions): devices of our account by last activity with their
+/// verification state, to spot forgotten or suspicious sessions
+pub async fn devices(matrirc: &Matrirc, _origin: &str, _args: CommandArgs<'_>) -> Result<()> {
+    let client = matrirc.matrix();
+    let user_id = client.user_id().context("client has no user?")?;
+    let own_device = client.device_id();
+    let mut devices = client.devices().await?.devices;
+    // most recently seen first, never seen last
+    devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen_ts));
+    let keys = client.encryption().get_user_devices(user_id).await?;
+    let mut unverified = 0;
+    let mut lines = vec![];
+    for device in &devices {
+        let status = match keys.get(&device.device_id) {
+            Some(key) => device_status(&key),
+            None => "no encryption keys",
+        };
+        if status != "verified" {
+            unverified += 1;
+        }
+        let current = if Some(&*device.device_id) == own_device {
+            ", this session"
+        } else {
+            ""
+        };
+        lines.push(format!(
+            "{}: {} ({}{}), last seen {} from {}",
+            device.device_id,
+            device.display_name.as_deref().unwrap_or("(no name)"),
+            status,
+            current,
+            device
+                .last_seen_ts
+                .and_then(|ts| ts.localtime(&matrirc.config().timestamps))
+                .unwrap_or_else(|| "never".to_string()),
+            device.last_seen_ip.as_deref().unwrap_or("unknown ip"),
+        ));
+    }
+    lines.insert(
+        0,
+        format!("{} sessions, {} unverified:", devices.len(), unverified),
+    );
+    matrirc.mappings().matrirc_query(lines.join("\n")).await
+}
+
+/// auth <secret|cancel>: answer the authentication prompt of a pending operation
+pub async fn auth(matrirc: &Matrirc, _origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    uiaa::resume(matrirc, args.required_rest("secret")?).await
+}
+
+/// create cross-signing keys if we have none yet (the homeserver wants our
+/// password for that), then secret storage with key backup
+async fn create_secret_storage(matrirc: &Matrirc, auth: Option<AuthData>) -> Result<()> {
+    let encryption = matrirc.matrix().encryption();
+    encryption.bootstrap_cross_signing_if_needed(auth).await?;
+    matrirc
+        .mappings()
+        .matrirc_query("Creating secret storage and uploading key backup...")
+        .await?;
+    let recovery_key =
+        match encryption
+            .recovery()
+            .enable()
+            .wait_for_backups_to_upload()
+            .await
+        {
+            Ok(key) => key,
+            Err(RecoveryError::BackupExistsOnServer) => return Err(Error::msg(
+                "A key backup already exists on the server, recover it from another client first",
+            )),
+            Err(e) => return Err(e.into()),
+        };
+    matrirc
+        .mappings()
+        .matrirc_query(format!(
+            "Secret storage ready. Recovery key (only shown once, store it safely): {}",
+            recovery_key
+        ))
+        .await
+}
+
+/// 4s <create|status>: set up secret storage (with key backup and
+/// cross-signing keys) and print its recovery key
+pub async fn secret_storage(
+    matrirc: &Matrirc,
+    _origin: &str,
+    mut args: CommandArgs<'_>,
+) -> Result<()> {
+    let recovery = matrirc.matrix().encryption().recovery();
+    match args.required("create|status")? {
+        "status" => {
+            matrirc
+                .mappings()
+                .matrirc_query(format!("Recovery state: {:?}", recovery.state()))
+                .await
+        }
+        "create" => {
+            uiaa::run(
+                matrirc,
+                "Secret storage creation",
+                Arc::new(|matrirc, auth| {
+                    Box::pin(async move { create_secret_storage(&matrirc, auth).await })
+                }),
+            )
+            .await
+        }
+        other => Err(Error::msg(format!("Unknown 4s action {}", other))),
+    }
+}
+
+/// passwd <old> <new>: change password used to log in (and encrypt our state)
+pub async fn passwd(matrirc: &Matrirc, _origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let old_pass = args.required("old password")?.to_string();
+    let new_pass = args.required("new password")?.to_string();
+    if args.next().is_some() {
+        return Err(Error::msg("passwords cannot contain spaces"));
+    }
+    let nick = matrirc.irc().nick.clone();
+    let session_key = matrirc.session_key().clone();
+    // key derivations are slow on purpose, don't block other tasks
+    task::spawn_blocking(move || state::change_password(&nick, &old_pass, &new_pass, &session_key))
+        .await??;
+    matrirc
+        .mappings()
+        .matrirc_query("Password changed, use the new one next time you connect")
+        .await
+}
+
+/// logout: invalidate our access token and remove local state, then
+/// disconnect
+pub async fn logout(matrirc: &Matrirc, _origin: &str, _args: CommandArgs<'_>) -> Result<()> {
+    if let Err(e) = matrirc.matrix().matrix_auth().logout().await {
+        // token might already be invalid, clean up anyway
+        warn!("Could not logout from homeserver: {:?}", e);
+        matrirc
+            .mappings()
+            .matrirc_query(format!(
+                "Homeserver logout failed ({}), removing local state anyway",
+                e
+            ))
+            .await?;
+    }
+    matrirc.mappings().part_all().await?;
+    matrirc.stop("Logged out").await?;
+    let nick = matrirc.irc().nick.clone();
+    task::spawn_blocking(move || state::delete_user(&nick)).await?
+}
+
+/// relogin <password>: login again after soft logout, keeping the same device
+pub async fn relogin(matrirc: &Matrirc, _origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    let pass = args.required_rest("matrix password")?;
+    let client = matrirc.matrix();
+    let user_id = client.user_id().context("client has no user?")?;
+    let device_id = client.device_id().context("client has no device?")?;
+    client
+        .matrix_auth()
+        .login_username(user_id, pass)
+        .device_id(device_id.as_str())
+        .request_refresh_token()
+        .send()
+        .await?;
+    let session = client.session().context("client has no auth session")?;
+    matrirc.session_key().save(session)?;
+    matrirc.relogin_done();
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use anyhow::{Context, Error, Result};
+use chrono::{offset::Local, DateTime, Days, NaiveDate, Utc};
+use irc::proto::message::Tag;
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    room::{MessagesOptions, Room},
+    ruma::{
+        api::{client::room::get_event_by_timestamp, Direction},
+        events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent},
+        uint, MilliSecondsSinceUnixEpoch,
+    },
+};
+use regex::RegexBuilder;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tokio::task;
+
+use crate::commands::CommandArgs;
+use crate::ircd::proto::{privmsg, raw_msg};
+use crate::matrirc::Matrirc;
+use crate::matrix::{message_like_to_str, time::ToLocal};
+
+/// max number of lines replayed per room
+const GREP_LIMIT: usize = 50;
+/// max number of messages replayed by history
+const HISTORY_LIMIT: usize = 1000;
+/// messages fetched per /messages request
+const HISTORY_PAGE: u32 = 100;
+
+/// grep [#chan] <regex>: search local message logs.
+/// Defaults to the current room, or all rooms from the matrirc query.
+pub async fn grep(matrirc: &Matrirc, origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    let logger = matrirc
+        .logger()
+        .context("Message logs are disabled, add a [log] section to config.toml")?
+        .clone();
+    let mut rest = args;
+    let name = Some(rest.optional_chan(origin)).filter(|name| *name != "matrirc");
+    let pattern = rest.required_rest("regex")?;
+    let room_id = match name {
+        Some(name) => Some(
+            matrirc
+                .mappings()
+                .room(name)
+                .await
+                .with_context(|| format!("No room for {}", name))?
+                .room_id()
+                .to_owned(),
+        ),
+        None => None,
+    };
+    let re = RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| Error::msg(format!("Invalid regex: {}", e)))?;
+    // logs can be large, don't hog the runtime
+    let results =
+        task::spawn_blocking(move || logger.search(room_id.as_deref(), &re, GREP_LIMIT)).await??;
+    if results.is_empty() {
+        return matrirc
+            .mappings()
+            .matrirc_query(format!("No match for {}", pattern))
+            .await;
+    }
+    let mut names = HashMap::new();
+    for (room_id, target) in matrirc.mappings().list_rooms().await {
+        names.insert(room_id, target.irc_name().await);
+    }
+    let mut lines = vec![];
+    for (room_id, found) in results {
+        lines.push(format!(
+            "Matches in {}:",
+            names
+                .get(&room_id)
+                .cloned()
+                .unwrap_or_else(|| room_id.to_string())
+        ));
+        lines.extend(found);
+    }
+    matrirc.mappings().matrirc_query(lines.join("\n")).await
+}
+
+/// room messages only, skipping state, reactions and redacted events
+fn to_message(event: &TimelineEvent) -> Option<AnySyncMessageLikeEvent> {
+    match event.raw().deserialize() {
+        Ok(AnySyncTimelineEvent::MessageLike(
+            m @ AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(_)),
+        )) => Some(m),
+        _ => None,
+    }
+}
+
+/// local midnight of YYYY-MM-DD
+fn parse_date(date: &str) -> Result<DateTime<Local>> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0)?.and_local_timezone(Local).earliest())
+        .with_context(|| format!("Invalid date {}, expected YYYY-MM-DD", date))
+}
+
+fn to_ts(datetime: DateTime<Local>) -> Option<MilliSecondsSinceUnixEpoch> {
+    MilliSecondsSinceUnixEpoch::from_system_time(datetime.into())
+}
+
+/// last count messages, oldest first
+async fn fetch_last(room: &Room, count: usize) -> Result<Vec<AnySyncMessageLikeEvent>> {
+    let mut messages = vec![];
+    let mut from = None;
+    while messages.len() < count {
+        let mut options = MessagesOptions::backward().from(from.as_deref());
+        options.limit = HISTORY_PAGE.into();
+        let page = room.messages(options).await?;
+        if page.chunk.is_empty() {
+            break;
+        }
+        messages.extend(page.chunk.iter().filter_map(to_message));
+        from = page.end;
+        if from.is_none() {
+            break;
+        }
+    }
+    messages.truncate(count);
+    messages.reverse();
+    Ok(messages)
+}
+
+/// messages between start (included) and end (excluded), oldest first
+async fn fetch_range(
+    matrirc: &Matrirc,
+    room: &Room,
+    start: MilliSecondsSinceUnixEpoch,
+    end: MilliSecondsSinceUnixEpoch,
+) -> Result<Vec<AnySyncMessageLikeEvent>> {
+    // jump to start directly instead of paginating back from now
+    let request = get_event_by_timestamp::v1::Request::new(
+        room.room_id().to_owned(),
+        start,
+        Direction::Forward,
+    );
+    let found = matrirc
+        .matrix()
+        .send(request, None)
+        .await
+        .context("Could not find messages at start date")?;
+    let mut messages = vec![];
+    if found.origin_server_ts >= end {
+        return Ok(messages);
+    }
+    let context = room
+        .event_with_context(&found.event_id, false, uint!(0), None)
+        .await?;
+    let mut from = context.prev_batch_token;
+    while messages.len() < HISTORY_LIMIT {
+        let mut options = MessagesOptions::forward().from(from.as_deref());
+        options.limit = HISTORY_PAGE.into();
+        let page = room.messages(options).await?;
+        if page.chunk.is_empty() {
+            break;
+        }
+        for message in page.chunk.iter().filter_map(to_message) {
+            if message.origin_server_ts() >= end {
+                return Ok(messages);
+            }
+            if message.origin_server_ts() >= start {
+                messages.push(message);
+            }
+        }
+        from = page.end;
+        if from.is_none() {
+            break;
+        }
+    }
+    messages.truncate(HISTORY_LIMIT);
+    Ok(messages)
+}
+
+/// history [#chan] <from-date> [to-date] | history [#chan] last <count>:
+/// replay messages from server into chan, in a batch if client supports it
+pub async fn history(matrirc: &Matrirc, origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    let mut rest = args;
+    let name = rest.optional_chan(origin);
+    let room = matrirc
+        .mappings()
+        .room(name)
+        .await
+        .with_context(|| format!("No room for {}", name))?;
+    let messages = match rest.required("date")? {
+        "last" => {
+            let count: usize = rest
+                .required("count")?
+                .parse()
+                .context("Invalid message count")?;
+            fetch_last(&room, count.min(HISTORY_LIMIT)).await?
+        }
+        date => {
+            let start = parse_date(date)?;
+            let last_day = match rest.next() {
+                Some(date) => parse_date(date)?,
+                None => start,
+            };
+            // end date is included
+            let end = last_day
+                .checked_add_days(Days::new(1))
+                .context("Invalid end date")?;
+            let (start, end) = to_ts(start).zip(to_ts(end)).context("Invalid date range")?;
+            fetch_range(matrirc, &room, start, end).await?
+        }
+    };
+    if messages.is_empty() {
+        return matrirc
+            .mappings()
+            .matrirc_query(format!("No messages found in {}", name))
+            .await;
+    }
+    replay(matrirc, &room, messages).await
+}
+
+async fn replay(
+    matrirc: &Matrirc,
+    room: &Room,
+    messages: Vec<AnySyncMessageLikeEvent>,
+) -> Result<()> {
+    let irc = matrirc.irc();
+    let target = matrirc.mappings().room_target(room).await;
+    let name = target.irc_name().await;
+    let is_chan = name.starts_with('#');
+    let server_time = irc.has_cap("server-time");
+    let batch = irc.has_cap("batch").then(|| {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("history{}", now.as_millis())
+    });
+    if let Some(batch) = &batch {
+        irc.send(raw_msg(format!(
+            ":matrirc BATCH +{} chathistory {}",
+            batch, name
+        )))
+        .await?;
+    }
+    for message in messages {
+        let sender = target.member_nick(message.sender()).await;
+        let mut text = message_like_to_str(&message);
+        if !is_chan {
+            // queries come from the query name
+            text = format!("<{}> {}", sender, text);
+        }
+        if !server_time {
+            let ts = message.origin_server_ts();
+            text = format!(
+                "{} {}",
+                ts.message_time(&matrirc.config().timestamps)
+                    .unwrap_or_default(),
+                text
+            );
+        }
+        let mut tags = vec![];
+        if let Some(batch) = &batch {
+            tags.push(Tag("batch".to_string(), Some(batch.clone())));
+        }
+        if server_time {
+            let time: DateTime<Utc> = message
+                .origin_server_ts()
+                .to_system_time()
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .into();
+            let time = time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+            tags.push(Tag("time".to_string(), Some(time)));
+        }
+        for line in text.lines() {
+            let mut irc_message = match is_chan {
+                true => privmsg(&sender, &name, line),
+                false => privmsg(&name, &irc.nick, line),
+            };
+            if !tags.is_empty() {
+                irc_message.tags = Some(tags.clone());
+            }
+            irc.send(irc_message).await?;
+        }
+    }
+    if let Some(batch) = batch {
+        irc.send(raw_msg(format!(":matrirc BATCH -{}", batch)))
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Error, Result};
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    room::Room,
+    ruma::{
+        events::{
+            reaction::ReactionEventContent,
+            relation::{Annotation, InReplyTo},
+            room::message::{Relation, ReplacementMetadata, RoomMessageEventContent},
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+        },
+        uint, OwnedEventId,
+    },
+};
+
+use crate::commands::CommandArgs;
+use crate::matrirc::Matrirc;
+use crate::matrix::{
+    media_info, message_like_to_str, rekey, shortcodes, time::ToLocal, upload_url, SourceUri,
+};
+
+async fn origin_room(matrirc: &Matrirc, origin: &str) -> Result<Room> {
+    matrirc
+        .mappings()
+        .room(origin)
+        .await
+        .with_context(|| format!("No room for {}", origin))
+}
+
+/// find message from short id, or full event id in origin room
+async fn resolve_message(
+    matrirc: &Matrirc,
+    origin: &str,
+    id: &str,
+) -> Result<(Room, OwnedEventId)> {
+    if id.starts_with('$') {
+        let room = origin_room(matrirc, origin).await?;
+        return Ok((room, id.try_into().context("Invalid event id")?));
+    }
+    let (room_id, event_id) = matrirc
+        .message_lookup(id)
+        .await
+        .with_context(|| format!("No message with id {}", id))?;
+    let room = matrirc
+        .matrix()
+        .get_room(&room_id)
+        .with_context(|| format!("Room {} for message {} is gone", room_id, id))?;
+    Ok((room, event_id))
+}
+
+/// link [id]: give matrix.to permalink for message (last message in room by default)
+pub async fn link(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let (room, event_id) = match args.next() {
+        Some(id) => resolve_message(matrirc, origin, id).await?,
+        None => {
+            let room = origin_room(matrirc, origin).await?;
+            let event_id = matrirc
+                .last_message_get(room.room_id())
+                .await
+                .context("No message seen in room yet")?;
+            (room, event_id)
+        }
+    };
+    let permalink = room.matrix_to_event_permalink(event_id).await?;
+    matrirc
+        .mappings()
+        .matrirc_query(format!("Permalink: {}", permalink))
+        .await
+}
+
+/// upload [#chan] <url>: download url and send it to room as attachment
+pub async fn upload(matrirc: &Matrirc, origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    let mut rest = args;
+    let name = rest.optional_chan(origin);
+    let url = rest.required("url")?;
+    let room = origin_room(matrirc, name).await?;
+    let url = filtered(matrirc, &room, url.to_string()).await?;
+    let (filename, size, content_type) = upload_url(matrirc, &room, &url).await?;
+    matrirc
+        .mappings()
+        .matrirc_query(format!(
+            "Uploaded {} ({}, {} bytes) to {}",
+            filename, content_type, size, name
+        ))
+        .await
+}
+
+/// outgoing filters of room applied to text
+async fn filtered(matrirc: &Matrirc, room: &Room, text: String) -> Result<String> {
+    let name = matrirc.mappings().room_target(room).await.irc_name().await;
+    matrirc
+        .filters()
+        .outgoing(&name, text)
+        .await
+        .context("Not sent: blocked by filter")
+}
+
+/// r <id> <text>: reply to message
+pub async fn reply(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id = args.required("id")?;
+    let text = args.required_rest("text")?;
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    let text = filtered(matrirc, &room, matrirc.config().outgoing_text(text)).await?;
+    let mut content = RoomMessageEventContent::text_plain(text);
+    content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(event_id),
+    });
+    room.send_queue().send(content.into()).await?;
+    Ok(())
+}
+
+/// confirm: send messages held because of confirm_members
+pub async fn confirm(matrirc: &Matrirc, _origin: &str, _args: CommandArgs<'_>) -> Result<()> {
+    let (target, messages) = matrirc.confirm_held().await?;
+    for (message_type, message) in messages {
+        matrirc
+            .mappings()
+            .to_matrix(&target, message_type, message)
+            .await?;
+    }
+    Ok(())
+}
+
+/// rekey <id>: get key of a message that could not be decrypted from key backup
+/// and show it again
+pub async fn rekey_message(
+    matrirc: &Matrirc,
+    origin: &str,
+    mut args: CommandArgs<'_>,
+) -> Result<()> {
+    let id = args.required("id")?;
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    if !rekey(matrirc, &room, &event_id).await? {
+        return Err(Error::msg(format!(
+            "Still no key for {}, check key backup or verify this session",
+            id
+        )));
+    }
+    Ok(())
+}
+
+/// react <id> <emoji>: react to message
+pub async fn react(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id = args.required("id")?;
+    let key = args.required_rest("emoji")?;
+    // react with either an emoji or its :shortcode:, even if
+    // emoji_shortcodes is off as reactions are never meant literally
+    let key = shortcodes::expand(key);
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    room.send_queue()
+        .send(ReactionEventContent::new(Annotation::new(event_id, key)).into())
+        .await?;
+    Ok(())
+}
+
+/// redact <id> [reason]: redact message
+pub async fn redact(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id = args.required("id")?;
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    room.redact(&event_id, args.rest(), None).await?;
+    Ok(())
+}
+
+/// edit <id> <text>: replace message content
+pub async fn edit(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id = args.required("id")?;
+    let text = args.required_rest("text")?;
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    let text = filtered(matrirc, &room, matrirc.config().outgoing_text(text)).await?;
+    let content = RoomMessageEventContent::text_plain(text)
+        .make_replacement(ReplacementMetadata::new(event_id, None), None);
+    room.send_queue().send(content.into()).await?;
+    Ok(())
+}
+
+/// fetch <id>: download attachment that was over media max size
+pub async fn fetch(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id = args.required("id")?;
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    let event = room.event(&event_id, None).await?;
+    let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(message),
+    ))) = event.raw().deserialize()
+    else {
+        return Err(Error::msg(format!("{} is not a message", id)));
+    };
+    let (source, filename, size, _) =
+        media_info(&message.content.msgtype).context("Message has no attachment")?;
+    let url = source.to_uri(matrirc, filename, size, None).await?;
+    matrirc
+        .mappings()
+        .matrirc_query(format!("Fetched {}: {}", id, url))
+        .await
+}
+
+fn timeline_event_to_str(matrirc: &Matrirc, event: &TimelineEvent) -> String {
+    match event.raw().deserialize() {
+        Ok(AnySyncTimelineEvent::MessageLike(m)) => format!(
+            "{} {}: {}",
+            m.origin_server_ts()
+                .localtime(&matrirc.config().timestamps)
+                .unwrap_or_else(|| "just now".to_string()),
+            m.sender(),
+            message_like_to_str(&m)
+        ),
+        Ok(AnySyncTimelineEvent::State(s)) => format!(
+            "{} {}: <{}>",
+            s.origin_server_ts()
+                .localtime(&matrirc.config().timestamps)
+                .unwrap_or_else(|| "just now".to_string()),
+            s.sender(),
+            s.event_type()
+        ),
+        Err(e) => format!("<could not parse event: {}>", e),
+    }
+}
+
+/// context <id>: show a few messages around message
+pub async fn context(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id = args.required("id")?;
+    let (room, event_id) = resolve_message(matrirc, origin, id).await?;
+    let context = room
+        .event_with_context(&event_id, true, uint!(3), None)
+        .await?;
+    let mut lines = vec![format!("Context for {}:", id)];
+    // events_before is in reverse chronological order
+    lines.extend(
+        context
+            .events_before
+            .iter()
+            .rev()
+            .map(|e| timeline_event_to_str(matrirc, e)),
+    );
+    if let Some(event) = &context.event {
+        lines.push(format!("> {}", timeline_event_to_str(matrirc, event)));
+    }
+    lines.extend(
+        context
+            .events_after
+            .iter()
+            .map(|e| timeline_event_to_str(matrirc, e)),
+    );
+    matrirc.mappings().matrirc_query(lines.join("\n")).await
+}
@@ -0,0 +1,430 @@
+use anyhow::{Context, Error, Result};
+use futures::future::BoxFuture;
+use log::debug;
+use std::fmt;
+
+use crate::matrirc::Matrirc;
+
+mod account;
+mod history;
+mod messages;
+mod receipts;
+mod rooms;
+
+/// commands get matrirc, the target they were typed in (channel or query name,
+/// used as default when the command takes an optional target) and arguments
+type Handler = for<'a> fn(&'a Matrirc, &'a str, CommandArgs<'a>) -> BoxFuture<'a, Result<()>>;
+
+pub struct Command {
+    pub name: &'static str,
+    /// arguments, as displayed in help
+    pub usage: &'static str,
+    /// feature the command belongs to, help lists commands by section
+    pub section: &'static str,
+    /// one line description
+    pub help: &'static str,
+    /// longer explanation or examples for `help <command>`, can be empty
+    pub details: &'static str,
+    handler: Handler,
+}
+
+/// all known commands, keep sorted
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "4s",
+        section: "account",
+        usage: "<create|status>",
+        help: "set up secret storage with key backup and cross-signing, printing its recovery key",
+        details: "The matrix password is asked for (see auth) if cross-signing keys must be created. Keep the recovery key: it is only shown once.",
+        handler: |m, o, a| Box::pin(account::secret_storage(m, o, a)),
+    },
+    Command {
+        name: "auth",
+        section: "account",
+        usage: "<secret|cancel>",
+        help: "answer an authentication prompt (matrix password or registration token)",
+        details: "Some account operations require authenticating again: they wait for this command, and resume once the homeserver accepted it. The secret is not stored.",
+        handler: |m, o, a| Box::pin(account::auth(m, o, a)),
+    },
+    Command {
+        name: "confirm",
+        section: "messages",
+        usage: "",
+        help: "send messages held by confirm_members",
+        details: "Messages to rooms with more members than confirm_members (config.toml), or starting with @room, are held until confirmed. Confirming a room once lets later messages through, except @room.",
+        handler: |m, o, a| Box::pin(messages::confirm(m, o, a)),
+    },
+    Command {
+        name: "context",
+        section: "history",
+        usage: "<id>",
+        help: "show messages around a message",
+        details:
+            "id is the short id shown with messages (see --message-ids) or a full $event:server id.",
+        handler: |m, o, a| Box::pin(messages::context(m, o, a)),
+    },
+    Command {
+        name: "devices",
+        section: "account",
+        usage: "",
+        help: "audit sessions of the account: last seen time and ip, verification state",
+        details: "Sessions are listed from most to least recently seen; last seen information is only shown if the homeserver provides it. Sessions you do not recognize can be checked with trust, or logged out from another client.",
+        handler: |m, o, a| Box::pin(account::devices(m, o, a)),
+    },
+    Command {
+        name: "dm",
+        section: "rooms",
+        usage: "<@user:server>",
+        help: "open (or create) a direct chat with user",
+        details: "",
+        handler: |m, o, a| Box::pin(rooms::dm(m, o, a)),
+    },
+    Command {
+        name: "edit",
+        section: "messages",
+        usage: "<id> <text>",
+        help: "replace content of a message",
+        details: "Only your own messages can be edited.",
+        handler: |m, o, a| Box::pin(messages::edit(m, o, a)),
+    },
+    Command {
+        name: "fetch",
+        section: "messages",
+        usage: "<id>",
+        help: "download an attachment that was over media max size",
+        details: "The file is saved to media dir (within quota) and linked here.",
+        handler: |m, o, a| Box::pin(messages::fetch(m, o, a)),
+    },
+    Command {
+        name: "grep",
+        section: "history",
+        usage: "[#chan] <regex>",
+        help: "search local message logs (current room, or all rooms from matrirc query)",
+        details: "Needs [log] enabled in config.toml. Shows at most 50 matches, most recent last.",
+        handler: |m, o, a| Box::pin(history::grep(m, o, a)),
+    },
+    Command {
+        name: "help",
+        section: "general",
+        usage: "[command]",
+        help: "list commands, or describe one",
+        details: "help <section> lists commands of that section.",
+        handler: |m, o, a| Box::pin(help(m, o, a)),
+    },
+    Command {
+        name: "history",
+        section: "history",
+        usage: "[#chan] <from-date> [to-date] | [#chan] last <count>",
+        help: "replay messages from the homeserver into the room",
+        details: "Dates are YYYY-MM-DD in local time, both included, e.g. history #work 2024-05-01 2024-05-02. At most 1000 messages are replayed.",
+        handler: |m, o, a| Box::pin(history::history(m, o, a)),
+    },
+    Command {
+        name: "join",
+        section: "rooms",
+        usage: "<#alias:server|!roomid:server>",
+        help: "join a matrix room",
+        details: "",
+        handler: |m, o, a| Box::pin(rooms::join(m, o, a)),
+    },
+    Command {
+        name: "joins",
+        section: "rooms",
+        usage: "[#chan] [off|smart|on|notice|default]",
+        help: "show or set which member joins and parts are shown in chan",
+        details: "smart only shows joins, parts and nick changes of members who spoke in the last 30 minutes. notice summarizes joins and parts in one notice every joins_notice_seconds (config.toml) without touching the irc member list. default follows show_joins from config.toml. Applies to the current chan if none is given.",
+        handler: |m, o, a| Box::pin(rooms::joins(m, o, a)),
+    },
+    Command {
+        name: "link",
+        section: "messages",
+        usage: "[id]",
+        help: "get permalink to a message (last message of current room by default)",
+        details: "",
+        handler: |m, o, a| Box::pin(messages::link(m, o, a)),
+    },
+    Command {
+        name: "logout",
+        section: "account",
+        usage: "",
+        help: "log this session out of matrix, delete its local state and disconnect",
+        details: "Encryption keys of this session are lost unless backed up (see 4s). Connecting again with the same nick starts a fresh login if --allow-register is set.",
+        handler: |m, o, a| Box::pin(account::logout(m, o, a)),
+    },
+    Command {
+        name: "notify",
+        section: "rooms",
+        usage: "[#chan] [all|mentions|mute|default]",
+        help: "show or set push notifications for chan",
+        details: "Updates the push rules of the matrix account, so phones and other clients follow the same setting. mentions also notifies for keywords set in other clients, default removes the room rules to follow account settings. Applies to the current chan if none is given.",
+        handler: |m, o, a| Box::pin(rooms::notify(m, o, a)),
+    },
+    Command {
+        name: "passwd",
+        section: "account",
+        usage: "<old password> <new password>",
+        help: "change password used to connect",
+        details: "",
+        handler: |m, o, a| Box::pin(account::passwd(m, o, a)),
+    },
+    Command {
+        name: "r",
+        section: "messages",
+        usage: "<id> <text>",
+        help: "reply to a message",
+        details: "e.g. r abc sounds good",
+        handler: |m, o, a| Box::pin(messages::reply(m, o, a)),
+    },
+    Command {
+        name: "react",
+        section: "messages",
+        usage: "<id> <emoji>",
+        help: "react to a message",
+        details: "emoji can also be a :shortcode: such as :thumbs_up:",
+        handler: |m, o, a| Box::pin(messages::react(m, o, a)),
+    },
+    Command {
+        name: "read",
+        section: "rooms",
+        usage: "[#chan]",
+        help: "mark a room as read up to its last message",
+        details: "Receipts are private if private_receipts is set in config.toml, and not sent at all if read_receipts is false.",
+        handler: |m, o, a| Box::pin(receipts::read(m, o, a)),
+    },
+    Command {
+        name: "redact",
+        section: "messages",
+        usage: "<id> [reason]",
+        help: "redact (delete) a message",
+        details: "",
+        handler: |m, o, a| Box::pin(messages::redact(m, o, a)),
+    },
+    Command {
+        name: "rekey",
+        section: "messages",
+        usage: "<id>",
+        help: "retry decrypting a message, using the server-side key backup",
+        details: "Undecryptable messages are announced with their id, and shown automatically if their key arrives later (keys are requested from our other sessions). This asks again and checks the key backup.",
+        handler: |m, o, a| Box::pin(messages::rekey_message(m, o, a)),
+    },
+    Command {
+        name: "relogin",
+        section: "account",
+        usage: "<matrix password>",
+        help: "login again after the homeserver logged this session out",
+        details: "The password is only used to log in again and is not stored.",
+        handler: |m, o, a| Box::pin(account::relogin(m, o, a)),
+    },
+    Command {
+        name: "rooms",
+        section: "rooms",
+        usage: "",
+        help: "list rooms and their irc names",
+        details: "",
+        handler: |m, o, a| Box::pin(rooms::rooms(m, o, a)),
+    },
+    Command {
+        name: "sessions",
+        section: "account",
+        usage: "",
+        help: "same as devices",
+        details: "",
+        handler: |m, o, a| Box::pin(account::devices(m, o, a)),
+    },
+    Command {
+        name: "trust",
+        section: "account",
+        usage: "[@user:server]",
+        help: "show cross-signing status and unverified devices of a user",
+        details: "Defaults to the other member of the current direct chat, or to yourself.",
+        handler: |m, o, a| Box::pin(account::trust(m, o, a)),
+    },
+    Command {
+        name: "unread",
+        section: "rooms",
+        usage: "",
+        help: "list rooms with unread messages and highlight counts",
+        details: "Counts come from the homeserver and read receipts, reading a room from another client or with read clears them.",
+        handler: |m, o, a| Box::pin(rooms::unread(m, o, a)),
+    },
+    Command {
+        name: "upload",
+        section: "messages",
+        usage: "[#chan] <url>",
+        help: "download a file and send it to a room as attachment",
+        details: "The file is downloaded by matrirc, up to media max size (50 MiB if unset); loopback, link-local and private addresses are refused. Uploads to the current chan if none is given.",
+        handler: |m, o, a| Box::pin(messages::upload(m, o, a)),
+    },
+    Command {
+        name: "whoread",
+        section: "rooms",
+        usage: "[#chan]",
+        help: "list who read up to the last message of a room",
+        details: "Disabled if read_receipts is false in config.toml.",
+        handler: |m, o, a| Box::pin(receipts::whoread(m, o, a)),
+    },
+];
+
+/// error returned when a required argument is missing, so we can print usage
+#[derive(Debug)]
+struct MissingArgument(&'static str);
+
+impl fmt::Display for MissingArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "missing {}", self.0)
+    }
+}
+
+impl std::error::Error for MissingArgument {}
+
+/// whitespace-separated arguments, the last one can also be taken as free text
+#[derive(Clone, Copy)]
+pub struct CommandArgs<'a> {
+    rest: &'a str,
+}
+
+impl<'a> CommandArgs<'a> {
+    fn new(args: &'a str) -> Self {
+        CommandArgs { rest: args.trim() }
+    }
+    /// next word, or usage error
+    pub fn required(&mut self, name: &'static str) -> Result<&'a str> {
+        self.next().ok_or_else(|| MissingArgument(name).into())
+    }
+    /// everything left, as typed
+    pub fn rest(self) -> Option<&'a str> {
+        Some(self.rest).filter(|rest| !rest.is_empty())
+    }
+    /// leading #chan if given, else the query or channel the command came from
+    pub fn optional_chan(&mut self, origin: &'a str) -> &'a str {
+        let mut rest = *self;
+        match rest.next() {
+            Some(chan) if chan.starts_with('#') => {
+                *self = rest;
+                chan
+            }
+            _ => origin,
+        }
+    }
+    /// everything left, or usage error
+    pub fn required_rest(self, name: &'static str) -> Result<&'a str> {
+        self.rest().ok_or_else(|| MissingArgument(name).into())
+    }
+}
+
+impl<'a> Iterator for CommandArgs<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let (word, rest) = self
+            .rest
+            .split_once(char::is_whitespace)
+            .unwrap_or((self.rest, ""));
+        self.rest = rest.trim_start();
+        Some(word)
+    }
+}
+
+fn find_command(name: &str) -> Result<&'static Command> {
+    COMMANDS
+        .iter()
+        .find(|c| c.name == name)
+        .with_context(|| format!("Unknown command {}, try help", name))
+}
+
+/// handle a `command args` line, typed with the command prefix in any target
+/// or directly in the matrirc query.
+pub async fn handle_command(matrirc: &Matrirc, origin: &str, line: &str) -> Result<()> {
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    // don't log args, they can contain passwords
+    debug!("Running command {} from {}", name, origin);
+    let command = find_command(name)?;
+    (command.handler)(matrirc, origin, CommandArgs::new(args))
+        .await
+        .map_err(|e| {
+            if e.is::<MissingArgument>() {
+                Error::msg(format!("{} (usage: {} {})", e, command.name, command.usage))
+            } else {
+                e
+            }
+        })
+}
+
+/// help [command|section]
+async fn help(matrirc: &Matrirc, _origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let message = match args.next() {
+        // commands win over sections with the same name
+        Some(name) if find_command(name).is_err() && COMMANDS.iter().any(|c| c.section == name) => {
+            let mut message = format!("Commands in {}:", name);
+            for command in COMMANDS.iter().filter(|c| c.section == name) {
+                message.push_str(&format!(
+                    "\n{} {}: {}",
+                    command.name, command.usage, command.help
+                ));
+            }
+            message
+        }
+        Some(name) => {
+            let command = find_command(name)?;
+            let mut message = format!(
+                "usage: {} {}\n{}",
+                command.name, command.usage, command.help
+            );
+            if !command.details.is_empty() {
+                message.push('\n');
+                message.push_str(command.details);
+            }
+            message
+        }
+        None => {
+            let mut message = match matrirc.config().command_prefix.as_str() {
+                "" => "Available commands (only in this query):".to_string(),
+                prefix => format!(
+                    "Available commands (prefix with {} outside of this query, {}{} sends it as is):",
+                    prefix, prefix, prefix
+                ),
+            };
+            let mut sections: Vec<&str> = COMMANDS.iter().map(|c| c.section).collect();
+            sections.sort_unstable();
+            sections.dedup();
+            for section in sections {
+                message.push_str(&format!("\n[{}]", section));
+                for command in COMMANDS.iter().filter(|c| c.section == section) {
+                    message.push_str(&format!(
+                        "\n  {} {}: {}",
+                        command.name, command.usage, command.help
+                    ));
+                }
+            }
+            message.push_str("\nhelp <command> for details");
+            message
+        }
+    };
+    matrirc.mappings().matrirc_query(message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_commands_sorted() {
+        assert!(COMMANDS.windows(2).all(|w| w[0].name < w[1].name));
+        assert!(COMMANDS.iter().all(|c| !c.section.is_empty()));
+    }
+
+    #[test]
+    fn check_optional_chan() {
+        let mut args = CommandArgs::new("#chan url");
+        assert_eq!(args.optional_chan("origin"), "#chan");
+        assert_eq!(args.rest(), Some("url"));
+        let mut args = CommandArgs::new("url text");
+        assert_eq!(args.optional_chan("origin"), "origin");
+        assert_eq!(args.rest(), Some("url text"));
+        let mut args = CommandArgs::new("");
+        assert_eq!(args.optional_chan("origin"), "origin");
+        assert_eq!(args.rest(), None);
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::{Context, Error, Result};
+use matrix_sdk::{
+    ruma::{
+        api::client::receipt::create_receipt::v3::ReceiptType as SendReceiptType,
+        events::receipt::{Receipt, ReceiptThread, ReceiptType},
+        OwnedEventId,
+    },
+    RoomMemberships,
+};
+
+use crate::commands::CommandArgs;
+use crate::matrirc::Matrirc;
+use crate::matrix::time::ToLocal;
+
+fn check_enabled(matrirc: &Matrirc) -> Result<()> {
+    if !matrirc.config().read_receipts {
+        return Err(Error::msg(
+            "Read receipts are disabled (read_receipts in config.toml)",
+        ));
+    }
+    Ok(())
+}
+
+/// read [#chan]: send read receipt for the last message of a room
+pub async fn read(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    check_enabled(matrirc)?;
+    let name = args.optional_chan(origin);
+    let room = matrirc
+        .mappings()
+        .room(name)
+        .await
+        .with_context(|| format!("No room for {}", name))?;
+    let event_id = matrirc
+        .last_message_get(room.room_id())
+        .await
+        .context("No message seen in room yet")?;
+    let receipt_type = match matrirc.config().private_receipts {
+        true => SendReceiptType::ReadPrivate,
+        false => SendReceiptType::Read,
+    };
+    room.send_single_receipt(receipt_type, ReceiptThread::Unthreaded, event_id)
+        .await?;
+    matrirc
+        .mappings()
+        .matrirc_query(format!("Marked {} as read", name))
+        .await
+}
+
+/// whoread [#chan]: list who read up to the last message of a room
+pub async fn whoread(matrirc: &Matrirc, origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    check_enabled(matrirc)?;
+    let name = args.optional_chan(origin);
+    let room = matrirc
+        .mappings()
+        .room(name)
+        .await
+        .with_context(|| format!("No room for {}", name))?;
+    let own_user = matrirc.matrix().user_id().context("client has no user?")?;
+    let last_message = matrirc.last_message_get(room.room_id()).await;
+
+    let mut read = vec![];
+    let mut behind = vec![];
+    for member in room.members(RoomMemberships::JOIN).await? {
+        if member.user_id() == own_user {
+            continue;
+        }
+        let mut receipt: Option<(OwnedEventId, Receipt)> = None;
+        // clients aware of threads send receipts for main timeline instead of unthreaded
+        for thread in [ReceiptThread::Unthreaded, ReceiptThread::Main] {
+            if let Some((event_id, r)) = room
+                .load_user_receipt(ReceiptType::Read, thread, member.user_id())
+                .await?
+            {
+                if receipt.as_ref().map_or(true, |(_, prev)| prev.ts < r.ts) {
+                    receipt = Some((event_id, r));
+                }
+            }
+        }
+        match receipt {
+            Some((event_id, _)) if Some(&event_id) == last_message.as_ref() => {
+                read.push(member.name().to_string())
+            }
+            Some((_, r)) => behind.push(format!(
+                "{} (last read {})",
+                member.name(),
+                r.ts.and_then(|ts| ts.localtime(&matrirc.config().timestamps))
+                    .unwrap_or_else(|| "just now".to_string())
+            )),
+            None => behind.push(format!("{} (no receipt)", member.name())),
+        }
+    }
+    let mut message = format!("Read receipts for {}:", name);
+    if last_message.is_none() {
+        message.push_str(" (no message seen since connection)");
+    }
+    if !read.is_empty() {
+        message.push_str(&format!("\nUp to date: {}", read.join(", ")));
+    }
+    if !behind.is_empty() {
+        message.push_str(&format!("\nBehind: {}", behind.join(", ")));
+    }
+    matrirc.mappings().matrirc_query(message).await
+}
@@ -0,0 +1,221 @@
+use anyhow::{Context, Error, Result};
+use matrix_sdk::{
+    notification_settings::{IsEncrypted, IsOneToOne, NotificationSettings, RoomNotificationMode},
+    room::Room,
+    ruma::{OwnedRoomOrAliasId, OwnedUserId},
+};
+
+use crate::commands::CommandArgs;
+use crate::config::Joins;
+use crate::matrirc::Matrirc;
+use crate::matrix::unread_counts;
+
+/// rooms: list rooms with their irc name
+pub async fn rooms(matrirc: &Matrirc, _origin: &str, _args: CommandArgs<'_>) -> Result<()> {
+    let mut lines = vec![];
+    for (room_id, target) in matrirc.mappings().list_rooms().await {
+        let name = match matrirc.matrix().get_room(&room_id) {
+            Some(room) => matrirc.mappings().room_name(&room).await,
+            None => room_id.to_string(),
+        };
+        lines.push(format!(
+            "{}: {} ({})",
+            target.irc_name().await,
+            name,
+            room_id
+        ));
+    }
+    lines.sort();
+    lines.insert(0, format!("{} rooms:", lines.len()));
+    matrirc.mappings().matrirc_query(lines.join("\n")).await
+}
+
+/// unread: list rooms with unread messages and highlights
+pub async fn unread(matrirc: &Matrirc, _origin: &str, _args: CommandArgs<'_>) -> Result<()> {
+    let mut unread = vec![];
+    for (room_id, target) in matrirc.mappings().list_rooms().await {
+        let Some(room) = matrirc.matrix().get_room(&room_id) else {
+            continue;
+        };
+        let (messages, highlights) = unread_counts(&room);
+        if messages == 0 && highlights == 0 {
+            continue;
+        }
+        unread.push((highlights, messages, target.irc_name().await));
+    }
+    if unread.is_empty() {
+        return matrirc.mappings().matrirc_query("No unread messages").await;
+    }
+    // most highlights first, then busiest
+    unread.sort_by(|a, b| b.cmp(a));
+    let mut lines = vec![format!("{} rooms with unread messages:", unread.len())];
+    lines.extend(
+        unread
+            .into_iter()
+            .map(|(highlights, messages, name)| match highlights {
+                0 => format!("{}: {} unread", name, messages),
+                _ => format!("{}: {} unread, {} highlights", name, messages, highlights),
+            }),
+    );
+    matrirc.mappings().matrirc_query(lines.join("\n")).await
+}
+
+/// joins [#chan] [off|smart|on|notice|default]: show or set which joins and parts
+/// are sent to irc for room
+pub async fn joins(matrirc: &Matrirc, origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    let mut rest = args;
+    let name = rest.optional_chan(origin);
+    let room = matrirc
+        .mappings()
+        .room(name)
+        .await
+        .with_context(|| format!("No room for {}", name))?;
+    let message = match rest.next() {
+        None => format!(
+            "joins in {}: {}",
+            name,
+            matrirc.joins(room.room_id()).as_str()
+        ),
+        Some("default") => {
+            matrirc.joins_set(room.room_id(), None).await?;
+            format!(
+                "joins in {} reset to default ({})",
+                name,
+                matrirc.joins(room.room_id()).as_str()
+            )
+        }
+        Some(mode) => {
+            let joins: Joins = mode.parse()?;
+            matrirc.joins_set(room.room_id(), Some(joins)).await?;
+            format!("joins in {} set to {}", name, joins.as_str())
+        }
+    };
+    matrirc.mappings().matrirc_query(message).await
+}
+
+fn notify_str(mode: RoomNotificationMode) -> &'static str {
+    match mode {
+        RoomNotificationMode::AllMessages => "all",
+        RoomNotificationMode::MentionsAndKeywordsOnly => "mentions",
+        RoomNotificationMode::Mute => "mute",
+    }
+}
+
+/// notification mode of room from account push rules: user defined or
+/// server default
+async fn notify_mode(settings: &NotificationSettings, room: &Room) -> (RoomNotificationMode, bool) {
+    if let Some(mode) = settings
+        .get_user_defined_room_notification_mode(room.room_id())
+        .await
+    {
+        return (mode, false);
+    }
+    let is_encrypted = match room.is_encrypted().await.unwrap_or(false) {
+        true => IsEncrypted::Yes,
+        false => IsEncrypted::No,
+    };
+    let is_one_to_one = match room.active_members_count() == 2 {
+        true => IsOneToOne::Yes,
+        false => IsOneToOne::No,
+    };
+    let mode = settings
+        .get_default_room_notification_mode(is_encrypted, is_one_to_one)
+        .await;
+    (mode, true)
+}
+
+/// notify [#chan] [all|mentions|mute|default]: show or set push notification
+/// mode of room, shared with other matrix clients
+pub async fn notify(matrirc: &Matrirc, origin: &str, args: CommandArgs<'_>) -> Result<()> {
+    let mut rest = args;
+    let name = rest.optional_chan(origin);
+    let room = matrirc
+        .mappings()
+        .room(name)
+        .await
+        .with_context(|| format!("No room for {}", name))?;
+    // the sdk would silently use empty rules and show wrong modes
+    matrirc
+        .matrix()
+        .account()
+        .push_rules()
+        .await
+        .context("Could not get push rules")?;
+    let settings = matrirc.matrix().notification_settings().await;
+    let message = match rest.next() {
+        None => {
+            let (mode, default) = notify_mode(&settings, &room).await;
+            format!(
+                "notifications in {}: {}{}",
+                name,
+                notify_str(mode),
+                if default { " (default)" } else { "" }
+            )
+        }
+        Some("default") => {
+            settings
+                .delete_user_defined_room_rules(room.room_id())
+                .await?;
+            let (mode, _) = notify_mode(&settings, &room).await;
+            format!(
+                "notifications in {} reset to default ({})",
+                name,
+                notify_str(mode)
+            )
+        }
+        Some(mode) => {
+            let mode = match mode {
+                "all" => RoomNotificationMode::AllMessages,
+                "mentions" => RoomNotificationMode::MentionsAndKeywordsOnly,
+                "mute" => RoomNotificationMode::Mute,
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Invalid notification mode {}: use all, mentions, mute or default",
+                        mode
+                    )))
+                }
+            };
+            settings
+                .set_room_notification_mode(room.room_id(), mode)
+                .await?;
+            format!("notifications in {} set to {}", name, notify_str(mode))
+        }
+    };
+    matrirc.mappings().matrirc_query(message).await
+}
+
+/// join <#alias|!roomid>: join matrix room
+pub async fn join(matrirc: &Matrirc, _origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let id: OwnedRoomOrAliasId = args
+        .required("room")?
+        .try_into()
+        .context("Invalid room id or alias")?;
+    let room = matrirc.matrix().join_room_by_id_or_alias(&id, &[]).await?;
+    let target = matrirc.mappings().room_target(&room).await;
+    matrirc
+        .mappings()
+        .matrirc_query(format!("Joined {} as {}", id, target.irc_name().await))
+        .await
+}
+
+/// dm <@user>: open direct chat with user, creating it if required
+pub async fn dm(matrirc: &Matrirc, _origin: &str, mut args: CommandArgs<'_>) -> Result<()> {
+    let user: OwnedUserId = args
+        .required("user")?
+        .try_into()
+        .context("Invalid user id")?;
+    let client = matrirc.matrix();
+    let room = match client.get_dm_room(&user) {
+        Some(room) => room,
+        None => client.create_dm(&user).await?,
+    };
+    let target = matrirc.mappings().room_target(&room).await;
+    matrirc
+        .mappings()
+        .matrirc_query(format!(
+            "Direct chat with {} is {}",
+            user,
+            target.irc_name().await
+        ))
+        .await
+}
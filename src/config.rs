@@ -0,0 +1,580 @@
+use anyhow::{Context, Error, Result};
+use chrono::{
+    format::{Item, StrftimeItems},
+    FixedOffset,
+};
+use log::debug;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::args::{args, invite_pattern};
+use crate::matrix::shortcodes;
+
+/// what to do with room invitations
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoJoin {
+    /// prompt in an 'invite' query
+    #[default]
+    Ask,
+    /// join immediately
+    Always,
+    /// only mention it in matrirc query
+    Never,
+}
+
+/// which member joins and parts are sent to irc, set per room with the
+/// `joins` command (default from show_joins)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Joins {
+    Off,
+    /// only members who spoke recently
+    Smart,
+    On,
+    /// batched in a notice every joins_notice_seconds instead of JOIN/PART
+    Notice,
+}
+
+impl Joins {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Joins::Off => "off",
+            Joins::Smart => "smart",
+            Joins::On => "on",
+            Joins::Notice => "notice",
+        }
+    }
+}
+
+impl std::str::FromStr for Joins {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Joins> {
+        match s {
+            "off" => Ok(Joins::Off),
+            "smart" => Ok(Joins::Smart),
+            "on" => Ok(Joins::On),
+            "notice" => Ok(Joins::Notice),
+            _ => Err(Error::msg(format!(
+                "expected off, smart, on or notice, got {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// which messages of encrypted rooms get a `[!]` marker
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustMarkers {
+    #[default]
+    Off,
+    /// devices not signed by their owner, or unknown
+    Devices,
+    /// also any user we haven't verified
+    All,
+}
+
+/// whether reactions are also sent as `+draft/react` TAGMSG to clients
+/// with message-tags, when message ids are sent as tags
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReactionTags {
+    #[default]
+    Off,
+    /// in addition to the usual text
+    Also,
+    /// instead of the text, if a TAGMSG could be sent
+    Only,
+}
+
+/// when bridged messages get a timestamp prefix
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampMode {
+    /// only messages older than recent_seconds (e.g. backlog)
+    #[default]
+    Auto,
+    Always,
+    /// never, e.g. for clients using server-time tags
+    Off,
+}
+
+/// strftime-like formats used for message timestamps
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimestampFormat {
+    /// messages less than date_hours old
+    pub time: String,
+    /// older messages
+    pub date: String,
+    pub mode: TimestampMode,
+    /// fixed offset such as "+09:00" or "UTC", server local time if unset
+    pub timezone: Option<String>,
+    /// messages more recent than this get no timestamp in auto mode
+    pub recent_seconds: u64,
+    /// messages older than this are shown with date format
+    pub date_hours: u64,
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat {
+            time: "%H:%M:%S".to_string(),
+            date: "%Y-%m-%d %H:%M:%S".to_string(),
+            mode: TimestampMode::default(),
+            timezone: None,
+            recent_seconds: 10,
+            date_hours: 12,
+        }
+    }
+}
+
+impl TimestampFormat {
+    /// timezone override, checked when loading config
+    pub fn offset(&self) -> Option<FixedOffset> {
+        match self.timezone.as_deref()? {
+            "UTC" | "Z" => FixedOffset::east_opt(0),
+            offset => offset.parse().ok(),
+        }
+    }
+}
+
+/// what to do when saving media would go over quota
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPolicy {
+    /// don't save the new file
+    #[default]
+    Refuse,
+    /// remove oldest files until new one fits
+    Evict,
+}
+
+/// on-disk format of message logs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `date <sender> text`
+    #[default]
+    Plain,
+    /// `date\tsender\ttext`, as weechat logger
+    Weechat,
+    /// one json object per line
+    Jsonl,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// rotate room log when it gets bigger than this (bytes)
+    pub max_size: u64,
+    /// number of rotated files to keep
+    pub keep: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::default(),
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// messages a filter applies to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterDirection {
+    /// received from matrix
+    In,
+    /// sent from irc
+    Out,
+    #[default]
+    Both,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FilterKind {
+    /// replace regex matches, `$1` for capture groups
+    Rewrite { pattern: String, replace: String },
+    /// drop messages matching regex
+    Block { pattern: String },
+    /// shell command with text on stdin, output replaces it
+    Command { command: String },
+}
+
+/// message filter, see filters.rs
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FilterConfig {
+    #[serde(flatten)]
+    pub kind: FilterKind,
+    #[serde(default)]
+    pub direction: FilterDirection,
+    /// irc chan or query names (* matches anything), all rooms if empty
+    #[serde(default)]
+    pub rooms: Vec<String>,
+}
+
+/// how many recent messages to remember (for reactions, replies...)
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageCacheConfig {
+    pub size: u32,
+    /// keep `size` messages for each room instead of overall, so busy rooms
+    /// don't push out quiet ones
+    pub per_room: bool,
+}
+
+impl Default for MessageCacheConfig {
+    fn default() -> Self {
+        MessageCacheConfig {
+            size: 1000,
+            per_room: false,
+        }
+    }
+}
+
+/// per-user preferences, read from config.toml in user state dir.
+/// Everything is optional and defaults to command line values when there is one.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub autojoin: AutoJoin,
+    pub timestamps: TimestampFormat,
+    /// overrides --media-dir
+    media_dir: Option<String>,
+    /// overrides --media-url
+    media_url: Option<String>,
+    /// overrides --proxy
+    proxy: Option<String>,
+    /// max bytes of media saved for this user, unlimited if unset
+    pub media_quota: Option<u64>,
+    pub media_quota_policy: QuotaPolicy,
+    /// overrides --media-max-size
+    media_max_size: Option<u64>,
+    /// images bigger than this (bytes) are shown as their thumbnail, if
+    /// they have one. Disabled if unset
+    pub image_thumbnails: Option<u64>,
+    /// overrides --autoaccept-invites
+    autoaccept_invites: Option<Vec<String>>,
+    /// chan names template, with {name}, {server} and {alias-localpart}
+    pub chan_name: String,
+    /// present every room as a query, with `<nick>` prefixes for members
+    pub force_queries: bool,
+    /// direct chats with users matching these patterns (* matches
+    /// anything) are chans instead of queries
+    pub dm_chans: Vec<String>,
+    /// in queries, name other members in a header once per burst of
+    /// messages instead of prefixing every line with `<nick>`
+    pub query_bursts: bool,
+    /// send irc JOIN/PART when members join or leave rooms
+    pub show_joins: bool,
+    /// membership changes in `joins notice` rooms are batched that long, and
+    /// joins/parts held while the irc queue is busy are sent after it
+    pub joins_notice_seconds: u64,
+    /// reactions to a message are summarized after that many seconds,
+    /// 0 sends each reaction on its own line
+    pub reactions_seconds: u64,
+    /// forward reactions as TAGMSG to clients supporting message-tags
+    pub reaction_tags: ReactionTags,
+    /// nicks are matrix id localparts instead of display names, so they
+    /// don't change when people rename themselves
+    pub localpart_nicks: bool,
+    /// case-insensitive keywords: channel messages containing them are
+    /// repeated in the matrirc query
+    pub highlights: Vec<String>,
+    /// messages to rooms with more members than this, or starting with
+    /// @room, are held until `confirm`. Disabled if unset
+    pub confirm_members: Option<u64>,
+    /// after reconnecting, summarize unread mentions and direct messages
+    /// in matrirc query
+    pub digest: bool,
+    /// set matrix presence to unavailable after this many minutes without
+    /// sending anything from irc. Disabled if unset
+    pub idle_minutes: Option<u64>,
+    /// mark messages from unverified devices (or users) in encrypted rooms
+    pub trust_markers: TrustMarkers,
+    /// send a `✓` notice (with message id if enabled) when our messages
+    /// come back from the homeserver
+    pub delivery_acks: bool,
+    /// allow sending (`read`) and showing (`whoread`) read receipts
+    pub read_receipts: bool,
+    /// read receipts sent by `read` are private (m.read.private) and not
+    /// shown to other members
+    pub private_receipts: bool,
+    /// bearer token for --webhook-listen, webhooks are refused if unset
+    pub webhook_token: Option<String>,
+    /// replace `:shortcode:` by emoji in messages we send
+    pub emoji_shortcodes: bool,
+    /// replace emoji by `:shortcode:` in messages we receive
+    pub emoji_to_shortcodes: bool,
+    /// code blocks longer than this are written to media dir and linked,
+    /// 0 to disable
+    pub paste_lines: usize,
+    /// messages starting with this are commands, doubling it sends it
+    /// literally. Empty means commands only work in matrirc query
+    pub command_prefix: String,
+    /// log bridged messages to files, disabled if unset
+    pub log: Option<LogConfig>,
+    /// message filters applied in order, see filters.rs
+    pub filters: Vec<FilterConfig>,
+    pub message_cache: MessageCacheConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            autojoin: AutoJoin::default(),
+            timestamps: TimestampFormat::default(),
+            media_dir: None,
+            media_url: None,
+            proxy: None,
+            media_quota: None,
+            media_quota_policy: QuotaPolicy::default(),
+            media_max_size: None,
+            image_thumbnails: None,
+            autoaccept_invites: None,
+            chan_name: "{name}".to_string(),
+            force_queries: false,
+            dm_chans: vec![],
+            query_bursts: false,
+            show_joins: true,
+            joins_notice_seconds: 60,
+            reactions_seconds: 5,
+            reaction_tags: ReactionTags::default(),
+            localpart_nicks: false,
+            highlights: vec![],
+            confirm_members: None,
+            digest: false,
+            idle_minutes: None,
+            trust_markers: TrustMarkers::default(),
+            delivery_acks: false,
+            read_receipts: true,
+            private_receipts: false,
+            webhook_token: None,
+            emoji_shortcodes: false,
+            emoji_to_shortcodes: false,
+            paste_lines: 10,
+            command_prefix: "\\".to_string(),
+            log: None,
+            filters: vec![],
+            message_cache: MessageCacheConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// load config for user, missing file is the same as empty config
+    pub fn load(nick: &str) -> Result<Config> {
+        let path = Path::new(&args().state_dir).join(nick).join("config.toml");
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e).context("Could not read config.toml"),
+        };
+        debug!("Loading config {}", path.display());
+        Config::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Config> {
+        let config: Config = toml::from_str(text).context("Invalid config.toml")?;
+        // chrono panics when formatting with an invalid format, check early
+        for format in [&config.timestamps.time, &config.timestamps.date] {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(Error::msg(format!("Invalid timestamp format {}", format)));
+            }
+        }
+        if let Some(timezone) = &config.timestamps.timezone {
+            if config.timestamps.offset().is_none() {
+                return Err(Error::msg(format!(
+                    "Invalid timezone {} (expected UTC or +HH:MM)",
+                    timezone
+                )));
+            }
+        }
+        for pattern in config.autoaccept_invites.iter().flatten() {
+            invite_pattern(pattern).map_err(|e| {
+                Error::msg(format!("Invalid autoaccept_invites {}: {}", pattern, e))
+            })?;
+        }
+        Ok(config)
+    }
+
+    pub fn media_dir(&self) -> Option<&String> {
+        self.media_dir.as_ref().or(args().media_dir.as_ref())
+    }
+
+    pub fn media_url(&self) -> Option<&String> {
+        self.media_url.as_ref().or(args().media_url.as_ref())
+    }
+
+    pub fn media_max_size(&self) -> Option<u64> {
+        self.media_max_size.or(args().media_max_size)
+    }
+
+    pub fn proxy(&self) -> Option<&String> {
+        self.proxy.as_ref().or(args().proxy.as_ref())
+    }
+
+    /// outgoing message text after configured transformations
+    pub fn outgoing_text(&self, text: &str) -> String {
+        if self.emoji_shortcodes {
+            shortcodes::expand(text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// incoming message text after configured transformations
+    pub fn incoming_text(&self, text: &str) -> String {
+        if self.emoji_to_shortcodes {
+            shortcodes::transliterate(text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// command line if message is a command (prefixed, but not escaped)
+    pub fn command_line<'a>(&self, message: &'a str) -> Option<&'a str> {
+        if self.command_prefix.is_empty() {
+            return None;
+        }
+        let line = message.strip_prefix(&self.command_prefix)?;
+        (!line.starts_with(&self.command_prefix)).then_some(line)
+    }
+
+    /// message to send for non-command, i.e. with escaped prefix undoubled
+    pub fn unescape_command<'a>(&self, message: &'a str) -> &'a str {
+        if self.command_prefix.is_empty() {
+            return message;
+        }
+        match message.strip_prefix(&self.command_prefix) {
+            Some(rest) if rest.starts_with(&self.command_prefix) => rest,
+            _ => message,
+        }
+    }
+
+    /// invitation from sender should be joined without asking
+    pub fn autoaccept_invite(&self, sender: &str) -> bool {
+        autoaccept_matches(
+            self.autoaccept_invites
+                .as_ref()
+                .unwrap_or(&args().autoaccept_invites),
+            sender,
+        )
+    }
+
+    pub fn is_highlight(&self, message: &str) -> bool {
+        if self.highlights.is_empty() {
+            return false;
+        }
+        let message = message.to_lowercase();
+        self.highlights
+            .iter()
+            .any(|keyword| message.contains(&keyword.to_lowercase()))
+    }
+}
+
+fn autoaccept_matches(patterns: &[String], sender: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        pattern
+            .strip_prefix("from:")
+            .is_some_and(|pattern| glob_match(pattern, sender))
+    })
+}
+
+/// match text against pattern where * matches any (possibly empty) string
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one item
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no *: must be exact
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_autoaccept() -> Result<()> {
+        let patterns = vec![
+            "from:@*:trusted.server".to_string(),
+            "from:@bot".to_string(),
+        ];
+        assert!(autoaccept_matches(&patterns, "@alice:trusted.server"));
+        assert!(autoaccept_matches(&patterns, "@bot"));
+        assert!(!autoaccept_matches(&patterns, "@bot:other.server"));
+        assert!(!autoaccept_matches(&patterns, "@alice:untrusted.server"));
+        assert!(!autoaccept_matches(&patterns, "@eve:trusted.server.evil"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        assert!(Config::parse("autoaccept_invites = [\"@bot:server\"]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_config_parse() -> Result<()> {
+        assert_eq!(Config::parse("")?, Config::default());
+        let default = Config::default();
+        assert_eq!(default.command_line("\\help"), Some("help"));
+        assert_eq!(default.command_line("\\\\o/"), None);
+        assert_eq!(default.unescape_command("\\\\o/"), "\\o/");
+        assert_eq!(default.unescape_command("hi"), "hi");
+        let config = Config::parse(
+            r#"
+autojoin = "always"
+show_joins = false
+highlights = ["Matrirc"]
+[timestamps]
+time = "%H:%M"
+"#,
+        )?;
+        assert_eq!(config.autojoin, AutoJoin::Always);
+        assert!(!config.show_joins);
+        assert_eq!(config.timestamps.time, "%H:%M");
+        assert_eq!(config.timestamps.date, TimestampFormat::default().date);
+        assert!(config.is_highlight("hello matrirc!"));
+        assert!(!config.is_highlight("hello"));
+        assert_eq!(config.log, None);
+        let config = Config::parse("[log]\nformat = \"jsonl\"")?;
+        assert_eq!(
+            config.log,
+            Some(LogConfig {
+                format: LogFormat::Jsonl,
+                ..LogConfig::default()
+            })
+        );
+        let config = Config::parse("reaction_tags = \"only\"")?;
+        assert_eq!(config.reaction_tags, ReactionTags::Only);
+        let config = Config::parse("trust_markers = \"devices\"")?;
+        assert_eq!(config.trust_markers, TrustMarkers::Devices);
+        assert!(Config::parse("typo = 1").is_err());
+        assert!(Config::parse("[timestamps]\ntime = \"%Q\"").is_err());
+        let config = Config::parse("[timestamps]\nmode = \"off\"\ntimezone = \"+09:00\"")?;
+        assert_eq!(config.timestamps.mode, TimestampMode::Off);
+        assert_eq!(config.timestamps.offset(), FixedOffset::east_opt(9 * 3600));
+        assert!(Config::parse("[timestamps]\ntimezone = \"Mars/Olympus\"").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::{Context, Error, Result};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use log::{trace, warn};
+use regex::Regex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use crate::config::{glob_match, FilterConfig, FilterDirection, FilterKind};
+
+/// command filters taking longer than this leave the message unchanged
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// messages waiting for slow filters per room before sync waits too
+const QUEUE_SIZE: usize = 100;
+
+/// one step of the filter pipeline
+#[async_trait]
+pub trait Filter: Send + Sync {
+    /// transformed text, or None to drop the message
+    async fn apply(&self, text: String) -> Result<Option<String>>;
+    /// filter can take a while, keep it off the sync loop
+    fn is_slow(&self) -> bool {
+        false
+    }
+}
+
+/// replace regex matches, `$1` refers to capture groups
+struct Rewrite {
+    regex: Regex,
+    replace: String,
+}
+
+#[async_trait]
+impl Filter for Rewrite {
+    async fn apply(&self, text: String) -> Result<Option<String>> {
+        Ok(Some(
+            self.regex.replace_all(&text, &self.replace).into_owned(),
+        ))
+    }
+}
+
+/// drop messages matching regex
+struct Block {
+    regex: Regex,
+}
+
+#[async_trait]
+impl Filter for Block {
+    async fn apply(&self, text: String) -> Result<Option<String>> {
+        Ok((!self.regex.is_match(&text)).then_some(text))
+    }
+}
+
+/// shell command getting text on stdin, its output replaces the text (e.g.
+/// translation); no output drops the message
+struct ShellCommand {
+    command: String,
+}
+
+#[async_trait]
+impl Filter for ShellCommand {
+    async fn apply(&self, text: String) -> Result<Option<String>> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().context("no stdin")?;
+        let output = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            stdin.write_all(text.as_bytes()).await?;
+            drop(stdin);
+            child.wait_with_output().await
+        })
+        .await
+        .context("filter command timed out")??;
+        if !output.status.success() {
+            return Err(Error::msg(format!(
+                "filter command failed: {}",
+                output.status
+            )));
+        }
+        let output = String::from_utf8(output.stdout)?;
+        let output = output.trim_end();
+        Ok((!output.is_empty()).then(|| output.to_string()))
+    }
+
+    fn is_slow(&self) -> bool {
+        true
+    }
+}
+
+struct Rule {
+    direction: FilterDirection,
+    /// irc names (chans with #) the rule applies to, all if empty
+    rooms: Vec<String>,
+    filter: Box<dyn Filter>,
+}
+
+impl Rule {
+    fn applies(&self, direction: FilterDirection, room: &str) -> bool {
+        (matches!(self.direction, FilterDirection::Both) || self.direction == direction)
+            && (self.rooms.is_empty()
+                || self
+                    .rooms
+                    .iter()
+                    .any(|pattern| glob_match(&pattern.to_lowercase(), room)))
+    }
+}
+
+/// per-user pipeline of filters from config.toml, applied in order
+pub struct Filters {
+    rules: Vec<Rule>,
+    /// per-room queues running messages through slow filters in order
+    queues: Mutex<HashMap<String, mpsc::Sender<BoxFuture<'static, ()>>>>,
+}
+
+impl Filters {
+    pub fn new(configs: &[FilterConfig]) -> Result<Filters> {
+        let mut rules = vec![];
+        for config in configs {
+            let filter: Box<dyn Filter> = match &config.kind {
+                FilterKind::Rewrite { pattern, replace } => Box::new(Rewrite {
+                    regex: Regex::new(pattern).context("Invalid filter pattern")?,
+                    replace: replace.clone(),
+                }),
+                FilterKind::Block { pattern } => Box::new(Block {
+                    regex: Regex::new(pattern).context("Invalid filter pattern")?,
+                }),
+                FilterKind::Command { command } => Box::new(ShellCommand {
+                    command: command.clone(),
+                }),
+            };
+            rules.push(Rule {
+                direction: config.direction,
+                rooms: config.rooms.clone(),
+                filter,
+            });
+        }
+        Ok(Filters {
+            rules,
+            queues: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// incoming messages of room go through a slow filter
+    pub fn incoming_slow(&self, room: &str) -> bool {
+        let room = room.to_lowercase();
+        self.rules
+            .iter()
+            .any(|rule| rule.filter.is_slow() && rule.applies(FilterDirection::In, &room))
+    }
+
+    /// run task after previous tasks queued for the same room, in the background
+    pub async fn queue(&self, room: &str, task: impl Future<Output = ()> + Send + 'static) {
+        let sender = self
+            .queues
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(room.to_lowercase())
+            .or_insert_with(|| {
+                let (sender, mut receiver) = mpsc::channel::<BoxFuture<'static, ()>>(QUEUE_SIZE);
+                tokio::spawn(async move {
+                    while let Some(task) = receiver.recv().await {
+                        task.await;
+                    }
+                });
+                sender
+            })
+            .clone();
+        if sender.send(Box::pin(task)).await.is_err() {
+            warn!("Filter queue for {} stopped", room);
+        }
+    }
+
+    /// text of message from room, None if it should be dropped
+    pub async fn incoming(&self, room: &str, text: String) -> Option<String> {
+        self.run(FilterDirection::In, room, text).await
+    }
+
+    /// text of message sent to room, None if it should be dropped
+    pub async fn outgoing(&self, room: &str, text: String) -> Option<String> {
+        self.run(FilterDirection::Out, room, text).await
+    }
+
+    async fn run(
+        &self,
+        direction: FilterDirection,
+        room: &str,
+        mut text: String,
+    ) -> Option<String> {
+        let room = room.to_lowercase();
+        for rule in &self.rules {
+            if !rule.applies(direction, &room) {
+                continue;
+            }
+            text = match rule.filter.apply(text.clone()).await {
+                Ok(Some(text)) => text,
+                Ok(None) => {
+                    trace!("Message to/from {} dropped by filter", room);
+                    return None;
+                }
+                Err(e) => {
+                    // keep message rather than losing it
+                    warn!("Filter failed: {:?}", e);
+                    text
+                }
+            };
+        }
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_filters() -> Result<()> {
+        let config: Vec<FilterConfig> = toml::from_str::<toml::Table>(
+            r##"
+            [[filters]]
+            type = "rewrite"
+            pattern = "colou?r"
+            replace = "hue"
+            [[filters]]
+            type = "block"
+            pattern = "^spam"
+            direction = "in"
+            rooms = ["#ads*"]
+            [[filters]]
+            type = "command"
+            command = "translate"
+            direction = "in"
+            rooms = ["#foreign"]
+            "##,
+        )?["filters"]
+            .clone()
+            .try_into()?;
+        let filters = Filters::new(&config)?;
+        assert_eq!(
+            filters.outgoing("#chan", "nice colour".to_string()).await,
+            Some("nice hue".to_string())
+        );
+        assert_eq!(
+            filters.incoming("#ads-room", "spam!".to_string()).await,
+            None
+        );
+        assert_eq!(
+            filters.outgoing("#ads-room", "spam!".to_string()).await,
+            Some("spam!".to_string())
+        );
+        assert_eq!(
+            filters.incoming("#chan", "spam!".to_string()).await,
+            Some("spam!".to_string())
+        );
+        assert!(filters.incoming_slow("#Foreign"));
+        assert!(!filters.incoming_slow("#chan"));
+        assert!(Filters::new(&[FilterConfig {
+            kind: FilterKind::Block {
+                pattern: "(".to_string()
+            },
+            direction: FilterDirection::Both,
+            rooms: vec![],
+        }])
+        .is_err());
+        Ok(())
+    }
+}
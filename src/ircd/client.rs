@@ -1,10 +1,107 @@
 use anyhow::Result;
 use irc::client::prelude::Message;
+use irc::proto::message::Tag;
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::sync::{mpsc, Mutex};
 
 use crate::ircd::proto;
 
+tokio::task_local! {
+    /// labeled client message being handled, only set in the irc read task
+    /// so messages sent from other tasks (e.g. matrix sync) are not labeled
+    static LABELED: RefCell<Option<Labeled>>;
+}
+
+/// run irc read loop f, allowing it to use start_labeled
+pub async fn with_labels<F: Future>(f: F) -> F::Output {
+    LABELED.scope(RefCell::new(None), f).await
+}
+
+struct Labeled {
+    label: String,
+    /// labeled-response batch, opened with the first reply so replies are
+    /// sent as they come
+    batch: Option<String>,
+    sent: bool,
+}
+
+impl Labeled {
+    /// tag reply, returning the batch start message to send first if needed
+    fn tag(&mut self, msg: &mut Message, batch_cap: bool) -> Option<Message> {
+        self.sent = true;
+        let tags = msg.tags.get_or_insert_with(Vec::new);
+        if !batch_cap {
+            tags.push(Tag("label".to_string(), Some(self.label.clone())));
+            return None;
+        }
+        // nested batches (e.g. chathistory) are started in ours
+        let start = self.batch.is_none().then(|| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            let batch = format!("labeled{}", now.as_millis());
+            let mut start = proto::raw_msg(format!(":matrirc BATCH +{} labeled-response", batch));
+            start.tags = Some(vec![Tag("label".to_string(), Some(self.label.clone()))]);
+            self.batch = Some(batch);
+            start
+        });
+        if !tags.iter().any(|Tag(key, _)| key == "batch") {
+            tags.push(Tag("batch".to_string(), self.batch.clone()));
+        }
+        start
+    }
+}
+
+/// low priority membership change, see send_low_priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Membership {
+    Join,
+    Part,
+}
+
+/// joins/parts held while the queue was busy. A join and a part of the same
+/// nick cancel out, so only the net change is sent and the client's nick
+/// list still ends up right.
+#[derive(Debug, Default)]
+struct HeldMembership {
+    /// chan -> nick -> change not sent yet
+    chans: HashMap<String, HashMap<String, Membership>>,
+    /// changes held, including those that cancelled out
+    count: usize,
+}
+
+impl HeldMembership {
+    fn push(&mut self, chan: &str, nick: &str, change: Membership) {
+        self.count += 1;
+        let nicks = self.chans.entry(chan.to_string()).or_default();
+        match nicks.get(nick) {
+            Some(held) if *held != change => {
+                nicks.remove(nick);
+            }
+            _ => {
+                nicks.insert(nick.to_string(), change);
+            }
+        }
+    }
+
+    fn messages(&self) -> Vec<Message> {
+        self.chans
+            .iter()
+            .flat_map(|(chan, nicks)| {
+                nicks.iter().map(move |(nick, change)| match change {
+                    Membership::Join => proto::join(Some(nick), chan),
+                    Membership::Part => proto::part(Some(nick), chan),
+                })
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IrcClient {
     /// Avoid waiting on network: queue messages for another task
@@ -13,19 +110,142 @@ pub struct IrcClient {
     pub sink: Arc<Mutex<mpsc::Sender<Message>>>,
     pub nick: String,
     pub user: String,
+    /// IRCv3 capabilities negotiated by client
+    pub caps: Vec<String>,
+    /// low priority messages held while queue was busy
+    held: Arc<std::sync::Mutex<HeldMembership>>,
 }
 
 impl IrcClient {
-    pub fn new(sink: mpsc::Sender<Message>, nick: String, user: String) -> IrcClient {
+    pub fn new(
+        sink: mpsc::Sender<Message>,
+        nick: String,
+        user: String,
+        caps: Vec<String>,
+    ) -> IrcClient {
         IrcClient {
             sink: Arc::new(Mutex::new(sink)),
             nick,
             user,
+            caps,
+            held: Default::default(),
         }
     }
 
-    pub async fn send(&self, msg: Message) -> Result<()> {
-        self.sink.lock().await.send(msg).await?;
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.iter().any(|c| c == cap)
+    }
+
+    pub async fn send(&self, mut msg: Message) -> Result<()> {
+        let batch_cap = self.has_cap("batch");
+        let start = LABELED
+            .try_with(|labeled| {
+                labeled
+                    .borrow_mut()
+                    .as_mut()
+                    .and_then(|labeled| labeled.tag(&mut msg, batch_cap))
+            })
+            .ok()
+            .flatten();
+        let sink = self.sink.lock().await;
+        if let Some(start) = start {
+            sink.send(start).await?;
+        }
+        sink.send(msg).await?;
+        Ok(())
+    }
+
+    /// replies to client message with label (IRCv3 labeled-response) until
+    /// end_labeled. Only applies to messages sent from the current task, and
+    /// only within with_labels
+    pub fn start_labeled(&self, label: Option<String>) {
+        let Some(label) = label.filter(|_| self.has_cap("labeled-response")) else {
+            return;
+        };
+        let _ = LABELED.try_with(|labeled| {
+            *labeled.borrow_mut() = Some(Labeled {
+                label,
+                batch: None,
+                sent: false,
+            })
+        });
+    }
+
+    /// close labeled-response batch, or acknowledge if nothing was sent
+    pub async fn end_labeled(&self) -> Result<()> {
+        let Some(labeled) = LABELED
+            .try_with(|labeled| labeled.borrow_mut().take())
+            .ok()
+            .flatten()
+        else {
+            return Ok(());
+        };
+        let end = match (labeled.sent, labeled.batch) {
+            (false, _) => {
+                let mut ack = proto::raw_msg(":matrirc ACK");
+                ack.tags = Some(vec![Tag("label".to_string(), Some(labeled.label))]);
+                ack
+            }
+            (true, Some(batch)) => proto::raw_msg(format!(":matrirc BATCH -{}", batch)),
+            (true, None) => return Ok(()),
+        };
+        self.sink.lock().await.send(end).await?;
+        Ok(())
+    }
+
+    /// send join or part of nick in chan, unless the queue is more than
+    /// 3/4 full: then it is held so it doesn't delay real messages, and
+    /// merged with other held changes until the next one that goes through,
+    /// or after flush_delay
+    pub async fn send_low_priority(
+        &self,
+        chan: &str,
+        nick: &str,
+        change: Membership,
+        flush_delay: Duration,
+    ) -> Result<()> {
+        let sink = self.sink.lock().await;
+        if sink.capacity() < sink.max_capacity() / 4 {
+            let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+            let first = held.count == 0;
+            held.push(chan, nick, change);
+            drop(held);
+            if first {
+                let irc = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(flush_delay).await;
+                    let sink = irc.sink.lock().await;
+                    if let Err(e) = irc.send_held(&sink).await {
+                        warn!("Could not send held joins/parts: {:?}", e);
+                    }
+                });
+            }
+            return Ok(());
+        }
+        self.send_held(&sink).await?;
+        let msg = match change {
+            Membership::Join => proto::join(Some(nick), chan),
+            Membership::Part => proto::part(Some(nick), chan),
+        };
+        sink.send(msg).await?;
+        Ok(())
+    }
+
+    /// send net joins/parts held since last flush
+    async fn send_held(&self, sink: &mpsc::Sender<Message>) -> Result<()> {
+        let held = std::mem::take(&mut *self.held.lock().unwrap_or_else(|e| e.into_inner()));
+        if held.count == 0 {
+            return Ok(());
+        }
+        let messages = held.messages();
+        info!(
+            "Merged {} joins/parts held while busy into {}",
+            held.count,
+            messages.len()
+        );
+        for msg in messages {
+            sink.send(msg).await?;
+        }
         Ok(())
     }
 
@@ -38,3 +258,35 @@ impl IrcClient {
         self.send(proto::privmsg(from, target, msg)).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_held_membership() {
+        let mut held = HeldMembership::default();
+        held.push("#a", "joined", Membership::Join);
+        held.push("#a", "left", Membership::Part);
+        // in and out, client never saw it
+        held.push("#a", "passing", Membership::Join);
+        held.push("#a", "passing", Membership::Part);
+        // out and back in, client still has it
+        held.push("#b", "back", Membership::Part);
+        held.push("#b", "back", Membership::Join);
+        assert_eq!(held.count, 6);
+        let mut lines: Vec<String> = held
+            .messages()
+            .iter()
+            .map(|msg| msg.to_string().trim_end().to_string())
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                ":joined!joined@matrirc JOIN #a",
+                ":left!left@matrirc PART #a"
+            ]
+        );
+    }
+}
@@ -1,26 +1,54 @@
 use anyhow::{Context, Error, Result};
-use irc::{client::prelude::Command, proto::IrcCodec};
+use irc::{client::prelude::Command, proto::CapSubCommand};
 use log::{debug, info, trace, warn};
-use tokio::net::TcpStream;
+use std::net::IpAddr;
 use tokio::sync::oneshot;
-use tokio_util::codec::Framed;
 // for Framed.tryNext()
 // Note there's also a StreamExt in tokio-stream which covers
 // streams, but we it's not the same and we don't care about the
 // difference here
 use futures::{SinkExt, TryStreamExt};
 use matrix_sdk::{
-    ruma::api::client::session::get_login_types::v3::LoginType, Client as MatrixClient,
+    ruma::api::client::{
+        account::register,
+        session::get_login_types::v3::LoginType,
+        uiaa::{AuthData, AuthType, Dummy, RegistrationToken},
+    },
+    ruma::UserId,
+    Client as MatrixClient,
 };
+use matrix_sdk_store_encryption::StoreCipher;
 
-use crate::{ircd::proto, matrix, state};
+use crate::{
+    args::args,
+    ircd::{proto, throttle, IrcStream},
+    matrix,
+    matrix::room_mappings::NAME_MAX_LEN,
+    matrix::uiaa,
+    state::{self, SessionKey},
+    store,
+};
+
+/// capabilities we know how to handle
+const SUPPORTED_CAPS: &[&str] = &["batch", "labeled-response", "message-tags", "server-time"];
+
+/// everything needed to start a session once the client is authenticated
+pub struct Login {
+    pub nick: String,
+    pub user: String,
+    pub caps: Vec<String>,
+    pub matrix: MatrixClient,
+    pub store_cipher: StoreCipher,
+    pub session_key: SessionKey,
+}
 
-pub async fn auth_loop(
-    stream: &mut Framed<TcpStream, IrcCodec>,
-) -> Result<(String, String, MatrixClient)> {
+pub async fn auth_loop(stream: &mut IrcStream, addr: IpAddr) -> Result<Login> {
     let mut client_nick = None;
     let mut client_user = None;
     let mut client_pass = None;
+    let mut caps = vec![];
+    // registration is suspended until CAP END if client started negotiating
+    let mut cap_negotiation = false;
     while let Some(event) = stream.try_next().await? {
         trace!("auth loop: got {:?}", event);
         match event.command {
@@ -28,13 +56,45 @@ pub async fn auth_loop(
             Command::PASS(pass) => client_pass = Some(pass),
             Command::USER(user, _, _) => {
                 client_user = Some(user);
-                break;
+                if !cap_negotiation {
+                    break;
+                }
             }
             Command::PING(server, server2) => stream.send(proto::pong(server, server2)).await?,
-            Command::CAP(_, _, Some(code), _) => {
-                // required for recent-ish versions of irssi
-                if code == "302" {
-                    stream.send(proto::raw_msg(":matrirc CAP * LS :")).await?;
+            // also required for recent-ish versions of irssi
+            Command::CAP(_, CapSubCommand::LS, _, _) => {
+                cap_negotiation = true;
+                stream
+                    .send(proto::raw_msg(format!(
+                        ":matrirc CAP * LS :{}",
+                        SUPPORTED_CAPS.join(" ")
+                    )))
+                    .await?;
+            }
+            Command::CAP(_, CapSubCommand::REQ, Some(requested), _) => {
+                cap_negotiation = true;
+                let requested: Vec<&str> = requested.split_whitespace().collect();
+                if requested.iter().all(|cap| SUPPORTED_CAPS.contains(cap)) {
+                    caps.extend(requested.iter().map(|cap| cap.to_string()));
+                    stream
+                        .send(proto::raw_msg(format!(
+                            ":matrirc CAP * ACK :{}",
+                            requested.join(" ")
+                        )))
+                        .await?;
+                } else {
+                    stream
+                        .send(proto::raw_msg(format!(
+                            ":matrirc CAP * NAK :{}",
+                            requested.join(" ")
+                        )))
+                        .await?;
+                }
+            }
+            Command::CAP(_, CapSubCommand::END, _, _) => {
+                cap_negotiation = false;
+                if client_user.is_some() {
+                    break;
                 }
             }
             _ => (), // ignore
@@ -52,12 +112,44 @@ pub async fn auth_loop(
             nick
         )))
         .await?;
+    stream
+        .send(proto::raw_msg(format!(
+            ":matrirc 005 {} CASEMAPPING=ascii CHANTYPES=# NICKLEN={} CHANNELLEN={} :are supported by this server",
+            nick,
+            NAME_MAX_LEN,
+            NAME_MAX_LEN + 1
+        )))
+        .await?;
     info!("Processing login from {}!{}", nick, user);
-    let client = match state::login(&nick, &pass)? {
-        Some(session) => matrix_restore_session(stream, &nick, &pass, session).await?,
-        None => matrix_login_loop(stream, &nick, &pass).await?,
+    let login_nick = nick.clone();
+    let login_pass = pass.clone();
+    // key derivation is slow on purpose, don't block other clients
+    let login = tokio::task::spawn_blocking(move || state::login(&login_nick, &login_pass));
+    let session =
+        throttle::throttled(throttle::Scope::Login, &nick, addr, async { login.await? }).await?;
+    // the detached session must not use the matrix store with the new one
+    matrix::stop_detached(&nick);
+    let ((client, session_key), pass) = match session {
+        Some((session, session_key)) => (
+            matrix_restore_session(stream, &nick, &pass, session, session_key).await?,
+            pass,
+        ),
+        None => {
+            let pass = state::register_pass(&nick, &pass)?.to_string();
+            (matrix_login_loop(stream, &nick, &pass).await?, pass)
+        }
     };
-    Ok((nick, user, client))
+    let store_nick = nick.clone();
+    let store_cipher =
+        tokio::task::spawn_blocking(move || store::cipher(&store_nick, &pass)).await??;
+    Ok(Login {
+        nick,
+        user,
+        caps,
+        matrix: client,
+        store_cipher,
+        session_key,
+    })
 }
 
 /// equivalent to ruma's LoginType, we need our own type for partialeq later
@@ -72,16 +164,31 @@ enum LoginFlow {
     Init,
     /// got homeserver, letting user pick auth method
     Homeserver(String, MatrixClient, Vec<LoginChoice>),
+    /// registration pending, waiting for a registration token
+    Register(String, MatrixClient, Box<register::v3::Request>),
     /// Done, login types is no longer used but
     Complete(String, MatrixClient),
 }
 
 struct LoginState<'a> {
-    stream: &'a mut Framed<TcpStream, IrcCodec>,
+    stream: &'a mut IrcStream,
     nick: &'a str,
     irc_pass: &'a str,
 }
 
+async fn matrix_discover(state: &mut LoginState<'_>, user_id: &str) -> Result<MatrixClient> {
+    let user_id = UserId::parse(user_id).context("Invalid matrix user id")?;
+    state
+        .stream
+        .send(proto::privmsg(
+            "matrirc",
+            state.nick,
+            format!("Looking up homeserver for {}", user_id.server_name()),
+        ))
+        .await?;
+    matrix::login::discover_client(&user_id, state.nick, state.irc_pass).await
+}
+
 async fn matrix_login_choices(
     state: &mut LoginState<'_>,
     client: MatrixClient,
@@ -174,11 +281,75 @@ async fn matrix_login_password(
         .matrix_auth()
         .login_username(user, pass)
         .initial_device_display_name("matrirc")
+        .request_refresh_token()
         .send()
         .await?;
     Ok(LoginFlow::Complete(homeserver.to_string(), client))
 }
 
+async fn matrix_register(
+    state: &mut LoginState<'_>,
+    client: MatrixClient,
+    homeserver: &str,
+    mut request: register::v3::Request,
+) -> Result<LoginFlow> {
+    loop {
+        let err = match client.matrix_auth().register(request.clone()).await {
+            Ok(_) => return Ok(LoginFlow::Complete(homeserver.to_string(), client)),
+            Err(e) => e,
+        };
+        let Some(uiaa) = err.as_uiaa_response() else {
+            return Err(err.into());
+        };
+        if let Some(e) = &uiaa.auth_error {
+            state
+                .stream
+                .send(proto::privmsg(
+                    "matrirc",
+                    state.nick,
+                    format!("Registration step failed: {}", e.message),
+                ))
+                .await?;
+        }
+        let Some(stage) = uiaa::next_stage(uiaa, &[AuthType::Dummy, AuthType::RegistrationToken])
+        else {
+            debug!("Unsupported registration flows: {:?}", uiaa.flows);
+            return Err(Error::msg(
+                "homeserver requires registration steps we cannot handle (captcha, email...)",
+            ));
+        };
+        match stage {
+            AuthType::Dummy => {
+                if matches!(request.auth, Some(AuthData::Dummy(_))) {
+                    return Err(Error::msg("homeserver did not accept registration"));
+                }
+                let mut dummy = Dummy::new();
+                dummy.session = uiaa.session.clone();
+                request.auth = Some(AuthData::Dummy(dummy));
+            }
+            _ => {
+                // session is optional, token is filled in when the user replies
+                let mut token = RegistrationToken::new(String::new());
+                token.session = uiaa.session.clone();
+                request.auth = Some(AuthData::RegistrationToken(token));
+                state
+                    .stream
+                    .send(proto::privmsg(
+                        "matrirc",
+                        state.nick,
+                        "Registration requires a token, reply with: token <token> (or 'reset')",
+                    ))
+                    .await?;
+                return Ok(LoginFlow::Register(
+                    homeserver.to_string(),
+                    client,
+                    Box::new(request),
+                ));
+            }
+        }
+    }
+}
+
 async fn matrix_login_sso(
     state: &mut LoginState<'_>,
     homeserver: String,
@@ -207,6 +378,7 @@ async fn matrix_login_sso(
         Ok(())
     });
 
+    login_builder = login_builder.request_refresh_token();
     if let Some(idp) = idp {
         login_builder = login_builder.identity_provider_id(idp);
     }
@@ -251,11 +423,31 @@ async fn matrix_login_state(
         LoginFlow::Init => {
             // accept either single word (homeserver) or three words (homeserver user pass)
             match &message.split(' ').collect::<Vec<&str>>()[..] {
+                [user_id] if user_id.starts_with('@') => {
+                    let client = matrix_discover(state, user_id).await?;
+                    let homeserver = client.homeserver().to_string();
+                    matrix_login_choices(state, client, &homeserver).await
+                }
+                [user_id, pass] if user_id.starts_with('@') => {
+                    let client = matrix_discover(state, user_id).await?;
+                    let homeserver = client.homeserver().to_string();
+                    matrix_login_password(state, client, &homeserver, user_id, pass).await
+                }
                 [homeserver] => {
                     let client =
                         matrix::login::client(homeserver, state.nick, state.irc_pass).await?;
                     matrix_login_choices(state, client, homeserver).await
                 }
+                ["register", homeserver, user, pass] if args().allow_register => {
+                    let client =
+                        matrix::login::client(homeserver, state.nick, state.irc_pass).await?;
+                    let mut request = register::v3::Request::new();
+                    request.username = Some(user.to_string());
+                    request.password = Some(pass.to_string());
+                    request.initial_device_display_name = Some("matrirc".to_string());
+                    request.refresh_token = true;
+                    matrix_register(state, client, homeserver, request).await
+                }
                 [homeserver, user, pass] => {
                     let client =
                         matrix::login::client(homeserver, state.nick, state.irc_pass).await?;
@@ -267,7 +459,7 @@ async fn matrix_login_state(
                         .send(proto::privmsg(
                             "matrirc",
                             state.nick,
-                            "Message not in <homeserver> [<user> <pass>] format, ignoring.",
+                            "Message not in <homeserver> [<user> <pass>] or @user:server [<pass>] format, ignoring.",
                         ))
                         .await?;
                     Ok(LoginFlow::Init)
@@ -309,21 +501,63 @@ async fn matrix_login_state(
                 }
             }
         }
+        LoginFlow::Register(homeserver, client, mut request) => {
+            match &message.split(' ').collect::<Vec<&str>>()[..] {
+                ["token", token] => {
+                    let Some(AuthData::RegistrationToken(auth)) = &mut request.auth else {
+                        return Err(Error::msg("No registration session to resume"));
+                    };
+                    auth.token = token.to_string();
+                    matrix_register(state, client, &homeserver, *request).await
+                }
+                ["reset"] => {
+                    state
+                        .stream
+                        .send(proto::privmsg(
+                            "matrirc",
+                            state.nick,
+                            "Start over from: <homeserver> [<user> <pass>]",
+                        ))
+                        .await?;
+                    Ok(LoginFlow::Init)
+                }
+                _ => {
+                    state
+                        .stream
+                        .send(proto::privmsg(
+                            "matrirc",
+                            state.nick,
+                            "Reply with: token <token> (or 'reset')",
+                        ))
+                        .await?;
+                    Ok(LoginFlow::Register(homeserver, client, request))
+                }
+            }
+        }
         _ => Err(Error::msg("Should never be called with complete type")),
     }
 }
 
 async fn matrix_login_loop(
-    stream: &mut Framed<TcpStream, IrcCodec>,
+    stream: &mut IrcStream,
     nick: &str,
     irc_pass: &str,
-) -> Result<MatrixClient> {
+) -> Result<(MatrixClient, SessionKey)> {
     stream.send(proto::privmsg(
         "matrirc",
         nick,
-        "Welcome to matrirc. Please login to matrix by replying with: <homeserver> [<user> <pass>]",
+        "Welcome to matrirc. Please login to matrix by replying with: <homeserver> [<user> <pass>], or @user:server [<pass>]",
     ))
     .await?;
+    if args().allow_register {
+        stream
+            .send(proto::privmsg(
+                "matrirc",
+                nick,
+                "To create a new matrix account instead, reply with: register <homeserver> <user> <pass>",
+            ))
+            .await?;
+    }
     let mut state = LoginState {
         stream,
         nick,
@@ -339,13 +573,15 @@ async fn matrix_login_loop(
             Command::PRIVMSG(_, body) => {
                 flow = match matrix_login_state(&mut state, flow, body).await {
                     Ok(LoginFlow::Complete(homeserver, client)) => {
-                        state::create_user(
-                            nick,
-                            irc_pass,
-                            &homeserver,
-                            client.session().context("client has no auth session")?,
-                        )?;
-                        return Ok(client);
+                        let auth_session =
+                            client.session().context("client has no auth session")?;
+                        let (nick, irc_pass) = (nick.to_string(), irc_pass.to_string());
+                        let session_key = tokio::task::spawn_blocking(move || {
+                            state::create_user(&nick, &irc_pass, &homeserver, auth_session)
+                        })
+                        .await??;
+                        matrix::login::save_refreshed_sessions(&client, session_key.clone())?;
+                        return Ok((client, session_key));
                     }
                     Ok(f) => f,
                     Err(e) => {
@@ -387,11 +623,12 @@ async fn matrix_login_loop(
 }
 
 async fn matrix_restore_session(
-    stream: &mut Framed<TcpStream, IrcCodec>,
+    stream: &mut IrcStream,
     nick: &str,
     irc_pass: &str,
     session: state::Session,
-) -> Result<MatrixClient> {
+    session_key: SessionKey,
+) -> Result<(MatrixClient, SessionKey)> {
     stream
         .send(proto::privmsg(
             "matrirc",
@@ -411,7 +648,10 @@ async fn matrix_restore_session(
     .await
     {
         // XXX can't make TryFutureExt's or_else work, give up
-        Ok(client) => Ok(client),
+        Ok(client) => {
+            matrix::login::save_refreshed_sessions(&client, session_key.clone())?;
+            Ok((client, session_key))
+        }
         Err(e) => {
             stream.send(proto::privmsg(
                 "matrirc",
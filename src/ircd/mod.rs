@@ -1,56 +1,265 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Error, Result};
 use futures::{SinkExt, StreamExt};
 use irc::client::prelude::Message;
 use irc::proto::IrcCodec;
+use lazy_static::lazy_static;
 use log::{debug, info};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tokio_util::codec::Framed;
 
 use crate::args::args;
 use crate::matrirc::Matrirc;
 use crate::matrix;
+use crate::systemd;
 
 mod chan;
 mod client;
 mod login;
 pub mod proto;
+pub mod throttle;
+mod websocket;
 
 pub use chan::{join_irc_chan, join_irc_chan_finish};
-pub use client::IrcClient;
-
-pub async fn listen() -> tokio::task::JoinHandle<()> {
-    info!("listening to {}", args().ircd_listen);
-    let listener = TcpListener::bind(args().ircd_listen)
-        .await
-        .context("bind ircd port")
-        .unwrap();
-    tokio::spawn(async move {
+pub use client::{IrcClient, Membership};
+
+/// connection irc clients talk through: tcp socket, or websocket bridge
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type IrcStream = Framed<Box<dyn Transport>, IrcCodec>;
+
+/// websocket clients must complete their handshake within that time
+const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// number of authenticated irc clients, for LUSERS
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn clients() -> usize {
+    CLIENTS.load(Ordering::Relaxed)
+}
+
+/// open connections, authenticated or not, for --max-connections
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// irc listener still accepting connections, for the systemd watchdog
+static ACCEPTING: AtomicBool = AtomicBool::new(false);
+
+/// liveness check for the systemd watchdog
+pub fn alive() -> bool {
+    ACCEPTING.load(Ordering::Relaxed) && !SESSIONS.is_poisoned() && !NICK_CLIENTS.is_poisoned()
+}
+
+lazy_static! {
+    /// authenticated clients per nick, for --max-connections-per-nick
+    static ref NICK_CLIENTS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    /// sessions of connected clients by nick, most recent last
+    static ref SESSIONS: Mutex<HashMap<String, Vec<Matrirc>>> = Mutex::new(HashMap::new());
+}
+
+/// most recent session of nick, if connected
+pub fn session(nick: &str) -> Option<Matrirc> {
+    SESSIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(nick)?
+        .last()
+        .cloned()
+}
+
+/// counts a connection in CONNECTIONS while alive
+struct ConnectionGuard;
+
+impl ConnectionGuard {
+    fn new() -> Result<Self> {
+        let count = CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        // build guard first so the count is dropped on error
+        let guard = ConnectionGuard;
+        match args().max_connections {
+            Some(max) if count > max => Err(Error::msg(
+                "Too many connections to this server, try again later",
+            )),
+            _ => Ok(guard),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// counts a client in CLIENTS and NICK_CLIENTS while alive, and its
+/// session in SESSIONS once set
+struct ClientGuard {
+    nick: String,
+    session: Option<Matrirc>,
+}
+
+impl ClientGuard {
+    fn new(nick: &str) -> Result<Self> {
+        let mut nick_clients = NICK_CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+        let count = nick_clients.entry(nick.to_string()).or_default();
+        if let Some(max) = args().max_connections_per_nick {
+            if *count >= max {
+                return Err(Error::msg(format!(
+                    "Too many connections for {} (at most {}), close another client first",
+                    nick, max
+                )));
+            }
+        }
+        *count += 1;
+        CLIENTS.fetch_add(1, Ordering::Relaxed);
+        Ok(ClientGuard {
+            nick: nick.to_string(),
+            session: None,
+        })
+    }
+
+    fn set_session(&mut self, matrirc: &Matrirc) {
+        SESSIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(self.nick.clone())
+            .or_default()
+            .push(matrirc.clone());
+        self.session = Some(matrirc.clone());
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(list) = sessions.get_mut(&self.nick) {
+                list.retain(|s| !s.same_session(&session));
+                if list.is_empty() {
+                    sessions.remove(&self.nick);
+                    // last client left: keep notifying highlights through the hook
+                    matrix::watch_detached(session);
+                }
+            }
+        }
+        CLIENTS.fetch_sub(1, Ordering::Relaxed);
+        let mut nick_clients = NICK_CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = nick_clients.get_mut(&self.nick) {
+            *count -= 1;
+            if *count == 0 {
+                nick_clients.remove(&self.nick);
+            }
+        }
+    }
+}
+
+/// listen on socket passed by systemd (see systemd::listen_socket) or --ircd-listen
+pub async fn listen(systemd_socket: Option<std::net::TcpListener>) -> tokio::task::JoinHandle<()> {
+    let listener = match systemd_socket {
+        Some(socket) => {
+            info!("listening to systemd socket {:?}", socket.local_addr());
+            TcpListener::from_std(socket)
+                .context("systemd socket")
+                .unwrap()
+        }
+        None => {
+            info!("listening to {}", args().ircd_listen);
+            TcpListener::bind(args().ircd_listen)
+                .await
+                .context("bind ircd port")
+                .unwrap()
+        }
+    };
+    if let Some(ws_listen) = args().ircd_ws_listen {
+        info!("listening to websocket {}", ws_listen);
+        let ws_listener = TcpListener::bind(ws_listen)
+            .await
+            .context("bind websocket port")
+            .unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, addr)) = ws_listener.accept().await {
+                info!("Accepted websocket connection from {}", addr);
+                // count connection before handshake so slow handshakes are limited too
+                let guard = match ConnectionGuard::new() {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        info!("Refusing websocket connection from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                // handshake in its own task so it doesn't hold up the listener
+                tokio::spawn(async move {
+                    let socket =
+                        match tokio::time::timeout(WS_HANDSHAKE_TIMEOUT, websocket::accept(socket))
+                            .await
+                        {
+                            Ok(Ok(socket)) => socket,
+                            Ok(Err(e)) => {
+                                info!("Websocket handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                info!("Websocket handshake with {} timed out", addr);
+                                return;
+                            }
+                        };
+                    let stream = match IrcCodec::new("utf-8") {
+                        Ok(codec) => Framed::new(Box::new(socket) as Box<dyn Transport>, codec),
+                        Err(e) => {
+                            info!("Could not spawn worker: {}", e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = handle_client(stream, addr).await {
+                        info!("Terminating {}: {}", addr, e);
+                    }
+                    drop(guard);
+                });
+            }
+        });
+    }
+    ACCEPTING.store(true, Ordering::Relaxed);
+    let handle = tokio::spawn(async move {
         while let Ok((socket, addr)) = listener.accept().await {
             info!("Accepted connection from {}", addr);
-            if let Err(e) = handle_connection(socket, addr).await {
+            if let Err(e) = handle_connection(Box::new(socket), addr).await {
                 info!("Could not spawn worker: {}", e);
             }
         }
-    })
+        ACCEPTING.store(false, Ordering::Relaxed);
+    });
+    systemd::notify_ready(alive);
+    handle
 }
 
-async fn handle_connection(socket: TcpStream, addr: SocketAddr) -> Result<()> {
+async fn handle_connection(socket: Box<dyn Transport>, addr: SocketAddr) -> Result<()> {
     let codec = IrcCodec::new("utf-8")?;
-    let stream = Framed::new(socket, codec);
+    let mut stream = Framed::new(socket, codec);
+    let guard = match ConnectionGuard::new() {
+        Ok(guard) => guard,
+        Err(e) => {
+            let _ = stream.send(proto::error(e.to_string())).await;
+            return Err(e);
+        }
+    };
     tokio::spawn(async move {
-        if let Err(e) = handle_client(stream).await {
+        let _guard = guard;
+        if let Err(e) = handle_client(stream, addr).await {
             info!("Terminating {}: {}", addr, e);
         }
     });
     Ok(())
 }
 
-async fn handle_client(mut stream: Framed<TcpStream, IrcCodec>) -> Result<()> {
+async fn handle_client(mut stream: IrcStream, addr: SocketAddr) -> Result<()> {
     debug!("Awaiting auth");
-    let (nick, user, matrix) = match login::auth_loop(&mut stream).await {
-        Ok(data) => data,
+    let login = match login::auth_loop(&mut stream, addr.ip()).await {
+        Ok(login) => login,
         Err(e) => {
             // keep original error, but try to tell client we're not ok
             let _ = stream
@@ -59,11 +268,27 @@ async fn handle_client(mut stream: Framed<TcpStream, IrcCodec>) -> Result<()> {
             return Err(e);
         }
     };
+    let login::Login {
+        nick,
+        user,
+        caps,
+        matrix,
+        store_cipher,
+        session_key,
+    } = login;
     info!("Authenticated {}!{}", nick, user);
+    let mut guard = match ClientGuard::new(&nick) {
+        Ok(guard) => guard,
+        Err(e) => {
+            let _ = stream.send(proto::error(e.to_string())).await;
+            return Err(e);
+        }
+    };
     let (writer, reader_stream) = stream.split();
-    let (irc_sink, irc_sink_rx) = mpsc::channel::<Message>(100);
-    let irc = IrcClient::new(irc_sink, nick, user);
-    let matrirc = Matrirc::new(matrix, irc);
+    let (irc_sink, irc_sink_rx) = mpsc::channel::<Message>(args().irc_queue_size as usize);
+    let irc = IrcClient::new(irc_sink, nick, user, caps);
+    let matrirc = Matrirc::new(matrix, irc, store_cipher, session_key)?;
+    guard.set_session(&matrirc);
 
     let writer_matrirc = matrirc.clone();
     tokio::spawn(async move {
@@ -90,7 +315,8 @@ async fn handle_client(mut stream: Framed<TcpStream, IrcCodec>) -> Result<()> {
         .irc()
         .send_privmsg("matrirc", &matrirc.irc().nick, "okay")
         .await?;
-    if let Err(e) = proto::ircd_sync_read(reader_stream, reader_matrirc).await {
+    if let Err(e) = client::with_labels(proto::ircd_sync_read(reader_stream, reader_matrirc)).await
+    {
         info!("irc read task failed: {:?}", e);
     }
     matrirc.stop("Reached end of handle_client").await?;
@@ -1,16 +1,15 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::{offset::Local, DateTime};
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
 use irc::client::prelude::{Command, Message, Prefix};
-use irc::proto::{ChannelMode, IrcCodec, Mode};
+use irc::proto::{message::Tag, ChannelMode, Mode};
 use log::{info, trace, warn};
 use std::cmp::min;
 use std::time::SystemTime;
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio_util::codec::Framed;
 
-use crate::{matrirc::Matrirc, matrix::MatrixMessageType};
+use crate::{commands, ircd, ircd::IrcStream, matrirc::Matrirc, matrix, matrix::MatrixMessageType};
 
 /// it's a bit of a pain to redo the work twice for notice/privmsg,
 /// so these types wrap it around a bit
@@ -30,6 +29,8 @@ pub struct IrcMessage {
     pub target: String,
     /// message content
     pub text: String,
+    /// message id tag, only set if client supports message-tags
+    pub msgid: Option<String>,
 }
 
 impl IntoIterator for IrcMessage {
@@ -45,14 +46,19 @@ impl IntoIterator for IrcMessage {
             message_type,
             from,
             target,
+            msgid,
         } = self;
-        text.split('\n')
+        let mut messages = text
+            .split('\n')
             .map(|line| match message_type {
                 IrcMessageType::Privmsg => privmsg(from.clone(), target.clone(), line),
                 IrcMessageType::Notice => notice(from.clone(), target.clone(), line),
             })
-            .collect::<Vec<Message>>()
-            .into_iter()
+            .collect::<Vec<Message>>();
+        if let (Some(msgid), Some(first)) = (msgid, messages.first_mut()) {
+            first.tags = Some(vec![Tag("msgid".to_string(), Some(msgid))]);
+        }
+        messages.into_iter()
     }
 }
 
@@ -112,6 +118,26 @@ where
     message_of_option(who, Command::PART(chan.into(), None))
 }
 
+pub fn kick<S, T, U>(who: S, chan: T, nick: U, reason: Option<String>) -> Message
+where
+    S: Into<String>,
+    T: Into<String>,
+    U: Into<String>,
+{
+    message_of(who, Command::KICK(chan.into(), nick.into(), reason))
+}
+
+/// single channel mode change, e.g. +b mask or +m
+pub fn mode<S, T>(who: S, chan: T, mode: &str, arg: Option<String>) -> Message
+where
+    S: Into<String>,
+    T: Into<String>,
+{
+    let mut args = vec![chan.into(), mode.to_string()];
+    args.extend(arg);
+    message_of(who, Command::Raw("MODE".to_string(), args))
+}
+
 pub fn pong(server: String, server2: Option<String>) -> Message {
     message_of_noprefix(Command::PONG(server, server2))
 }
@@ -136,6 +162,20 @@ where
     message_of(from, Command::NOTICE(target.into(), msg.into()))
 }
 
+/// message with only tags, e.g. `+draft/react`
+pub fn tagmsg<S, T>(from: S, target: T, tags: Vec<Tag>) -> Message
+where
+    S: Into<String>,
+    T: Into<String>,
+{
+    let mut message = message_of(
+        from,
+        Command::Raw("TAGMSG".to_string(), vec![target.into()]),
+    );
+    message.tags = Some(tags);
+    message
+}
+
 pub fn error<S>(reason: S) -> Message
 where
     S: Into<String>,
@@ -144,7 +184,7 @@ where
 }
 
 pub async fn ircd_sync_write(
-    mut writer: SplitSink<Framed<TcpStream, IrcCodec>, Message>,
+    mut writer: SplitSink<IrcStream, Message>,
     mut irc_sink_rx: mpsc::Receiver<Message>,
 ) -> Result<()> {
     while let Some(message) = irc_sink_rx.recv().await {
@@ -162,10 +202,28 @@ pub async fn ircd_sync_write(
     Ok(())
 }
 
-pub async fn ircd_sync_read(
-    mut reader: SplitStream<Framed<TcpStream, IrcCodec>>,
-    matrirc: Matrirc,
+/// filter message from irc and send it to target, unless held for confirm
+async fn forward_to_matrix(
+    matrirc: &Matrirc,
+    target: &str,
+    message_type: MatrixMessageType,
+    msg: String,
 ) -> Result<()> {
+    let msg = matrirc
+        .filters()
+        .outgoing(target, msg)
+        .await
+        .context("blocked by filter")?;
+    if matrirc.hold_unconfirmed(target, message_type, &msg).await? {
+        return Ok(());
+    }
+    matrirc
+        .mappings()
+        .to_matrix(target, message_type, msg)
+        .await
+}
+
+pub async fn ircd_sync_read(mut reader: SplitStream<IrcStream>, matrirc: Matrirc) -> Result<()> {
     while let Some(input) = reader.next().await {
         let message = match input {
             Err(e) => {
@@ -175,19 +233,42 @@ pub async fn ircd_sync_read(
             Ok(m) => m,
         };
         trace!("Got message {}", message);
+        let label = message
+            .tags
+            .iter()
+            .flatten()
+            .find(|Tag(key, _)| key == "label")
+            .and_then(|Tag(_, value)| value.clone());
+        matrirc.irc().start_labeled(label);
         match message.command.clone() {
             Command::PING(server, server2) => matrirc.irc().send(pong(server, server2)).await?,
+            Command::PRIVMSG(target, msg)
+                if target.eq_ignore_ascii_case("matrirc")
+                    || matrirc.config().command_line(&msg).is_some() =>
+            {
+                let line = matrirc.config().command_line(&msg).unwrap_or(&msg);
+                if let Err(e) = commands::handle_command(&matrirc, &target, line).await {
+                    warn!("Command failed: {:?}", e);
+                    if let Err(e2) = matrirc
+                        .mappings()
+                        .matrirc_query(format!("Command failed: {}", e))
+                        .await
+                    {
+                        warn!("Furthermore, reply errored too: {:?}", e2);
+                    }
+                }
+            }
             Command::PRIVMSG(target, msg) => {
+                matrix::presence::active(&matrirc).await;
+                let msg = matrirc.config().unescape_command(&msg).to_string();
                 let (message_type, msg) = if let Some(emote) = msg.strip_prefix("\u{001}ACTION ") {
                     (MatrixMessageType::Emote, emote.to_string())
                 } else {
                     (MatrixMessageType::Text, msg)
                 };
-                if let Err(e) = matrirc
-                    .mappings()
-                    .to_matrix(&target, message_type, msg)
-                    .await
-                {
+                let msg = matrirc.config().outgoing_text(&msg);
+                let sent = forward_to_matrix(&matrirc, &target, message_type, msg).await;
+                if let Err(e) = sent {
                     warn!("Could not forward message: {:?}", e);
                     if let Err(e2) = matrirc
                         .irc()
@@ -203,11 +284,11 @@ pub async fn ircd_sync_read(
                 }
             }
             Command::NOTICE(target, msg) => {
-                if let Err(e) = matrirc
-                    .mappings()
-                    .to_matrix(&target, MatrixMessageType::Notice, msg)
-                    .await
-                {
+                matrix::presence::active(&matrirc).await;
+                let msg = matrirc.config().outgoing_text(&msg);
+                let sent =
+                    forward_to_matrix(&matrirc, &target, MatrixMessageType::Notice, msg).await;
+                if let Err(e) = sent {
                     warn!("Could not forward message: {:?}", e);
                     if let Err(e2) = matrirc
                         .irc()
@@ -223,17 +304,37 @@ pub async fn ircd_sync_read(
                 }
             }
             Command::ChannelMODE(chan, modes) if modes.is_empty() => {
+                // fall back to now if the room creation time is unknown
+                let created = match matrirc.mappings().creation_time(&chan).await {
+                    Some(created) => created,
+                    None => SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                };
+                let modes = match matrirc.mappings().read_only(&chan).await {
+                    true => "+m",
+                    false => "+",
+                };
+                if let Err(e) = matrirc
+                    .irc()
+                    .send(raw_msg(format!(
+                        ":matrirc 324 {} {} {}",
+                        matrirc.irc().nick,
+                        chan,
+                        modes
+                    )))
+                    .await
+                {
+                    warn!("Could not reply to mode: {:?}", e)
+                }
                 if let Err(e) = matrirc
                     .irc()
                     .send(raw_msg(format!(
                         ":matrirc 329 {} {} {}",
                         matrirc.irc().nick,
                         chan,
-                        // normally chan creation timestamp
-                        SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .map(|d| d.as_secs())
-                            .unwrap_or_default()
+                        created
                     )))
                     .await
                 {
@@ -255,6 +356,119 @@ pub async fn ircd_sync_read(
                     warn!("Could not reply to mode: {:?}", e)
                 }
             }
+            Command::USERHOST(nicks) => {
+                let mut replies = vec![];
+                for nick in nicks {
+                    let user_id = if nick == matrirc.irc().nick {
+                        matrirc.matrix().user_id().map(|u| u.to_owned())
+                    } else {
+                        matrirc.mappings().member_id(&nick).await
+                    };
+                    if let Some(user_id) = user_id {
+                        replies.push(format!(
+                            "{}=+{}@{}",
+                            nick,
+                            user_id.localpart(),
+                            user_id.server_name()
+                        ));
+                    }
+                }
+                if let Err(e) = matrirc
+                    .irc()
+                    .send(raw_msg(format!(
+                        ":matrirc 302 {} :{}",
+                        matrirc.irc().nick,
+                        replies.join(" ")
+                    )))
+                    .await
+                {
+                    warn!("Could not reply to userhost: {:?}", e)
+                }
+            }
+            Command::LUSERS(_, _) => {
+                let nick = &matrirc.irc().nick;
+                let (chans, queries) = matrirc.mappings().target_counts().await;
+                let clients = ircd::clients();
+                for line in [
+                    format!(
+                        ":matrirc 251 {} :There are {} users and 0 invisible on 1 servers",
+                        nick, queries
+                    ),
+                    format!(":matrirc 254 {} {} :channels formed", nick, chans),
+                    format!(
+                        ":matrirc 255 {} :I have {} clients and 0 servers",
+                        nick, clients
+                    ),
+                ] {
+                    if let Err(e) = matrirc.irc().send(raw_msg(line)).await {
+                        warn!("Could not reply to lusers: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            Command::TIME(_) => {
+                if let Err(e) = matrirc
+                    .irc()
+                    .send(raw_msg(format!(
+                        ":matrirc 391 {} matrirc :{}",
+                        matrirc.irc().nick,
+                        Local::now().to_rfc2822()
+                    )))
+                    .await
+                {
+                    warn!("Could not reply to time: {:?}", e)
+                }
+            }
+            Command::VERSION(_) => {
+                if let Err(e) = matrirc
+                    .irc()
+                    .send(raw_msg(format!(
+                        ":matrirc 351 {} matrirc-{} matrirc :matrix-sdk {}",
+                        matrirc.irc().nick,
+                        env!("CARGO_PKG_VERSION"),
+                        env!("MATRIX_SDK_VERSION")
+                    )))
+                    .await
+                {
+                    warn!("Could not reply to version: {:?}", e)
+                }
+            }
+            Command::WHOWAS(nick, _, _) => {
+                let me = &matrirc.irc().nick;
+                let departed = matrirc.mappings().whowas(&nick).await;
+                let mut lines = vec![];
+                if departed.is_empty() {
+                    lines.push(format!(
+                        ":matrirc 406 {} {} :There was no such nickname",
+                        me, nick
+                    ));
+                }
+                for d in departed {
+                    let left: DateTime<Local> = d.left.into();
+                    lines.push(format!(
+                        ":matrirc 314 {} {} {} {} * :{}",
+                        me,
+                        d.nick,
+                        d.user_id.localpart(),
+                        d.user_id.server_name(),
+                        d.display_name.as_deref().unwrap_or(d.user_id.as_str())
+                    ));
+                    lines.push(format!(
+                        ":matrirc 312 {} {} matrirc :left {} {}",
+                        me,
+                        d.nick,
+                        d.target,
+                        left.to_rfc2822()
+                    ));
+                }
+                lines.push(format!(":matrirc 369 {} {} :End of WHOWAS", me, nick));
+                for line in lines {
+                    if let Err(e) = matrirc.irc().send(raw_msg(line)).await {
+                        warn!("Could not reply to whowas: {:?}", e);
+                        break;
+                    }
+                }
+            }
             Command::WHO(Some(chan), _) => {
                 if let Err(e) = matrirc
                     .irc()
@@ -270,6 +484,7 @@ pub async fn ircd_sync_read(
             }
             _ => info!("Unhandled message {:?}", message),
         }
+        matrirc.irc().end_labeled().await?;
     }
     info!("Stopping read task to stream closed");
     Ok(())
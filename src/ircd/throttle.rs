@@ -0,0 +1,195 @@
+//! slow down password guessing: each failed login for a nick or source address
+//! doubles the delay before the next attempt is checked, and too many failures
+//! lock the nick/address out for a while.
+//! IRC logins and webhook posts are counted separately, so failed posts
+//! cannot lock a user out of IRC.
+
+use anyhow::{Error, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// failures before lockout
+const LOCKOUT_FAILURES: u32 = 10;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// failures are forgotten after that long without new attempts
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// what is being checked, each has its own failure counters
+#[derive(Debug, Clone, Copy)]
+pub enum Scope {
+    Login,
+    Webhook,
+}
+
+impl Scope {
+    fn prefix(self) -> &'static str {
+        match self {
+            Scope::Login => "",
+            Scope::Webhook => "webhook ",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Login => "login",
+            Scope::Webhook => "webhook post",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+#[derive(Default)]
+struct Throttle {
+    failures: HashMap<String, Failures>,
+}
+
+impl Throttle {
+    fn keys(scope: Scope, nick: &str, ip: IpAddr) -> [String; 2] {
+        [
+            format!("{}nick {}", scope.prefix(), nick),
+            format!("{}ip {}", scope.prefix(), ip),
+        ]
+    }
+
+    /// delay to apply before checking password, or error if locked out
+    fn check(&mut self, scope: Scope, nick: &str, ip: IpAddr, now: Instant) -> Result<Duration> {
+        self.failures
+            .retain(|_, f| now.duration_since(f.last) < FORGET_AFTER);
+        let mut delay = Duration::ZERO;
+        for key in Throttle::keys(scope, nick, ip) {
+            let Some(failures) = self.failures.get(&key) else {
+                continue;
+            };
+            if failures.count >= LOCKOUT_FAILURES {
+                if now.duration_since(failures.last) < LOCKOUT_DURATION {
+                    return Err(Error::msg(
+                        "Too many failed login attempts, try again later",
+                    ));
+                }
+                continue;
+            }
+            let key_delay = Duration::from_secs(1 << failures.count.min(5)).min(MAX_DELAY);
+            delay = delay.max(key_delay);
+        }
+        Ok(delay)
+    }
+
+    fn failed(&mut self, scope: Scope, nick: &str, ip: IpAddr, now: Instant) {
+        for key in Throttle::keys(scope, nick, ip) {
+            let failures = self.failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            failures.count += 1;
+            failures.last = now;
+            if failures.count == LOCKOUT_FAILURES {
+                warn!(
+                    "Locking out {} for {:?} after {} failed logins",
+                    key, LOCKOUT_DURATION, failures.count
+                );
+            }
+        }
+    }
+
+    fn succeeded(&mut self, scope: Scope, nick: &str, ip: IpAddr) {
+        for key in Throttle::keys(scope, nick, ip) {
+            self.failures.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    static ref THROTTLE: Mutex<Throttle> = Mutex::new(Throttle::default());
+}
+
+fn throttle() -> std::sync::MutexGuard<'static, Throttle> {
+    THROTTLE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// run login check with throttling for nick and address
+pub async fn throttled<T>(
+    scope: Scope,
+    nick: &str,
+    ip: IpAddr,
+    login: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let delay = throttle().check(scope, nick, ip, Instant::now())?;
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    match login.await {
+        Ok(v) => {
+            throttle().succeeded(scope, nick, ip);
+            Ok(v)
+        }
+        Err(e) => {
+            warn!("Failed {} for {} from {}: {}", scope.name(), nick, ip, e);
+            throttle().failed(scope, nick, ip, Instant::now());
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_throttle() -> Result<()> {
+        let mut throttle = Throttle::default();
+        let ip: IpAddr = "::1".parse()?;
+        let other_ip: IpAddr = "::2".parse()?;
+        let now = Instant::now();
+        assert_eq!(
+            throttle.check(Scope::Login, "nick", ip, now)?,
+            Duration::ZERO
+        );
+        throttle.failed(Scope::Login, "nick", ip, now);
+        assert_eq!(
+            throttle.check(Scope::Login, "nick", ip, now)?,
+            Duration::from_secs(2)
+        );
+        // nick is throttled from other addresses too
+        assert_eq!(
+            throttle.check(Scope::Login, "nick", other_ip, now)?,
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            throttle.check(Scope::Login, "other", other_ip, now)?,
+            Duration::ZERO
+        );
+        for _ in 1..LOCKOUT_FAILURES {
+            throttle.failed(Scope::Login, "nick", ip, now);
+        }
+        assert!(throttle.check(Scope::Login, "other", ip, now).is_err());
+        assert!(throttle
+            .check(Scope::Login, "nick", ip, now + LOCKOUT_DURATION)
+            .is_ok());
+        throttle.succeeded(Scope::Login, "nick", ip);
+        assert_eq!(
+            throttle.check(Scope::Login, "nick", ip, now)?,
+            Duration::ZERO
+        );
+        // webhook failures don't count against logins
+        for _ in 0..LOCKOUT_FAILURES {
+            throttle.failed(Scope::Webhook, "nick", ip, now);
+        }
+        assert!(throttle.check(Scope::Webhook, "nick", ip, now).is_err());
+        assert_eq!(
+            throttle.check(Scope::Login, "nick", ip, now)?,
+            Duration::ZERO
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! irc over websocket (https://ircv3.net/specs/extensions/websocket): one irc
+//! line per frame without CRLF. Frames are bridged to a pipe so the rest of
+//! ircd sees the same byte stream as for plain tcp clients.
+
+use anyhow::{Error, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, info};
+use sha1::{Digest, Sha1};
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::{tcp::OwnedWriteHalf, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::args::args;
+
+/// appended to client key for Sec-WebSocket-Accept (RFC 6455)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// bigger handshakes and messages are refused, irc lines are short
+const MAX_SIZE: usize = 64 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// handshake headers we care about
+#[derive(Default)]
+struct Handshake {
+    key: Option<String>,
+    origin: Option<String>,
+    host: Option<String>,
+    upgrade: Option<String>,
+    connection: Option<String>,
+    version: Option<String>,
+    protocols: Vec<String>,
+}
+
+/// whether comma separated header value has token, case insensitive
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// "GET <path> HTTP/1.1", or later versions
+fn valid_request_line(line: &str) -> bool {
+    let mut parts = line.split(' ');
+    let (Some("GET"), Some(_), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    match version
+        .strip_prefix("HTTP/")
+        .and_then(|v| v.split_once('.'))
+    {
+        Some((major, minor)) => match (major.parse::<u32>(), minor.parse::<u32>()) {
+            (Ok(major), Ok(minor)) => (major, minor) >= (1, 1),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+impl Handshake {
+    /// RFC 6455 4.2.1 checks, with the error response to send otherwise
+    fn check(&self) -> Result<&str, (&'static str, &'static str)> {
+        const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n\r\n";
+        if !has_token(self.upgrade.as_deref(), "websocket")
+            || !has_token(self.connection.as_deref(), "upgrade")
+        {
+            return Err((BAD_REQUEST, "not a websocket upgrade request"));
+        }
+        if self.version.as_deref() != Some("13") {
+            return Err((
+                "HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                "unsupported websocket version",
+            ));
+        }
+        match &self.key {
+            Some(key) if STANDARD.decode(key).is_ok_and(|k| k.len() == 16) => Ok(key),
+            _ => Err((BAD_REQUEST, "missing or invalid websocket key")),
+        }
+    }
+}
+
+/// browsers always send Origin, refuse pages from other sites unless allowed
+/// so they cannot drive a local user's connection
+fn origin_allowed(origin: Option<&str>, host: Option<&str>, allowed: &[String]) -> bool {
+    let Some(origin) = origin else {
+        // not a browser
+        return true;
+    };
+    if allowed
+        .iter()
+        .any(|a| a == "*" || a.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    {
+        return true;
+    }
+    let origin_host = origin
+        .split_once("://")
+        .map_or(origin, |(_, rest)| rest)
+        .trim_end_matches('/');
+    host.is_some_and(|host| host.eq_ignore_ascii_case(origin_host))
+}
+
+/// do websocket handshake on socket, returning a stream of irc lines
+pub async fn accept(socket: TcpStream) -> Result<DuplexStream> {
+    let (read, mut write) = socket.into_split();
+    let mut read = BufReader::new(read);
+    let mut request_line = None;
+    let mut handshake = Handshake::default();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let n = (&mut read)
+            .take((MAX_SIZE - size) as u64)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 || !line.ends_with('\n') {
+            return Err(Error::msg("websocket handshake interrupted or too long"));
+        }
+        size += n;
+        let line = line.trim_end();
+        if request_line.is_none() {
+            request_line = Some(line.to_string());
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "sec-websocket-key" => handshake.key = Some(value),
+            "origin" => handshake.origin = Some(value),
+            "host" => handshake.host = Some(value),
+            "upgrade" => handshake.upgrade = Some(value),
+            "connection" => handshake.connection = Some(value),
+            "sec-websocket-version" => handshake.version = Some(value),
+            "sec-websocket-protocol" => handshake
+                .protocols
+                .extend(value.split(',').map(|p| p.trim().to_string())),
+            _ => (),
+        }
+    }
+    if !request_line.as_deref().is_some_and(valid_request_line) {
+        write.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+        return Err(Error::msg("not a GET HTTP/1.1 request"));
+    }
+    let key = match handshake.check() {
+        Ok(key) => key,
+        Err((response, error)) => {
+            write.write_all(response.as_bytes()).await?;
+            return Err(Error::msg(error));
+        }
+    };
+    let (origin, host) = (handshake.origin.as_deref(), handshake.host.as_deref());
+    if !origin_allowed(origin, host, &args().ircd_ws_origin) {
+        write.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        return Err(Error::msg(format!(
+            "origin {} not allowed, see --ircd-ws-origin",
+            origin.unwrap_or_default()
+        )));
+    }
+    let protocol = ["text.ircv3.net", "binary.ircv3.net"]
+        .into_iter()
+        .find(|p| handshake.protocols.iter().any(|o| o == p));
+    let opcode = match protocol {
+        Some("binary.ircv3.net") => OP_BINARY,
+        _ => OP_TEXT,
+    };
+    debug!("websocket handshake done, protocol {:?}", protocol);
+    write
+        .write_all(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+                accept_key(key),
+                protocol
+                    .map(|p| format!("Sec-WebSocket-Protocol: {}\r\n", p))
+                    .unwrap_or_default()
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let write = Arc::new(Mutex::new(write));
+    let (irc, bridge) = tokio::io::duplex(MAX_SIZE);
+    let (bridge_read, bridge_write) = tokio::io::split(bridge);
+    let frames_write = write.clone();
+    tokio::spawn(async move {
+        if let Err(e) = frames_to_irc(read, bridge_write, frames_write).await {
+            info!("websocket read stopped: {}", e);
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = irc_to_frames(bridge_read, write, opcode).await {
+            info!("websocket write stopped: {}", e);
+        }
+    });
+    Ok(irc)
+}
+
+async fn frames_to_irc<R: AsyncRead + Unpin>(
+    mut read: R,
+    mut irc: WriteHalf<DuplexStream>,
+    write: Arc<Mutex<OwnedWriteHalf>>,
+) -> Result<()> {
+    let mut message = vec![];
+    loop {
+        let (fin, opcode, payload) = read_frame(&mut read).await?;
+        match opcode {
+            OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                message.extend_from_slice(&payload);
+                if message.len() > MAX_SIZE {
+                    return Err(Error::msg("websocket message too big"));
+                }
+                if !fin {
+                    continue;
+                }
+                // some clients send line endings anyway
+                while message.last().is_some_and(|c| *c == b'\r' || *c == b'\n') {
+                    message.pop();
+                }
+                message.extend_from_slice(b"\r\n");
+                irc.write_all(&message).await?;
+                message.clear();
+            }
+            OP_PING => write_frame(&write, OP_PONG, &payload).await?,
+            OP_CLOSE => {
+                let _ = write_frame(&write, OP_CLOSE, &[]).await;
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn irc_to_frames(
+    irc: ReadHalf<DuplexStream>,
+    write: Arc<Mutex<OwnedWriteHalf>>,
+    opcode: u8,
+) -> Result<()> {
+    let mut lines = BufReader::new(irc).split(b'\n');
+    while let Some(mut line) = lines.next_segment().await? {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        write_frame(&write, opcode, &line).await?;
+    }
+    write_frame(&write, OP_CLOSE, &[]).await?;
+    write.lock().await.shutdown().await?;
+    Ok(())
+}
+
+/// (fin, opcode, unmasked payload) of next frame
+async fn read_frame<R: AsyncRead + Unpin>(read: &mut R) -> Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    read.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let len = match header[1] & 0x7f {
+        126 => read.read_u16().await? as u64,
+        127 => read.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_SIZE as u64 {
+        return Err(Error::msg("websocket frame too big"));
+    }
+    // clients must mask all their frames (RFC 6455 5.1)
+    if header[1] & 0x80 == 0 {
+        return Err(Error::msg("unmasked client frame"));
+    }
+    let mut mask = [0u8; 4];
+    read.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    read.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+/// server frames are never masked or fragmented
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+async fn write_frame(write: &Mutex<OwnedWriteHalf>, opcode: u8, payload: &[u8]) -> Result<()> {
+    write
+        .lock()
+        .await
+        .write_all(&frame(opcode, payload))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_frames() -> Result<()> {
+        // examples from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let masked: &[u8] = &[
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let mut read = masked;
+        assert_eq!(
+            read_frame(&mut read).await?,
+            (true, OP_TEXT, b"Hello".to_vec())
+        );
+        assert_eq!(
+            frame(OP_TEXT, b"Hello"),
+            [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+        // our frames are unmasked, which clients must not send
+        let mut read = &frame(OP_BINARY, &[0; 256])[..];
+        assert!(read_frame(&mut read).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_handshake() {
+        assert!(valid_request_line("GET /irc HTTP/1.1"));
+        assert!(!valid_request_line("POST /irc HTTP/1.1"));
+        assert!(!valid_request_line("GET /irc HTTP/1.0"));
+        assert!(!valid_request_line("GET /irc"));
+        let mut handshake = Handshake {
+            key: Some("dGhlIHNhbXBsZSBub25jZQ==".to_string()),
+            upgrade: Some("websocket".to_string()),
+            connection: Some("keep-alive, Upgrade".to_string()),
+            version: Some("13".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(handshake.check(), Ok("dGhlIHNhbXBsZSBub25jZQ=="));
+        handshake.version = Some("8".to_string());
+        assert!(handshake.check().is_err_and(|(r, _)| r.contains(" 426 ")));
+        handshake.version = Some("13".to_string());
+        handshake.connection = Some("keep-alive".to_string());
+        assert!(handshake.check().is_err_and(|(r, _)| r.contains(" 400 ")));
+        handshake.connection = Some("Upgrade".to_string());
+        handshake.key = Some("short".to_string());
+        assert!(handshake.check().is_err());
+    }
+
+    #[test]
+    fn check_origin() {
+        let allowed = ["https://web.example.com".to_string()];
+        assert!(origin_allowed(None, None, &[]));
+        assert!(origin_allowed(
+            Some("https://irc.example.com"),
+            Some("irc.example.com"),
+            &[]
+        ));
+        assert!(!origin_allowed(
+            Some("https://evil.example.com"),
+            Some("irc.example.com"),
+            &[]
+        ));
+        assert!(origin_allowed(
+            Some("https://web.example.com"),
+            Some("irc.example.com"),
+            &allowed
+        ));
+        assert!(!origin_allowed(
+            Some("https://evil.example.com"),
+            Some("irc.example.com"),
+            &allowed
+        ));
+    }
+}
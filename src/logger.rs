@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{debug, warn};
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+
+use crate::args::args;
+use crate::config::{LogConfig, LogFormat};
+
+#[derive(Deserialize)]
+struct LogEntry {
+    ts: String,
+    sender: String,
+    text: String,
+}
+
+/// lines queued for the writer task, more are dropped while the disk is slow
+const LOG_QUEUE: usize = 1000;
+
+/// writes bridged messages to one file per room, in user state dir.
+/// Writes happen in a blocking task fed through a channel so sync never waits
+/// on the disk. Cheap to clone, searches should run in a blocking task.
+#[derive(Clone)]
+pub struct Logger {
+    files: LogFiles,
+    /// formatted entries for the writer task, which stops once all loggers
+    /// are dropped
+    writer: mpsc::Sender<(OwnedRoomId, String)>,
+}
+
+/// log files location and rotation settings
+#[derive(Clone)]
+struct LogFiles {
+    dir: PathBuf,
+    config: LogConfig,
+}
+
+impl Logger {
+    pub fn new(nick: &str, config: LogConfig) -> Result<Logger> {
+        let dir = Path::new(&args().state_dir).join(nick).join("logs");
+        if !dir.is_dir() {
+            fs::DirBuilder::new()
+                .mode(0o700)
+                .recursive(true)
+                .create(&dir)
+                .context("mkdir of log dir failed")?
+        }
+        debug!("Logging messages to {}", dir.display());
+        let files = LogFiles { dir, config };
+        let (writer, mut entries) = mpsc::channel::<(OwnedRoomId, String)>(LOG_QUEUE);
+        let writer_files = files.clone();
+        tokio::task::spawn_blocking(move || {
+            while let Some((room_id, entry)) = entries.blocking_recv() {
+                if let Err(e) = writer_files.write(&room_id, &entry) {
+                    warn!("Could not log message for {}: {:?}", room_id, e);
+                }
+            }
+        });
+        Ok(Logger { files, writer })
+    }
+
+    fn format(
+        &self,
+        room_id: &RoomId,
+        ts: MilliSecondsSinceUnixEpoch,
+        sender: &str,
+        text: &str,
+    ) -> Result<String> {
+        let datetime: DateTime<Local> =
+            ts.to_system_time().unwrap_or(SystemTime::UNIX_EPOCH).into();
+        Ok(match self.files.config.format {
+            LogFormat::Plain => {
+                let date = datetime.format("%Y-%m-%d %H:%M:%S");
+                text.split('\n')
+                    .map(|line| format!("{} <{}> {}\n", date, sender, line))
+                    .collect()
+            }
+            LogFormat::Weechat => {
+                let date = datetime.format("%Y-%m-%d %H:%M:%S");
+                text.split('\n')
+                    .map(|line| format!("{}\t{}\t{}\n", date, sender, line))
+                    .collect()
+            }
+            LogFormat::Jsonl => {
+                serde_json::to_string(&serde_json::json!({
+                    "ts": datetime.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "room": room_id,
+                    "sender": sender,
+                    "text": text,
+                }))? + "\n"
+            }
+        })
+    }
+
+    /// search logs of given room (or all rooms), returning up to limit most
+    /// recent matching lines for each room
+    pub fn search(
+        &self,
+        room_id: Option<&RoomId>,
+        re: &Regex,
+        limit: usize,
+    ) -> Result<Vec<(OwnedRoomId, Vec<String>)>> {
+        let rooms = match room_id {
+            Some(room_id) => vec![room_id.to_owned()],
+            None => self.files.rooms()?,
+        };
+        let mut results = vec![];
+        for room_id in rooms {
+            let mut found = self.files.search_room(&room_id, re)?;
+            if found.is_empty() {
+                continue;
+            }
+            found.drain(..found.len().saturating_sub(limit));
+            results.push((room_id, found));
+        }
+        Ok(results)
+    }
+
+    /// queue a message for logging, errors are only warned about
+    pub fn log(&self, room_id: &RoomId, ts: MilliSecondsSinceUnixEpoch, sender: &str, text: &str) {
+        let entry = match self.format(room_id, ts, sender, text) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Could not log message for {}: {:?}", room_id, e);
+                return;
+            }
+        };
+        if let Err(e) = self.writer.try_send((room_id.to_owned(), entry)) {
+            warn!("Could not log message for {}: {}", room_id, e);
+        }
+    }
+}
+
+impl LogFiles {
+    fn path(&self, room_id: &RoomId) -> PathBuf {
+        self.dir.join(format!("{}.log", room_id))
+    }
+
+    /// move room.log to room.log.1, room.log.1 to room.log.2 etc, dropping
+    /// the oldest file
+    fn rotate(&self, path: &Path) -> Result<()> {
+        let rotated = |i| PathBuf::from(format!("{}.{}", path.display(), i));
+        if self.config.keep == 0 {
+            return Ok(fs::remove_file(path)?);
+        }
+        for i in (1..self.config.keep).rev() {
+            let from = rotated(i);
+            if from.exists() {
+                fs::rename(from, rotated(i + 1))?;
+            }
+        }
+        fs::rename(path, rotated(1))?;
+        Ok(())
+    }
+
+    fn write(&self, room_id: &RoomId, entry: &str) -> Result<()> {
+        let path = self.path(room_id);
+        if fs::metadata(&path).is_ok_and(|m| m.len() >= self.config.max_size) {
+            self.rotate(&path).context("Could not rotate log")?;
+        }
+        fs::OpenOptions::new()
+            .mode(0o600)
+            .append(true)
+            .create(true)
+            .open(&path)?
+            .write_all(entry.as_bytes())?;
+        Ok(())
+    }
+
+    /// rooms we have logs for
+    fn rooms(&self) -> Result<Vec<OwnedRoomId>> {
+        let mut rooms = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let Some(room_id) = name.to_str().and_then(|n| n.strip_suffix(".log")) else {
+                continue;
+            };
+            if let Ok(room_id) = room_id.try_into() {
+                rooms.push(room_id);
+            }
+        }
+        Ok(rooms)
+    }
+
+    /// log lines matching regex for a room, oldest first
+    fn search_room(&self, room_id: &RoomId, re: &Regex) -> Result<Vec<String>> {
+        let path = self.path(room_id);
+        let mut files: Vec<PathBuf> = (1..=self.config.keep)
+            .rev()
+            .map(|i| PathBuf::from(format!("{}.{}", path.display(), i)))
+            .collect();
+        files.push(path);
+        let mut found = vec![];
+        for file in files {
+            let file = match fs::File::open(&file) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                // match on the message itself for jsonl, anything otherwise
+                let line = match self.config.format {
+                    LogFormat::Jsonl => {
+                        let Ok(entry) = serde_json::from_str::<LogEntry>(&line) else {
+                            continue;
+                        };
+                        if !re.is_match(&entry.text) {
+                            continue;
+                        }
+                        format!("{} <{}> {}", entry.ts, entry.sender, entry.text)
+                    }
+                    _ if re.is_match(&line) => line.replace('\t', " "),
+                    _ => continue,
+                };
+                found.push(line);
+            }
+        }
+        Ok(found)
+    }
+}
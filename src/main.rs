@@ -1,18 +1,36 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 mod args;
+mod commands;
+mod config;
+mod filters;
 mod ircd;
+mod logger;
 mod matrirc;
 mod matrix;
+mod media_server;
 mod state;
+mod store;
+mod systemd;
+mod tools;
+mod webhook;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     env_logger::init();
     // ensure args parse early
-    let _ = args::args();
+    if let Some(tool) = &args::args().command {
+        return tools::run(tool);
+    }
+    // changes the environment, so before any thread is started
+    let systemd_socket = systemd::listen_socket().context("systemd socket")?;
+    run(systemd_socket)
+}
 
-    let ircd = ircd::listen().await;
+#[tokio::main]
+async fn run(systemd_socket: Option<std::net::TcpListener>) -> Result<()> {
+    media_server::listen().await?;
+    webhook::listen().await?;
+    let ircd = ircd::listen(systemd_socket).await;
 
     ircd.await?;
 
@@ -1,15 +1,33 @@
-use anyhow::{Context, Result};
-use lru::LruCache;
+use anyhow::{Context, Error, Result};
+use log::warn;
 use matrix_sdk::{
-    ruma::{EventId, OwnedEventId},
+    ruma::{
+        api::{client::discovery::get_supported_versions, MatrixVersion},
+        EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, RoomId,
+    },
     Client,
 };
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use matrix_sdk_store_encryption::StoreCipher;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, OnceCell, RwLock, Semaphore};
 
-use crate::matrix::room_mappings::Mappings;
+use crate::config::{Config, Joins};
+use crate::filters::Filters;
+use crate::logger::Logger;
+use crate::matrix::room_mappings::{
+    self, casefold, Mappings, MatrixMessageType, NickPolicy, TargetPolicy,
+};
+use crate::matrix::uiaa;
+use crate::state::SessionKey;
+use crate::store::{CachedEvent, Store};
 use crate::{ircd, ircd::IrcClient};
 
+/// media downloaded at the same time
+const MEDIA_DOWNLOADS: usize = 4;
+
 /// client state struct
 #[derive(Clone)]
 pub struct Matrirc {
@@ -19,15 +37,77 @@ pub struct Matrirc {
 
 struct MatrircInner {
     matrix: Client,
+    /// key to save refreshed session, dropped with the session
+    session_key: SessionKey,
     /// stop indicator
     running: RwLock<Running>,
     /// room mappings in both directions
     /// implementation in matrix/room_mappings.rs
     mappings: Mappings,
-    /// recent messages (for reactions, redactions)
-    recent_messages: RwLock<LruCache<OwnedEventId, String>>,
+    /// recent messages (for reactions, redactions) and other persistent data
+    /// implementation in store.rs
+    store: Store,
+    /// per-user preferences, loaded at login
+    config: Config,
+    /// message logs, if enabled in config
+    logger: Option<Logger>,
+    /// message filters from config
+    filters: Filters,
+    /// last message seen in each room (for read receipts)
+    last_messages: RwLock<HashMap<OwnedRoomId, OwnedEventId>>,
+    /// rooms with an open invite query
+    pending_invites: RwLock<HashSet<OwnedRoomId>>,
+    /// messages waiting for `confirm`, see Config::confirm_members
+    held: RwLock<Option<HeldMessages>>,
+    /// big rooms already confirmed once (casefolded names)
+    confirmed: RwLock<HashSet<String>>,
+    /// last message sent from irc, for idle presence
+    last_activity: Mutex<Instant>,
+    /// presence was set to unavailable because of idle_minutes
+    idle: AtomicBool,
+    /// operation waiting for `auth`
+    uiaa: RwLock<Option<uiaa::Pending>>,
+    /// wakes up sync after soft logout
+    relogin: Notify,
+    /// bounds concurrent media downloads
+    media_downloads: Semaphore,
+    /// media quota space reserved by files being saved, not in store yet
+    media_reserved: AtomicU64,
+    /// serializes quota checks so reservations don't race each other
+    media_reserving: tokio::sync::Mutex<()>,
+    /// per-room `joins` settings, persisted in store
+    joins: Mutex<HashMap<OwnedRoomId, Joins>>,
+    /// undecryptable events by megolm session, replayed when the key arrives
+    undecrypted: Mutex<HashMap<String, Vec<(OwnedRoomId, OwnedEventId)>>>,
+    /// megolm sessions already looked up in key backup
+    backup_tried: Mutex<HashSet<String>>,
+    /// homeserver /versions, fetched once. The sdk caches them too but does
+    /// not expose them
+    server_versions: OnceCell<get_supported_versions::Response>,
+}
+
+/// lines sent to a target before `confirm`, kept together e.g. for pastes
+struct HeldMessages {
+    target: String,
+    messages: Vec<(MatrixMessageType, String)>,
+    since: Instant,
+}
+
+impl HeldMessages {
+    fn expired(&self) -> bool {
+        self.since.elapsed() > HELD_EXPIRY
+    }
 }
 
+/// lines held at most before `confirm`, more are refused
+const HELD_MAX: usize = 100;
+/// held messages are dropped if not confirmed in time
+const HELD_EXPIRY: Duration = Duration::from_secs(5 * 60);
+/// undecryptable events remembered at most, more can only be fixed with `rekey`
+const UNDECRYPTED_MAX: usize = 500;
+/// backup lookups remembered at most before trying sessions again
+const BACKUP_TRIED_MAX: usize = 1000;
+
 #[derive(Clone, Copy)]
 pub enum Running {
     First,
@@ -36,17 +116,62 @@ pub enum Running {
 }
 
 impl Matrirc {
-    pub fn new(matrix: Client, irc: IrcClient) -> Matrirc {
-        Matrirc {
+    pub fn new(
+        matrix: Client,
+        irc: IrcClient,
+        store_cipher: StoreCipher,
+        session_key: SessionKey,
+    ) -> Result<Matrirc> {
+        let config = Config::load(&irc.nick)?;
+        let target_policy = TargetPolicy {
+            chan_template: config.chan_name.clone(),
+            force_queries: config.force_queries,
+            dm_chans: config.dm_chans.clone(),
+            query_bursts: config.query_bursts,
+        };
+        let nick_policy = NickPolicy {
+            localpart_nicks: config.localpart_nicks,
+            own_user: matrix.user_id().map(|u| u.to_owned()),
+            own_nick: irc.nick.clone(),
+        };
+        let logger = match &config.log {
+            Some(log_config) => Some(Logger::new(&irc.nick, log_config.clone())?),
+            None => None,
+        };
+        let filters = Filters::new(&config.filters)?;
+        let store = Store::open(&irc.nick, store_cipher, config.message_cache)?;
+        let joins = store
+            .room_settings("joins")?
+            .into_iter()
+            .filter_map(|(room_id, value)| Some((room_id, value.parse().ok()?)))
+            .collect();
+        Ok(Matrirc {
             inner: Arc::new(MatrircInner {
                 matrix,
+                session_key,
                 running: RwLock::new(Running::First),
-                mappings: Mappings::new(irc),
-                recent_messages: RwLock::new(LruCache::new(
-                    std::num::NonZeroUsize::new(1000).unwrap(),
-                )),
+                store,
+                config,
+                logger,
+                filters,
+                mappings: Mappings::new(irc, nick_policy, target_policy),
+                last_messages: RwLock::new(HashMap::new()),
+                pending_invites: RwLock::new(HashSet::new()),
+                held: RwLock::new(None),
+                confirmed: RwLock::new(HashSet::new()),
+                last_activity: Mutex::new(Instant::now()),
+                idle: AtomicBool::new(false),
+                uiaa: RwLock::new(None),
+                relogin: Notify::new(),
+                media_downloads: Semaphore::new(MEDIA_DOWNLOADS),
+                media_reserved: AtomicU64::new(0),
+                media_reserving: tokio::sync::Mutex::new(()),
+                joins: Mutex::new(joins),
+                undecrypted: Mutex::new(HashMap::new()),
+                backup_tried: Mutex::new(HashSet::new()),
+                server_versions: OnceCell::new(),
             }),
-        }
+        })
     }
 
     pub fn irc(&self) -> &IrcClient {
@@ -58,6 +183,52 @@ impl Matrirc {
     pub fn mappings(&self) -> &Mappings {
         &self.inner.mappings
     }
+    pub fn session_key(&self) -> &SessionKey {
+        &self.inner.session_key
+    }
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+    pub fn filters(&self) -> &Filters {
+        &self.inner.filters
+    }
+    pub fn media_downloads(&self) -> &Semaphore {
+        &self.inner.media_downloads
+    }
+    pub fn media_reserved(&self) -> &AtomicU64 {
+        &self.inner.media_reserved
+    }
+    pub fn media_reserving(&self) -> &tokio::sync::Mutex<()> {
+        &self.inner.media_reserving
+    }
+    /// homeserver /versions response, fetched once
+    async fn versions_response(&self) -> Result<&get_supported_versions::Response> {
+        Ok(self
+            .inner
+            .server_versions
+            .get_or_try_init(|| async {
+                self.matrix()
+                    .send(get_supported_versions::Request::new(), None)
+                    .await
+            })
+            .await?)
+    }
+    /// matrix versions supported by the homeserver, as known by ruma
+    pub async fn server_versions(&self) -> Result<Vec<MatrixVersion>> {
+        Ok(self.versions_response().await?.known_versions().collect())
+    }
+    /// all versions advertised by the homeserver ("v1.13"), including ones
+    /// ruma does not know yet
+    pub async fn server_version_strings(&self) -> Result<&[String]> {
+        Ok(&self.versions_response().await?.versions)
+    }
+    /// log error and best-effort forward it to matrirc query
+    pub async fn report_error<S: Into<String>>(&self, message: S) {
+        room_mappings::report_error(self.irc(), message.into()).await
+    }
+    pub fn store(&self) -> &Store {
+        &self.inner.store
+    }
     pub async fn running(&self) -> Running {
         // need let to drop read lock
         let v = *self.inner.running.read().await;
@@ -75,17 +246,361 @@ impl Matrirc {
             run => run,
         }
     }
+    /// like running() == Break, without consuming First
+    pub async fn stopped(&self) -> bool {
+        matches!(*self.inner.running.read().await, Running::Break)
+    }
+    /// wait for relogin after soft logout (or stop)
+    pub async fn wait_relogin(&self) {
+        let notified = self.inner.relogin.notified();
+        tokio::pin!(notified);
+        // register before checking so a stop in between is not missed
+        notified.as_mut().enable();
+        if self.stopped().await {
+            return;
+        }
+        notified.await
+    }
+    pub fn relogin_done(&self) {
+        self.inner.relogin.notify_one()
+    }
     pub async fn stop<S: Into<String>>(&self, reason: S) -> Result<()> {
         *self.inner.running.write().await = Running::Break;
+        if let Err(e) = self.mappings().save_pending(self.store()).await {
+            warn!("Could not save pending messages: {:?}", e);
+        }
+        // sync might be waiting for relogin: only wake current waiters, a
+        // stored permit would skip the next wait
+        self.inner.relogin.notify_waiters();
         self.irc()
             .send(ircd::proto::error(reason))
             .await
             .context("stop quit message")
     }
     pub async fn message_get(&self, id: &EventId) -> Option<String> {
-        self.inner.recent_messages.read().await.peek(id).cloned()
+        let event_id = id.to_owned();
+        self.store()
+            .blocking(move |store| store.message_get(&event_id))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Could not get message {}: {:?}", id, e);
+                None
+            })
+    }
+    pub async fn event_get(&self, id: &EventId) -> Option<CachedEvent> {
+        let event_id = id.to_owned();
+        self.store()
+            .blocking(move |store| store.event_get(&event_id))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Could not get event {}: {:?}", id, e);
+                None
+            })
+    }
+    pub async fn event_put(&self, room_id: &RoomId, id: &EventId, event: CachedEvent) {
+        let (room_id, event_id) = (room_id.to_owned(), id.to_owned());
+        if let Err(e) = self
+            .store()
+            .blocking(move |store| store.event_put(&room_id, &event_id, &event))
+            .await
+        {
+            warn!("Could not store event {}: {:?}", id, e);
+        }
+    }
+    /// true if event was already delivered to irc
+    pub async fn is_delivered(&self, id: &EventId) -> bool {
+        let event_id = id.to_owned();
+        self.store()
+            .blocking(move |store| store.is_delivered(&event_id))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Could not check delivered event {}: {:?}", id, e);
+                false
+            })
+    }
+    /// remember event was delivered, call once it has been sent to irc
+    pub async fn mark_delivered(&self, room_id: &RoomId, id: &EventId) {
+        let (room_id, event_id) = (room_id.to_owned(), id.to_owned());
+        if let Err(e) = self
+            .store()
+            .blocking(move |store| store.mark_delivered(&room_id, &event_id))
+            .await
+        {
+            warn!("Could not remember delivered event {}: {:?}", id, e);
+        }
+    }
+    /// store message and return its short id
+    pub async fn message_put(
+        &self,
+        room_id: &RoomId,
+        id: &EventId,
+        message: &str,
+    ) -> Option<String> {
+        let (room_id, event_id, message) = (room_id.to_owned(), id.to_owned(), message.to_string());
+        self.store()
+            .blocking(move |store| store.message_put(&room_id, &event_id, &message))
+            .await
+            .map_err(|e| warn!("Could not store message {}: {:?}", id, e))
+            .ok()
+    }
+    pub async fn message_short_id(&self, id: &EventId) -> Option<String> {
+        let event_id = id.to_owned();
+        self.store()
+            .blocking(move |store| store.message_short_id(&event_id))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Could not get short id of {}: {:?}", id, e);
+                None
+            })
     }
-    pub async fn message_put(&self, id: OwnedEventId, message: String) {
-        let _ = self.inner.recent_messages.write().await.put(id, message);
+    /// find message from short id
+    pub async fn message_lookup(&self, id: &str) -> Option<(OwnedRoomId, OwnedEventId)> {
+        let short_id = id.to_string();
+        self.store()
+            .blocking(move |store| store.message_lookup(&short_id))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Could not lookup message {}: {:?}", id, e);
+                None
+            })
+    }
+    /// log bridged message if enabled
+    pub fn log_message(
+        &self,
+        room_id: &RoomId,
+        ts: MilliSecondsSinceUnixEpoch,
+        sender: &str,
+        text: &str,
+    ) {
+        if let Some(logger) = &self.inner.logger {
+            logger.log(room_id, ts, sender, text)
+        }
+    }
+    pub fn logger(&self) -> Option<&Logger> {
+        self.inner.logger.as_ref()
+    }
+    pub async fn last_message_get(&self, room_id: &RoomId) -> Option<OwnedEventId> {
+        self.inner.last_messages.read().await.get(room_id).cloned()
+    }
+    pub async fn last_message_put(&self, room_id: OwnedRoomId, id: OwnedEventId) {
+        let _ = self.inner.last_messages.write().await.insert(room_id, id);
+    }
+    /// remember event waiting for the key of megolm session
+    pub fn undecrypted_put(&self, session_id: &str, room_id: OwnedRoomId, event_id: OwnedEventId) {
+        let mut undecrypted = self
+            .inner
+            .undecrypted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if undecrypted.values().map(Vec::len).sum::<usize>() >= UNDECRYPTED_MAX {
+            return;
+        }
+        undecrypted
+            .entry(session_id.to_string())
+            .or_default()
+            .push((room_id, event_id));
+    }
+    /// events waiting for the key of megolm session, forgetting them
+    pub fn undecrypted_take(&self, session_id: &str) -> Vec<(OwnedRoomId, OwnedEventId)> {
+        self.inner
+            .undecrypted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(session_id)
+            .unwrap_or_default()
+    }
+    /// true the first time a session is looked up in key backup
+    pub fn backup_try(&self, session_id: &str) -> bool {
+        let mut tried = self
+            .inner
+            .backup_tried
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if tried.len() >= BACKUP_TRIED_MAX {
+            tried.clear();
+        }
+        tried.insert(session_id.to_string())
+    }
+    /// mark invite as being asked about, false if it already was
+    pub async fn invite_pending(&self, room_id: OwnedRoomId) -> bool {
+        self.inner.pending_invites.write().await.insert(room_id)
+    }
+    pub async fn invite_done(&self, room_id: &RoomId) {
+        self.inner.pending_invites.write().await.remove(room_id);
+    }
+    /// replaces any operation previously waiting for `auth`
+    pub async fn set_uiaa(&self, pending: uiaa::Pending) {
+        *self.inner.uiaa.write().await = Some(pending);
+    }
+    pub async fn take_uiaa(&self) -> Option<uiaa::Pending> {
+        self.inner.uiaa.write().await.take()
+    }
+    /// join/part visibility in room: `joins` setting, or show_joins
+    pub fn joins(&self, room_id: &RoomId) -> Joins {
+        let default = match self.config().show_joins {
+            true => Joins::On,
+            false => Joins::Off,
+        };
+        self.inner
+            .joins
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(room_id)
+            .copied()
+            .unwrap_or(default)
+    }
+    pub async fn joins_set(&self, room_id: &RoomId, joins: Option<Joins>) -> Result<()> {
+        {
+            let mut cache = self.inner.joins.lock().unwrap_or_else(|e| e.into_inner());
+            match joins {
+                Some(joins) => cache.insert(room_id.to_owned(), joins),
+                None => cache.remove(room_id),
+            };
+        }
+        let room_id = room_id.to_owned();
+        self.store()
+            .blocking(move |store| {
+                store.room_setting_set(&room_id, "joins", joins.map(Joins::as_str))
+            })
+            .await
+    }
+    /// record irc activity, returns whether we were idle
+    pub fn touch(&self) -> bool {
+        *self.inner.last_activity.lock().unwrap() = Instant::now();
+        self.inner.idle.swap(false, Ordering::Relaxed)
+    }
+    pub fn idle_time(&self) -> Duration {
+        self.inner.last_activity.lock().unwrap().elapsed()
+    }
+    /// whether both are handles to the same client session
+    pub fn same_session(&self, other: &Matrirc) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+    pub fn is_idle(&self) -> bool {
+        self.inner.idle.load(Ordering::Relaxed)
+    }
+    /// mark idle, returns whether we weren't already
+    pub fn set_idle(&self) -> bool {
+        !self.inner.idle.swap(true, Ordering::Relaxed)
+    }
+    /// hold message to a big room or mentioning @room until `confirm`,
+    /// returns whether it was held
+    pub async fn hold_unconfirmed(
+        &self,
+        target: &str,
+        message_type: MatrixMessageType,
+        message: &str,
+    ) -> Result<bool> {
+        let Some(max_members) = self.config().confirm_members else {
+            return Ok(false);
+        };
+        let mut held = self.inner.held.write().await;
+        if let Some(old) = held.take() {
+            if !old.expired() {
+                *held = Some(old);
+            } else {
+                self.tell_held(
+                    &old.target,
+                    format!(
+                        "Dropped {} message(s) not confirmed in time",
+                        old.messages.len()
+                    ),
+                )
+                .await?;
+            }
+        }
+        if let Some(held) = held
+            .as_mut()
+            .filter(|h| casefold(&h.target) == casefold(target))
+        {
+            // rest of a paste
+            if held.messages.len() >= HELD_MAX {
+                return Err(Error::msg(format!(
+                    "{} messages already waiting for confirm",
+                    HELD_MAX
+                )));
+            }
+            held.messages.push((message_type, message.to_string()));
+            return Ok(true);
+        }
+        let reason = if message.starts_with("@room") {
+            Some("it mentions @room".to_string())
+        } else if self
+            .inner
+            .confirmed
+            .read()
+            .await
+            .contains(&casefold(target))
+        {
+            None
+        } else {
+            match self.mappings().room(target).await {
+                Some(room) if room.joined_members_count() > max_members => {
+                    Some(format!("{} members", room.joined_members_count()))
+                }
+                _ => None,
+            }
+        };
+        let Some(reason) = reason else {
+            return Ok(false);
+        };
+        let previous = held.replace(HeldMessages {
+            target: target.to_string(),
+            messages: vec![(message_type, message.to_string())],
+            since: Instant::now(),
+        });
+        drop(held);
+        if let Some(previous) = previous {
+            self.tell_held(
+                &previous.target,
+                format!(
+                    "Dropped {} unconfirmed message(s) for new message to {}",
+                    previous.messages.len(),
+                    target
+                ),
+            )
+            .await?;
+        }
+        self.tell_held(
+            target,
+            format!(
+                "Message not sent yet ({}): send {}confirm within {} minutes to send it",
+                reason,
+                self.config().command_prefix,
+                HELD_EXPIRY.as_secs() / 60
+            ),
+        )
+        .await?;
+        Ok(true)
+    }
+    /// notice in the chan messages were held for
+    async fn tell_held(&self, target: &str, message: String) -> Result<()> {
+        self.irc()
+            .send(ircd::proto::notice("matrirc", target, message))
+            .await
+    }
+    /// take held messages, sending to their target won't need confirm again
+    /// (unless mentioning @room)
+    pub async fn confirm_held(&self) -> Result<(String, Vec<(MatrixMessageType, String)>)> {
+        let held = self
+            .inner
+            .held
+            .write()
+            .await
+            .take()
+            .context("No message waiting for confirmation")?;
+        if held.expired() {
+            return Err(Error::msg(format!(
+                "{} message(s) to {} not confirmed in time were dropped",
+                held.messages.len(),
+                held.target
+            )));
+        }
+        self.inner
+            .confirmed
+            .write()
+            .await
+            .insert(casefold(&held.target));
+        Ok((held.target, held.messages))
     }
 }
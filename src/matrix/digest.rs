@@ -0,0 +1,49 @@
+use anyhow::Result;
+use matrix_sdk::room::Room;
+
+use crate::matrirc::Matrirc;
+
+/// unread messages and highlights in room: server counts from sync,
+/// client ones from receipts can be better for encrypted rooms
+pub fn unread_counts(room: &Room) -> (u64, u64) {
+    let counts = room.unread_notification_counts();
+    (
+        counts.notification_count.max(room.num_unread_messages()),
+        counts.highlight_count.max(room.num_unread_mentions()),
+    )
+}
+
+/// after reconnecting, summarize mentions and direct messages that nobody
+/// read in the meantime, e.g. "3 mentions in #work, 1 message from alice"
+pub async fn send_digest(matrirc: &Matrirc) -> Result<()> {
+    let mut missed = vec![];
+    for (room_id, target) in matrirc.mappings().list_rooms().await {
+        let Some(room) = matrirc.matrix().get_room(&room_id) else {
+            continue;
+        };
+        let (messages, highlights) = unread_counts(&room);
+        let name = target.irc_name().await;
+        if room.is_direct().await.unwrap_or(false) && messages > 0 {
+            let plural = if messages > 1 { "s" } else { "" };
+            missed.push((
+                messages,
+                format!("{} message{} from {}", messages, plural, name),
+            ));
+        } else if highlights > 0 {
+            let plural = if highlights > 1 { "s" } else { "" };
+            missed.push((
+                highlights,
+                format!("{} mention{} in {}", highlights, plural, name),
+            ));
+        }
+    }
+    if missed.is_empty() {
+        return Ok(());
+    }
+    missed.sort_by(|a, b| b.cmp(a));
+    let summary: Vec<String> = missed.into_iter().map(|(_, text)| text).collect();
+    matrirc
+        .mappings()
+        .matrirc_query(format!("Missed while away: {}", summary.join(", ")))
+        .await
+}
@@ -0,0 +1,201 @@
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use matrix_sdk::ruma::{
+    api::client::{error::ErrorKind, push::get_notifications},
+    events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent},
+    MilliSecondsSinceUnixEpoch, OwnedEventId,
+};
+use matrix_sdk::Room;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+use crate::args::args;
+use crate::matrirc::Matrirc;
+
+/// hook commands running at once, for all users: more are dropped
+const HOOKS_MAX: usize = 8;
+/// hook commands still running after this are killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+/// how often notifications are checked while no client is connected
+const DETACHED_POLL: Duration = Duration::from_secs(30);
+/// notifications looked at per check, older ones were already handled
+const DETACHED_LIMIT: u32 = 20;
+
+lazy_static! {
+    static ref HOOKS: Arc<Semaphore> = Arc::new(Semaphore::new(HOOKS_MAX));
+    /// notification watchers of sessions whose clients all left, by nick
+    static ref DETACHED: Mutex<HashMap<String, AbortHandle>> = Mutex::new(HashMap::new());
+}
+
+fn detached() -> MutexGuard<'static, HashMap<String, AbortHandle>> {
+    DETACHED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// run --hook-command for a message received while the client is connected:
+/// only while idle, if idle_minutes is set, as the user is not looking then
+pub fn notify(matrirc: &Matrirc, kind: &str, room: &str, sender: &str, message: &str) {
+    if matrirc.config().idle_minutes.is_none() || !matrirc.is_idle() {
+        return;
+    }
+    run(&matrirc.irc().nick, kind, room, sender, message)
+}
+
+/// run --hook-command with message details as json on stdin, e.g. for
+/// push notifications
+fn run(nick: &str, kind: &str, room: &str, sender: &str, message: &str) {
+    let Some(command) = &args().hook_command else {
+        return;
+    };
+    let Ok(permit) = HOOKS.clone().try_acquire_owned() else {
+        warn!("Too many hook commands running, dropping {} hook", kind);
+        return;
+    };
+    let event = json!({
+        "nick": nick,
+        "type": kind,
+        "room": room,
+        "sender": sender,
+        "message": message,
+    });
+    let command = command.clone();
+    tokio::spawn(async move {
+        let _permit = permit;
+        debug!("Running hook for {}", event);
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Could not run hook command: {:?}", e);
+                return;
+            }
+        };
+        let run = async {
+            if let Some(mut stdin) = child.stdin.take() {
+                if let Err(e) = stdin.write_all(event.to_string().as_bytes()).await {
+                    warn!("Could not write to hook command: {:?}", e);
+                }
+            }
+            child.wait().await
+        };
+        match tokio::time::timeout(HOOK_TIMEOUT, run).await {
+            Ok(Ok(status)) if !status.success() => warn!("Hook command failed: {}", status),
+            Ok(Err(e)) => warn!("Could not wait for hook command: {:?}", e),
+            Err(_) => warn!("Hook command timed out"),
+            Ok(Ok(_)) => (),
+        }
+    });
+}
+
+/// keep running --hook-command for highlights and direct messages after the
+/// last client of this session left, until one logs in again (stop_detached).
+/// Nothing is synced meanwhile so the next client still gets these messages:
+/// the homeserver's notifications list is polled instead.
+pub fn watch_detached(matrirc: Matrirc) {
+    if args().hook_command.is_none() {
+        return;
+    }
+    let nick = matrirc.irc().nick.clone();
+    info!("Watching notifications of {} while detached", nick);
+    let handle = tokio::spawn(poll_notifications(matrirc));
+    if let Some(previous) = detached().insert(nick, handle.abort_handle()) {
+        previous.abort();
+    }
+}
+
+/// stop watching notifications of nick, e.g. before its client logs in again
+pub fn stop_detached(nick: &str) {
+    if let Some(handle) = detached().remove(nick) {
+        debug!("Stopped watching notifications of {}", nick);
+        handle.abort();
+    }
+}
+
+async fn poll_notifications(matrirc: Matrirc) {
+    let nick = matrirc.irc().nick.clone();
+    let since = MilliSecondsSinceUnixEpoch::now();
+    // notifications of the last check, the list only ever grows at the front
+    let mut seen: HashSet<OwnedEventId> = HashSet::new();
+    loop {
+        tokio::time::sleep(DETACHED_POLL).await;
+        let mut request = get_notifications::v3::Request::new();
+        request.limit = Some(DETACHED_LIMIT.into());
+        let notifications = match matrirc.matrix().send(request, None).await {
+            Ok(response) => response.notifications,
+            Err(e) => {
+                if let Some(ErrorKind::UnknownToken { .. }) = e.client_api_error_kind() {
+                    info!("Stopped watching notifications of {}: logged out", nick);
+                    return;
+                }
+                warn!("Could not get notifications of {}: {}", nick, e);
+                continue;
+            }
+        };
+        let mut current = HashSet::new();
+        for notification in notifications {
+            let Ok(event) = notification.event.deserialize() else {
+                continue;
+            };
+            current.insert(event.event_id().to_owned());
+            if notification.read
+                || notification.ts < since
+                || seen.contains(event.event_id())
+                || Some(event.sender()) == matrirc.matrix().user_id()
+            {
+                continue;
+            }
+            let Some(room) = matrirc.matrix().get_room(&notification.room_id) else {
+                continue;
+            };
+            let kind = if notification.actions.iter().any(|a| a.is_highlight()) {
+                "highlight"
+            } else if room.is_direct().await.unwrap_or(false) {
+                "dm"
+            } else {
+                continue;
+            };
+            let Some(message) = message_text(&event) else {
+                continue;
+            };
+            let name = room_name(&matrirc, &room).await;
+            run(&nick, kind, &name, event.sender().as_str(), &message);
+        }
+        seen = current;
+    }
+}
+
+/// body of messages, the server cannot give us anything better for encrypted
+/// ones as room keys only come with sync
+fn message_text(event: &AnySyncTimelineEvent) -> Option<String> {
+    match event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(event),
+        )) => Some(event.content.body().to_string()),
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(_)) => {
+            Some("(encrypted message)".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// name of the room on irc if the session had it mapped, matrix name otherwise
+async fn room_name(matrirc: &Matrirc, room: &Room) -> String {
+    for (room_id, target) in matrirc.mappings().list_rooms().await {
+        if room_id == room.room_id() {
+            return target.irc_name().await;
+        }
+    }
+    matrirc.mappings().room_name(room).await
+}
@@ -1,16 +1,126 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Error, Result};
 use async_trait::async_trait;
 use log::{trace, warn};
 use matrix_sdk::{
-    event_handler::Ctx, room::Room, ruma::events::room::member::StrippedRoomMemberEvent, RoomState,
+    deserialized_responses::MemberEvent,
+    deserialized_responses::RawSyncOrStrippedState,
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        api::client::{membership::leave_room, room::report_content},
+        events::room::member::{RoomMemberEventContent, StrippedRoomMemberEvent},
+        OwnedEventId, OwnedUserId,
+    },
+    BaseRoom, RoomState,
 };
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::{sleep, Duration};
 
+use crate::config::AutoJoin;
 use crate::matrirc::Matrirc;
 use crate::matrix::room_mappings::{room_name, MatrixMessageType, MessageHandler, RoomTarget};
 
+/// `POST /_matrix/client/v3/rooms/{roomId}/report` (matrix 1.13), not in
+/// our ruma version yet. ruma's request macros cannot be used outside of
+/// ruma, so this is what they would generate for a client.
+mod report_room {
+    use matrix_sdk::bytes::BufMut;
+    use matrix_sdk::ruma::{
+        api::{
+            client::Error,
+            error::{FromHttpResponseError, IntoHttpError},
+            EndpointError, IncomingResponse, MatrixVersion, Metadata, OutgoingRequest,
+            SendAccessToken,
+        },
+        metadata,
+        serde::json_to_buf,
+        OwnedRoomId,
+    };
+    use serde_json::json;
+
+    #[derive(Clone, Debug)]
+    pub struct Request {
+        pub room_id: OwnedRoomId,
+        pub reason: Option<String>,
+    }
+
+    #[derive(Debug)]
+    pub struct Response {}
+
+    impl OutgoingRequest for Request {
+        type EndpointError = Error;
+        type IncomingResponse = Response;
+
+        // ruma does not know matrix 1.13 yet so this cannot be declared here,
+        // check supports_report_room before sending
+        const METADATA: Metadata = metadata! {
+            method: POST,
+            rate_limited: true,
+            authentication: AccessToken,
+            history: {
+                1.1 => "/_matrix/client/v3/rooms/:room_id/report",
+            }
+        };
+
+        fn try_into_http_request<T: Default + BufMut>(
+            self,
+            base_url: &str,
+            access_token: SendAccessToken<'_>,
+            considering_versions: &[MatrixVersion],
+        ) -> Result<http::Request<T>, IntoHttpError> {
+            let url = Self::METADATA.make_endpoint_url(
+                considering_versions,
+                base_url,
+                &[&self.room_id],
+                "",
+            )?;
+            let mut builder = http::Request::builder()
+                .method(Self::METADATA.method)
+                .uri(url)
+                .header(http::header::CONTENT_TYPE, "application/json");
+            if let Some((name, value)) = Self::METADATA.authorization_header(access_token)? {
+                builder = builder.header(name, value);
+            }
+            Ok(builder.body(json_to_buf(&json!({ "reason": self.reason }))?)?)
+        }
+    }
+
+    impl IncomingResponse for Response {
+        type EndpointError = Error;
+
+        fn try_from_http_response<T: AsRef<[u8]>>(
+            response: http::Response<T>,
+        ) -> Result<Self, FromHttpResponseError<Error>> {
+            if response.status().is_success() {
+                Ok(Response {})
+            } else {
+                Err(FromHttpResponseError::Server(Error::from_http_response(
+                    response,
+                )))
+            }
+        }
+    }
+}
+
+/// whether a /versions entry ("v1.13") is at least matrix major.minor
+fn version_at_least(version: &str, major: u32, minor: u32) -> bool {
+    let Some((v_major, v_minor)) = version
+        .strip_prefix('v')
+        .and_then(|v| v.split_once('.'))
+        .and_then(|(ma, mi)| Some((ma.parse::<u32>().ok()?, mi.parse::<u32>().ok()?)))
+    else {
+        return false;
+    };
+    (v_major, v_minor) >= (major, minor)
+}
+
+/// homeserver implements report_room (matrix 1.13), which ruma does not know
+async fn supports_report_room(matrirc: &Matrirc) -> Result<bool> {
+    let versions = matrirc.server_version_strings().await?;
+    Ok(versions.iter().any(|v| version_at_least(v, 1, 13)))
+}
+
 #[derive(Clone)]
 struct InvitationContext {
     inner: Arc<InvitationContextInner>,
@@ -19,15 +129,18 @@ struct InvitationContextInner {
     matrirc: Matrirc,
     room: Room,
     room_name: String,
+    /// who sent the invite, for ignore
+    inviter: OwnedUserId,
     target: RwLock<Option<RoomTarget>>,
 }
 
 impl InvitationContext {
-    async fn new(matrirc: Matrirc, room: Room) -> Self {
+    async fn new(matrirc: Matrirc, room: Room, inviter: OwnedUserId) -> Self {
         InvitationContext {
             inner: Arc::new(InvitationContextInner {
+                room_name: matrirc.mappings().room_name(&room).await,
+                inviter,
                 matrirc,
-                room_name: room_name(&room),
                 room,
                 target: RwLock::new(None),
             }),
@@ -45,7 +158,56 @@ impl InvitationContext {
             .send_simple_query(self.inner.matrirc.irc(), message)
             .await
     }
+    /// reject invite, with optional reason
+    async fn decline(&self, reason: Option<&str>) -> Result<()> {
+        let mut request = leave_room::v3::Request::new(self.inner.room.room_id().to_owned());
+        request.reason = reason.map(str::to_string);
+        self.inner.matrirc.matrix().send(request, None).await?;
+        Ok(())
+    }
+    /// event id of our invite, if the server included it in stripped state
+    async fn invite_event_id(&self) -> Option<OwnedEventId> {
+        let room = &self.inner.room;
+        match room
+            .get_state_event_static_for_key::<RoomMemberEventContent, _>(room.own_user_id())
+            .await
+        {
+            Ok(Some(RawSyncOrStrippedState::Stripped(raw))) => {
+                raw.get_field("event_id").ok().flatten()
+            }
+            Ok(Some(RawSyncOrStrippedState::Sync(raw))) => raw.get_field("event_id").ok().flatten(),
+            _ => None,
+        }
+    }
+    /// report invite event to homeserver admins, or the whole room (matrix
+    /// 1.13) as stripped state usually has no event id
+    async fn report(&self, reason: Option<&str>) -> Result<()> {
+        let room_id = self.inner.room.room_id().to_owned();
+        let reason = reason.map(str::to_string);
+        let client = self.inner.matrirc.matrix();
+        match self.invite_event_id().await {
+            Some(event_id) => {
+                let request = report_content::v3::Request::new(room_id, event_id, None, reason);
+                client.send(request, None).await?;
+            }
+            None => {
+                if !supports_report_room(&self.inner.matrirc).await? {
+                    return Err(Error::msg(
+                        "the invite has no event id and the homeserver cannot report rooms (matrix 1.13)",
+                    ));
+                }
+                client
+                    .send(report_room::Request { room_id, reason }, None)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
     async fn stop(&self) -> Result<()> {
+        self.inner
+            .matrirc
+            .invite_done(self.inner.room.room_id())
+            .await;
         self.inner
             .matrirc
             .mappings()
@@ -72,7 +234,11 @@ impl MessageHandler for InvitationContext {
         _message_type: MatrixMessageType,
         message: String,
     ) -> Result<()> {
-        match message.as_str() {
+        let (answer, reason) = match message.split_once(' ') {
+            Some((answer, reason)) => (answer, Some(reason.trim())),
+            None => (message.as_str(), None),
+        };
+        match answer {
             "yes" => {
                 let clone = self.clone();
                 tokio::spawn(async move {
@@ -122,11 +288,33 @@ impl MessageHandler for InvitationContext {
             "no" => {
                 self.to_irc("Okay").await?;
                 // XXX log failure?
-                self.inner.room.leave().await?;
+                self.decline(reason).await?;
+                self.stop().await?;
+            }
+            "ignore" => {
+                self.decline(reason).await?;
+                self.inner
+                    .matrirc
+                    .matrix()
+                    .account()
+                    .ignore_user(&self.inner.inviter)
+                    .await?;
+                self.to_irc(format!("Declined and ignored {}", self.inner.inviter))
+                    .await?;
+                self.stop().await?;
+            }
+            "report" => {
+                if let Err(e) = self.report(reason).await {
+                    self.to_irc(format!("Could not report room: {}", e)).await?;
+                } else {
+                    self.to_irc("Reported room").await?;
+                }
+                self.decline(reason).await?;
                 self.stop().await?;
             }
             _ => {
-                self.to_irc("expecting yes or no").await?;
+                self.to_irc("expecting yes, no [reason], ignore [reason] or report [reason]")
+                    .await?;
             }
         };
         Ok(())
@@ -137,6 +325,35 @@ impl MessageHandler for InvitationContext {
     }
 }
 
+/// inviter, reason and what we know of the room from stripped state
+async fn describe_invite(room: &Room, event: &StrippedRoomMemberEvent) -> Vec<String> {
+    let inviter = match room.get_member_no_sync(&event.sender).await {
+        Ok(Some(member)) if member.display_name().is_some() => {
+            format!("{} ({})", member.name(), event.sender)
+        }
+        _ => event.sender.to_string(),
+    };
+    let mut lines = vec![format!("Invited by {}", inviter)];
+    if let Some(reason) = &event.content.reason {
+        lines.push(format!("Reason: {}", reason));
+    }
+    if let Some(topic) = room.topic() {
+        lines.push(format!("Topic: {}", topic));
+    }
+    let members = room.joined_members_count();
+    let encrypted = if BaseRoom::is_encrypted(room) {
+        "encrypted"
+    } else {
+        "not encrypted"
+    };
+    if members > 0 {
+        lines.push(format!("{} members, {}", members, encrypted));
+    } else {
+        lines.push(format!("Room is {}", encrypted));
+    }
+    lines
+}
+
 pub async fn on_stripped_state_member(
     room_member: StrippedRoomMemberEvent,
     room: Room,
@@ -155,14 +372,116 @@ pub async fn on_stripped_state_member(
     if room.state() != RoomState::Invited {
         return Ok(());
     };
-    let invite = InvitationContext::new(matrirc.clone(), room.clone()).await;
+    handle_invite(&matrirc, room, room_member).await
+}
+
+/// prompt again for invites received while we were not connected
+pub async fn resurface_invites(matrirc: &Matrirc) -> Result<()> {
+    let user_id = matrirc
+        .matrix()
+        .user_id()
+        .context("Matrix client without user_id?")?
+        .to_owned();
+    for room in matrirc.matrix().invited_rooms() {
+        let Some(member) = room.get_member_no_sync(&user_id).await? else {
+            continue;
+        };
+        let MemberEvent::Stripped(event) = member.event().as_ref() else {
+            continue;
+        };
+        if let Err(e) = handle_invite(matrirc, room.clone(), event.clone()).await {
+            matrirc
+                .report_error(format!(
+                    "Could not show invite to {}: {}",
+                    room.room_id(),
+                    e
+                ))
+                .await;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_invite(
+    matrirc: &Matrirc,
+    room: Room,
+    room_member: StrippedRoomMemberEvent,
+) -> Result<()> {
+    let autojoin = if matrirc
+        .config()
+        .autoaccept_invite(room_member.sender.as_str())
+    {
+        AutoJoin::Always
+    } else {
+        matrirc.config().autojoin
+    };
+    if autojoin == AutoJoin::Never {
+        matrirc
+            .mappings()
+            .matrirc_query(format!(
+                "Ignoring invitation for {} (autojoin = never)",
+                room_name(&room)
+            ))
+            .await?;
+        return Ok(());
+    }
+    if !matrirc.invite_pending(room.room_id().to_owned()).await {
+        trace!("Already asked about {}", room.room_id());
+        return Ok(());
+    }
+    let invite =
+        InvitationContext::new(matrirc.clone(), room.clone(), room_member.sender.clone()).await;
     matrirc.mappings().insert_deduped("invite", &invite).await;
-    // XXX add reason and whatever else to message
-    invite
-        .to_irc(format!(
-            "Got an invitation for {}, accept? [yes/no]",
-            invite.inner.room_name
-        ))
-        .await?;
+    if autojoin == AutoJoin::Always {
+        return invite
+            .handle_message(MatrixMessageType::Text, "yes".to_string())
+            .await;
+    }
+    let mut lines = vec![format!("Got an invitation for {}", invite.inner.room_name)];
+    lines.extend(describe_invite(&room, &room_member).await);
+    lines.push("Accept? [yes/no [reason]/ignore [reason]/report [reason]]".to_string());
+    invite.to_irc(lines.join("\n")).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{
+        api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+        room_id,
+    };
+
+    #[test]
+    fn check_report_room_request() {
+        let request = report_room::Request {
+            room_id: room_id!("!room:domain.tld").to_owned(),
+            reason: Some("spam".to_string()),
+        };
+        let http_request = request
+            .try_into_http_request::<Vec<u8>>(
+                "https://domain.tld",
+                SendAccessToken::Always("token"),
+                &[MatrixVersion::V1_1],
+            )
+            .unwrap();
+        assert_eq!(
+            http_request.uri().to_string(),
+            "https://domain.tld/_matrix/client/v3/rooms/!room:domain.tld/report"
+        );
+        assert_eq!(http_request.body().as_slice(), br#"{"reason":"spam"}"#);
+        assert_eq!(
+            http_request.headers()["authorization"].to_str().unwrap(),
+            "Bearer token"
+        );
+    }
+
+    #[test]
+    fn check_version_at_least() {
+        assert!(version_at_least("v1.13", 1, 13));
+        assert!(version_at_least("v1.14", 1, 13));
+        assert!(version_at_least("v2.0", 1, 13));
+        assert!(!version_at_least("v1.12", 1, 13));
+        assert!(!version_at_least("r0.6.1", 1, 13));
+    }
+}
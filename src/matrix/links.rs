@@ -0,0 +1,138 @@
+use anyhow::{Error, Result};
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use log::trace;
+use matrix_sdk::{
+    room::Room,
+    ruma::{matrix_uri::MatrixId, MatrixToUri, MatrixUri, RoomId, RoomOrAliasId, UserId},
+    RoomState,
+};
+use regex::Regex;
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::matrirc::Matrirc;
+use crate::matrix::room_mappings::room_name;
+use crate::matrix::sync_reaction::get_message_from_event_id;
+
+/// max length of quoted messages
+const SNIPPET_LEN: usize = 60;
+/// links still unresolved after this are shown as timed out, so a slow
+/// homeserver does not hold the message back
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn find_links(body: &str) -> Vec<MatrixId> {
+    lazy_static! {
+        static ref LINK: Regex =
+            Regex::new(r#"(https://matrix\.to/#/|matrix:)[^\s<>"'()]+"#).unwrap();
+    }
+    LINK.find_iter(body)
+        .filter_map(|m| {
+            let link = m.as_str().trim_end_matches(['.', ',', '!', '?', ':', ';']);
+            if link.starts_with("matrix:") {
+                MatrixUri::parse(link).ok().map(|uri| uri.id().clone())
+            } else {
+                MatrixToUri::parse(link).ok().map(|uri| uri.id().clone())
+            }
+        })
+        .collect()
+}
+
+/// message on a single line, cut after len characters
+pub fn snippet(message: &str, len: usize) -> String {
+    let message = message.replace('\n', " ");
+    match message.char_indices().nth(len) {
+        Some((idx, _)) => format!("{}…", &message[..idx]),
+        None => message,
+    }
+}
+
+async fn describe_user(matrirc: &Matrirc, room: &Room, user_id: &UserId) -> String {
+    if let Ok(Some(member)) = room.get_member_no_sync(user_id).await {
+        return member.name().to_string();
+    }
+    match matrirc
+        .matrix()
+        .account()
+        .fetch_user_profile_of(user_id)
+        .await
+    {
+        Ok(profile) => profile
+            .displayname
+            .unwrap_or_else(|| user_id.localpart().to_string()),
+        Err(_) => user_id.to_string(),
+    }
+}
+
+async fn resolve_room(matrirc: &Matrirc, room_or_alias: &RoomOrAliasId) -> Option<Room> {
+    let room_id = match <&RoomId>::try_from(room_or_alias) {
+        Ok(room_id) => room_id.to_owned(),
+        Err(alias) => {
+            matrirc
+                .matrix()
+                .resolve_room_alias(alias)
+                .await
+                .ok()?
+                .room_id
+        }
+    };
+    matrirc.matrix().get_room(&room_id)
+}
+
+async fn describe_room(matrirc: &Matrirc, room_or_alias: &RoomOrAliasId) -> String {
+    let Some(room) = resolve_room(matrirc, room_or_alias).await else {
+        return room_or_alias.to_string();
+    };
+    if room.state() == RoomState::Joined {
+        let target = matrirc.mappings().room_target(&room).await;
+        let name = matrirc.mappings().room_name(&room).await;
+        return format!("{} ({})", name, target.target().await);
+    }
+    match room.canonical_alias() {
+        Some(alias) => format!("{} ({})", room_name(&room), alias),
+        None => room_name(&room),
+    }
+}
+
+async fn describe(matrirc: &Matrirc, room: &Room, id: MatrixId) -> Result<String> {
+    Ok(match id {
+        MatrixId::User(user_id) => {
+            format!("user {}", describe_user(matrirc, room, &user_id).await)
+        }
+        MatrixId::Room(room_id) => {
+            format!("room {}", describe_room(matrirc, (&*room_id).into()).await)
+        }
+        MatrixId::RoomAlias(alias) => {
+            format!("room {}", describe_room(matrirc, (&*alias).into()).await)
+        }
+        MatrixId::Event(room_or_alias, event_id) => {
+            let event_room = match resolve_room(matrirc, &room_or_alias).await {
+                Some(r) => r,
+                None => room.clone(),
+            };
+            let message = get_message_from_event_id(matrirc, &event_room, &event_id).await?;
+            snippet(&message, SNIPPET_LEN)
+        }
+        _ => return Err(Error::msg("unknown link type")),
+    })
+}
+
+/// append a short description of any matrix.to / matrix: link to message.
+/// links are resolved concurrently, waiting at most RESOLVE_TIMEOUT
+pub async fn annotate_links(matrirc: &Matrirc, room: &Room, body: &str) -> String {
+    let descriptions = join_all(find_links(body).into_iter().map(|id| {
+        trace!("Resolving link to {:?}", id);
+        timeout(RESOLVE_TIMEOUT, describe(matrirc, room, id))
+    }))
+    .await;
+    let mut annotated = body.to_string();
+    for description in descriptions {
+        let description = match description {
+            Ok(Ok(description)) => description,
+            Ok(Err(e)) => format!("<could not resolve: {}>", e),
+            Err(_) => "<timed out>".to_string(),
+        };
+        annotated.push_str(&format!(" [→ {}]", description));
+    }
+    annotated
+}
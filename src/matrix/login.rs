@@ -1,26 +1,69 @@
 use anyhow::{Context, Result};
-use log::debug;
+use log::{debug, warn};
 use matrix_sdk::{
     matrix_auth::{MatrixSession, MatrixSessionTokens},
-    Client, SessionMeta,
+    ruma::UserId,
+    Client, ClientBuilder, SessionMeta,
 };
 use std::path::Path;
 
-use crate::{args::args, state::SerializedMatrixSession};
+use crate::{
+    args::args,
+    config::Config,
+    state::{SerializedMatrixSession, SessionKey},
+};
 
 pub async fn client(homeserver: &str, db_nick: &str, db_pass: &str) -> Result<Client> {
+    build(
+        Client::builder().homeserver_url(homeserver),
+        db_nick,
+        db_pass,
+    )
+    .await
+}
+
+/// client for user's server, found through .well-known discovery
+pub async fn discover_client(user_id: &UserId, db_nick: &str, db_pass: &str) -> Result<Client> {
+    debug!("Discovering homeserver for {}", user_id);
+    build(
+        Client::builder().server_name(user_id.server_name()),
+        db_nick,
+        db_pass,
+    )
+    .await
+}
+
+async fn build(builder: ClientBuilder, db_nick: &str, db_pass: &str) -> Result<Client> {
     let db_path = Path::new(&args().state_dir)
         .join(db_nick)
         .join("sqlite_store");
     debug!("Connection to matrix for {}", db_nick);
+    let mut builder = builder
+        .sqlite_store(db_path, Some(db_pass))
+        .handle_refresh_tokens();
+    if let Some(proxy) = Config::load(db_nick)?.proxy() {
+        debug!("Using proxy {}", proxy);
+        builder = builder.proxy(proxy);
+    }
     // note: error 'Building matrix client' is matched as a string to get next error
     // to user on irc
-    Client::builder()
-        .homeserver_url(homeserver)
-        .sqlite_store(db_path, Some(db_pass))
-        .build()
-        .await
-        .context("Building matrix client")
+    builder.build().await.context("Building matrix client")
+}
+
+/// persist tokens when the sdk refreshes them, so we can still restore
+/// the session after access token expired
+pub fn save_refreshed_sessions(client: &Client, session_key: SessionKey) -> Result<()> {
+    client.set_session_callbacks(
+        Box::new(|_| Err("reloading session is not supported".into())),
+        Box::new(move |client| {
+            let session = client.session().ok_or("client has no auth session")?;
+            session_key.save(session).map_err(|e| {
+                warn!("Could not save refreshed session: {:?}", e);
+                e.into()
+            })
+        }),
+    )?;
+    Ok(())
 }
 
 pub async fn restore_session(
@@ -1,50 +1,234 @@
 use anyhow::Result;
-use log::warn;
-use matrix_sdk::{config::SyncSettings, LoopCtrl};
+use log::{info, warn};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{api::client::error::ErrorKind, presence::PresenceState},
+    LoopCtrl,
+};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use crate::matrirc::{Matrirc, Running};
+use crate::webhook;
 
+mod digest;
+mod hook;
 mod invite;
+mod links;
 pub mod login;
 mod outgoing;
+mod paste;
+pub mod presence;
+mod retry;
 pub mod room_mappings;
+pub mod shortcodes;
 mod sync_reaction;
+mod sync_room_encrypted;
 mod sync_room_member;
 mod sync_room_message;
+mod sync_room_name;
 pub mod time;
+mod translit;
+pub mod uiaa;
 mod verification;
 
+pub use digest::unread_counts;
+pub use hook::{stop_detached, watch_detached};
+pub use outgoing::upload_url;
 pub use room_mappings::MatrixMessageType;
+pub use sync_reaction::message_like_to_str;
+pub use sync_room_encrypted::rekey;
+pub use sync_room_message::{media_info, SourceUri};
 
 pub async fn matrix_sync(matrirc: Matrirc) -> Result<()> {
+    let send_queue_task = tokio::spawn(retry::watch_send_queue(matrirc.clone()));
+    let idle_task = tokio::spawn(presence::watch_idle(matrirc.clone()));
+    let res = sync_loop(matrirc).await;
+    send_queue_task.abort();
+    idle_task.abort();
+    res
+}
+
+/// only bother user about sync failures when they last that long
+const SYNC_LAG_REPORT: Duration = Duration::from_secs(60);
+
+fn format_lag(lag: Duration) -> String {
+    match lag.as_secs() {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s => format!("{}h{:02}m", s / 3600, s / 60 % 60),
+    }
+}
+
+/// time since last successful sync, to tell user when homeserver is unreachable
+struct SyncStatus {
+    failures: AtomicU32,
+    last_success: Mutex<Instant>,
+    lag_reported: AtomicBool,
+}
+
+impl SyncStatus {
+    fn new() -> Self {
+        SyncStatus {
+            failures: AtomicU32::new(0),
+            last_success: Mutex::new(Instant::now()),
+            lag_reported: AtomicBool::new(false),
+        }
+    }
+
+    fn lag(&self) -> Duration {
+        self.last_success.lock().unwrap().elapsed()
+    }
+
+    /// report lag once over threshold, and wait before retrying
+    async fn failed(&self, matrirc: &Matrirc, error: &matrix_sdk::Error) {
+        let delay = retry::backoff(error, self.failures.fetch_add(1, Ordering::Relaxed));
+        warn!("Sync error: {}, retrying in {:?}", error, delay);
+        let lag = self.lag();
+        if lag >= SYNC_LAG_REPORT && !self.lag_reported.swap(true, Ordering::Relaxed) {
+            let _ = matrirc
+                .mappings()
+                .matrirc_query(format!(
+                    "Homeserver unreachable for {}, retrying ({})",
+                    format_lag(lag),
+                    error
+                ))
+                .await;
+        }
+        tokio::time::sleep(delay).await;
+    }
+
+    async fn succeeded(&self, matrirc: &Matrirc) {
+        self.failures.store(0, Ordering::Relaxed);
+        let lag = self.lag();
+        *self.last_success.lock().unwrap() = Instant::now();
+        if self.lag_reported.swap(false, Ordering::Relaxed) {
+            let _ = matrirc
+                .mappings()
+                .matrirc_query(format!(
+                    "Homeserver reachable again after {}",
+                    format_lag(lag)
+                ))
+                .await;
+        }
+    }
+}
+
+async fn sync_loop(matrirc: Matrirc) -> Result<()> {
     // add filter like with_lazy_loading() ?
-    let sync_settings = SyncSettings::default();
+    let mut sync_settings = SyncSettings::default();
+    if matrirc.config().idle_minutes.is_some() {
+        // sync would mark us online all the time, presence::watch_idle handles it
+        sync_settings = sync_settings.set_presence(PresenceState::Offline);
+    }
     let client = matrirc.matrix();
+    // keep synced events in memory for reaction/redaction lookups
+    if let Err(e) = client.event_cache().subscribe() {
+        warn!("Could not enable event cache: {}", e);
+    }
     client.add_event_handler_context(matrirc.clone());
     client.add_event_handler(sync_room_message::on_room_message);
+    client.add_event_handler(sync_room_encrypted::on_room_encrypted);
+    client.add_event_handler(sync_room_encrypted::on_room_key);
+    client.add_event_handler(sync_room_encrypted::on_forwarded_room_key);
     client.add_event_handler(sync_reaction::on_sync_reaction);
     client.add_event_handler(sync_reaction::on_sync_room_redaction);
     client.add_event_handler(verification::on_device_key_verification_request);
     client.add_event_handler(invite::on_stripped_state_member);
     client.add_event_handler(sync_room_member::on_room_member);
+    client.add_event_handler(sync_room_member::on_room_third_party_invite);
+    client.add_event_handler(sync_room_name::on_room_name);
+    client.add_event_handler(sync_room_name::on_room_canonical_alias);
+    client.add_event_handler(sync_room_name::on_room_topic);
+    client.add_event_handler(sync_room_name::on_room_avatar);
+    client.add_event_handler(sync_room_name::on_room_encryption);
+    client.add_event_handler(sync_room_name::on_room_power_levels);
 
     let loop_matrirc = &matrirc.clone();
-    client
-        .sync_with_result_callback(sync_settings, |_| async move {
-            match loop_matrirc.running().await {
-                Running::First => {
-                    if let Err(e) = loop_matrirc.mappings().sync_rooms(loop_matrirc).await {
-                        warn!("Got an error syncing rooms on first loop: {}", e);
-                        // XXX send to irc
-                        Ok(LoopCtrl::Break)
-                    } else {
-                        Ok(LoopCtrl::Continue)
+    let soft_logout = &AtomicBool::new(false);
+    let status = &SyncStatus::new();
+    loop {
+        let result = client
+            .sync_with_result_callback(sync_settings.clone(), |result| async move {
+                match &result {
+                    Err(e) => match e.client_api_error_kind() {
+                        Some(ErrorKind::UnknownToken { soft_logout: true }) => {
+                            soft_logout.store(true, Ordering::Relaxed);
+                            return Ok(LoopCtrl::Break);
+                        }
+                        Some(ErrorKind::UnknownToken { soft_logout: false }) => {
+                            let _ = loop_matrirc
+                                .stop("Logged out by homeserver, reconnect to login again")
+                                .await;
+                            return Ok(LoopCtrl::Break);
+                        }
+                        _ => {
+                            status.failed(loop_matrirc, e).await;
+                            // don't run initial room sync on a failed sync
+                            return match loop_matrirc.stopped().await {
+                                true => Ok(LoopCtrl::Break),
+                                false => Ok(LoopCtrl::Continue),
+                            };
+                        }
+                    },
+                    Ok(_) => status.succeeded(loop_matrirc).await,
+                }
+                match loop_matrirc.running().await {
+                    Running::First => {
+                        if let Err(e) = loop_matrirc.mappings().sync_rooms(loop_matrirc).await {
+                            loop_matrirc
+                                .report_error(format!("Could not sync rooms: {}", e))
+                                .await;
+                            Ok(LoopCtrl::Break)
+                        } else {
+                            if let Err(e) = invite::resurface_invites(loop_matrirc).await {
+                                loop_matrirc
+                                    .report_error(format!("Could not list pending invites: {}", e))
+                                    .await;
+                            }
+                            if loop_matrirc.config().digest {
+                                if let Err(e) = digest::send_digest(loop_matrirc).await {
+                                    warn!("Could not send digest: {:?}", e);
+                                }
+                            }
+                            if let Err(e) = webhook::send_queued(loop_matrirc).await {
+                                warn!("Could not send queued webhooks: {:?}", e);
+                            }
+                            Ok(LoopCtrl::Continue)
+                        }
                     }
+                    Running::Continue => Ok(LoopCtrl::Continue),
+                    Running::Break => Ok(LoopCtrl::Break),
                 }
-                Running::Continue => Ok(LoopCtrl::Continue),
-                Running::Break => Ok(LoopCtrl::Break),
+            })
+            .await;
+        if let Err(e) = result {
+            // sync stopped on its own: restart it, rooms and chans are kept as is
+            if matrirc.stopped().await {
+                return Ok(());
             }
-        })
-        .await?;
-    Ok(())
+            status.failed(&matrirc, &e).await;
+            continue;
+        }
+        if !soft_logout.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        // keep everything (crypto store, mappings) and wait for user to login again
+        info!("Soft logout, waiting for relogin");
+        matrirc
+            .mappings()
+            .matrirc_query(
+                "Homeserver logged this session out. Login again with: relogin <matrix password>",
+            )
+            .await?;
+        matrirc.wait_relogin().await;
+        if let Running::Break = matrirc.running().await {
+            return Ok(());
+        }
+        matrirc
+            .mappings()
+            .matrirc_query("Logged in again, resuming sync")
+            .await?;
+    }
 }
@@ -1,12 +1,203 @@
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result};
 use async_trait::async_trait;
+use futures::StreamExt;
 use matrix_sdk::{
+    attachment::AttachmentConfig,
+    reqwest::{
+        self,
+        dns::{Addrs, Name, Resolve, Resolving},
+        header::CONTENT_TYPE,
+        redirect, Url,
+    },
     room::Room,
     ruma::events::room::message::{MessageType, RoomMessageEventContent},
     RoomState,
 };
 
-use crate::matrix::room_mappings::{MatrixMessageType, MessageHandler, RoomTarget};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::matrirc::Matrirc;
+use crate::matrix::room_mappings::{self, MatrixMessageType, MessageHandler, RoomTarget};
+
+/// max size of files uploaded from urls if media max size is unset
+const UPLOAD_MAX_SIZE: u64 = 50 * 1024 * 1024;
+
+/// mime type from magic bytes, for servers not giving a useful content type
+fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    Some(match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "video/mp4",
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => "video/webm",
+        [b'O', b'g', b'g', b'S', ..] => "audio/ogg",
+        [b'I', b'D', b'3', ..] => "audio/mpeg",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        _ => return None,
+    })
+}
+
+/// addresses urls given to upload must not point to: the bridge would
+/// otherwise fetch services only reachable from its own host or network
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // link local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+fn check_host(url: &Url) -> Result<()> {
+    let host = url.host_str().context("Url has no host")?;
+    // ipv6 hosts are bracketed, anything not parsing is a name
+    let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() else {
+        return Ok(());
+    };
+    if is_internal(ip) {
+        return Err(Error::msg(format!(
+            "Refusing to fetch internal address {}",
+            ip
+        )));
+    }
+    Ok(())
+}
+
+/// resolver refusing internal addresses, so names cannot be used to reach
+/// them either
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// http client for user-given urls: hosts are checked after resolution and
+/// on each redirect. When a proxy is configured names are resolved by it,
+/// so only literal addresses can be checked.
+fn upload_client(matrirc: &Matrirc) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .no_proxy()
+        .dns_resolver(std::sync::Arc::new(PublicResolver))
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= 10 {
+                return attempt.error("too many redirects");
+            }
+            match check_host(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e.to_string()),
+            }
+        }));
+    if let Some(proxy) = matrirc.config().proxy() {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// download url and send it to room as attachment, returns its filename,
+/// size and mime type
+pub async fn upload_url(
+    matrirc: &Matrirc,
+    room: &Room,
+    url: &str,
+) -> Result<(String, usize, String)> {
+    if room.state() != RoomState::Joined {
+        return Err(Error::msg(format!(
+            "Room {} was not joined",
+            room.room_id()
+        )));
+    }
+    if !room_mappings::can_post(room).await {
+        return Err(Error::msg(
+            "Room is read-only (+m): your power level is too low to post",
+        ));
+    }
+    let url = Url::parse(url).context("Invalid url")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Error::msg("Only http and https urls can be uploaded"));
+    }
+    check_host(&url)?;
+    let max_size = matrirc.config().media_max_size().unwrap_or(UPLOAD_MAX_SIZE);
+    let response = upload_client(matrirc)?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|size| size > max_size)
+    {
+        return Err(Error::msg(format!(
+            "File is bigger than media max size ({} bytes)",
+            max_size
+        )));
+    }
+    let filename = response
+        .url()
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .filter(|f| !f.is_empty())
+        .unwrap_or("upload")
+        .to_string();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .and_then(|c| c.parse::<mime::Mime>().ok())
+        .filter(|c| *c != mime::APPLICATION_OCTET_STREAM);
+    let mut data = vec![];
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk?);
+        if data.len() as u64 > max_size {
+            return Err(Error::msg(format!(
+                "File is bigger than media max size ({} bytes)",
+                max_size
+            )));
+        }
+    }
+    let content_type = match content_type {
+        Some(content_type) => content_type,
+        None => sniff_mime(&data)
+            .unwrap_or("application/octet-stream")
+            .parse()?,
+    };
+    let size = data.len();
+    room.send_attachment(&filename, &content_type, data, AttachmentConfig::new())
+        .await?;
+    Ok((filename, size, content_type.to_string()))
+}
 
 #[async_trait]
 impl MessageHandler for Room {
@@ -17,6 +208,11 @@ impl MessageHandler for Room {
                 self.room_id()
             )))?;
         };
+        if !room_mappings::can_post(self).await {
+            Err(Error::msg(
+                "Room is read-only (+m): your power level is too low to post",
+            ))?;
+        }
         let content = match message_type {
             MatrixMessageType::Text => RoomMessageEventContent::text_plain(message),
             MatrixMessageType::Emote => RoomMessageEventContent::new(MessageType::new(
@@ -26,9 +222,41 @@ impl MessageHandler for Room {
             )?),
             MatrixMessageType::Notice => RoomMessageEventContent::notice_plain(message),
         };
-        self.send(content).await?;
+        // the send queue retries transient errors, see retry::watch_send_queue
+        self.send_queue().send(content.into()).await?;
         Ok(())
     }
     // can't remove room from irc, we don't want (and can't anyway) keep target in room
     async fn set_target(&self, _target: RoomTarget) {}
+    fn room(&self) -> Option<Room> {
+        Some(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_internal_hosts() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://10.1.2.3:8080/x",
+            "http://169.254.169.254/latest",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:192.168.0.1]/",
+        ] {
+            assert!(check_host(&Url::parse(url).unwrap()).is_err(), "{}", url);
+        }
+        for url in [
+            "https://example.com/",
+            "http://1.1.1.1/",
+            "http://[2001:db8::1]/",
+        ] {
+            assert!(check_host(&Url::parse(url).unwrap()).is_ok(), "{}", url);
+        }
+    }
 }
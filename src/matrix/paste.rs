@@ -0,0 +1,172 @@
+use log::warn;
+use matrix_sdk::ruma::{events::room::message::FormattedBody, EventId};
+
+use crate::matrirc::Matrirc;
+use crate::matrix::sync_room_message::save_media;
+
+/// lines of a pasted code block still shown on irc
+const PREVIEW_LINES: usize = 3;
+
+/// part of a message, code blocks include their fences if any
+#[derive(Debug, PartialEq)]
+struct Block<'a> {
+    code: bool,
+    lines: Vec<&'a str>,
+}
+
+impl Block<'_> {
+    /// code lines without fences
+    fn code_lines(&self) -> &[&str] {
+        let lines = &self.lines[..];
+        let lines = match lines.first() {
+            Some(l) if l.trim_start().starts_with("```") => &lines[1..],
+            _ => lines,
+        };
+        match lines.last() {
+            Some(l) if l.trim() == "```" => &lines[..lines.len() - 1],
+            _ => lines,
+        }
+    }
+}
+
+/// split text around ``` fenced blocks
+fn split_blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks: Vec<Block> = vec![];
+    let mut in_code = false;
+    for line in text.split('\n') {
+        let fence = line.trim_start().starts_with("```");
+        if fence && !in_code {
+            in_code = true;
+            blocks.push(Block {
+                code: true,
+                lines: vec![line],
+            });
+            continue;
+        }
+        match blocks.last_mut() {
+            Some(block) if block.code == in_code => block.lines.push(line),
+            _ => blocks.push(Block {
+                code: in_code,
+                lines: vec![line],
+            }),
+        }
+        if fence {
+            in_code = false;
+        }
+    }
+    blocks
+}
+
+/// replace code blocks longer than configured paste_lines with a link to
+/// a file in media dir and their first few lines
+pub async fn paste_code_blocks(
+    matrirc: &Matrirc,
+    event_id: &EventId,
+    body: &str,
+    formatted: Option<&FormattedBody>,
+) -> String {
+    let limit = matrirc.config().paste_lines;
+    let Some(dir_path) = matrirc.config().media_dir() else {
+        return body.to_string();
+    };
+    if limit == 0 || body.split('\n').count() <= limit {
+        return body.to_string();
+    }
+    let mut blocks = split_blocks(body);
+    // html <pre> without fences in plain body: whole message is code
+    // (the quoted message of a reply doesn't count)
+    let html = formatted.map(|f| remove_html_reply_fallback(&f.body));
+    if !blocks.iter().any(|b| b.code)
+        && html.is_some_and(|html| html.contains("<pre>") || html.contains("<pre "))
+    {
+        blocks = vec![Block {
+            code: true,
+            lines: body.split('\n').collect(),
+        }];
+    }
+    let event_name: String = event_id
+        .as_str()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    let mut result = vec![];
+    for (i, block) in blocks.iter().enumerate() {
+        let code = block.code_lines();
+        if !block.code || code.len() <= limit {
+            result.extend(block.lines.iter().map(|l| l.to_string()));
+            continue;
+        }
+        let filename = format!("{}-{}.txt", event_name, i);
+        let content = code.join("\n") + "\n";
+        match save_media(matrirc, dir_path, &filename, content.as_bytes()).await {
+            Ok(url) => {
+                let preview = PREVIEW_LINES.min(limit);
+                result.extend(code[..preview].iter().map(|l| l.to_string()));
+                result.push(format!(
+                    "[... {} more lines: {}]",
+                    code.len() - preview,
+                    url
+                ));
+            }
+            Err(e) => {
+                warn!("Could not save code block: {}", e);
+                result.extend(block.lines.iter().map(|l| l.to_string()));
+            }
+        }
+    }
+    result.join("\n")
+}
+
+/// html body without the `<mx-reply>` rich reply fallback
+fn remove_html_reply_fallback(html: &str) -> String {
+    match (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &html[..start], &html[end + "</mx-reply>".len()..])
+        }
+        _ => html.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_remove_html_reply_fallback() {
+        assert_eq!(
+            remove_html_reply_fallback("<mx-reply><pre>quoted</pre></mx-reply>reply"),
+            "reply"
+        );
+        assert_eq!(
+            remove_html_reply_fallback("<pre>code</pre>"),
+            "<pre>code</pre>"
+        );
+    }
+
+    #[test]
+    fn check_split_blocks() {
+        let blocks = split_blocks("look:\n```rust\nfn main() {}\n```\ndone");
+        assert_eq!(
+            blocks,
+            vec![
+                Block {
+                    code: false,
+                    lines: vec!["look:"]
+                },
+                Block {
+                    code: true,
+                    lines: vec!["```rust", "fn main() {}", "```"]
+                },
+                Block {
+                    code: false,
+                    lines: vec!["done"]
+                },
+            ]
+        );
+        assert_eq!(blocks[1].code_lines(), ["fn main() {}"]);
+        // unclosed fence runs to the end
+        let blocks = split_blocks("```\na\nb");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code_lines(), ["a", "b"]);
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use matrix_sdk::ruma::{api::client::presence::set_presence, presence::PresenceState};
+use std::time::Duration;
+
+use crate::matrirc::Matrirc;
+
+async fn set_presence(matrirc: &Matrirc, presence: PresenceState) -> Result<()> {
+    let user_id = matrirc.matrix().user_id().context("client has no user?")?;
+    debug!("Setting presence to {}", presence);
+    let request = set_presence::v3::Request::new(user_id.to_owned(), presence);
+    matrirc.matrix().send(request, None).await?;
+    Ok(())
+}
+
+/// something was sent from irc: back online if we were idle
+pub async fn active(matrirc: &Matrirc) {
+    if matrirc.config().idle_minutes.is_none() || !matrirc.touch() {
+        return;
+    }
+    if let Err(e) = set_presence(matrirc, PresenceState::Online).await {
+        warn!("Could not set presence online: {:?}", e);
+    }
+}
+
+/// mark us unavailable after idle_minutes without anything sent from irc,
+/// like irc idle time
+pub async fn watch_idle(matrirc: Matrirc) {
+    let Some(idle_minutes) = matrirc.config().idle_minutes else {
+        return;
+    };
+    let idle_after = Duration::from_secs(idle_minutes * 60);
+    if let Err(e) = set_presence(&matrirc, PresenceState::Online).await {
+        warn!("Could not set presence online: {:?}", e);
+    }
+    loop {
+        let idle = matrirc.idle_time();
+        if idle < idle_after {
+            tokio::time::sleep(idle_after - idle).await;
+            continue;
+        }
+        if matrirc.set_idle() {
+            if let Err(e) = set_presence(&matrirc, PresenceState::Unavailable).await {
+                warn!("Could not set presence unavailable: {:?}", e);
+            }
+        }
+        // wait for activity
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
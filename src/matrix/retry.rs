@@ -0,0 +1,124 @@
+use log::{info, warn};
+use matrix_sdk::{
+    ruma::{
+        api::client::error::{ErrorKind, RetryAfter},
+        events::AnyMessageLikeEventContent,
+        OwnedRoomId, OwnedTransactionId,
+    },
+    send_queue::LocalEchoContent,
+};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::ircd::proto::IrcMessageType;
+use crate::matrirc::Matrirc;
+
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// give up on a message after that many transient failures
+const MAX_SEND_ATTEMPTS: u32 = 5;
+
+/// how long to wait before retrying after `attempt` consecutive failures,
+/// using the server's retry_after_ms if it rate limited us
+pub fn backoff(error: &matrix_sdk::Error, attempt: u32) -> Duration {
+    if let Some(ErrorKind::LimitExceeded {
+        retry_after: Some(retry_after),
+    }) = error.client_api_error_kind()
+    {
+        match retry_after {
+            RetryAfter::Delay(delay) => return *delay,
+            RetryAfter::DateTime(time) => {
+                return time.duration_since(SystemTime::now()).unwrap_or_default()
+            }
+        }
+    }
+    Duration::from_secs(1)
+        .saturating_mul(1 << attempt.min(9))
+        .min(BACKOFF_MAX)
+}
+
+/// The send queue keeps messages that failed with a transient error (rate limit,
+/// network...) and pauses the room: resume it after a while, and give up on a
+/// message after a few attempts or on a permanent error, sending its text back
+/// to irc so it can be resent.
+pub async fn watch_send_queue(matrirc: Matrirc) {
+    let mut errors = matrirc.matrix().send_queue().subscribe_errors();
+    let mut attempts: HashMap<OwnedRoomId, HashMap<OwnedTransactionId, u32>> = HashMap::new();
+    while let Ok(failure) = errors.recv().await {
+        let Some(room) = matrirc.matrix().get_room(&failure.room_id) else {
+            continue;
+        };
+        let echoes = match room.send_queue().subscribe().await {
+            Ok((echoes, _)) => echoes,
+            Err(e) => {
+                warn!(
+                    "Could not list pending messages in {}: {}",
+                    room.room_id(),
+                    e
+                );
+                continue;
+            }
+        };
+        let room_attempts = attempts.entry(failure.room_id.clone()).or_default();
+        room_attempts.retain(|txn_id, _| echoes.iter().any(|e| &e.transaction_id == txn_id));
+        // the queue is ordered: the failed message is the first one with matching state
+        let Some((txn_id, content, handle)) =
+            echoes.into_iter().find_map(|echo| match echo.content {
+                LocalEchoContent::Event {
+                    serialized_event,
+                    send_handle,
+                    send_error,
+                } if send_error.is_none() == failure.is_recoverable => {
+                    Some((echo.transaction_id, serialized_event, send_handle))
+                }
+                _ => None,
+            })
+        else {
+            warn!("Could not find failed message in {}", room.room_id());
+            continue;
+        };
+        let attempt = room_attempts.entry(txn_id.clone()).or_default();
+        *attempt += 1;
+        if failure.is_recoverable && *attempt < MAX_SEND_ATTEMPTS {
+            let delay = backoff(&failure.error, *attempt - 1);
+            info!(
+                "Sending to {} failed ({}), retrying in {:?}",
+                failure.room_id, failure.error, delay
+            );
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                room.send_queue().set_enabled(true);
+            });
+            continue;
+        }
+        room_attempts.remove(&txn_id);
+        warn!(
+            "Giving up sending to {}: {}",
+            failure.room_id, failure.error
+        );
+        if let Err(e) = handle.abort().await {
+            warn!("Could not remove failed message from queue: {}", e);
+        }
+        if failure.is_recoverable {
+            // let the next messages go through
+            room.send_queue().set_enabled(true);
+        }
+        let text = match content.deserialize() {
+            Ok(AnyMessageLikeEventContent::RoomMessage(message)) => message.body().to_string(),
+            _ => format!("<{}>", content.raw().1),
+        };
+        if let Err(e) = matrirc
+            .mappings()
+            .room_target(&room)
+            .await
+            .send_text_to_irc(
+                matrirc.irc(),
+                IrcMessageType::Notice,
+                &"matrirc".to_string(),
+                format!("Could not send ({}): {}", failure.error, text),
+            )
+            .await
+        {
+            warn!("Furthermore, reply errored too: {:?}", e);
+        }
+    }
+}
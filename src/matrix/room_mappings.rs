@@ -1,10 +1,16 @@
 use anyhow::{Error, Result};
 use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use irc::proto::message::Tag;
 use lazy_static::lazy_static;
 use log::{trace, warn};
 use matrix_sdk::{
+    deserialized_responses::SyncOrStrippedState,
     room::Room,
-    ruma::{OwnedRoomId, OwnedUserId},
+    ruma::{
+        events::{room::create::RoomCreateEventContent, tag::TagName, MessageLikeEventType},
+        OwnedEventId, OwnedRoomId, OwnedUserId, UserId,
+    },
     RoomMemberships,
 };
 use regex::Regex;
@@ -14,16 +20,37 @@ use std::collections::{
     VecDeque,
 };
 use std::sync::Arc;
-use tokio::sync::{RwLock, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
+use crate::args::{args, MessageIds};
+use crate::config::glob_match;
 use crate::ircd;
 use crate::ircd::{
     join_irc_chan, join_irc_chan_finish,
     proto::{IrcMessage, IrcMessageType},
-    IrcClient,
+    IrcClient, Membership,
 };
 use crate::matrirc::Matrirc;
+use crate::matrix::translit;
+use crate::store::{PendingMessage, Store};
 
+/// rooms set up at the same time on initial sync
+const SYNC_ROOMS_CONCURRENCY: usize = 8;
+/// report initial sync progress every that many rooms
+const SYNC_ROOMS_PROGRESS: usize = 50;
+/// queries used for interactive flows, see invite.rs and verification.rs
+const CONTROL_TARGETS: &[&str] = &["invite", "verif"];
+/// members remembered per room after they left, for WHOWAS
+const DEPARTED_PER_ROOM: usize = 16;
+/// members who spoke less than that long ago get their joins/parts shown
+/// in `joins smart` rooms
+const SMART_JOINS_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// max length of nicks and chan names (without #), advertised in ISUPPORT
+pub const NAME_MAX_LEN: usize = 30;
+
+#[derive(Debug, Clone, Copy)]
 pub enum MatrixMessageType {
     Text,
     Emote,
@@ -38,6 +65,8 @@ struct TargetMessage {
     from: String,
     /// actual message
     text: String,
+    /// short message id, as tag
+    msgid: Option<String>,
 }
 
 impl TargetMessage {
@@ -46,17 +75,30 @@ impl TargetMessage {
             message_type,
             from,
             text,
+            msgid: None,
         }
     }
 }
 
+/// member that left a room
+#[derive(Debug, Clone)]
+pub struct Departed {
+    /// irc nick they had
+    pub nick: String,
+    /// chan or query they left
+    pub target: String,
+    pub user_id: OwnedUserId,
+    pub display_name: Option<String>,
+    pub left: SystemTime,
+}
+
 #[derive(Debug, Clone)]
 pub struct RoomTarget {
     /// the Arc/RwLock let us return/modify it without holding the mappings lock
     inner: Arc<RwLock<RoomTargetInner>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum RoomTargetType {
     /// room maps to a query e.g. single other member (or alone!)
     Query,
@@ -67,6 +109,9 @@ enum RoomTargetType {
     LeftChan,
     /// Join in progress
     JoiningChan,
+    /// Just created, members are being fetched to decide if it is a chan or
+    /// a query: messages are queued until then
+    Filling,
 }
 
 #[derive(Debug)]
@@ -83,22 +128,110 @@ struct RoomTargetInner {
     members: HashMap<String, String>,
     /// list of irc names in channel
     /// used to enforce unicity, and perhaps later to convert
-    /// `mentions:` to matric mentions
+    /// `mentions:` to matric mentions.
+    /// Keys are casefolded like Mappings' targets, members has the original case.
     names: HashMap<String, OwnedUserId>,
     /// used for error messages, and to queue messages in joinin chan:
     /// if someone tries to grab a chan we're currently joining they just
     /// append to it instead of sending message to irc -- it needs its own lock
     /// because we'll modify it while holding read lock on room target (to get target type)
-    /// XXX: If there are any pending messages left when we exit (because e.g. client exited while
-    /// we weren't done with join yet), these messages will have been ack'd on matrix side and
-    /// won't ever be sent to irc. This should be rare enough but probably worth fixing somehow...
+    /// Messages still pending when the client exits (e.g. it left while we weren't done
+    /// joining) are saved in store and queued again on next connection.
     pending_messages: RwLock<VecDeque<TargetMessage>>,
+    /// m.room.create timestamp, in seconds, looked up on first MODE
+    created: Option<u64>,
+    /// last members who left, oldest first
+    departed: VecDeque<Departed>,
+    /// our power level is too low to post, shown as +m
+    read_only: bool,
+    /// when members last sent a message, for `joins smart`
+    spoke: HashMap<OwnedUserId, Instant>,
+    /// membership changes waiting to be sent, for `joins notice`
+    churn: Option<Churn>,
+    /// reactions waiting to be summarized, per reacted to event
+    reactions: HashMap<OwnedEventId, Reactions>,
+    /// query messages from other members get a header per burst instead
+    /// of a `<nick>` prefix per line
+    bursts: bool,
+    /// sender of the current burst, if a header was sent
+    burst_from: Option<String>,
+}
+
+/// nicks that joined or left since last `joins notice` summary
+#[derive(Debug, Default)]
+struct Churn {
+    joined: Vec<String>,
+    left: Vec<String>,
+}
+
+/// what a reaction summary is about, taken from the first reaction
+#[derive(Debug, Default)]
+pub struct Reacted {
+    pub time_prefix: String,
+    /// short id and snippet of the reacted to message
+    pub description: String,
+}
+
+/// reactions to a single event since last summary
+#[derive(Debug, Default)]
+struct Reactions {
+    reacted: Reacted,
+    senders: Vec<String>,
+    /// reaction keys with their count, in order of arrival
+    keys: Vec<(String, usize)>,
 }
 
 pub struct Mappings {
     inner: RwLock<MappingsInner>,
     pub irc: IrcClient,
     mt: RoomTarget,
+    /// computed room display names, cleared on name/alias change
+    room_names: RwLock<HashMap<OwnedRoomId, String>>,
+    nick_policy: NickPolicy,
+    target_policy: TargetPolicy,
+}
+
+/// how rooms are presented on irc, from config
+#[derive(Debug, Clone)]
+pub struct TargetPolicy {
+    /// chan names template
+    pub chan_template: String,
+    /// every room is a query, members prefixed as `<nick>`
+    pub force_queries: bool,
+    /// direct chats with users matching these patterns are chans
+    pub dm_chans: Vec<String>,
+    /// in queries, name other members once per burst of messages
+    pub query_bursts: bool,
+}
+
+impl TargetPolicy {
+    /// chan or query regardless of members, if configured
+    async fn forced_type(&self, room: &Room) -> Option<RoomTargetType> {
+        if self.force_queries {
+            return Some(RoomTargetType::Query);
+        }
+        if self.dm_chans.is_empty() || !room.is_direct().await.unwrap_or(false) {
+            return None;
+        }
+        room.direct_targets()
+            .iter()
+            .any(|user_id| {
+                self.dm_chans
+                    .iter()
+                    .any(|pattern| glob_match(pattern, user_id.as_str()))
+            })
+            .then_some(RoomTargetType::LeftChan)
+    }
+}
+
+/// how matrix users get their irc nick
+#[derive(Debug, Clone)]
+pub struct NickPolicy {
+    /// use matrix id localparts instead of display names, from config
+    pub localpart_nicks: bool,
+    /// our own matrix user, shown with our irc nick
+    pub own_user: Option<OwnedUserId>,
+    pub own_nick: String,
 }
 
 #[derive(Default)]
@@ -113,22 +246,171 @@ struct MappingsInner {
     /// dedup and received (irc -> matrirc) messages go
     /// TODO: add a metacommand to force iterating Matrirc.matrix().rooms() ?
     /// (probably want this to list available query targets too...)
-    /// TODO: also reserve 'matrirc', irc.nick()...
+    ///
+    /// Keys are casefolded, as irc names are case insensitive.
     targets: HashMap<String, Box<dyn MessageHandler + Send + Sync>>,
+    /// casefolded names no target can take: matrirc and our own nick
+    reserved: Vec<String>,
+}
+
+impl MappingsInner {
+    /// name is free for a target. Rooms additionally cannot take names of
+    /// control targets (invite, verif_2...) so they cannot shadow them
+    fn name_available(&self, name: &str, room: bool) -> bool {
+        let name = casefold(name);
+        if self.targets.contains_key(&name) || self.reserved.contains(&name) {
+            return false;
+        }
+        if room {
+            let base = match name.rsplit_once('_') {
+                Some((base, count)) if count.chars().all(|c| c.is_ascii_digit()) => base,
+                _ => &name,
+            };
+            return !CONTROL_TARGETS.contains(&base);
+        }
+        true
+    }
+
+    /// insert target under an unique name, compared without case.
+    /// Returns the name with its original case
+    fn insert_target(
+        &mut self,
+        candidate: &str,
+        seed: Option<&str>,
+        room: bool,
+        target: Box<dyn MessageHandler + Send + Sync>,
+    ) -> String {
+        let name = dedup_candidates(candidate, seed)
+            .find(|name| self.name_available(name, room))
+            .expect("dedup candidates are infinite");
+        self.targets.insert(casefold(&name), target);
+        name
+    }
 }
 
 #[async_trait]
 pub trait MessageHandler {
     async fn handle_message(&self, message_type: MatrixMessageType, message: String) -> Result<()>;
     async fn set_target(&self, target: RoomTarget);
+    /// matrix room behind the target, if any
+    fn room(&self) -> Option<Room> {
+        None
+    }
 }
 
+/// keep only characters valid in irc nicks (RFC 2812 letters, digits and
+/// special characters)
 fn sanitize<S: Into<String>>(str: S) -> String {
     // replace with rust 1.70 OnceCell? eventually
     lazy_static! {
-        static ref SANITIZE: Regex = Regex::new("[^a-zA-Z_-]+").unwrap();
+        static ref SANITIZE: Regex = Regex::new(r"[^a-zA-Z0-9_\-\[\]{}\\|^]+").unwrap();
+    }
+    let mut name: String = SANITIZE
+        .replace_all(&translit::to_ascii(&str.into()), "")
+        .into();
+    // nicks cannot start with a digit or dash
+    if name.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        name.insert(0, '_');
+    }
+    // only ascii left
+    name.truncate(NAME_MAX_LEN);
+    name
+}
+
+/// same as sanitize for channel names, which can also have dots
+fn sanitize_chan<S: Into<String>>(str: S) -> String {
+    lazy_static! {
+        static ref SANITIZE_CHAN: Regex = Regex::new(r"[^a-zA-Z0-9_.\-\[\]{}\\|^]+").unwrap();
+    }
+    let mut name: String = SANITIZE_CHAN
+        .replace_all(&translit::to_ascii(&str.into()), "")
+        .into();
+    name.truncate(NAME_MAX_LEN);
+    name
+}
+
+/// expand {name}, {server} and {alias-localpart} in chan name template.
+/// server and alias come from canonical alias if any, server falls back to
+/// room id's server and alias to room name
+fn expand_chan_template(template: &str, name: &str, room: &Room) -> String {
+    let alias = room.canonical_alias();
+    let server = match &alias {
+        Some(alias) => alias.server_name().as_str(),
+        None => room
+            .room_id()
+            .server_name()
+            .map(|s| s.as_str())
+            .unwrap_or_default(),
+    };
+    let alias = alias.as_ref().map(|a| a.alias()).unwrap_or(name);
+    expand_template(template, name, server, alias)
+}
+
+fn expand_template(template: &str, name: &str, server: &str, alias: &str) -> String {
+    template
+        .trim_start_matches('#')
+        .replace("{name}", name)
+        .replace("{server}", server)
+        .replace("{alias-localpart}", alias)
+}
+
+/// sanitized member name, falling back to matrix id for names that
+/// couldn't be transliterated
+fn sanitize_member(name: &str, user_id: &UserId) -> String {
+    let name = sanitize(name);
+    if !name.is_empty() {
+        return name;
+    }
+    let name = sanitize(user_id.localpart());
+    if !name.is_empty() {
+        return name;
+    }
+    format!("user-{}", translit::short_hash(user_id.as_str()))
+}
+
+/// irc names are compared with ascii casemapping (advertised in ISUPPORT)
+pub fn casefold(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// orig with suffix appended, truncating orig to fit NAME_MAX_LEN
+fn with_suffix(orig: &str, suffix: &str) -> String {
+    let keep = NAME_MAX_LEN.saturating_sub(suffix.len()).min(orig.len());
+    // orig might not have been sanitized, don't split chars
+    let keep = (0..=keep)
+        .rev()
+        .find(|&i| orig.is_char_boundary(i))
+        .unwrap_or(0);
+    format!("{}{}", &orig[..keep], suffix)
+}
+
+/// name, then name_<hash of seed> so the same user or room gets the same
+/// name after restarts whatever the order they were seen in, then hashes
+/// of seed and a counter on collisions.
+/// Without seed, name_2, name_3...
+fn dedup_candidates<'a>(orig: &'a str, seed: Option<&'a str>) -> impl Iterator<Item = String> + 'a {
+    let suffixes: Box<dyn Iterator<Item = String>> = match seed {
+        Some(seed) => Box::new(
+            std::iter::once(translit::short_hash(seed)[..4].to_string()).chain((2..).map(
+                move |count| translit::short_hash(&format!("{}/{}", seed, count))[..4].to_string(),
+            )),
+        ),
+        None => Box::new((2..).map(|count: u32| count.to_string())),
+    };
+    std::iter::once(orig.to_string())
+        .chain(suffixes.map(move |suffix| with_suffix(orig, &format!("_{}", suffix))))
+}
+
+/// log error and best-effort tell the user about it in matrirc query
+pub async fn report_error(irc: &IrcClient, message: String) {
+    warn!("{}", message);
+    // not through mappings' matrirc target: this is used while joining chans
+    for line in message.lines() {
+        if let Err(e) = irc.send_privmsg("matrirc", &irc.nick, line).await {
+            warn!("Furthermore, reporting it to irc failed: {:?}", e);
+            return;
+        }
     }
-    SANITIZE.replace_all(&str.into(), "").into()
 }
 
 pub fn room_name(room: &matrix_sdk::BaseRoom) -> String {
@@ -142,61 +424,124 @@ pub fn room_name(room: &matrix_sdk::BaseRoom) -> String {
 }
 
 trait InsertDedup<V> {
-    fn insert_deduped(&mut self, orig_key: &str, value: V) -> String;
+    /// insert value under orig_key or a free variant of it, see dedup_candidates.
+    /// Keys are casefolded, returns the name with its original case
+    fn insert_deduped(&mut self, orig_key: &str, seed: Option<&str>, value: V) -> String;
 }
 
 impl<V> InsertDedup<V> for HashMap<String, V> {
-    fn insert_deduped(&mut self, orig_key: &str, value: V) -> String {
-        let mut key: String = orig_key.to_string();
-        let mut count = 1;
-        loop {
-            if let Entry::Vacant(entry) = self.entry(key) {
-                let found = entry.key().clone();
+    fn insert_deduped(&mut self, orig_key: &str, seed: Option<&str>, value: V) -> String {
+        for name in dedup_candidates(orig_key, seed) {
+            if let Entry::Vacant(entry) = self.entry(casefold(&name)) {
                 entry.insert(value);
-                return found;
+                return name;
+            }
+        }
+        unreachable!("dedup candidates are infinite")
+    }
+}
+
+impl RoomTargetInner {
+    /// add member under a free irc nick, from their name or matrix id
+    /// localpart if localpart_nicks is set
+    fn insert_member(&mut self, user_id: &UserId, name: &str, policy: &NickPolicy) -> String {
+        let nick = if policy.own_user.as_deref() == Some(user_id) {
+            // e.g. messages sent from another device
+            policy.own_nick.clone()
+        } else if policy.localpart_nicks {
+            let nick = sanitize_member(user_id.localpart(), user_id);
+            if self.names.contains_key(&casefold(&nick)) {
+                // same localpart on another homeserver
+                sanitize(format!("{}_{}", user_id.localpart(), user_id.server_name()))
+            } else {
+                nick
+            }
+        } else {
+            sanitize_member(name, user_id)
+        };
+        let nick = self
+            .names
+            .insert_deduped(&nick, Some(user_id.as_str()), user_id.to_owned());
+        self.members.insert(user_id.into(), nick.clone());
+        nick
+    }
+
+    /// add all members, sorted so the same members get the same nicks
+    /// whatever order the server listed them in
+    fn fill_members(
+        &mut self,
+        mut members: Vec<(OwnedUserId, String)>,
+        room_name: &str,
+        policy: &NickPolicy,
+    ) {
+        // give our own user our nick before anyone else can take it
+        members.sort_by(|(a, _), (b, _)| {
+            (policy.own_user.as_ref() != Some(a), a).cmp(&(policy.own_user.as_ref() != Some(b), b))
+        });
+        for (user_id, name) in members {
+            // ensure we preseve room target's name to simplify member's nick in queries
+            if name == room_name && policy.own_user.as_ref() != Some(&user_id) {
+                let nick = self.names.insert_deduped(
+                    &self.target.clone(),
+                    Some(user_id.as_str()),
+                    user_id.clone(),
+                );
+                self.members.insert(user_id.into(), nick);
+            } else {
+                self.insert_member(&user_id, &name, policy);
             }
-            count += 1;
-            key = format!("{}_{}", orig_key, count);
         }
     }
 }
 
+async fn is_server_notices(room: &Room) -> bool {
+    match room.tags().await {
+        Ok(Some(tags)) => tags.contains_key(&TagName::ServerNotice),
+        _ => false,
+    }
+}
+
+/// whether our power level allows sending messages in room
+pub async fn can_post(room: &Room) -> bool {
+    room.can_user_send_message(room.own_user_id(), MessageLikeEventType::RoomMessage)
+        .await
+        .unwrap_or(true)
+}
+
+/// fetch members without holding target lock: messages are queued while
+/// target is Filling, and we only lock to apply the result
 async fn fill_room_members(
-    mut target_lock: RwLockWriteGuard<'_, RoomTargetInner>,
+    target: &RoomTarget,
     room: Room,
     room_name: String,
+    policy: &NickPolicy,
+    forced_type: Option<RoomTargetType>,
 ) -> Result<()> {
+    let read_only = !can_post(&room).await;
     let members = room.members(RoomMemberships::ACTIVE).await?;
-    match members.len() {
-        0 => {
+    let mut guard = target.inner.write().await;
+    let target_lock = &mut *guard;
+    target_lock.read_only = read_only;
+    target_lock.target_type = match (forced_type, members.len()) {
+        (_, 0) => {
             // XXX remove room from mappings, but this should never happen anyway
             return Err(Error::msg(format!("Message in empty room {}?", room_name)));
         }
+        (Some(target_type), _) => target_type,
         // promote to chan if other member name isn't room name
-        1 | 2 => {
-            if members.iter().all(|m| m.name() != room_name) {
-                target_lock.target_type = RoomTargetType::LeftChan;
-            }
-        }
-        _ => target_lock.target_type = RoomTargetType::LeftChan,
-    }
-    for member in members {
-        // XXX qol improvement: rename own user id to irc.nick
-        // ensure we preseve room target's name to simplify member's nick in queries
-        let member_name = match member.name() {
-            n if n == room_name => target_lock.target.clone(),
-            n => sanitize(n),
-        };
-        let name = target_lock
-            .names
-            .insert_deduped(&member_name, member.user_id().to_owned());
-        target_lock.members.insert(member.user_id().into(), name);
-    }
+        (None, 1 | 2) if members.iter().any(|m| m.name() == room_name) => RoomTargetType::Query,
+        _ => RoomTargetType::LeftChan,
+    };
+    let members = members
+        .iter()
+        .map(|m| (m.user_id().to_owned(), m.name().to_string()))
+        .collect();
+    target_lock.fill_members(members, &room_name, policy);
     Ok(())
 }
 
 impl RoomTarget {
-    fn new<S: Into<String>>(target_type: RoomTargetType, target: S) -> Self {
+    fn new<S: Into<String>>(target_type: RoomTargetType, target: S, bursts: bool) -> Self {
         RoomTarget {
             inner: Arc::new(RwLock::new(RoomTargetInner {
                 target: target.into(),
@@ -204,15 +549,323 @@ impl RoomTarget {
                 members: HashMap::new(),
                 names: HashMap::new(),
                 pending_messages: RwLock::new(VecDeque::new()),
+                created: None,
+                departed: VecDeque::new(),
+                read_only: false,
+                spoke: HashMap::new(),
+                churn: None,
+                reactions: HashMap::new(),
+                bursts,
+                burst_from: None,
             })),
         }
     }
     fn query<S: Into<String>>(target: S) -> Self {
-        RoomTarget::new(RoomTargetType::Query, target)
+        RoomTarget::new(RoomTargetType::Query, target, false)
+    }
+
+    /// fetch members in background to find out if room is a chan or query,
+    /// then deliver messages that were queued in the meantime
+    fn spawn_fill(
+        &self,
+        irc: &IrcClient,
+        room: Room,
+        room_name: String,
+        policy: NickPolicy,
+        forced_type: Option<RoomTargetType>,
+    ) -> JoinHandle<()> {
+        let target = self.clone();
+        let irc = irc.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fill_room_members(&target, room, room_name, &policy, forced_type).await
+            {
+                let mut lock = target.inner.write().await;
+                report_error(
+                    &irc,
+                    format!("Could not get members of {}: {}", lock.target, e),
+                )
+                .await;
+                lock.target_type = RoomTargetType::Query;
+                drop(lock);
+                target
+                    .clone()
+                    .set_error(format!("Could not get room members: {}", e))
+                    .await;
+            }
+            target.deliver_pending(&irc).await;
+        })
+    }
+
+    /// join chan or send to query if messages were queued
+    async fn deliver_pending(&self, irc: &IrcClient) {
+        let inner = self.inner.read().await;
+        let pending = !inner.pending_messages.read().await.is_empty();
+        let is_chan = inner.target_type == RoomTargetType::LeftChan;
+        drop(inner);
+        if !pending {
+            // chans are joined on first message
+            return;
+        }
+        if is_chan {
+            self.join_chan(irc).await;
+        } else if let Err(e) = self.flush_pending_messages(irc).await {
+            let name = self.target().await;
+            report_error(
+                irc,
+                format!("Could not send queued messages to {}: {}", name, e),
+            )
+            .await;
+        }
+    }
+
+    /// take messages that couldn't be sent, to save them on exit
+    async fn take_pending(&self) -> Vec<PendingMessage> {
+        let inner = self.inner.read().await;
+        let mut pending = inner.pending_messages.write().await;
+        pending
+            .drain(..)
+            .map(|m| PendingMessage {
+                notice: matches!(m.message_type, IrcMessageType::Notice),
+                from: m.from,
+                text: m.text,
+                msgid: m.msgid,
+            })
+            .collect()
+    }
+
+    /// queue messages saved on last exit and send them
+    async fn restore_pending(&self, irc: &IrcClient, messages: Vec<PendingMessage>) {
+        {
+            let inner = self.inner.read().await;
+            let mut pending = inner.pending_messages.write().await;
+            for (i, m) in messages.into_iter().enumerate() {
+                let message_type = match m.notice {
+                    true => IrcMessageType::Notice,
+                    false => IrcMessageType::Privmsg,
+                };
+                let message = TargetMessage {
+                    message_type,
+                    from: m.from,
+                    text: m.text,
+                    msgid: m.msgid,
+                };
+                // saved messages are older than anything queued since
+                pending.insert(i, message);
+            }
+        }
+        self.deliver_pending(irc).await;
     }
     pub async fn target(&self) -> String {
         self.inner.read().await.target.clone()
     }
+    /// room creation time in seconds, from m.room.create
+    pub async fn creation_time(&self, room: &Room) -> Option<u64> {
+        if let Some(created) = self.inner.read().await.created {
+            return Some(created);
+        }
+        let event = match room
+            .get_state_event_static::<RoomCreateEventContent>()
+            .await
+        {
+            Ok(Some(event)) => event.deserialize().ok()?,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Could not get create event of {}: {}", room.room_id(), e);
+                return None;
+            }
+        };
+        let SyncOrStrippedState::Sync(event) = event else {
+            return None;
+        };
+        let created = event.origin_server_ts().as_secs().into();
+        self.inner.write().await.created = Some(created);
+        Some(created)
+    }
+    /// name as seen on irc: with leading # for chans
+    pub async fn irc_name(&self) -> String {
+        let lock = self.inner.read().await;
+        match lock.target_type {
+            RoomTargetType::Query => lock.target.clone(),
+            _ => format!("#{}", lock.target),
+        }
+    }
+
+    /// matrix user behind irc name in this room
+    pub async fn member_id(&self, name: &str) -> Option<OwnedUserId> {
+        self.inner.read().await.names.get(&casefold(name)).cloned()
+    }
+
+    /// members that left with given irc name, most recent first
+    pub async fn departed(&self, nick: &str) -> Vec<Departed> {
+        self.inner
+            .read()
+            .await
+            .departed
+            .iter()
+            .rev()
+            .filter(|d| d.nick.eq_ignore_ascii_case(nick))
+            .cloned()
+            .collect()
+    }
+
+    /// irc nick of member, or their matrix id if not in room
+    pub async fn member_nick(&self, user_id: &UserId) -> String {
+        self.inner
+            .read()
+            .await
+            .members
+            .get(user_id.as_str())
+            .cloned()
+            .unwrap_or_else(|| user_id.to_string())
+    }
+
+    /// channel name if it is currently joined on irc
+    pub async fn joined_chan(&self) -> Option<String> {
+        let inner = self.inner.read().await;
+        match inner.target_type {
+            RoomTargetType::Chan => Some(format!("#{}", inner.target)),
+            _ => None,
+        }
+    }
+
+    /// remember member sent a message, see recently_spoke
+    pub async fn spoke(&self, user_id: &UserId) {
+        let mut inner = self.inner.write().await;
+        inner
+            .spoke
+            .retain(|_, when| when.elapsed() < SMART_JOINS_WINDOW);
+        inner.spoke.insert(user_id.to_owned(), Instant::now());
+    }
+
+    pub async fn recently_spoke(&self, user_id: &UserId) -> bool {
+        self.inner
+            .read()
+            .await
+            .spoke
+            .get(user_id)
+            .is_some_and(|when| when.elapsed() < SMART_JOINS_WINDOW)
+    }
+
+    /// queue membership change for a summary notice sent after delay
+    pub async fn churn(&self, irc: &IrcClient, nick: String, joined: bool, delay: Duration) {
+        let mut inner = self.inner.write().await;
+        let first = inner.churn.is_none();
+        let churn = inner.churn.get_or_insert_with(Churn::default);
+        match joined {
+            true => churn.joined.push(nick),
+            false => churn.left.push(nick),
+        }
+        drop(inner);
+        if !first {
+            return;
+        }
+        let target = self.clone();
+        let irc = irc.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(churn) = target.inner.write().await.churn.take() else {
+                return;
+            };
+            let mut summary = vec![];
+            if !churn.joined.is_empty() {
+                summary.push(format!("→ {} joined", churn.joined.join(", ")));
+            }
+            if !churn.left.is_empty() {
+                summary.push(format!("← {} left", churn.left.join(", ")));
+            }
+            if let Err(e) = target
+                .send_text_to_irc(
+                    &irc,
+                    IrcMessageType::Notice,
+                    &"matrirc".to_string(),
+                    summary.join("; "),
+                )
+                .await
+            {
+                warn!("Could not send joins summary: {:?}", e);
+            }
+        });
+    }
+
+    /// queue reaction to event for a summary sent after delay, only the
+    /// first reaction's `reacted` is kept
+    pub async fn react(
+        &self,
+        irc: &IrcClient,
+        event_id: OwnedEventId,
+        reacted: Reacted,
+        sender: String,
+        key: String,
+        delay: Duration,
+    ) {
+        let mut inner = self.inner.write().await;
+        let first = !inner.reactions.contains_key(&event_id);
+        let reactions = inner.reactions.entry(event_id.clone()).or_default();
+        if first {
+            reactions.reacted = reacted;
+        }
+        if !reactions.senders.contains(&sender) {
+            reactions.senders.push(sender);
+        }
+        match reactions.keys.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, count)) => *count += 1,
+            None => reactions.keys.push((key, 1)),
+        }
+        drop(inner);
+        if !first {
+            return;
+        }
+        let target = self.clone();
+        let irc = irc.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(mut reactions) = target.inner.write().await.reactions.remove(&event_id) else {
+                return;
+            };
+            let keys: Vec<String> = reactions
+                .keys
+                .iter()
+                .map(|(key, count)| format!("{}×{}", key, count))
+                .collect();
+            let text = format!(
+                "{}{} on {}",
+                reactions.reacted.time_prefix,
+                keys.join(" "),
+                reactions.reacted.description
+            );
+            // single reactor keeps the reaction attributed to them
+            let (message_type, sender) = match reactions.senders.len() {
+                1 => (IrcMessageType::Privmsg, reactions.senders.remove(0)),
+                _ => (IrcMessageType::Notice, "matrirc".to_string()),
+            };
+            if let Err(e) = target
+                .send_text_to_irc(&irc, message_type, &sender, text)
+                .await
+            {
+                warn!("Could not send reactions summary: {:?}", e);
+            }
+        });
+    }
+
+    /// part our own nick from chan if joined, e.g. on logout
+    pub async fn part_chan(&self, irc: &IrcClient) -> Result<()> {
+        let mut lock = self.inner.write().await;
+        if !matches!(
+            lock.target_type,
+            RoomTargetType::Chan | RoomTargetType::JoiningChan
+        ) {
+            return Ok(());
+        }
+        lock.target_type = RoomTargetType::LeftChan;
+        let chan = format!("#{}", lock.target);
+        drop(lock);
+        irc.send(ircd::proto::part(Some(irc.nick.clone()), chan))
+            .await
+    }
+
+    pub async fn is_query(&self) -> bool {
+        self.inner.read().await.target_type == RoomTargetType::Query
+    }
 
     async fn join_chan(&self, irc: &IrcClient) -> bool {
         let mut lock = self.inner.write().await;
@@ -222,6 +875,8 @@ impl RoomTarget {
             // got raced or already joined
             RoomTargetType::JoiningChan => return false,
             RoomTargetType::Chan => return false,
+            // will join once members are known
+            RoomTargetType::Filling => return true,
         };
         lock.target_type = RoomTargetType::JoiningChan;
         let chan = format!("#{}", lock.target);
@@ -229,30 +884,53 @@ impl RoomTarget {
 
         // we need to initate the join before getting members in another task
         if let Err(e) = join_irc_chan(irc, &chan).await {
-            warn!("Could not join irc: {e}");
-            // XXX send message to irc through matrirc query
+            report_error(irc, format!("Could not join {}: {}", chan, e)).await;
             return false;
         }
         let target = self.clone();
         let irc = irc.clone();
         tokio::spawn(async move {
             let names_list = target.names_list().await;
-            if let Err(e) = join_irc_chan_finish(&irc, chan, names_list).await {
-                warn!("Could not join irc: {e}");
-                // XXX send message to irc through matrirc query
+            if let Err(e) = join_irc_chan_finish(&irc, chan.clone(), names_list).await {
+                report_error(&irc, format!("Could not join {}: {}", chan, e)).await;
                 return;
             }
+            if target.inner.read().await.read_only {
+                let _ = irc
+                    .send(ircd::proto::mode("matrirc", &chan, "+m", None))
+                    .await;
+            }
             if let Err(e) = target.finish_join(&irc).await {
-                warn!("Could not finish join: {e}");
-                // XXX irc message
+                report_error(&irc, format!("Could not finish joining {}: {}", chan, e)).await;
             }
         });
         true
     }
 
+    /// recheck if we can post after power levels changed, and tell irc
+    pub async fn update_read_only(&self, irc: &IrcClient, room: &Room) -> Result<()> {
+        let read_only = !can_post(room).await;
+        let mut lock = self.inner.write().await;
+        if lock.read_only == read_only {
+            return Ok(());
+        }
+        lock.read_only = read_only;
+        if lock.target_type != RoomTargetType::Chan {
+            return Ok(());
+        }
+        let chan = format!("#{}", lock.target);
+        drop(lock);
+        let mode = match read_only {
+            true => "+m",
+            false => "-m",
+        };
+        irc.send(ircd::proto::mode("matrirc", chan, mode, None))
+            .await
+    }
+
     async fn names_list(&self) -> Vec<String> {
         // need to clone because of lock -- could do better?
-        self.inner.read().await.names.keys().cloned().collect()
+        self.inner.read().await.members.values().cloned().collect()
     }
 
     async fn finish_join(&self, irc: &IrcClient) -> Result<()> {
@@ -268,23 +946,32 @@ impl RoomTarget {
         irc: &IrcClient,
         member: OwnedUserId,
         name: Option<String>,
+        policy: &NickPolicy,
+        announce: bool,
+        flush_delay: Duration,
     ) -> Result<()> {
         let mut guard = self.inner.write().await;
         let chan = format!("#{}", guard.target);
         trace!("{:?} ({}) joined {}", name, member, chan);
         // XXX wait a bit and list room members if name is none?
-        let name = sanitize(name.unwrap_or_else(|| member.to_string()));
-        let name = guard.names.insert_deduped(&name, member.clone());
-        guard.members.insert(member.into(), name.clone());
+        let name = guard.insert_member(&member, name.as_deref().unwrap_or(member.as_str()), policy);
         drop(guard);
-        if !self.join_chan(irc).await {
+        if !self.join_chan(irc).await && announce {
             // already joined chan, send join to irc
-            irc.send(ircd::proto::join(Some(name), chan)).await?;
+            irc.send_low_priority(&chan, &name, Membership::Join, flush_delay)
+                .await?;
         }
         Ok(())
     }
 
-    pub async fn member_part(&self, irc: &IrcClient, member: OwnedUserId) -> Result<()> {
+    pub async fn member_part(
+        &self,
+        irc: &IrcClient,
+        member: OwnedUserId,
+        display_name: Option<String>,
+        announce: bool,
+        flush_delay: Duration,
+    ) -> Result<()> {
         let mut guard = self.inner.write().await;
         let Some(name) = guard.members.remove(member.as_str()) else {
             // not in chan
@@ -292,12 +979,63 @@ impl RoomTarget {
         };
         let chan = format!("#{}", guard.target);
         trace!("{:?} ({}) part {}", name, member, chan);
-        let _ = guard.names.remove(&name);
+        let _ = guard.names.remove(&casefold(&name));
+        if guard.departed.len() >= DEPARTED_PER_ROOM {
+            guard.departed.pop_front();
+        }
+        let departed = Departed {
+            nick: name.clone(),
+            target: chan.clone(),
+            user_id: member,
+            display_name,
+            left: SystemTime::now(),
+        };
+        guard.departed.push_back(departed);
         drop(guard);
-        irc.send(ircd::proto::part(Some(name), chan)).await?;
+        if announce {
+            irc.send_low_priority(&chan, &name, Membership::Part, flush_delay)
+                .await?;
+        }
         Ok(())
     }
 
+    /// update member nick after a display name change, and tell irc if
+    /// it changed. Display names are per room, so the member leaves and joins
+    /// again with its new nick in this chan only (NICK would be global).
+    /// Returns whether the nick changed.
+    pub async fn member_rename(
+        &self,
+        irc: &IrcClient,
+        member: &UserId,
+        name: Option<String>,
+        policy: &NickPolicy,
+    ) -> Result<bool> {
+        let mut guard = self.inner.write().await;
+        let Some(old) = guard.members.get(member.as_str()).cloned() else {
+            // not in chan
+            return Ok(false);
+        };
+        let _ = guard.names.remove(&casefold(&old));
+        let new = guard.insert_member(member, name.as_deref().unwrap_or(member.as_str()), policy);
+        if new == old {
+            return Ok(false);
+        }
+        trace!(
+            "{} ({}) renamed to {} in {}",
+            old,
+            member,
+            new,
+            guard.target
+        );
+        if guard.target_type == RoomTargetType::Chan {
+            let chan = format!("#{}", guard.target);
+            drop(guard);
+            irc.send(ircd::proto::part(Some(old), &chan)).await?;
+            irc.send(ircd::proto::join(Some(new), &chan)).await?;
+        }
+        Ok(true)
+    }
+
     /// error will be sent next time a message from channel is sent
     /// (or when it's finished joining in case of chan trying to join)
     async fn set_error(self, error: String) -> Self {
@@ -315,43 +1053,81 @@ impl RoomTarget {
         self
     }
 
-    async fn target_message_to_irc(&self, irc: &IrcClient, message: TargetMessage) -> IrcMessage {
-        match &*self.inner.read().await {
-            RoomTargetInner {
-                target,
-                target_type: RoomTargetType::Query,
-                ..
-            } => IrcMessage {
+    async fn target_message_to_irc(
+        &self,
+        irc: &IrcClient,
+        message: TargetMessage,
+    ) -> Vec<IrcMessage> {
+        let mut guard = self.inner.write().await;
+        let inner = &mut *guard;
+        match inner.target_type {
+            RoomTargetType::Query if message.from == inner.target => {
+                inner.burst_from = None;
+                vec![IrcMessage {
+                    message_type: message.message_type,
+                    from: inner.target.clone(),
+                    target: irc.nick.clone(),
+                    text: message.text,
+                    msgid: message.msgid,
+                }]
+            }
+            RoomTargetType::Query if inner.bursts => {
+                let mut messages = vec![];
+                if inner.burst_from.as_ref() != Some(&message.from) {
+                    messages.push(IrcMessage {
+                        message_type: IrcMessageType::Notice,
+                        from: inner.target.clone(),
+                        target: irc.nick.clone(),
+                        text: format!("— {} —", message.from),
+                        msgid: None,
+                    });
+                    inner.burst_from = Some(message.from);
+                }
+                messages.push(IrcMessage {
+                    message_type: message.message_type,
+                    from: inner.target.clone(),
+                    target: irc.nick.clone(),
+                    text: message.text,
+                    msgid: message.msgid,
+                });
+                messages
+            }
+            RoomTargetType::Query => vec![IrcMessage {
                 message_type: message.message_type,
-                from: target.clone(),
+                from: inner.target.clone(),
                 target: irc.nick.clone(),
-                text: if &message.from == target {
-                    message.text
-                } else {
-                    format!("<{}> {}", message.from, message.text)
-                },
-            },
+                text: format!("<{}> {}", message.from, message.text),
+                msgid: message.msgid,
+            }],
             // mostly normal chan, but finish_join can also use ths on JoningChan
             // we could error on LeftChan but what's the point?
-            RoomTargetInner { target, .. } => IrcMessage {
+            _ => vec![IrcMessage {
                 message_type: message.message_type,
                 from: message.from,
-                target: format!("#{}", target),
+                target: format!("#{}", inner.target),
                 text: message.text,
-            },
+                msgid: message.msgid,
+            }],
         }
     }
 
     pub async fn flush_pending_messages(&self, irc: &IrcClient) -> Result<()> {
-        let inner = self.inner.read().await;
-        if !inner.pending_messages.read().await.is_empty() {
-            while let Some(target_message) = inner.pending_messages.write().await.pop_front() {
-                for irc_message in self.target_message_to_irc(irc, target_message).await {
-                    irc.send(irc_message).await?
-                }
+        loop {
+            // don't hold the lock: converting messages updates burst state
+            let inner = self.inner.read().await;
+            let Some(target_message) = inner.pending_messages.write().await.pop_front() else {
+                return Ok(());
+            };
+            drop(inner);
+            for irc_message in self
+                .target_message_to_irc(irc, target_message)
+                .await
+                .into_iter()
+                .flatten()
+            {
+                irc.send(irc_message).await?
             }
-        };
-        Ok(())
+        }
     }
 
     pub async fn send_text_to_irc<S>(
@@ -364,6 +1140,28 @@ impl RoomTarget {
     where
         S: Into<String>,
     {
+        self.send_message_to_irc(irc, message_type, sender, text, None)
+            .await
+    }
+
+    /// same as send_text_to_irc, with short message id
+    pub async fn send_message_to_irc<S>(
+        &self,
+        irc: &IrcClient,
+        message_type: IrcMessageType,
+        sender: &String,
+        text: S,
+        msgid: Option<String>,
+    ) -> Result<()>
+    where
+        S: Into<String>,
+    {
+        let text = text.into();
+        let (text, msgid) = match (msgid, args().message_ids) {
+            (Some(id), MessageIds::Suffix) => (format!("{} [{}]", text, id), None),
+            (Some(id), MessageIds::Tag) if irc.has_cap("message-tags") => (text, Some(id)),
+            _ => (text, None),
+        };
         let inner = self.inner.read().await;
         let message = TargetMessage {
             message_type,
@@ -373,7 +1171,8 @@ impl RoomTarget {
                 .map(Cow::Borrowed)
                 .unwrap_or_else(|| Cow::Owned(sender.clone()))
                 .to_string(),
-            text: text.into(),
+            text,
+            msgid,
         };
         match inner.target_type {
             RoomTargetType::LeftChan => {
@@ -383,8 +1182,8 @@ impl RoomTarget {
                 self.join_chan(irc).await;
                 return Ok(());
             }
-            RoomTargetType::JoiningChan => {
-                trace!("Queueing message (join in progress)");
+            RoomTargetType::JoiningChan | RoomTargetType::Filling => {
+                trace!("Queueing message (join or member fetch in progress)");
                 inner.pending_messages.write().await.push_back(message);
                 return Ok(());
             }
@@ -395,11 +1194,46 @@ impl RoomTarget {
         // really send -- start with pending messages if any
         self.flush_pending_messages(irc).await?;
 
-        for irc_message in self.target_message_to_irc(irc, message).await {
+        for irc_message in self
+            .target_message_to_irc(irc, message)
+            .await
+            .into_iter()
+            .flatten()
+        {
             irc.send(irc_message).await?
         }
         Ok(())
     }
+    /// send reaction as `+draft/react` TAGMSG replying to msgid, returns
+    /// false if client cannot get it
+    pub async fn send_reaction_tag(
+        &self,
+        irc: &IrcClient,
+        sender: &String,
+        msgid: String,
+        key: &str,
+    ) -> Result<bool> {
+        if args().message_ids != MessageIds::Tag || !irc.has_cap("message-tags") {
+            return Ok(false);
+        }
+        let inner = self.inner.read().await;
+        let (from, target) = match inner.target_type {
+            RoomTargetType::Query => (inner.target.clone(), irc.nick.clone()),
+            RoomTargetType::Chan => (
+                inner.members.get(sender).unwrap_or(sender).to_string(),
+                format!("#{}", inner.target),
+            ),
+            _ => return Ok(false),
+        };
+        drop(inner);
+        let tags = vec![
+            Tag("+draft/react".to_string(), Some(key.to_string())),
+            Tag("+draft/reply".to_string(), Some(msgid)),
+        ];
+        irc.send(ircd::proto::tagmsg(from, target, tags)).await?;
+        Ok(true)
+    }
+
     pub async fn send_simple_query<S>(&self, irc: &IrcClient, text: S) -> Result<()>
     where
         S: Into<String>,
@@ -410,13 +1244,47 @@ impl RoomTarget {
 }
 
 impl Mappings {
-    pub fn new(irc: IrcClient) -> Self {
+    pub fn new(irc: IrcClient, nick_policy: NickPolicy, target_policy: TargetPolicy) -> Self {
         Mappings {
-            inner: MappingsInner::default().into(),
+            inner: MappingsInner {
+                reserved: vec!["matrirc".to_string(), casefold(&irc.nick)],
+                ..Default::default()
+            }
+            .into(),
             irc,
             mt: RoomTarget::query("matrirc"),
+            room_names: RwLock::new(HashMap::new()),
+            nick_policy,
+            target_policy,
         }
     }
+
+    pub fn nick_policy(&self) -> &NickPolicy {
+        &self.nick_policy
+    }
+
+    /// room display name, only computed once until invalidated
+    pub async fn room_name(&self, room: &Room) -> String {
+        if let Some(name) = self.room_names.read().await.get(room.room_id()) {
+            return name.clone();
+        }
+        let name = match room.compute_display_name().await {
+            Ok(name) => name.to_string(),
+            Err(e) => {
+                warn!("Could not compute name of {}: {}", room.room_id(), e);
+                room_name(room)
+            }
+        };
+        self.room_names
+            .write()
+            .await
+            .insert(room.room_id().into(), name.clone());
+        name
+    }
+
+    pub async fn room_name_invalidate(&self, room: &Room) {
+        self.room_names.write().await.remove(room.room_id());
+    }
     pub async fn room_target(&self, room: &Room) -> RoomTarget {
         match self.try_room_target(room).await {
             Ok(target) => target,
@@ -429,6 +1297,10 @@ impl Mappings {
             }
         }
     }
+    /// whether target is the matrirc query, e.g. for server notices room
+    pub fn is_matrirc_query(&self, target: &RoomTarget) -> bool {
+        Arc::ptr_eq(&target.inner, &self.mt.inner)
+    }
     pub async fn matrirc_query<S>(&self, message: S) -> Result<()>
     where
         S: Into<String>,
@@ -442,16 +1314,89 @@ impl Mappings {
         target: &(impl MessageHandler + Send + Sync + Clone + 'static),
     ) -> RoomTarget {
         let mut guard = self.inner.write().await;
-        let name = guard
-            .targets
-            .insert_deduped(candidate, Box::new(target.clone()));
+        let name = guard.insert_target(candidate, None, false, Box::new(target.clone()));
         let room_target = RoomTarget::query(name);
         target.set_target(room_target.clone()).await;
         room_target
     }
 
+    /// matrix user for irc nick, looked up in all rooms
+    pub async fn member_id(&self, nick: &str) -> Option<OwnedUserId> {
+        for (_, target) in self.list_rooms().await {
+            if let Some(user_id) = target.member_id(nick).await {
+                return Some(user_id);
+            }
+        }
+        None
+    }
+
+    /// members that left with given irc name in any room, most recent first
+    pub async fn whowas(&self, nick: &str) -> Vec<Departed> {
+        let mut departed = vec![];
+        for (_, target) in self.list_rooms().await {
+            departed.extend(target.departed(nick).await);
+        }
+        departed.sort_by_key(|d| std::cmp::Reverse(d.left));
+        departed
+    }
+
+    /// number of (chans, queries) mapped to matrix rooms
+    pub async fn target_counts(&self) -> (usize, usize) {
+        let mut counts = (0, 0);
+        for (_, target) in self.list_rooms().await {
+            if target.is_query().await {
+                counts.1 += 1;
+            } else {
+                counts.0 += 1;
+            }
+        }
+        counts
+    }
+
+    /// creation time of room behind chan
+    pub async fn creation_time(&self, name: &str) -> Option<u64> {
+        let room = self.room(name).await?;
+        let target = self.inner.read().await.rooms.get(room.room_id())?.clone();
+        target.creation_time(&room).await
+    }
+
+    /// whether chan is read-only for us (+m)
+    pub async fn read_only(&self, name: &str) -> bool {
+        let Some(room) = self.room(name).await else {
+            return false;
+        };
+        match self.inner.read().await.rooms.get(room.room_id()) {
+            Some(target) => target.inner.read().await.read_only,
+            None => false,
+        }
+    }
+
+    /// get matrix room from irc name (with or without leading #)
+    pub async fn room(&self, name: &str) -> Option<Room> {
+        let name = name.strip_prefix('#').unwrap_or(name);
+        self.inner.read().await.targets.get(&casefold(name))?.room()
+    }
+
+    /// all rooms we currently have a target for
+    pub async fn list_rooms(&self) -> Vec<(OwnedRoomId, RoomTarget)> {
+        self.inner
+            .read()
+            .await
+            .rooms
+            .iter()
+            .map(|(id, target)| (id.clone(), target.clone()))
+            .collect()
+    }
+
+    pub async fn part_all(&self) -> Result<()> {
+        for (_, target) in self.list_rooms().await {
+            target.part_chan(&self.irc).await?;
+        }
+        Ok(())
+    }
+
     pub async fn remove_target(&self, name: &str) {
-        self.inner.write().await.targets.remove(name);
+        self.inner.write().await.targets.remove(&casefold(name));
     }
 
     // note this cannot use insert_free_target because we want to keep write lock
@@ -459,40 +1404,80 @@ impl Mappings {
     // with a more generic 'insert_free_target' that takes a couple of callbacks but
     // it's just not worth it
     async fn try_room_target(&self, room: &Room) -> Result<RoomTarget> {
+        Ok(self.try_room_target_fill(room).await?.0)
+    }
+
+    /// same as try_room_target, also returning the member fill task if the
+    /// target was just created
+    async fn try_room_target_fill(
+        &self,
+        room: &Room,
+    ) -> Result<(RoomTarget, Option<JoinHandle<()>>)> {
         // happy case first
         if let Some(target) = self.inner.read().await.rooms.get(room.room_id()) {
-            return Ok(target.clone());
+            return Ok((target.clone(), None));
+        }
+
+        // homeserver notices (quota, terms of service...) go to matrirc query
+        if is_server_notices(room).await {
+            let mut mappings = self.inner.write().await;
+            let target = mappings
+                .rooms
+                .entry(room.room_id().into())
+                .or_insert_with(|| self.mt.clone());
+            return Ok((target.clone(), None));
         }
 
         // create a new and try to insert it...
-        let desired_name = sanitize(room_name(room));
+        let room_name = sanitize_chan(self.room_name(room).await);
+        let forced_type = self.target_policy.forced_type(room).await;
+        // direct messages are queries: keep plain name
+        let mut desired_name = if forced_type == Some(RoomTargetType::Query)
+            || (forced_type.is_none() && room.is_direct().await.unwrap_or(false))
+        {
+            room_name.clone()
+        } else {
+            sanitize_chan(expand_chan_template(
+                &self.target_policy.chan_template,
+                &room_name,
+                room,
+            ))
+        };
+        if desired_name.is_empty() {
+            desired_name = format!("room-{}", translit::short_hash(room.room_id().as_str()));
+        }
 
         // lock mappings and insert into hashs
         let mut mappings = self.inner.write().await;
         if let Some(target) = mappings.rooms.get(room.room_id()) {
             // got raced
-            return Ok(target.clone());
+            return Ok((target.clone(), None));
         }
         // find unique irc name
-        let name = mappings
-            .targets
-            .insert_deduped(&desired_name, Box::new(room.clone()));
+        let name = mappings.insert_target(
+            &desired_name,
+            Some(room.room_id().as_str()),
+            true,
+            Box::new(room.clone()),
+        );
         trace!("Creating room {}", name);
-        // create a query anyway, we'll promote it when we get members
-        let target = RoomTarget::query(&name);
+        // messages are queued until we know if it's a chan or query
+        let target = RoomTarget::new(
+            RoomTargetType::Filling,
+            &name,
+            self.target_policy.query_bursts,
+        );
         mappings.rooms.insert(room.room_id().into(), target.clone());
-
-        // lock target and release mapping lock we no longer need
-        let target_lock = target.inner.write().await;
         drop(mappings);
 
-        let room_clone = room.clone();
-        // XXX do this in a tokio::spawn task:
-        // can't seem to pass target_lock as its lifetime depends on target (or
-        // its clone), but we can't pass target and target lock because target can't be used while
-        // target_lock is alive...
-        fill_room_members(target_lock, room_clone, desired_name).await?;
-        Ok(target)
+        let fill = target.spawn_fill(
+            &self.irc,
+            room.clone(),
+            room_name,
+            self.nick_policy.clone(),
+            forced_type,
+        );
+        Ok((target, Some(fill)))
     }
 
     pub async fn to_matrix(
@@ -505,28 +1490,241 @@ impl Mappings {
             Some(suffix) => suffix,
             None => name,
         };
-        if let Some(target) = self.inner.read().await.targets.get(name) {
+        if let Some(target) = self.inner.read().await.targets.get(&casefold(name)) {
             target.handle_message(message_type, message).await
         } else {
             Err(Error::msg(format!("No such target {}", name)))
         }
     }
 
-    pub async fn sync_rooms(&self, matrirc: &Matrirc) -> Result<()> {
-        let client = matrirc.matrix();
-        for joined in client.joined_rooms() {
-            if joined.is_tombstoned() {
-                trace!(
-                    "Skipping tombstoned {}",
-                    joined
-                        .name()
-                        .unwrap_or_else(|| joined.room_id().to_string())
-                );
+    /// save messages not sent to irc yet, for next connection
+    pub async fn save_pending(&self, store: &Store) -> Result<()> {
+        for (room_id, target) in self.list_rooms().await {
+            let messages = target.take_pending().await;
+            if messages.is_empty() {
                 continue;
             }
-            self.try_room_target(&joined).await?;
+            store
+                .blocking(move |store| {
+                    for message in messages {
+                        store.pending_put(&room_id, &message)?;
+                    }
+                    Ok(())
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// queue messages saved by save_pending on last exit
+    async fn restore_pending(&self, matrirc: &Matrirc) -> Result<()> {
+        let pending = matrirc
+            .store()
+            .blocking(|store| {
+                store
+                    .pending_rooms()?
+                    .into_iter()
+                    .map(|room_id| Ok((store.pending_take(&room_id)?, room_id)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await?;
+        for (messages, room_id) in pending {
+            let Some(room) = matrirc.matrix().get_room(&room_id) else {
+                continue;
+            };
+            let target = self.room_target(&room).await;
+            target.restore_pending(&self.irc, messages).await;
+        }
+        Ok(())
+    }
+
+    pub async fn sync_rooms(&self, matrirc: &Matrirc) -> Result<()> {
+        let rooms: Vec<Room> = matrirc
+            .matrix()
+            .joined_rooms()
+            .into_iter()
+            .filter(|joined| {
+                if joined.is_tombstoned() {
+                    trace!(
+                        "Skipping tombstoned {}",
+                        joined
+                            .name()
+                            .unwrap_or_else(|| joined.room_id().to_string())
+                    );
+                }
+                !joined.is_tombstoned()
+            })
+            .collect();
+        let total = rooms.len();
+        let mut synced = stream::iter(rooms)
+            .map(|room| async move {
+                if let (_, Some(fill)) = self.try_room_target_fill(&room).await? {
+                    fill.await?;
+                }
+                Ok::<(), Error>(())
+            })
+            .buffer_unordered(SYNC_ROOMS_CONCURRENCY);
+        let mut done = 0;
+        while let Some(result) = synced.next().await {
+            result?;
+            done += 1;
+            if done % SYNC_ROOMS_PROGRESS == 0 && done < total {
+                self.matrirc_query(format!("Initial room sync: {}/{} rooms", done, total))
+                    .await?;
+            }
         }
         self.matrirc_query("Finished initial room sync").await?;
+        self.restore_pending(matrirc).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_dedup() {
+        let mut map = HashMap::new();
+        assert_eq!(map.insert_deduped("foo", None, 1), "foo");
+        assert_eq!(map.insert_deduped("foo", None, 2), "foo_2");
+        assert_eq!(map.insert_deduped("foo", None, 3), "foo_3");
+
+        let long = "a".repeat(NAME_MAX_LEN);
+        assert_eq!(map.insert_deduped(&long, None, 4), long);
+        let deduped = map.insert_deduped(&long, None, 5);
+        assert_eq!(deduped.len(), NAME_MAX_LEN);
+        assert!(deduped.ends_with("_2"));
+
+        assert_eq!(sanitize("x".repeat(100)).len(), NAME_MAX_LEN);
+        assert_eq!(casefold("Rust"), casefold("rust"));
+
+        // names differing only by case collide, original case is kept
+        assert_eq!(map.insert_deduped("Bar", None, 6), "Bar");
+        assert_eq!(map.insert_deduped("bar", None, 7), "bar_2");
+        assert_eq!(map.get("bar"), Some(&6));
+    }
+
+    #[test]
+    fn check_sanitize() {
+        assert_eq!(sanitize("Some Room!"), "SomeRoom");
+        assert_eq!(sanitize("alice99"), "alice99");
+        assert_eq!(sanitize("[bot]|away^"), "[bot]|away^");
+        assert_eq!(sanitize("2fast"), "_2fast");
+        assert_eq!(sanitize("-dash"), "_-dash");
+        assert_eq!(sanitize("a.b"), "ab");
+        assert_eq!(sanitize_chan("team-2024"), "team-2024");
+        assert_eq!(sanitize_chan("rust.fr"), "rust.fr");
+        assert_eq!(sanitize("Привет"), "Privet");
+        let user_id = UserId::parse("@bob:example.org").unwrap();
+        assert_eq!(sanitize_member("東京", &user_id), "bob");
+
+        // digits are kept, so these no longer collide
+        let mut map = HashMap::new();
+        assert_eq!(map.insert_deduped(&sanitize("alice1"), None, 1), "alice1");
+        assert_eq!(map.insert_deduped(&sanitize("alice2"), None, 2), "alice2");
+        // but these still do
+        assert_eq!(
+            map.insert_deduped(&sanitize("alice 1"), None, 3),
+            "alice1_2"
+        );
+        assert_eq!(
+            map.insert_deduped(&sanitize("alice!1"), None, 4),
+            "alice1_3"
+        );
+    }
+
+    #[test]
+    fn check_reserved() {
+        let mappings = MappingsInner {
+            reserved: vec!["matrirc".to_string(), "me".to_string()],
+            ..Default::default()
+        };
+        assert!(!mappings.name_available("Matrirc", true));
+        assert!(!mappings.name_available("ME", false));
+        assert!(!mappings.name_available("invite", true));
+        assert!(!mappings.name_available("verif_2", true));
+        assert!(mappings.name_available("invite", false));
+        assert!(mappings.name_available("invited", true));
+        assert!(mappings.name_available("invite_ab12", true));
+    }
+
+    #[test]
+    fn check_chan_template() {
+        let expand = |t| sanitize_chan(expand_template(t, "general", "matrix.org", "rust"));
+        assert_eq!(expand("{name}"), "general");
+        assert_eq!(expand("#{name}.{server}"), "general.matrix.org");
+        // ':' is not valid in irc chan names
+        assert_eq!(expand("{name}:{server}"), "generalmatrix.org");
+        assert_eq!(expand("#{alias-localpart}"), "rust");
+    }
+
+    #[test]
+    fn check_dedup_stable() {
+        // same suffix whatever the arrival order
+        let mut map = HashMap::new();
+        map.insert_deduped("alice", Some("@alice:a.org"), 1);
+        let b = map.insert_deduped("alice", Some("@alice:b.org"), 2);
+        let mut map = HashMap::new();
+        map.insert_deduped("alice", Some("@alice:c.org"), 3);
+        assert_eq!(map.insert_deduped("alice", Some("@alice:b.org"), 2), b);
+        assert!(b.starts_with("alice_"));
+        assert_eq!(b.len(), "alice_".len() + 4);
+    }
+
+    #[test]
+    fn check_fill_members_order() {
+        let policy = NickPolicy {
+            localpart_nicks: false,
+            own_user: None,
+            own_nick: "me".to_string(),
+        };
+        let members: Vec<(OwnedUserId, String)> = ["@alice:a.org", "@alice:b.org", "@alice:c.org"]
+            .iter()
+            .map(|id| (UserId::parse(*id).unwrap(), "alice".to_string()))
+            .collect();
+        let nicks = |members: Vec<(OwnedUserId, String)>| {
+            let target = RoomTarget::new(RoomTargetType::Chan, "#room", false);
+            let mut inner = target.inner.try_write().unwrap();
+            inner.fill_members(members, "room", &policy);
+            let mut nicks: Vec<_> = inner.members.clone().into_iter().collect();
+            nicks.sort();
+            nicks
+        };
+        let forward = nicks(members.clone());
+        let reverse = nicks(members.into_iter().rev().collect());
+        assert_eq!(forward, reverse);
+        assert_eq!(forward[0].1, "alice");
+    }
+
+    #[tokio::test]
+    async fn check_member_rename() {
+        let policy = NickPolicy {
+            localpart_nicks: false,
+            own_user: None,
+            own_nick: "me".to_string(),
+        };
+        let (sink, mut rx) = tokio::sync::mpsc::channel(10);
+        let irc = IrcClient::new(sink, "me".to_string(), "me".to_string(), vec![]);
+        let target = RoomTarget::new(RoomTargetType::Chan, "room", false);
+        let bob = UserId::parse("@bob:example.org").unwrap();
+        target
+            .inner
+            .write()
+            .await
+            .insert_member(&bob, "bob", &policy);
+        assert!(target
+            .member_rename(&irc, &bob, Some("robert".to_string()), &policy)
+            .await
+            .unwrap());
+        assert_eq!(
+            rx.recv().await.unwrap().to_string(),
+            ":bob!bob@matrirc PART #room\r\n"
+        );
+        assert_eq!(
+            rx.recv().await.unwrap().to_string(),
+            ":robert!robert@matrirc JOIN #room\r\n"
+        );
+        assert_eq!(target.member_nick(&bob).await, "robert");
+    }
+}
@@ -0,0 +1,101 @@
+//! `:shortcode:` <-> emoji translation, shortcodes are emoji names with
+//! underscores instead of spaces (e.g. `:thumbs_up:`)
+
+fn lookup(name: &str) -> Option<&'static emoji::Emoji> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    let name = name.replace('_', " ");
+    emoji::lookup_by_name::lookup(&name)
+        .or_else(|| emoji::lookup_by_name::lookup(&name.to_lowercase()))
+}
+
+/// replace known `:shortcode:` sequences by their emoji, leaving anything else as is
+pub fn expand(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest[1..].find(':').map(|end| end + 1) else {
+            break;
+        };
+        match lookup(&rest[1..end]) {
+            Some(emoji) => {
+                result.push_str(emoji.glyph);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                // closing colon can start the next shortcode
+                result.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// longest emoji sequence we look for, in chars
+const MAX_GLYPH_CHARS: usize = 10;
+
+/// `:name:` shortcode for emoji
+pub fn shortcode(emoji: &emoji::Emoji) -> String {
+    format!(":{}:", emoji.name.replace(": ", "_").replace(' ', "_"))
+}
+
+/// replace emoji by their `:shortcode:`, for terminals that can't display them
+pub fn transliterate(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        // longest match first, so sequences (flags, zwj...) aren't split up
+        let found = (!c.is_ascii())
+            .then(|| {
+                rest.char_indices()
+                    .map(|(i, c)| i + c.len_utf8())
+                    .take(MAX_GLYPH_CHARS)
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .find_map(|end| {
+                        emoji::lookup_by_glyph::lookup(&rest[..end]).map(|emoji| (end, emoji))
+                    })
+            })
+            .flatten();
+        match found {
+            Some((end, emoji)) => {
+                result.push_str(&shortcode(emoji));
+                rest = &rest[end..];
+            }
+            None => {
+                result.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_expand() {
+        assert_eq!(expand("ok :thumbs_up:"), "ok 👍");
+        assert_eq!(expand(":Red_Heart::red_heart:"), "❤️❤️");
+        assert_eq!(
+            expand("at 12:30:00 :nope: :smiling face:"),
+            "at 12:30:00 :nope: :smiling face:"
+        );
+        assert_eq!(expand("a:b:thumbs_up: c:"), "a:b👍 c:");
+    }
+
+    #[test]
+    fn check_transliterate() {
+        assert_eq!(transliterate("ok 👍!"), "ok :thumbs_up:!");
+        assert_eq!(transliterate("été 👨‍👩‍👧"), "été :family_man,_woman,_girl:");
+        assert_eq!(expand(&transliterate("👍❤️")), "👍❤️");
+    }
+}
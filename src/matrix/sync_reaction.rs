@@ -1,20 +1,28 @@
 use anyhow::Result;
 use log::trace;
+use std::time::Duration;
+
 use matrix_sdk::{
     event_handler::Ctx,
     room::Room,
     ruma::events::{
-        reaction::OriginalSyncReactionEvent, room::message::MessageType,
-        room::redaction::OriginalSyncRoomRedactionEvent, AnySyncMessageLikeEvent,
-        AnySyncTimelineEvent, SyncMessageLikeEvent,
+        reaction::OriginalSyncReactionEvent,
+        room::message::{sanitize::remove_plain_reply_fallback, MessageType},
+        room::redaction::OriginalSyncRoomRedactionEvent,
+        AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
     },
-    ruma::EventId,
+    ruma::{EventId, OwnedEventId},
     RoomState,
 };
 
+use crate::config::ReactionTags;
 use crate::ircd::proto::IrcMessageType;
 use crate::matrirc::Matrirc;
+use crate::matrix::links::snippet;
+use crate::matrix::room_mappings::Reacted;
+use crate::matrix::shortcodes;
 use crate::matrix::time::ToLocal;
+use crate::store::CachedEvent;
 
 // OriginalRoomRedactionEvent for redactions
 pub fn message_like_to_str(event: &AnySyncMessageLikeEvent) -> String {
@@ -24,15 +32,20 @@ pub fn message_like_to_str(event: &AnySyncMessageLikeEvent) -> String {
     let SyncMessageLikeEvent::Original(event) = event else {
         return "(redacted)".to_string();
     };
+    let msgtype = &event.content.msgtype;
+    message_type_to_str(msgtype, remove_plain_reply_fallback(msgtype.body()))
+}
 
-    match &event.content.msgtype {
-        MessageType::Text(text_content) => text_content.body.clone(),
-        MessageType::Emote(emote_content) => format!("emote: {}", emote_content.body),
-        MessageType::Notice(notice_content) => notice_content.body.clone(),
-        MessageType::ServerNotice(snotice_content) => snotice_content.body.clone(),
-        MessageType::File(file_content) => format!("file: {}", &file_content.body),
-        MessageType::Image(image_content) => format!("image: {}", &image_content.body,),
-        MessageType::Video(video_content) => format!("video: {}", &video_content.body,),
+/// short text of a message to quote it, body without reply fallback
+pub fn message_type_to_str(msgtype: &MessageType, body: &str) -> String {
+    match msgtype {
+        MessageType::Text(_) | MessageType::Notice(_) | MessageType::ServerNotice(_) => {
+            body.to_string()
+        }
+        MessageType::Emote(_) => format!("emote: {}", body),
+        MessageType::File(_) => format!("file: {}", body),
+        MessageType::Image(_) => format!("image: {}", body),
+        MessageType::Video(_) => format!("video: {}", body),
         MessageType::VerificationRequest(_verif_content) => "(verification request)".to_string(),
         msg => {
             let data = if !msg.data().is_empty() {
@@ -40,11 +53,47 @@ pub fn message_like_to_str(event: &AnySyncMessageLikeEvent) -> String {
             } else {
                 ""
             };
-            format!("{}{}: {}", msg.msgtype(), data, msg.body())
+            format!("{}{}: {}", msg.msgtype(), data, body)
+        }
+    }
+}
+fn cached_event(event: AnySyncTimelineEvent) -> CachedEvent {
+    match event {
+        AnySyncTimelineEvent::MessageLike(m) => {
+            trace!("Got related message event: {:?}", m);
+            CachedEvent {
+                sender: m.sender().to_string(),
+                ts: m.origin_server_ts(),
+                message: Some(message_like_to_str(&m)),
+            }
         }
+        AnySyncTimelineEvent::State(s) => {
+            trace!("Got related state event: {:?}", s);
+            CachedEvent {
+                sender: s.sender().to_string(),
+                ts: s.origin_server_ts(),
+                message: None,
+            }
+        }
+    }
+}
+
+/// formatted on read: time is relative to now
+fn cached_event_to_str(matrirc: &Matrirc, event: &CachedEvent) -> String {
+    let time = event
+        .ts
+        .localtime(&matrirc.config().timestamps)
+        .unwrap_or_else(|| "just now".to_string());
+    match &event.message {
+        Some(message) => format!("message from {} @ {}: {}", event.sender, time, message),
+        None => format!("not a message from {} @ {}", event.sender, time),
     }
 }
-async fn get_message_from_event_id(
+
+/// text for event a reaction/redaction refers to: look in messages we
+/// processed, then events we looked up before and sdk's in-memory cache
+/// of synced events, and only then ask the server
+pub async fn get_message_from_event_id(
     matrirc: &Matrirc,
     room: &Room,
     event_id: &EventId,
@@ -52,42 +101,91 @@ async fn get_message_from_event_id(
     if let Some(message) = matrirc.message_get(event_id).await {
         return Ok(message);
     };
-    let raw_event = room.event(event_id, None).await?;
-
-    Ok(match raw_event.raw().deserialize()? {
-        AnySyncTimelineEvent::MessageLike(m) => {
-            trace!("Got related message event: {:?}", m);
+    if let Some(event) = matrirc.event_get(event_id).await {
+        return Ok(cached_event_to_str(matrirc, &event));
+    };
+    let event = cached_event(fetch_event(room, event_id).await?);
+    let message = cached_event_to_str(matrirc, &event);
+    matrirc.event_put(room.room_id(), event_id, event).await;
+    Ok(message)
+}
 
-            let message = message_like_to_str(&m);
-            format!(
-                "message from {} @ {}: {}",
-                m.sender(),
-                m.origin_server_ts()
-                    .localtime()
-                    .unwrap_or_else(|| "just now".to_string()),
-                message
-            )
+/// text of a message to quote it: same as above, without sender and time
+pub async fn get_body_from_event_id(
+    matrirc: &Matrirc,
+    room: &Room,
+    event_id: &EventId,
+) -> Result<String> {
+    if let Some(message) = matrirc.message_get(event_id).await {
+        return Ok(message);
+    };
+    let event = match matrirc.event_get(event_id).await {
+        Some(event) => event,
+        None => {
+            let event = cached_event(fetch_event(room, event_id).await?);
+            matrirc
+                .event_put(room.room_id(), event_id, event.clone())
+                .await;
+            event
         }
-        AnySyncTimelineEvent::State(s) => {
-            trace!("Got related state event: {:?}", s);
+    };
+    Ok(event
+        .message
+        .unwrap_or_else(|| "(not a message)".to_string()))
+}
 
-            format!(
-                "not a message from {} @ {}",
-                s.sender(),
-                s.origin_server_ts()
-                    .localtime()
-                    .unwrap_or_else(|| "just now".to_string()),
-            )
+/// event from sdk's in-memory cache of synced events, or from the server
+async fn fetch_event(room: &Room, event_id: &EventId) -> Result<AnySyncTimelineEvent> {
+    let cached = match room.event_cache().await {
+        Ok((cache, _)) => cache.event(event_id).await,
+        Err(e) => {
+            trace!("No event cache for {}: {}", room.room_id(), e);
+            None
         }
-    })
-    //match event {
-    // happy path:
-    // AnyTimelineEvent
-    // MessageLike(AnyMessageLikeEvent),
-    // (for redaction of reactions...) Reaction(ReactionEvent),
-    // RoomMessage(RoomMessageEvent),
-    // RoomMessageEvent = MessageLikeEvent<RoomMessageEventContent>;
-    // same as sync_room_message...
+    };
+    let raw_event = match cached {
+        Some(event) => event.into_raw(),
+        None => room.event(event_id, None).await?.into_raw(),
+    };
+    Ok(raw_event.deserialize()?)
+}
+
+/// max length of reacted to message in reaction summaries
+const REACTION_SNIPPET_LEN: usize = 40;
+
+fn reaction_text(matrirc: &Matrirc, key: &str) -> String {
+    match emoji::lookup_by_glyph::lookup(key) {
+        Some(e) if matrirc.config().emoji_to_shortcodes => shortcodes::shortcode(e),
+        Some(e) => format!("{} ({})", key, e.name),
+        None => key.to_string(),
+    }
+}
+
+/// short id and snippet of reacted to message, for summaries
+fn describe_reacted(short_id: Option<&str>, text: Option<&str>) -> String {
+    let id = match short_id {
+        Some(id) => format!("[{}] ", id),
+        None => String::new(),
+    };
+    match text {
+        None => format!("{}(unknown message)", id),
+        Some(text) => format!("{}'{}'", id, snippet(text, REACTION_SNIPPET_LEN)),
+    }
+}
+
+/// key and target of a reaction still in sdk's event cache: redacted
+/// events fetched from the server no longer have either
+async fn cached_reaction(room: &Room, event_id: &EventId) -> Option<(String, OwnedEventId)> {
+    let (cache, _) = room.event_cache().await.ok()?;
+    let event = cache.event(event_id).await?.into_raw().deserialize().ok()?;
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+        SyncMessageLikeEvent::Original(reaction),
+    )) = event
+    else {
+        return None;
+    };
+    let relation = reaction.content.relates_to;
+    Some((relation.key, relation.event_id))
 }
 
 pub async fn on_sync_reaction(
@@ -105,6 +203,10 @@ pub async fn on_sync_reaction(
         trace!("Ignored reaction in non-joined room");
         return Ok(());
     };
+    if matrirc.is_delivered(&event.event_id).await {
+        trace!("Ignored already delivered reaction {}", event.event_id);
+        return Ok(());
+    };
 
     trace!(
         "Processing reaction event {:?} to room {}",
@@ -115,33 +217,79 @@ pub async fn on_sync_reaction(
 
     let time_prefix = event
         .origin_server_ts
-        .localtime()
+        .message_time(&matrirc.config().timestamps)
         .map(|d| format!("<{}> ", d))
         .unwrap_or_default();
     let reaction = event.content.relates_to;
-    let reaction_text = emoji::lookup_by_glyph::lookup(&reaction.key)
-        .map(|e| format!("{} ({})", reaction.key, e.name))
-        .unwrap_or(reaction.key.clone());
-    let reacting_to = match get_message_from_event_id(&matrirc, &room, &reaction.event_id).await {
+    let reaction_text = reaction_text(&matrirc, &reaction.key);
+    let reacted_text = get_message_from_event_id(&matrirc, &room, &reaction.event_id)
+        .await
+        .map(|m| matrirc.config().incoming_text(&m));
+    let reacting_to = match &reacted_text {
         Err(e) => format!("<Could not retreive: {}>", e),
-        Ok(m) => m,
+        Ok(text) => text.clone(),
     };
+    let short_id = matrirc.message_short_id(&reaction.event_id).await;
     let message = format!(
         "{}<Reacted to {}>: {}",
         time_prefix, reacting_to, reaction_text
     );
-    matrirc
-        .message_put(event.event_id.clone(), message.clone())
+    let msgid = matrirc
+        .message_put(room.room_id(), &event.event_id, &message)
         .await;
+    let reaction_tags = matrirc.config().reaction_tags;
+    if reaction_tags != ReactionTags::Off {
+        if let Some(id) = &short_id {
+            let sent = target
+                .send_reaction_tag(
+                    matrirc.irc(),
+                    &event.sender.to_string(),
+                    id.clone(),
+                    &reaction.key,
+                )
+                .await?;
+            if sent && reaction_tags == ReactionTags::Only {
+                matrirc
+                    .mark_delivered(room.room_id(), &event.event_id)
+                    .await;
+                return Ok(());
+            }
+        }
+    }
+    let delay = matrirc.config().reactions_seconds;
+    if delay > 0 {
+        let reacted = Reacted {
+            time_prefix,
+            description: describe_reacted(short_id.as_deref(), reacted_text.ok().as_deref()),
+        };
+        target
+            .react(
+                matrirc.irc(),
+                reaction.event_id,
+                reacted,
+                event.sender.to_string(),
+                reaction_text,
+                Duration::from_secs(delay),
+            )
+            .await;
+        matrirc
+            .mark_delivered(room.room_id(), &event.event_id)
+            .await;
+        return Ok(());
+    }
     // get error if any (warn/matrirc channel?)
     target
-        .send_text_to_irc(
+        .send_message_to_irc(
             matrirc.irc(),
             IrcMessageType::Privmsg,
             &event.sender.into(),
             message,
+            msgid,
         )
         .await?;
+    matrirc
+        .mark_delivered(room.room_id(), &event.event_id)
+        .await;
 
     Ok(())
 }
@@ -160,6 +308,10 @@ pub async fn on_sync_room_redaction(
         trace!("Ignored reaction in non-joined room");
         return Ok(());
     };
+    if matrirc.is_delivered(&event.event_id).await {
+        trace!("Ignored already delivered redaction {}", event.event_id);
+        return Ok(());
+    };
 
     trace!(
         "Processing redaction event {:?} to room {}",
@@ -170,9 +322,36 @@ pub async fn on_sync_room_redaction(
 
     let time_prefix = event
         .origin_server_ts
-        .localtime()
+        .message_time(&matrirc.config().timestamps)
         .map(|d| format!("<{}> ", d))
         .unwrap_or_default();
+    if let Some(redacts) = &event.redacts {
+        if let Some((key, reacted)) = cached_reaction(&room, redacts).await {
+            let short_id = matrirc.message_short_id(&reacted).await;
+            let text = get_message_from_event_id(&matrirc, &room, &reacted)
+                .await
+                .ok()
+                .map(|m| matrirc.config().incoming_text(&m));
+            let reacted = describe_reacted(short_id.as_deref(), text.as_deref());
+            target
+                .send_text_to_irc(
+                    matrirc.irc(),
+                    IrcMessageType::Privmsg,
+                    &event.sender.into(),
+                    format!(
+                        "\u{001}ACTION {}removed their {} on {}",
+                        time_prefix,
+                        reaction_text(&matrirc, &key),
+                        reacted
+                    ),
+                )
+                .await?;
+            matrirc
+                .mark_delivered(room.room_id(), &event.event_id)
+                .await;
+            return Ok(());
+        }
+    }
     let reason = event.content.reason.as_deref().unwrap_or("(no reason)");
     let reacting_to = {
         match &event.redacts {
@@ -192,6 +371,9 @@ pub async fn on_sync_room_redaction(
             format!("{}<Redacted {}>: {}", time_prefix, reacting_to, reason),
         )
         .await?;
+    matrirc
+        .mark_delivered(room.room_id(), &event.event_id)
+        .await;
 
     Ok(())
 }
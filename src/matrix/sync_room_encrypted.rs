@@ -0,0 +1,156 @@
+use anyhow::{Error, Result};
+use log::{trace, warn};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        events::{
+            forwarded_room_key::ToDeviceForwardedRoomKeyEvent,
+            room::encrypted::{EncryptedEventScheme, OriginalSyncRoomEncryptedEvent},
+            room_key::ToDeviceRoomKeyEvent,
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+        },
+        EventId,
+    },
+    RoomState,
+};
+
+use crate::ircd::proto::IrcMessageType;
+use crate::matrirc::Matrirc;
+use crate::matrix::sync_room_message::on_room_message;
+
+/// fetch room key from server-side backup and decrypt event again, sending it
+/// to irc if that worked. Returns whether event could be decrypted.
+///
+/// Fetching the event also has matrix-sdk request the key from our other
+/// devices again (automatic-room-key-forwarding), on_room_key then replays it
+/// if one of them answers.
+pub async fn rekey(matrirc: &Matrirc, room: &Room, event_id: &EventId) -> Result<bool> {
+    let event = room.event(event_id, None).await?;
+    if let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(
+        SyncMessageLikeEvent::Original(encrypted),
+    ))) = event.raw().deserialize()
+    {
+        let EncryptedEventScheme::MegolmV1AesSha2(content) = &encrypted.content.scheme else {
+            return Err(Error::msg("Not a room (megolm) message"));
+        };
+        matrirc
+            .matrix()
+            .encryption()
+            .backups()
+            .download_room_key(room.room_id(), &content.session_id)
+            .await?;
+        retry_session(matrirc, &content.session_id).await;
+    }
+    replay(matrirc, room, event_id).await
+}
+
+/// replay events that were waiting for the key of a megolm session,
+/// keeping those still undecryptable
+async fn retry_session(matrirc: &Matrirc, session_id: &str) {
+    for (room_id, event_id) in matrirc.undecrypted_take(session_id) {
+        let Some(room) = matrirc.matrix().get_room(&room_id) else {
+            continue;
+        };
+        match replay(matrirc, &room, &event_id).await {
+            Ok(true) => (),
+            Ok(false) => matrirc.undecrypted_put(session_id, room_id, event_id),
+            Err(e) => warn!("Could not replay {}: {:?}", event_id, e),
+        }
+    }
+}
+
+/// room key shared late or forwarded by one of our devices after sdk's key request
+pub async fn on_room_key(event: ToDeviceRoomKeyEvent, matrirc: Ctx<Matrirc>) {
+    retry_session(&matrirc, &event.content.session_id).await;
+}
+
+pub async fn on_forwarded_room_key(event: ToDeviceForwardedRoomKeyEvent, matrirc: Ctx<Matrirc>) {
+    retry_session(&matrirc, &event.content.session_id).await;
+}
+
+/// decrypt event from store and send it as a normal message
+async fn replay(matrirc: &Matrirc, room: &Room, event_id: &EventId) -> Result<bool> {
+    let event = room.event(event_id, None).await?;
+    let encryption_info = event.encryption_info().cloned();
+    match event.raw().deserialize() {
+        Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(message),
+        ))) => {
+            on_room_message(message, room.clone(), Ctx(matrirc.clone()), encryption_info).await?;
+            Ok(true)
+        }
+        Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(_))) => {
+            Ok(false)
+        }
+        // decrypted, but not a message (reaction...)
+        _ => Ok(true),
+    }
+}
+
+/// messages we could not decrypt: try key backup once per session, or tell
+/// user and show the message when its key arrives
+pub async fn on_room_encrypted(
+    event: OriginalSyncRoomEncryptedEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    if room.state() != RoomState::Joined {
+        trace!("Ignored encrypted message in non-joined room");
+        return Ok(());
+    }
+    let matrirc = matrirc.0;
+    let session_id = match &event.content.scheme {
+        EncryptedEventScheme::MegolmV1AesSha2(content) => Some(content.session_id.clone()),
+        _ => None,
+    };
+    tokio::spawn(async move {
+        if let Some(session_id) = &session_id {
+            if matrirc.backup_try(session_id) {
+                if let Err(e) = matrirc
+                    .matrix()
+                    .encryption()
+                    .backups()
+                    .download_room_key(room.room_id(), session_id)
+                    .await
+                {
+                    warn!("Could not get key for {}: {:?}", event.event_id, e);
+                }
+                // other messages of that session may have been waiting for it
+                retry_session(&matrirc, session_id).await;
+            }
+        }
+        match replay(&matrirc, &room, &event.event_id).await {
+            Ok(true) => return,
+            Ok(false) => (),
+            Err(e) => warn!("Could not decrypt {}: {:?}", event.event_id, e),
+        }
+        if let Some(session_id) = &session_id {
+            matrirc.undecrypted_put(
+                session_id,
+                room.room_id().to_owned(),
+                event.event_id.clone(),
+            );
+        }
+        let id = matrirc
+            .message_put(room.room_id(), &event.event_id, "(undecryptable)")
+            .await
+            .unwrap_or_else(|| event.event_id.to_string());
+        let target = matrirc.mappings().room_target(&room).await;
+        if let Err(e) = target
+            .send_text_to_irc(
+                matrirc.irc(),
+                IrcMessageType::Notice,
+                &event.sender.to_string(),
+                format!(
+                    "<unable to decrypt message, shown if its key arrives or rekey {} to retry>",
+                    id
+                ),
+            )
+            .await
+        {
+            warn!("Could not report undecryptable message: {:?}", e);
+        }
+    });
+    Ok(())
+}
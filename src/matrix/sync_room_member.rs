@@ -3,18 +3,107 @@ use log::{info, trace};
 use matrix_sdk::{
     event_handler::Ctx,
     room::Room,
-    ruma::events::room::member::{MembershipChange, OriginalSyncRoomMemberEvent},
+    ruma::{
+        events::room::{
+            member::{MembershipChange, OriginalSyncRoomMemberEvent},
+            third_party_invite::OriginalSyncRoomThirdPartyInviteEvent,
+        },
+        OwnedUserId, UserId,
+    },
     RoomState,
 };
+use std::time::Duration;
 
-use crate::ircd::proto::IrcMessageType;
+use crate::config::Joins;
+use crate::ircd::proto::{self, IrcMessageType};
 use crate::matrirc::Matrirc;
+use crate::matrix::room_mappings::RoomTarget;
+
+/// show bans and kicks as MODE +b/-b and KICK in chans, or a notice in queries
+async fn moderate(
+    matrirc: &Matrirc,
+    target: &RoomTarget,
+    sender: &OwnedUserId,
+    member: OwnedUserId,
+    change: &MembershipChange<'_>,
+    reason: Option<String>,
+    prev_name: Option<String>,
+) -> Result<()> {
+    let mode = match change {
+        MembershipChange::Banned | MembershipChange::KickedAndBanned => Some("+b"),
+        MembershipChange::Unbanned => Some("-b"),
+        _ => None,
+    };
+    let kick = matches!(
+        change,
+        MembershipChange::Kicked | MembershipChange::KickedAndBanned
+    );
+    let from = target.member_nick(sender).await;
+    let nick = target.member_nick(&member).await;
+    if let Some(chan) = target.joined_chan().await {
+        if let Some(mode) = mode {
+            let mask = format!("{}!*@*", nick);
+            matrirc
+                .irc()
+                .send(proto::mode(&from, &chan, mode, Some(mask)))
+                .await?;
+        }
+        if kick {
+            matrirc
+                .irc()
+                .send(proto::kick(&from, &chan, &nick, reason))
+                .await?;
+        }
+    } else if target.is_query().await {
+        let action = match (mode, kick) {
+            (Some("-b"), _) => "unbanned",
+            (Some(_), true) => "kicked and banned",
+            (Some(_), false) => "banned",
+            (None, _) => "kicked",
+        };
+        let reason = reason.map(|r| format!(": {}", r)).unwrap_or_default();
+        target
+            .send_text_to_irc(
+                matrirc.irc(),
+                IrcMessageType::Notice,
+                &sender.to_string(),
+                format!("<{} {}{}>", action, nick, reason),
+            )
+            .await?;
+    }
+    if kick {
+        // KICK already removed them from irc names
+        target
+            .member_part(
+                matrirc.irc(),
+                member,
+                prev_name,
+                false,
+                Duration::from_secs(matrirc.config().joins_notice_seconds),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// whether joins, parts and profile changes of user are shown in room
+async fn show_membership(joins: Joins, target: &RoomTarget, user_id: &UserId) -> bool {
+    match joins {
+        Joins::On | Joins::Notice => true,
+        Joins::Off => false,
+        Joins::Smart => target.recently_spoke(user_id).await,
+    }
+}
 
 pub async fn on_room_member(
     event: OriginalSyncRoomMemberEvent,
     room: Room,
     matrirc: Ctx<Matrirc>,
 ) -> Result<()> {
+    // rooms without name or alias (DMs) are named after their members
+    if room.name().is_none() && room.canonical_alias().is_none() {
+        matrirc.mappings().room_name_invalidate(&room).await;
+    }
     // ignore events from our own client (transaction set)
     if event.unsigned.transaction_id.is_some() {
         trace!("Ignored member event with transaction id (coming from self)");
@@ -31,6 +120,11 @@ pub async fn on_room_member(
 
     let user = &event.sender;
     info!("Ok test user {}", user);
+    let joins = matrirc.joins(room.room_id());
+    let show = show_membership(joins, &target, user).await;
+    // joins/parts are summarized instead of sent as JOIN/PART
+    let churn = joins == Joins::Notice;
+    let churn_delay = Duration::from_secs(matrirc.config().joins_notice_seconds);
 
     let prev = event.unsigned.prev_content;
 
@@ -65,14 +159,147 @@ pub async fn on_room_member(
         }
         MembershipChange::Joined | MembershipChange::InvitationAccepted => {
             target
-                .member_join(matrirc.irc(), event.sender, event.content.displayname)
+                .member_join(
+                    matrirc.irc(),
+                    event.sender.clone(),
+                    event.content.displayname,
+                    matrirc.mappings().nick_policy(),
+                    show && !churn,
+                    churn_delay,
+                )
                 .await?;
+            if churn {
+                let nick = target.member_nick(&event.sender).await;
+                target.churn(matrirc.irc(), nick, true, churn_delay).await;
+            }
         }
         MembershipChange::Left => {
-            target.member_part(matrirc.irc(), event.sender).await?;
+            if churn {
+                let nick = target.member_nick(user).await;
+                target.churn(matrirc.irc(), nick, false, churn_delay).await;
+            }
+            target
+                .member_part(
+                    matrirc.irc(),
+                    event.sender,
+                    prev.and_then(|p| p.displayname),
+                    show && !churn,
+                    churn_delay,
+                )
+                .await?;
+        }
+        MembershipChange::Kicked
+        | MembershipChange::KickedAndBanned
+        | MembershipChange::Banned
+        | MembershipChange::Unbanned => {
+            moderate(
+                &matrirc,
+                &target,
+                &event.sender,
+                event.state_key.clone(),
+                &mchange,
+                event.content.reason.clone(),
+                prev.as_ref().and_then(|p| p.displayname.clone()),
+            )
+            .await?;
+        }
+        MembershipChange::InvitationRejected => {
+            target
+                .send_text_to_irc(
+                    matrirc.irc(),
+                    IrcMessageType::Notice,
+                    &event.sender.into(),
+                    "<rejected invitation>",
+                )
+                .await?;
+        }
+        MembershipChange::InvitationRevoked => {
+            target
+                .send_text_to_irc(
+                    matrirc.irc(),
+                    IrcMessageType::Notice,
+                    &event.sender.into(),
+                    format!("<revoked invitation for {}>", event.state_key),
+                )
+                .await?;
+        }
+        MembershipChange::Knocked => {
+            let reason = event
+                .content
+                .reason
+                .map(|r| format!(": {}", r))
+                .unwrap_or_default();
+            target
+                .send_text_to_irc(
+                    matrirc.irc(),
+                    IrcMessageType::Notice,
+                    &event.sender.into(),
+                    format!("<knocked{}>", reason),
+                )
+                .await?;
+        }
+        MembershipChange::ProfileChanged {
+            displayname_change,
+            avatar_url_change,
+        } => {
+            let mut changes = vec![];
+            if let Some(change) = displayname_change {
+                let renamed = target
+                    .member_rename(
+                        matrirc.irc(),
+                        &event.sender,
+                        change.new.map(str::to_string),
+                        matrirc.mappings().nick_policy(),
+                    )
+                    .await?;
+                // PART/JOIN is enough in chans, but queries have no member list
+                if !renamed || target.is_query().await {
+                    changes.push(format!(
+                        "is now known as {}",
+                        change.new.unwrap_or(event.sender.as_str())
+                    ));
+                }
+            }
+            if avatar_url_change.is_some() {
+                changes.push("changed avatar".to_string());
+            }
+            if !changes.is_empty() && show {
+                target
+                    .send_text_to_irc(
+                        matrirc.irc(),
+                        IrcMessageType::Notice,
+                        &event.sender.into(),
+                        format!("<{}>", changes.join(", ")),
+                    )
+                    .await?;
+            }
         }
         _ => (),
     }
 
     Ok(())
 }
+
+/// invites by email: the address is only given partially hidden as display name
+pub async fn on_room_third_party_invite(
+    event: OriginalSyncRoomThirdPartyInviteEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    if event.unsigned.transaction_id.is_some() || room.state() != RoomState::Joined {
+        trace!("Ignored third party invite from self or in non-joined room");
+        return Ok(());
+    }
+    let target = matrirc.mappings().room_target(&room).await;
+    target
+        .send_text_to_irc(
+            matrirc.irc(),
+            IrcMessageType::Notice,
+            &event.sender.into(),
+            format!(
+                "<invited someone by email ({})>",
+                event.content.display_name
+            ),
+        )
+        .await
+}
@@ -1,37 +1,78 @@
 use anyhow::{Context, Error, Result};
 use async_trait::async_trait;
+use futures::StreamExt;
 use log::{info, trace, warn};
 use matrix_sdk::{
+    crypto::{AttachmentDecryptor, MediaEncryptionInfo},
+    deserialized_responses::{EncryptionInfo, VerificationLevel, VerificationState},
     event_handler::Ctx,
-    media::{MediaFormat, MediaRequestParameters},
+    reqwest,
     room::Room,
+    ruma::api::{
+        client::{authenticated_media, media},
+        MatrixVersion, OutgoingRequest, SendAccessToken,
+    },
     ruma::events::room::{
-        message::{MessageType, OriginalSyncRoomMessageEvent},
-        MediaSource,
+        message::{
+            sanitize::remove_plain_reply_fallback, MessageType, OriginalSyncRoomMessageEvent,
+            Relation,
+        },
+        EncryptedFile, MediaSource,
     },
-    Client, RoomState,
+    RoomState,
 };
 use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::borrow::Cow;
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
 use crate::args::args;
+use crate::config::{QuotaPolicy, TrustMarkers};
 use crate::ircd::proto::IrcMessageType;
 use crate::matrirc::Matrirc;
+use crate::matrix::hook;
+use crate::matrix::links::{annotate_links, snippet};
+use crate::matrix::paste::paste_code_blocks;
+use crate::matrix::room_mappings::RoomTarget;
+use crate::matrix::sync_reaction::{get_body_from_event_id, message_type_to_str};
 use crate::matrix::time::ToLocal;
 use crate::matrix::verification::handle_verification_request;
+use crate::media_server;
+use crate::store::Store;
+
+/// report download progress in matrirc query for files bigger than this
+const MEDIA_PROGRESS_SIZE: u64 = 50 * 1024 * 1024;
+
+/// max length of replied to message shown in replies
+const REPLY_SNIPPET_LEN: usize = 40;
 
 /// https://url.spec.whatwg.org/#fragment-percent-encode-set
 const FRAGMENT: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
 
 #[async_trait]
 pub trait SourceUri {
-    async fn to_uri(&self, client: &Client, body: &str) -> Result<String>;
+    /// max_size: don't download encrypted media bigger than this.
+    /// size: from message info, for progress if the server does not send it
+    async fn to_uri(
+        &self,
+        matrirc: &Matrirc,
+        body: &str,
+        size: Option<u64>,
+        max_size: Option<u64>,
+    ) -> Result<String>;
 }
 #[async_trait]
 impl SourceUri for MediaSource {
-    async fn to_uri(&self, client: &Client, body: &str) -> Result<String> {
+    async fn to_uri(
+        &self,
+        matrirc: &Matrirc,
+        body: &str,
+        size: Option<u64>,
+        max_size: Option<u64>,
+    ) -> Result<String> {
+        let client = matrirc.matrix();
         match self {
             MediaSource::Plain(uri) => {
                 let homeserver = client.homeserver();
@@ -43,121 +84,614 @@ impl SourceUri for MediaSource {
                     ),
                 ))
             }
-            _ => {
-                let Some(dir_path) = &args().media_dir else {
+            MediaSource::Encrypted(file) => {
+                let Some(dir_path) = matrirc.config().media_dir() else {
                     return Err(Error::msg("<encrypted, no media dir set>"));
                 };
-                let media_request = MediaRequestParameters {
-                    source: self.clone(),
-                    format: MediaFormat::File,
-                };
-                let content = client
-                    .media()
-                    .get_media_content(&media_request, false)
-                    .await
-                    .context("Could not get decrypted data")?;
                 let filename = body.rsplit_once('/').map(|(_, f)| f).unwrap_or(body);
-                let dir = PathBuf::from(dir_path);
-                if !dir.is_dir() {
-                    fs::DirBuilder::new()
-                        .mode(0o700)
-                        .recursive(true)
-                        .create(&dir)
-                        .await?
+                download_media(matrirc, file, dir_path, filename, size, max_size)
+                    .await
+                    .context("Could not get decrypted data")
+            }
+        }
+    }
+}
+
+/// media dir, or token subdirectory of it for media_server
+async fn media_dir_create(dir_path: &str, token: Option<&str>) -> Result<PathBuf> {
+    let mut dir = PathBuf::from(dir_path);
+    if let Some(token) = token {
+        dir.push(token);
+    }
+    if !dir.is_dir() {
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .recursive(true)
+            .create(&dir)
+            .await?
+    }
+    Ok(dir)
+}
+
+fn media_file_url(
+    matrirc: &Matrirc,
+    dir_path: &str,
+    token: Option<&str>,
+    filename: &str,
+) -> String {
+    let url = match (matrirc.config().media_url(), token, args().media_listen) {
+        (Some(url), _, _) => url.clone(),
+        (None, Some(_), Some(addr)) => format!("http://{}", addr),
+        _ => dir_path.to_string(),
+    };
+    let filename = utf8_percent_encode(filename, FRAGMENT);
+    match token {
+        Some(token) => format!("{}/{}/{}", url, token, filename),
+        None => format!("{}/{}", url, filename),
+    }
+}
+
+/// write file to media dir within quota and return its url
+pub async fn save_media(
+    matrirc: &Matrirc,
+    dir_path: &str,
+    filename: &str,
+    content: &[u8],
+) -> Result<String> {
+    let token = media_server::new_token(dir_path);
+    let file = media_dir_create(dir_path, token.as_deref())
+        .await?
+        .join(filename);
+    let size = content.len() as u64;
+    let _reservation = reserve_media_space(matrirc, size).await?;
+    fs::File::create(&file).await?.write_all(content).await?;
+    let path = file.to_string_lossy().to_string();
+    matrirc
+        .store()
+        .blocking(move |store| store.media_add(&path, size))
+        .await?;
+    Ok(media_file_url(
+        matrirc,
+        dir_path,
+        token.as_deref(),
+        filename,
+    ))
+}
+
+/// media download request built by the sdk: authenticated media (matrix
+/// 1.11) or legacy endpoint for older servers.
+/// The sdk's media api keeps whole files in memory, so only the request is
+/// built there and the response is streamed here.
+async fn media_request(matrirc: &Matrirc, file: &EncryptedFile) -> Result<reqwest::Request> {
+    let client = matrirc.matrix();
+    let versions = matrirc.server_versions().await?;
+    let token = client.access_token().context("Not logged in")?;
+    let homeserver = client.homeserver();
+    let access_token = SendAccessToken::Always(&token);
+    let request = if versions.contains(&MatrixVersion::V1_11) {
+        authenticated_media::get_content::v1::Request::from_uri(&file.url)?
+            .try_into_http_request::<Vec<u8>>(homeserver.as_str(), access_token, &versions)?
+    } else {
+        #[allow(deprecated)]
+        media::get_content::v3::Request::from_url(&file.url)?.try_into_http_request::<Vec<u8>>(
+            homeserver.as_str(),
+            access_token,
+            &versions,
+        )?
+    };
+    Ok(reqwest::Request::try_from(request)?)
+}
+
+/// download and decrypt attachment to media dir without keeping it in memory:
+/// encrypted data goes to a temporary file first, which is then decrypted
+/// and renamed once complete. Returns url
+async fn download_media(
+    matrirc: &Matrirc,
+    file: &EncryptedFile,
+    dir_path: &str,
+    filename: &str,
+    info_size: Option<u64>,
+    max_size: Option<u64>,
+) -> Result<String> {
+    let request = media_request(matrirc, file).await?;
+    let response = matrirc
+        .matrix()
+        .http_client()
+        .execute(request)
+        .await?
+        .error_for_status()?;
+    let size = response.content_length();
+    // in case message info did not have size
+    if let Some(size) = size.filter(|size| max_size.is_some_and(|max| *size > max)) {
+        return Err(too_big(size));
+    }
+    // without size, reserve more as the download grows
+    let mut reservation = reserve_media_space(matrirc, size.unwrap_or(0)).await?;
+    let limit = match size {
+        Some(size) => Some(size),
+        None => [max_size, matrirc.config().media_quota]
+            .into_iter()
+            .flatten()
+            .min(),
+    };
+
+    let token = media_server::new_token(dir_path);
+    let dir = media_dir_create(dir_path, token.as_deref()).await?;
+    let encrypted = dir.join(format!(".{}.part", filename));
+    let decrypted = dir.join(format!(".{}.dec", filename));
+    let result = async {
+        let mut out = fs::File::create(&encrypted).await?;
+        let mut stream = response.bytes_stream();
+        let mut done: u64 = 0;
+        let mut reported = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            done += chunk.len() as u64;
+            if limit.is_some_and(|limit| done > limit) {
+                return Err(too_big(done));
+            }
+            reservation.grow(done).await?;
+            out.write_all(&chunk).await?;
+            // chunked responses have no length, the message info might
+            if let Some(size) = size.or(info_size).filter(|s| *s >= MEDIA_PROGRESS_SIZE) {
+                let percent = done * 100 / size;
+                if percent >= reported + 25 && percent < 100 {
+                    reported = percent - percent % 25;
+                    matrirc
+                        .mappings()
+                        .matrirc_query(format!("Downloading {}: {}%", filename, reported))
+                        .await?;
                 }
-                let file = dir.join(filename);
-                fs::File::create(file).await?.write_all(&content).await?;
-                let url = args().media_url.as_ref().unwrap_or(dir_path);
-                Ok(format!(
-                    "{}/{}",
-                    url,
-                    utf8_percent_encode(filename, FRAGMENT)
-                ))
             }
         }
+        out.flush().await?;
+        drop(out);
+
+        let info: MediaEncryptionInfo = file.clone().into();
+        let (encrypted, decrypted) = (encrypted.clone(), decrypted.clone());
+        tokio::task::spawn_blocking(move || -> Result<u64> {
+            let mut input = std::fs::File::open(encrypted)?;
+            let mut reader = AttachmentDecryptor::new(&mut input, info)?;
+            let mut output = std::fs::File::create(decrypted)?;
+            Ok(std::io::copy(&mut reader, &mut output)?)
+        })
+        .await?
+    }
+    .await;
+    let _ = fs::remove_file(&encrypted).await;
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = fs::remove_file(&decrypted).await;
+            return Err(e);
+        }
+    };
+    let path = dir.join(filename);
+    fs::rename(&decrypted, &path).await?;
+    let path = path.to_string_lossy().to_string();
+    matrirc
+        .store()
+        .blocking(move |store| store.media_add(&path, size))
+        .await?;
+    // now counted in store
+    drop(reservation);
+    Ok(media_file_url(
+        matrirc,
+        dir_path,
+        token.as_deref(),
+        filename,
+    ))
+}
+
+fn too_big(size: u64) -> Error {
+    Error::msg(format!(
+        "<{} bigger than media max size or quota, not downloaded>",
+        human_size(size)
+    ))
+}
+
+pub fn human_size(size: u64) -> String {
+    let mut size = size as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} TiB", size)
+}
+
+/// attachment of media messages: source, filename, size and mime type
+pub fn media_info(
+    msgtype: &MessageType,
+) -> Option<(&MediaSource, &str, Option<u64>, Option<&str>)> {
+    Some(match msgtype {
+        MessageType::File(c) => (
+            &c.source,
+            c.filename(),
+            c.info.as_ref().and_then(|i| i.size).map(u64::from),
+            c.info.as_ref().and_then(|i| i.mimetype.as_deref()),
+        ),
+        MessageType::Image(c) => (
+            &c.source,
+            c.filename(),
+            c.info.as_ref().and_then(|i| i.size).map(u64::from),
+            c.info.as_ref().and_then(|i| i.mimetype.as_deref()),
+        ),
+        MessageType::Video(c) => (
+            &c.source,
+            c.filename(),
+            c.info.as_ref().and_then(|i| i.size).map(u64::from),
+            c.info.as_ref().and_then(|i| i.mimetype.as_deref()),
+        ),
+        MessageType::Audio(c) => (
+            &c.source,
+            c.filename(),
+            c.info.as_ref().and_then(|i| i.size).map(u64::from),
+            c.info.as_ref().and_then(|i| i.mimetype.as_deref()),
+        ),
+        _ => return None,
+    })
+}
+
+/// thumbnail source, size and mime type of images bigger than
+/// image_thumbnails
+fn image_thumbnail<'a>(
+    matrirc: &Matrirc,
+    msgtype: &'a MessageType,
+) -> Option<(&'a MediaSource, Option<u64>, Option<&'a str>)> {
+    let MessageType::Image(c) = msgtype else {
+        return None;
+    };
+    let info = c.info.as_ref()?;
+    let max = matrirc.config().image_thumbnails?;
+    if info.size.map(u64::from)? <= max {
+        return None;
+    }
+    let thumbnail = info.thumbnail_info.as_ref();
+    Some((
+        info.thumbnail_source.as_ref()?,
+        thumbnail.and_then(|t| t.size).map(u64::from),
+        thumbnail.and_then(|t| t.mimetype.as_deref()),
+    ))
+}
+
+/// source, filename, size and mime type of the attachment shown
+type ShownMedia<'a> = (&'a MediaSource, Cow<'a, str>, Option<u64>, Option<&'a str>);
+
+/// attachment shown for message: its thumbnail if preferred, else as media_info
+fn shown_media<'a>(matrirc: &Matrirc, msgtype: &'a MessageType) -> Option<ShownMedia<'a>> {
+    let (source, filename, size, mimetype) = media_info(msgtype)?;
+    Some(match image_thumbnail(matrirc, msgtype) {
+        Some((source, size, mimetype)) => (
+            source,
+            Cow::Owned(format!("thumbnail-{}", filename)),
+            size,
+            mimetype,
+        ),
+        None => (source, Cow::Borrowed(filename), size, mimetype),
+    })
+}
+
+/// attachment we should download in background
+fn media_download<'a>(
+    matrirc: &Matrirc,
+    msgtype: &'a MessageType,
+) -> Option<(&'a MediaSource, Cow<'a, str>, Option<u64>)> {
+    let (source, filename, size, _) = shown_media(matrirc, msgtype)?;
+    let too_big = matches!(
+        (size, matrirc.config().media_max_size()),
+        (Some(size), Some(max)) if size > max
+    );
+    (matches!(source, MediaSource::Encrypted(_))
+        && matrirc.config().media_dir().is_some()
+        && !too_big)
+        .then_some((source, filename, size))
+}
+
+/// download attachment without blocking message processing, and send its
+/// link as a followup notice
+fn spawn_media_download(
+    matrirc: Matrirc,
+    target: RoomTarget,
+    sender: String,
+    source: MediaSource,
+    filename: String,
+    size: Option<u64>,
+) {
+    tokio::spawn(async move {
+        let Ok(_permit) = matrirc.media_downloads().acquire().await else {
+            return;
+        };
+        let url = source
+            .to_uri(&matrirc, &filename, size, matrirc.config().media_max_size())
+            .await
+            .unwrap_or_else(|e| format!("{}", e));
+        if let Err(e) = target
+            .send_text_to_irc(
+                matrirc.irc(),
+                IrcMessageType::Notice,
+                &sender,
+                format!("{}: {}", filename, url),
+            )
+            .await
+        {
+            warn!("Could not send media link: {:?}", e);
+        }
+    });
+}
+
+/// link to media, or why we didn't get it
+async fn media_link(matrirc: &Matrirc, msgtype: &MessageType) -> String {
+    if media_download(matrirc, msgtype).is_some() {
+        return "<downloading, link follows>".to_string();
+    }
+    let Some((source, filename, size, mimetype)) = shown_media(matrirc, msgtype) else {
+        return "<no attachment>".to_string();
+    };
+    let max_size = matrirc.config().media_max_size();
+    if let (MediaSource::Encrypted(_), Some(max), Some(size)) = (source, max_size, size) {
+        if size > max {
+            return format!(
+                "<not downloaded: {}{} is over media max size, use fetch command to get it>",
+                human_size(size),
+                mimetype.map(|m| format!(" {}", m)).unwrap_or_default()
+            );
+        }
+    }
+    source
+        .to_uri(matrirc, &filename, size, max_size)
+        .await
+        .unwrap_or_else(|e| format!("{}", e))
+}
+
+/// reserve grows downloads of unknown size by at least that much
+const MEDIA_RESERVE_STEP: u64 = 4 * 1024 * 1024;
+
+/// media quota space held for a file being saved, released when dropped:
+/// once the file is recorded in store, or on failure
+struct MediaReservation {
+    matrirc: Matrirc,
+    size: u64,
+}
+
+impl MediaReservation {
+    /// make sure at least `size` bytes are reserved
+    async fn grow(&mut self, size: u64) -> Result<()> {
+        let Some(quota) = self.matrirc.config().media_quota else {
+            return Ok(());
+        };
+        if size <= self.size {
+            return Ok(());
+        }
+        let target = size
+            .next_multiple_of(MEDIA_RESERVE_STEP)
+            .min(quota)
+            .max(size);
+        let mut more = reserve_media_space(&self.matrirc, target - self.size).await?;
+        self.size += std::mem::take(&mut more.size);
+        Ok(())
+    }
+}
+
+impl Drop for MediaReservation {
+    fn drop(&mut self) {
+        self.matrirc
+            .media_reserved()
+            .fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+/// make sure we can store `size` more bytes of media within user quota,
+/// counting files still being saved
+async fn reserve_media_space(matrirc: &Matrirc, size: u64) -> Result<MediaReservation> {
+    let Some(quota) = matrirc.config().media_quota else {
+        return Ok(MediaReservation {
+            matrirc: matrirc.clone(),
+            size: 0,
+        });
+    };
+    if size > quota {
+        return Err(Error::msg(format!(
+            "<file bigger than media quota ({} bytes), not saved>",
+            quota
+        )));
+    }
+    let policy = matrirc.config().media_quota_policy;
+    let _reserving = matrirc.media_reserving().lock().await;
+    let reserved = matrirc.media_reserved().load(Ordering::Relaxed);
+    matrirc
+        .store()
+        .blocking(move |store| evict_media(store, quota, policy, reserved, size))
+        .await?;
+    matrirc.media_reserved().fetch_add(size, Ordering::Relaxed);
+    Ok(MediaReservation {
+        matrirc: matrirc.clone(),
+        size,
+    })
+}
+
+fn evict_media(
+    store: &Store,
+    quota: u64,
+    policy: QuotaPolicy,
+    reserved: u64,
+    size: u64,
+) -> Result<()> {
+    let mut usage = store.media_usage()? + reserved;
+    while usage + size > quota {
+        if policy == QuotaPolicy::Refuse {
+            return Err(Error::msg(format!(
+                "<media quota exceeded ({}/{} bytes used), not saved>",
+                usage, quota
+            )));
+        }
+        let Some((path, old_size)) = store.media_oldest()? else {
+            break;
+        };
+        info!(
+            "Removing {} ({} bytes) to stay within media quota",
+            path, old_size
+        );
+        if let Err(e) = std::fs::remove_file(&path) {
+            // already gone is fine, anything else we can't free space
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(Error::from(e).context(format!("Could not remove {}", path)));
+            }
+        }
+        // token directories only hold that file
+        if let Some(dir) = std::path::Path::new(&path).parent() {
+            if dir
+                .file_name()
+                .is_some_and(|name| media_server::is_token(&name.to_string_lossy()))
+            {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+        store.media_remove(&path)?;
+        usage = usage.saturating_sub(old_size);
+    }
+    Ok(())
+}
+
+/// compact reference to the message replied to, `[re ab: snippet…] `
+async fn reply_prefix(
+    matrirc: &Matrirc,
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+) -> Option<String> {
+    let Some(Relation::Reply { in_reply_to }) = &event.content.relates_to else {
+        return None;
+    };
+    let id = match matrirc.message_short_id(&in_reply_to.event_id).await {
+        Some(id) => format!(" {}", id),
+        None => String::new(),
+    };
+    Some(
+        match get_body_from_event_id(matrirc, room, &in_reply_to.event_id).await {
+            Ok(message) => format!("[re{}: {}] ", id, snippet(&message, REPLY_SNIPPET_LEN)),
+            Err(e) => {
+                trace!("Could not get replied message: {}", e);
+                format!("[re{}] ", id)
+            }
+        },
+    )
+}
+
+/// flag messages that might not come from who they claim
+fn trust_prefix(prefix: String, untrusted: bool) -> String {
+    match untrusted {
+        true => format!("[!] {}", prefix),
+        false => prefix,
     }
 }
 
+/// decorations go after the CTCP tag so clients still show a /me
+fn emote_line(prefix: &str, body: &str) -> String {
+    format!("\u{001}ACTION {}{}", prefix, body)
+}
+
+/// render message for irc with the text to remember for quotes, None if
+/// dropped by incoming filters.
+/// Filters see the message body before any decoration is added.
 async fn process_message_like_to_str(
     event: &OriginalSyncRoomMessageEvent,
+    room: &Room,
+    name: &str,
     matrirc: &Matrirc,
-) -> (String, IrcMessageType) {
+    untrusted: bool,
+) -> Option<(String, String, IrcMessageType)> {
     let time_prefix = event
         .origin_server_ts
-        .localtime()
+        .message_time(&matrirc.config().timestamps)
         .map(|d| format!("<{}> ", d))
         .unwrap_or_default();
+    let time_prefix = trust_prefix(time_prefix, untrusted);
+    // the reference replaces the quoted fallback in replies
+    let (time_prefix, strip_reply): (String, fn(&str) -> &str) =
+        match reply_prefix(matrirc, room, event).await {
+            Some(reply) => (
+                format!("{}{}", time_prefix, reply),
+                remove_plain_reply_fallback,
+            ),
+            None => (time_prefix, |body| body),
+        };
+    let body = match &event.content.msgtype {
+        // not a message, never filter these
+        MessageType::VerificationRequest(_) => String::new(),
+        msg @ (MessageType::Text(_) | MessageType::Emote(_) | MessageType::Notice(_)) => {
+            matrirc
+                .filters()
+                .incoming(name, strip_reply(msg.body()).to_string())
+                .await?
+        }
+        msg => {
+            matrirc
+                .filters()
+                .incoming(name, msg.body().to_string())
+                .await?
+        }
+    };
 
-    match &event.content.msgtype {
-        MessageType::Text(text_content) => (
-            time_prefix + text_content.body.as_str(),
-            IrcMessageType::Privmsg,
-        ),
-        MessageType::Emote(emote_content) => (
-            format!("\u{001}ACTION {}{}", time_prefix, emote_content.body),
+    let quote = message_type_to_str(&event.content.msgtype, &body);
+
+    let (message, message_type) = match &event.content.msgtype {
+        MessageType::Text(text_content) => {
+            let body = paste_code_blocks(
+                matrirc,
+                &event.event_id,
+                &body,
+                text_content.formatted.as_ref(),
+            )
+            .await;
+            (
+                time_prefix + annotate_links(matrirc, room, &body).await.as_str(),
+                IrcMessageType::Privmsg,
+            )
+        }
+        MessageType::Emote(_) => (
+            emote_line(&time_prefix, &annotate_links(matrirc, room, &body).await),
             IrcMessageType::Privmsg,
         ),
-        MessageType::Notice(notice_content) => (
-            time_prefix + notice_content.body.as_str(),
-            IrcMessageType::Notice,
-        ),
-        MessageType::ServerNotice(snotice_content) => (
-            time_prefix + snotice_content.body.as_str(),
-            IrcMessageType::Notice,
-        ),
-        MessageType::File(file_content) => {
-            let url = file_content
-                .source
-                .to_uri(matrirc.matrix(), file_content.filename())
-                .await
-                .unwrap_or_else(|e| format!("{}", e));
+        MessageType::Notice(notice_content) => {
+            let body = paste_code_blocks(
+                matrirc,
+                &event.event_id,
+                &body,
+                notice_content.formatted.as_ref(),
+            )
+            .await;
             (
-                format!(
-                    "{}Sent a file, {}: {}",
-                    time_prefix, &file_content.body, url
-                ),
+                time_prefix + annotate_links(matrirc, room, &body).await.as_str(),
                 IrcMessageType::Notice,
             )
         }
-        MessageType::Image(image_content) => {
-            let url = image_content
-                .source
-                .to_uri(matrirc.matrix(), image_content.filename())
-                .await
-                .unwrap_or_else(|e| format!("{}", e));
+        MessageType::ServerNotice(_) => (time_prefix + body.as_str(), IrcMessageType::Notice),
+        MessageType::File(_) => {
+            let url = media_link(matrirc, &event.content.msgtype).await;
             (
-                format!(
-                    "{}Sent an image, {}: {}",
-                    time_prefix, &image_content.body, url
-                ),
+                format!("{}Sent a file, {}: {}", time_prefix, body, url),
                 IrcMessageType::Notice,
             )
         }
-        MessageType::Video(video_content) => {
-            let url = video_content
-                .source
-                .to_uri(matrirc.matrix(), video_content.filename())
-                .await
-                .unwrap_or_else(|e| format!("{}", e));
+        MessageType::Image(_) => {
+            let url = media_link(matrirc, &event.content.msgtype).await;
             (
-                format!(
-                    "{}Sent a video, {}: {}",
-                    time_prefix, &video_content.body, url
-                ),
+                format!("{}Sent an image, {}: {}", time_prefix, body, url),
                 IrcMessageType::Notice,
             )
         }
-        MessageType::Audio(audio_content) => {
-            let url = audio_content
-                .source
-                .to_uri(matrirc.matrix(), audio_content.filename())
-                .await
-                .unwrap_or_else(|e| format!("{}", e));
+        MessageType::Video(_) => {
+            let url = media_link(matrirc, &event.content.msgtype).await;
             (
-                format!(
-                    "{}Sent audio, {}: {}",
-                    time_prefix, &audio_content.body, url
-                ),
+                format!("{}Sent a video, {}: {}", time_prefix, body, url),
+                IrcMessageType::Notice,
+            )
+        }
+        MessageType::Audio(_) => {
+            let url = media_link(matrirc, &event.content.msgtype).await;
+            (
+                format!("{}Sent audio, {}: {}", time_prefix, body, url),
                 IrcMessageType::Notice,
             )
         }
@@ -189,16 +723,26 @@ async fn process_message_like_to_str(
                 ""
             };
             (
-                format!(
-                    "{}Sent {}{}: {}",
-                    time_prefix,
-                    msg.msgtype(),
-                    data,
-                    msg.body()
-                ),
+                format!("{}Sent {}{}: {}", time_prefix, msg.msgtype(), data, body),
                 IrcMessageType::Privmsg,
             )
         }
+    };
+    Some((message, quote, message_type))
+}
+
+/// whether message should be flagged as possibly not coming from who it claims
+fn untrusted(policy: TrustMarkers, encryption_info: Option<&EncryptionInfo>) -> bool {
+    // only set for encrypted messages
+    let Some(info) = encryption_info else {
+        return false;
+    };
+    match (policy, &info.verification_state) {
+        (TrustMarkers::Off, _) | (_, VerificationState::Verified) => false,
+        (TrustMarkers::All, VerificationState::Unverified(_)) => true,
+        (TrustMarkers::Devices, VerificationState::Unverified(level)) => {
+            !matches!(level, VerificationLevel::UnverifiedIdentity)
+        }
     }
 }
 
@@ -206,10 +750,39 @@ pub async fn on_room_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
     matrirc: Ctx<Matrirc>,
+    encryption_info: Option<EncryptionInfo>,
 ) -> Result<()> {
+    // remember latest message even if it's ours for read receipts
+    matrirc
+        .last_message_put(room.room_id().to_owned(), event.event_id.clone())
+        .await;
     // ignore events from our own client (transaction set)
     if event.unsigned.transaction_id.is_some() {
         trace!("Ignored message with transaction id (coming from self)");
+        // but keep it so it can be edited/redacted
+        let msgid = matrirc
+            .message_put(room.room_id(), &event.event_id, event.content.body())
+            .await;
+        matrirc.log_message(
+            room.room_id(),
+            event.origin_server_ts,
+            event.sender.as_str(),
+            event.content.body(),
+        );
+        if matrirc.config().delivery_acks {
+            matrirc
+                .mappings()
+                .room_target(&room)
+                .await
+                .send_message_to_irc(
+                    matrirc.irc(),
+                    IrcMessageType::Notice,
+                    &"matrirc".to_string(),
+                    "✓",
+                    msgid,
+                )
+                .await?;
+        }
         return Ok(());
     };
     // ignore non-joined rooms
@@ -218,17 +791,128 @@ pub async fn on_room_message(
         return Ok(());
     };
 
+    if matrirc.is_delivered(&event.event_id).await {
+        trace!("Ignored already delivered message {}", event.event_id);
+        return Ok(());
+    };
+
     trace!("Processing event {:?} to room {}", event, room.room_id());
     let target = matrirc.mappings().room_target(&room).await;
+    target.spoke(&event.sender).await;
 
-    let (message, message_type) = process_message_like_to_str(&event, &matrirc).await;
-    matrirc
-        .message_put(event.event_id.clone(), message.clone())
+    let name = target.irc_name().await;
+    if !matrirc.filters().incoming_slow(&name) {
+        return deliver_message(event, room, target, name, &matrirc, encryption_info).await;
+    }
+    // slow filters (commands) run in the background, in order for each room
+    let queue_name = name.clone();
+    let matrirc = (*matrirc).clone();
+    let task_matrirc = matrirc.clone();
+    let task = async move {
+        let event_id = event.event_id.clone();
+        if let Err(e) =
+            deliver_message(event, room, target, name, &task_matrirc, encryption_info).await
+        {
+            warn!("Could not deliver message {}: {:?}", event_id, e);
+        }
+    };
+    matrirc.filters().queue(&queue_name, task).await;
+    Ok(())
+}
+
+/// filter and forward message to irc
+async fn deliver_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    target: RoomTarget,
+    name: String,
+    matrirc: &Matrirc,
+    encryption_info: Option<EncryptionInfo>,
+) -> Result<()> {
+    let untrusted = untrusted(matrirc.config().trust_markers, encryption_info.as_ref());
+    let Some((message, quote, mut message_type)) =
+        process_message_like_to_str(&event, &room, &name, matrirc, untrusted).await
+    else {
+        trace!("Message {} dropped by filter", event.event_id);
+        return Ok(());
+    };
+    if matrirc.mappings().is_matrirc_query(&target) {
+        // server notices room
+        message_type = IrcMessageType::Notice;
+    }
+    let message = matrirc.config().incoming_text(&message);
+    let highlight = matrirc.config().is_highlight(&message);
+    // queries already stand out, only repeat channel messages
+    if highlight && name.starts_with('#') {
+        matrirc
+            .mappings()
+            .matrirc_query(format!(
+                "Highlight in {} from {}: {}",
+                name, event.sender, message
+            ))
+            .await?;
+    }
+    if Some(event.sender.as_ref()) != matrirc.matrix().user_id() {
+        if highlight {
+            hook::notify(matrirc, "highlight", &name, event.sender.as_str(), &message);
+        } else if room.is_direct().await.unwrap_or(false) {
+            hook::notify(matrirc, "dm", &name, event.sender.as_str(), &message);
+        }
+    }
+    let msgid = matrirc
+        .message_put(room.room_id(), &event.event_id, &quote)
         .await;
+    matrirc.log_message(
+        room.room_id(),
+        event.origin_server_ts,
+        event.sender.as_str(),
+        &message,
+    );
+    let message = match (image_thumbnail(matrirc, &event.content.msgtype), &msgid) {
+        (Some(_), Some(id)) => format!(
+            "{} [full: {}fetch {}]",
+            message,
+            matrirc.config().command_prefix,
+            id
+        ),
+        _ => message,
+    };
 
     target
-        .send_text_to_irc(matrirc.irc(), message_type, &event.sender.into(), message)
+        .send_message_to_irc(
+            matrirc.irc(),
+            message_type,
+            &event.sender.to_string(),
+            message,
+            msgid,
+        )
         .await?;
+    matrirc
+        .mark_delivered(room.room_id(), &event.event_id)
+        .await;
+    if let Some((source, filename, size)) = media_download(matrirc, &event.content.msgtype) {
+        spawn_media_download(
+            matrirc.clone(),
+            target,
+            event.sender.to_string(),
+            source.clone(),
+            filename.to_string(),
+            size,
+        );
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_untrusted_emote() {
+        let line = emote_line(&trust_prefix("<12:00> ".to_string(), true), "waves");
+        assert_eq!(line, "\u{001}ACTION [!] <12:00> waves");
+        let line = emote_line(&trust_prefix(String::new(), false), "waves");
+        assert_eq!(line, "\u{001}ACTION waves");
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::Room,
+    ruma::{
+        events::{
+            room::{
+                avatar::OriginalSyncRoomAvatarEvent, canonical_alias::SyncRoomCanonicalAliasEvent,
+                encryption::OriginalSyncRoomEncryptionEvent, name::SyncRoomNameEvent,
+                power_levels::SyncRoomPowerLevelsEvent, topic::OriginalSyncRoomTopicEvent,
+            },
+            SyncStateEvent,
+        },
+        UserId,
+    },
+    RoomState,
+};
+
+use crate::ircd::proto::IrcMessageType;
+use crate::matrirc::Matrirc;
+
+/// tell channel about a room state change, as a notice from whoever made it
+async fn announce(matrirc: &Matrirc, room: &Room, sender: &UserId, text: String) -> Result<()> {
+    if room.state() != RoomState::Joined {
+        return Ok(());
+    }
+    let target = matrirc.mappings().room_target(room).await;
+    target
+        .send_text_to_irc(
+            matrirc.irc(),
+            IrcMessageType::Notice,
+            &sender.to_string(),
+            format!("<{}>", text),
+        )
+        .await
+}
+
+pub async fn on_room_name(
+    event: SyncRoomNameEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    matrirc.mappings().room_name_invalidate(&room).await;
+    let SyncStateEvent::Original(event) = event else {
+        return Ok(());
+    };
+    if event.unsigned.prev_content.and_then(|p| p.name).as_deref()
+        == Some(event.content.name.as_str())
+    {
+        return Ok(());
+    }
+    let text = match event.content.name.as_str() {
+        "" => "removed room name".to_string(),
+        name => format!("renamed room to {}", name),
+    };
+    announce(&matrirc, &room, &event.sender, text).await
+}
+
+pub async fn on_room_canonical_alias(
+    _event: SyncRoomCanonicalAliasEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) {
+    matrirc.mappings().room_name_invalidate(&room).await;
+}
+
+pub async fn on_room_topic(
+    event: OriginalSyncRoomTopicEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    if event.unsigned.prev_content.and_then(|p| p.topic).as_deref()
+        == Some(event.content.topic.as_str())
+    {
+        return Ok(());
+    }
+    let text = match event.content.topic.as_str() {
+        "" => "removed topic".to_string(),
+        topic => format!("changed topic to: {}", topic),
+    };
+    announce(&matrirc, &room, &event.sender, text).await
+}
+
+pub async fn on_room_avatar(
+    event: OriginalSyncRoomAvatarEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    if event.unsigned.prev_content.map(|p| p.url) == Some(event.content.url.clone()) {
+        return Ok(());
+    }
+    let text = match event.content.url {
+        None => "removed room avatar",
+        Some(_) => "changed room avatar",
+    };
+    announce(&matrirc, &room, &event.sender, text.to_string()).await
+}
+
+pub async fn on_room_encryption(
+    event: OriginalSyncRoomEncryptionEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    // encryption cannot be disabled or changed, only announce the first one
+    if event.unsigned.prev_content.is_some() {
+        return Ok(());
+    }
+    let algorithm = event.content.algorithm.to_string();
+    let algorithm = match algorithm.contains("megolm") {
+        true => "megolm",
+        false => &algorithm,
+    };
+    announce(
+        &matrirc,
+        &room,
+        &event.sender,
+        format!("enabled encryption ({})", algorithm),
+    )
+    .await
+}
+
+/// power levels decide if we can post, i.e. channel +m
+pub async fn on_room_power_levels(
+    _event: SyncRoomPowerLevelsEvent,
+    room: Room,
+    matrirc: Ctx<Matrirc>,
+) -> Result<()> {
+    if room.state() != RoomState::Joined {
+        return Ok(());
+    }
+    let target = matrirc.mappings().room_target(&room).await;
+    target.update_read_only(matrirc.irc(), &room).await
+}
@@ -1,27 +1,50 @@
-use chrono::{offset::Local, DateTime, Duration};
+use chrono::{offset::Local, DateTime, Duration, Utc};
 use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
 use std::time::SystemTime;
 
+use crate::config::{TimestampFormat, TimestampMode};
+
 pub trait ToLocal {
-    fn localtime(&self) -> Option<String>;
+    /// time for command output, nothing if very recent
+    fn localtime(&self, format: &TimestampFormat) -> Option<String>;
+    /// time prefixed to bridged messages, as configured by timestamps.mode
+    fn message_time(&self, format: &TimestampFormat) -> Option<String>;
 }
 impl ToLocal for MilliSecondsSinceUnixEpoch {
-    fn localtime(&self) -> Option<String> {
-        let datetime: DateTime<Local> = self
-            .to_system_time()
-            .unwrap_or(SystemTime::UNIX_EPOCH)
-            .into();
-        // empty if within 10s, just hour/min/sec if < 12h from now, else full date
-        let now = Local::now();
-        if datetime < now - Duration::hours(12) {
-            Some(datetime.format("%Y-%m-%d %H:%M:%S").to_string())
-        } else if datetime < now - Duration::seconds(10) {
-            Some(datetime.format("%H:%M:%S").to_string())
-        } else if datetime < now + Duration::seconds(10) {
-            None
-        } else {
-            // date in the future?!
-            Some(datetime.format("%Y-%m-%d %H:%M:%S").to_string())
+    fn localtime(&self, format: &TimestampFormat) -> Option<String> {
+        format_time(self, format, true)
+    }
+    fn message_time(&self, format: &TimestampFormat) -> Option<String> {
+        match format.mode {
+            TimestampMode::Auto => format_time(self, format, true),
+            TimestampMode::Always => format_time(self, format, false),
+            TimestampMode::Off => None,
         }
     }
 }
+
+/// empty if within recent_seconds (unless always), just hour/min/sec if
+/// less than date_hours old, else full date
+fn format_time(
+    ts: &MilliSecondsSinceUnixEpoch,
+    format: &TimestampFormat,
+    skip_recent: bool,
+) -> Option<String> {
+    let datetime: DateTime<Utc> = ts.to_system_time().unwrap_or(SystemTime::UNIX_EPOCH).into();
+    let age = Utc::now() - datetime;
+    let recent =
+        Duration::try_seconds(format.recent_seconds as i64).unwrap_or(Duration::max_value());
+    let old = Duration::try_hours(format.date_hours as i64).unwrap_or(Duration::max_value());
+    // date in the future?! show it in full
+    let pattern = if age > old || age < -recent {
+        &format.date
+    } else if age >= recent || !skip_recent {
+        &format.time
+    } else {
+        return None;
+    };
+    Some(match format.offset() {
+        Some(offset) => datetime.with_timezone(&offset).format(pattern).to_string(),
+        None => datetime.with_timezone(&Local).format(pattern).to_string(),
+    })
+}
@@ -0,0 +1,221 @@
+//! Best effort transliteration of names to ascii before they get sanitized
+//! into irc nicks or chan names: diacritics and compatibility forms
+//! (fullwidth letters, ligatures...) through unicode NFKD decomposition,
+//! plus tables for the few latin letters that do not decompose, greek and
+//! cyrillic. Anything else (e.g. CJK) is dropped, callers need a fallback.
+
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+
+/// latin letters that do not decompose to ascii and combining marks
+fn latin(c: char) -> Option<&'static str> {
+    Some(match c {
+        'æ' => "ae",
+        'Æ' => "AE",
+        'đ' | 'ð' => "d",
+        'Đ' | 'Ð' => "D",
+        'ı' => "i",
+        'ł' => "l",
+        'Ł' => "L",
+        'ø' => "o",
+        'Ø' => "O",
+        'œ' => "oe",
+        'Œ' => "OE",
+        'ß' => "ss",
+        'þ' => "th",
+        'Þ' => "Th",
+        _ => return None,
+    })
+}
+
+fn greek(c: char) -> Option<&'static str> {
+    Some(match c {
+        'α' => "a",
+        'β' => "b",
+        'γ' => "g",
+        'δ' => "d",
+        'ε' => "e",
+        'ζ' => "z",
+        'η' => "i",
+        'θ' => "th",
+        'ι' => "i",
+        'κ' => "k",
+        'λ' => "l",
+        'μ' => "m",
+        'ν' => "n",
+        'ξ' => "x",
+        'ο' => "o",
+        'π' => "p",
+        'ρ' => "r",
+        'σ' | 'ς' => "s",
+        'τ' => "t",
+        'υ' => "y",
+        'φ' => "f",
+        'χ' => "ch",
+        'ψ' => "ps",
+        'ω' => "o",
+        'Α' => "A",
+        'Β' => "B",
+        'Γ' => "G",
+        'Δ' => "D",
+        'Ε' => "E",
+        'Ζ' => "Z",
+        'Η' => "I",
+        'Θ' => "Th",
+        'Ι' => "I",
+        'Κ' => "K",
+        'Λ' => "L",
+        'Μ' => "M",
+        'Ν' => "N",
+        'Ξ' => "X",
+        'Ο' => "O",
+        'Π' => "P",
+        'Ρ' => "R",
+        'Σ' => "S",
+        'Τ' => "T",
+        'Υ' => "Y",
+        'Φ' => "F",
+        'Χ' => "Ch",
+        'Ψ' => "Ps",
+        'Ω' => "O",
+        _ => return None,
+    })
+}
+
+fn cyrillic(c: char) -> Option<&'static str> {
+    Some(match c {
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' => "g",
+        'ґ' => "g",
+        'д' => "d",
+        'е' => "e",
+        'ё' => "yo",
+        'є' => "ye",
+        'ж' => "zh",
+        'з' => "z",
+        'и' => "i",
+        'і' => "i",
+        'ї' => "yi",
+        'й' => "y",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ў' => "u",
+        'ф' => "f",
+        'х' => "kh",
+        'ц' => "ts",
+        'ч' => "ch",
+        'ш' => "sh",
+        'щ' => "shch",
+        'ъ' | 'ь' => "",
+        'ы' => "y",
+        'э' => "e",
+        'ю' => "yu",
+        'я' => "ya",
+        'А' => "A",
+        'Б' => "B",
+        'В' => "V",
+        'Г' => "G",
+        'Ґ' => "G",
+        'Д' => "D",
+        'Е' => "E",
+        'Ё' => "Yo",
+        'Є' => "Ye",
+        'Ж' => "Zh",
+        'З' => "Z",
+        'И' => "I",
+        'І' => "I",
+        'Ї' => "Yi",
+        'Й' => "Y",
+        'К' => "K",
+        'Л' => "L",
+        'М' => "M",
+        'Н' => "N",
+        'О' => "O",
+        'П' => "P",
+        'Р' => "R",
+        'С' => "S",
+        'Т' => "T",
+        'У' => "U",
+        'Ў' => "U",
+        'Ф' => "F",
+        'Х' => "Kh",
+        'Ц' => "Ts",
+        'Ч' => "Ch",
+        'Ш' => "Sh",
+        'Щ' => "Shch",
+        'Ъ' | 'Ь' => "",
+        'Ы' => "Y",
+        'Э' => "E",
+        'Ю' => "Yu",
+        'Я' => "Ya",
+        _ => return None,
+    })
+}
+
+fn table(c: char) -> Option<&'static str> {
+    latin(c).or_else(|| greek(c)).or_else(|| cyrillic(c))
+}
+
+/// transliterate what we can to ascii, dropping other characters
+pub fn to_ascii(text: &str) -> String {
+    let mut ascii = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii() {
+            ascii.push(c);
+            continue;
+        }
+        // tables first so e.g. cyrillic ё gives yo rather than e
+        if let Some(s) = table(c) {
+            ascii.push_str(s);
+            continue;
+        }
+        // base letters of decomposed forms, combining marks are dropped
+        for c in std::iter::once(c).nfkd() {
+            if c.is_ascii() {
+                ascii.push(c);
+            } else if let Some(s) = table(c) {
+                ascii.push_str(s);
+            }
+        }
+    }
+    ascii
+}
+
+/// short stable hash, for names that had nothing left after transliteration
+pub fn short_hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_to_ascii() {
+        assert_eq!(to_ascii("Dominique"), "Dominique");
+        assert_eq!(to_ascii("Crème brûlée"), "Creme brulee");
+        assert_eq!(to_ascii("Привет мир"), "Privet mir");
+        assert_eq!(to_ascii("Αθήνα"), "Athina");
+        assert_eq!(to_ascii("Łódź Ærø"), "Lodz AEro");
+        assert_eq!(to_ascii("Ｒｕｓｔ ﬁsh Ŵŷ"), "Rust fish Wy");
+        assert_eq!(to_ascii("東京"), "");
+        assert_eq!(short_hash("!room:example.org").len(), 8);
+        assert_eq!(
+            short_hash("!room:example.org"),
+            short_hash("!room:example.org")
+        );
+    }
+}
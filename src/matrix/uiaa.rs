@@ -0,0 +1,161 @@
+use anyhow::{Context, Error, Result};
+use futures::future::BoxFuture;
+use log::debug;
+use matrix_sdk::ruma::api::client::uiaa::{
+    AuthData, AuthType, Dummy, Password, RegistrationToken, UiaaInfo, UserIdentifier,
+};
+use std::sync::Arc;
+
+use crate::matrirc::Matrirc;
+
+/// operation requiring user-interactive auth, called again with the auth
+/// data for each stage until the homeserver accepts it
+pub type Operation =
+    Arc<dyn Fn(Matrirc, Option<AuthData>) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// operation waiting for the user to reply with `auth`
+pub struct Pending {
+    what: String,
+    operation: Operation,
+    stage: AuthType,
+    session: Option<String>,
+}
+
+/// run operation, prompting in the matrirc query for any stage we cannot
+/// complete ourselves
+pub async fn run(matrirc: &Matrirc, what: &str, operation: Operation) -> Result<()> {
+    step(matrirc, what.to_string(), operation, None).await
+}
+
+/// resume the pending operation with the secret for the stage it is waiting on
+pub async fn resume(matrirc: &Matrirc, secret: &str) -> Result<()> {
+    let pending = matrirc
+        .take_uiaa()
+        .await
+        .context("No operation waiting for authentication")?;
+    if secret == "cancel" {
+        return matrirc
+            .mappings()
+            .matrirc_query(format!("{}: cancelled", pending.what))
+            .await;
+    }
+    let auth = match pending.stage {
+        AuthType::Password => {
+            let user_id = matrirc.matrix().user_id().context("client has no user?")?;
+            let mut password = Password::new(
+                UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+                secret.to_string(),
+            );
+            password.session = pending.session;
+            AuthData::Password(password)
+        }
+        _ => {
+            let mut token = RegistrationToken::new(secret.to_string());
+            token.session = pending.session;
+            AuthData::RegistrationToken(token)
+        }
+    };
+    step(matrirc, pending.what, pending.operation, Some(auth)).await
+}
+
+async fn step(
+    matrirc: &Matrirc,
+    what: String,
+    operation: Operation,
+    mut auth: Option<AuthData>,
+) -> Result<()> {
+    loop {
+        let dummy_sent = matches!(auth, Some(AuthData::Dummy(_)));
+        let err = match operation(matrirc.clone(), auth).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let Some(uiaa) = err
+            .downcast_ref::<matrix_sdk::Error>()
+            .and_then(|e| e.as_uiaa_response())
+        else {
+            return Err(err);
+        };
+        if let Some(e) = &uiaa.auth_error {
+            matrirc
+                .mappings()
+                .matrirc_query(format!("{}: authentication failed: {}", what, e.message))
+                .await?;
+        }
+        let stage = next_stage(uiaa, SUPPORTED_STAGES).with_context(|| {
+            debug!("Unsupported auth flows for {}: {:?}", what, uiaa.flows);
+            "homeserver requires authentication steps we cannot handle"
+        })?;
+        let session = uiaa.session.clone();
+        match stage {
+            AuthType::Dummy if dummy_sent => {
+                return Err(Error::msg("homeserver did not accept authentication"))
+            }
+            AuthType::Dummy => {
+                let mut dummy = Dummy::new();
+                dummy.session = session;
+                auth = Some(AuthData::Dummy(dummy));
+            }
+            _ => {
+                let prompt = match stage {
+                    AuthType::Password => "your matrix password",
+                    _ => "a registration token",
+                };
+                matrirc
+                    .mappings()
+                    .matrirc_query(format!(
+                        "{} requires {}, reply with: auth <secret> (or auth cancel)",
+                        what, prompt
+                    ))
+                    .await?;
+                matrirc
+                    .set_uiaa(Pending {
+                        what,
+                        operation,
+                        stage,
+                        session,
+                    })
+                    .await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// stages we know how to complete once logged in
+const SUPPORTED_STAGES: &[AuthType] = &[
+    AuthType::Dummy,
+    AuthType::Password,
+    AuthType::RegistrationToken,
+];
+
+/// next stage of the first flow we can complete with supported stages
+pub fn next_stage(uiaa: &UiaaInfo, supported: &[AuthType]) -> Option<AuthType> {
+    uiaa.flows
+        .iter()
+        .find(|f| f.stages.iter().all(|s| supported.contains(s)))
+        .and_then(|f| f.stages.iter().find(|s| !uiaa.completed.contains(s)))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_next_stage() -> Result<()> {
+        let uiaa: UiaaInfo = serde_json::from_str(
+            r#"{
+                "flows": [
+                    {"stages": ["m.login.recaptcha"]},
+                    {"stages": ["m.login.registration_token", "m.login.dummy"]}
+                ],
+                "completed": ["m.login.registration_token"],
+                "params": {}
+            }"#,
+        )?;
+        assert_eq!(next_stage(&uiaa, SUPPORTED_STAGES), Some(AuthType::Dummy));
+        assert_eq!(next_stage(&uiaa, &[AuthType::Dummy]), None);
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use futures::StreamExt;
-use log::warn;
 use matrix_sdk::{
     encryption::verification::{
         format_emojis, SasState, SasVerification, Verification, VerificationRequest,
@@ -248,7 +247,9 @@ pub async fn on_device_key_verification_request(
     if let Err(e) =
         handle_verification_request(&matrirc, &event.sender, &event.content.transaction_id).await
     {
-        warn!("Verif failed: {}", e);
+        matrirc
+            .report_error(format!("Verification with {} failed: {}", event.sender, e))
+            .await;
     }
 }
 
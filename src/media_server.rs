@@ -0,0 +1,115 @@
+//! optional http server for --media-dir, so media links work without a
+//! separate web server. Each file is saved in its own random token
+//! directory so links cannot be guessed from file names.
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs;
+use tokio::net::TcpListener;
+use tokio_util::io::ReaderStream;
+
+use crate::args::args;
+
+/// hex characters in token directory names
+const TOKEN_LEN: usize = 32;
+
+/// token directory for a new file in dir_path, if it is served by us
+pub fn new_token(dir_path: &str) -> Option<String> {
+    args().media_listen?;
+    if args().media_dir.as_deref() != Some(dir_path) {
+        return None;
+    }
+    let mut bytes = [0u8; TOKEN_LEN / 2];
+    OsRng.fill_bytes(&mut bytes);
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub fn is_token(name: &str) -> bool {
+    name.len() == TOKEN_LEN && name.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// types browsers can show inline, anything else is downloaded
+fn content_type(filename: &str) -> &'static str {
+    let ext = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "pdf" => "application/pdf",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn serve_file(token: &str, filename: &str) -> Result<Response, StatusCode> {
+    let dir = args().media_dir.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    // also skips partial downloads (.name.part)
+    if !is_token(token) || filename.starts_with('.') || filename.contains('/') {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let path = PathBuf::from(dir).join(token).join(filename);
+    let metadata = fs::metadata(&path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if let Some(hours) = args().media_expiry_hours {
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.elapsed().ok())
+            .unwrap_or_default();
+        if age > Duration::from_secs(hours * 3600) {
+            return Err(StatusCode::GONE);
+        }
+    }
+    let file = fs::File::open(&path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type(filename))
+        .header(header::CONTENT_LENGTH, metadata.len())
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn serve(Path((token, filename)): Path<(String, String)>) -> Response {
+    serve_file(&token, &filename)
+        .await
+        .unwrap_or_else(|status| status.into_response())
+}
+
+/// serve media dir on --media-listen, if set
+pub async fn listen() -> Result<()> {
+    let Some(addr) = args().media_listen else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(addr).await.context("bind media port")?;
+    info!("serving media on {}", addr);
+    let app = Router::new().route("/:token/:filename", get(serve));
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("Media server stopped: {:?}", e);
+        }
+    });
+    Ok(())
+}
@@ -1,20 +1,25 @@
 use anyhow::{Context, Error, Result};
 use argon2::{
     password_hash::rand_core::{OsRng, RngCore},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
 use base64_serde::base64_serde_type;
 use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305};
-use log::info;
+use log::{info, warn};
 use matrix_sdk::AuthSession;
+use matrix_sdk_store_encryption::StoreCipher;
+use rusqlite::Connection;
 use std::fs;
 use std::io::Write;
 use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 base64_serde_type!(Base64, base64::engine::general_purpose::STANDARD);
 
 use crate::args::args;
+use crate::config::glob_match;
 
 /// data we want to keep around
 #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -40,10 +45,55 @@ pub struct SerializedMatrixSession {
     pub device_id: String,
 }
 
+/// argon2 parameters, stored in blob so they can be raised later.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+/// blobs created before parameters were stored used argon2 defaults
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    fn from_args() -> Self {
+        KdfParams {
+            m_cost: args().argon2_memory,
+            t_cost: args().argon2_iterations,
+            ..KdfParams::default()
+        }
+    }
+
+    fn derive_key(&self, pass: &str, salt: &[u8]) -> Result<[u8; 32]> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|e| Error::msg(format!("Invalid argon2 parameters: {}", e)))?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(pass.as_bytes(), salt, &mut key)
+            .context("Could not hash password")?;
+        Ok(key)
+    }
+
+    fn weaker_than(&self, other: &KdfParams) -> bool {
+        self.m_cost < other.m_cost || self.t_cost < other.t_cost
+    }
+}
+
 /// data required for decryption
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Blob {
     version: String,
+    #[serde(default)]
+    kdf: KdfParams,
     #[serde(with = "Base64")]
     ciphertext: Vec<u8>,
     #[serde(with = "Base64")]
@@ -52,13 +102,44 @@ struct Blob {
     nonce: Vec<u8>,
 }
 
-/// try to decrypt session and return it
-fn check_pass(session_file: PathBuf, pass: &str) -> Result<Session> {
+/// try to decrypt session and return it with the key it is encrypted with.
+/// If the blob was encrypted with weaker parameters than configured, upgrade it.
+fn check_pass(session_file: &Path, pass: &str) -> Result<(Session, BlobKey)> {
     let blob_text = fs::read(session_file).context("Could not read user session file")?;
-    decrypt_blob(pass, &blob_text)
+    let (session, key) = decrypt_blob(pass, &blob_text)?;
+    let wanted_kdf = KdfParams::from_args();
+    if key.kdf.weaker_than(&wanted_kdf) {
+        info!("Upgrading session encryption parameters {:?}", wanted_kdf);
+        let upgraded = BlobKey::new(pass, &wanted_kdf).and_then(|key| {
+            replace_session_file(session_file, &key.encrypt(&session)?)?;
+            Ok(key)
+        });
+        match upgraded {
+            Ok(key) => return Ok((session, key)),
+            // old session still works, just try again next time
+            Err(e) => warn!("Could not upgrade session encryption: {:?}", e),
+        }
+    }
+    Ok((session, key))
 }
 
-fn decrypt_blob(pass: &str, blob_text: &[u8]) -> Result<Session> {
+/// atomically replace session file
+fn replace_session_file(session_file: &Path, blob_text: &[u8]) -> Result<()> {
+    let tmp_file = session_file.with_extension("new");
+    let _ = fs::remove_file(&tmp_file);
+    let mut file = fs::OpenOptions::new()
+        .mode(0o400)
+        .write(true)
+        .create_new(true)
+        .open(&tmp_file)
+        .context("creating new session file failed")?;
+    file.write_all(blob_text)
+        .context("Writing to new session file failed")?;
+    file.sync_all()?;
+    fs::rename(tmp_file, session_file).context("Could not replace session file")
+}
+
+fn decrypt_blob(pass: &str, blob_text: &[u8]) -> Result<(Session, BlobKey)> {
     let blob = serde_json::from_slice::<Blob>(blob_text)
         .context("Could not deserialize session file content.")?;
     if blob.version != "argon2+chacha20poly1305" {
@@ -66,10 +147,7 @@ fn decrypt_blob(pass: &str, blob_text: &[u8]) -> Result<Session> {
             "This version only supports argon2+chacha20poly1305",
         ));
     }
-    let mut key = [0u8; 32];
-    Argon2::default()
-        .hash_password_into(pass.as_bytes(), &blob.salt, &mut key)
-        .context("Could not hash password")?;
+    let key = blob.kdf.derive_key(pass, &blob.salt)?;
     let cipher = XChaCha20Poly1305::new(&key.into());
     let plaintext = cipher
         .decrypt(blob.nonce.as_slice().into(), &*blob.ciphertext)
@@ -78,54 +156,144 @@ fn decrypt_blob(pass: &str, blob_text: &[u8]) -> Result<Session> {
     let session = serde_json::from_slice::<Session>(&plaintext)
         .context("Could not deserialize stored session")?;
     info!("Decrypted {}", session.homeserver);
-    Ok(session)
-}
-
-fn encrypt_blob(pass: &str, homeserver: &str, auth_session: AuthSession) -> Result<Vec<u8>> {
-    let session_meta = auth_session.meta();
-    let session = Session {
-        homeserver: homeserver.into(),
-        matrix_session: SerializedMatrixSession {
-            access_token: auth_session.access_token().into(),
-            refresh_token: auth_session.get_refresh_token().map(str::to_string),
-            user_id: session_meta.user_id.as_str().into(),
-            device_id: session_meta.device_id.as_str().into(),
+    Ok((
+        session,
+        BlobKey {
+            kdf: blob.kdf,
+            salt: blob.salt,
+            key,
         },
-    };
-    let mut key = [0u8; 32];
-    let mut salt = vec![0u8; 32];
-    let mut nonce = vec![0u8; 24];
-    OsRng.fill_bytes(&mut salt);
-    OsRng.fill_bytes(&mut nonce);
-    Argon2::default()
-        .hash_password_into(pass.as_bytes(), &salt, &mut key)
-        .context("Could not hash password")?;
+    ))
+}
 
-    let cipher = XChaCha20Poly1305::new(&key.into());
-    let ciphertext = cipher
-        .encrypt(
-            nonce.as_slice().into(),
-            &*serde_json::to_vec(&session).context("could not serialize session")?,
-        )
-        .map_err(|_| Error::msg("Could not encrypt blob"))?;
-    let blob = Blob {
-        version: "argon2+chacha20poly1305".to_string(),
-        ciphertext,
-        salt,
-        nonce,
-    };
-    serde_json::to_vec(&blob).context("could not serialize blob")
+impl Session {
+    fn new(homeserver: &str, auth_session: AuthSession) -> Session {
+        let session_meta = auth_session.meta();
+        Session {
+            homeserver: homeserver.into(),
+            matrix_session: SerializedMatrixSession {
+                access_token: auth_session.access_token().into(),
+                refresh_token: auth_session.get_refresh_token().map(str::to_string),
+                user_id: session_meta.user_id.as_str().into(),
+                device_id: session_meta.device_id.as_str().into(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn encrypt_blob(
+    pass: &str,
+    homeserver: &str,
+    auth_session: AuthSession,
+    kdf: &KdfParams,
+) -> Result<Vec<u8>> {
+    encrypt_session(pass, &Session::new(homeserver, auth_session), kdf)
+}
+
+fn encrypt_session(pass: &str, session: &Session, kdf: &KdfParams) -> Result<Vec<u8>> {
+    BlobKey::new(pass, kdf)?.encrypt(session)
+}
+
+/// key derived from user password with the salt and parameters it needs
+/// to be stored with
+struct BlobKey {
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    key: [u8; 32],
 }
 
-/// encrypt session and store it
+impl BlobKey {
+    /// derive key with a new salt, slow on purpose
+    fn new(pass: &str, kdf: &KdfParams) -> Result<BlobKey> {
+        let mut salt = vec![0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+        let key = kdf.derive_key(pass, &salt)?;
+        Ok(BlobKey {
+            kdf: *kdf,
+            salt,
+            key,
+        })
+    }
+
+    fn encrypt(&self, session: &Session) -> Result<Vec<u8>> {
+        let mut nonce = vec![0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+        let ciphertext = cipher
+            .encrypt(
+                nonce.as_slice().into(),
+                &*serde_json::to_vec(session).context("could not serialize session")?,
+            )
+            .map_err(|_| Error::msg("Could not encrypt blob"))?;
+        let blob = Blob {
+            version: "argon2+chacha20poly1305".to_string(),
+            kdf: self.kdf,
+            ciphertext,
+            salt: self.salt.clone(),
+            nonce,
+        };
+        serde_json::to_vec(&blob).context("could not serialize blob")
+    }
+}
+
+/// key the session file is encrypted with, kept with the client session to
+/// save refreshed tokens without keeping the password itself. Clones share
+/// the key, which is dropped with the session.
+#[derive(Clone)]
+pub struct SessionKey {
+    inner: Arc<Mutex<SessionKeyInner>>,
+}
+
+struct SessionKeyInner {
+    session_file: PathBuf,
+    homeserver: String,
+    key: BlobKey,
+}
+
+impl SessionKey {
+    fn new(nick: &str, homeserver: &str, key: BlobKey) -> SessionKey {
+        SessionKey {
+            inner: Arc::new(Mutex::new(SessionKeyInner {
+                session_file: Path::new(&args().state_dir).join(nick).join("session"),
+                homeserver: homeserver.to_string(),
+                key,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SessionKeyInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// replace stored session, e.g. after tokens got refreshed
+    pub fn save(&self, auth_session: AuthSession) -> Result<()> {
+        let inner = self.lock();
+        let session = Session::new(&inner.homeserver, auth_session);
+        replace_session_file(&inner.session_file, &inner.key.encrypt(&session)?)?;
+        info!(
+            "Saved refreshed session to {}",
+            inner.session_file.display()
+        );
+        Ok(())
+    }
+}
+
+/// encrypt session and store it, returning the key for later saves.
+/// Key derivation is slow on purpose, call from a blocking task.
 pub fn create_user(
     nick: &str,
     pass: &str,
     homeserver: &str,
     auth_session: AuthSession,
-) -> Result<()> {
-    let blob_text = encrypt_blob(pass, homeserver, auth_session)?;
+) -> Result<SessionKey> {
+    let key = BlobKey::new(pass, &KdfParams::from_args())?;
+    write_new_session(nick, &key.encrypt(&Session::new(homeserver, auth_session))?)?;
+    Ok(SessionKey::new(nick, homeserver, key))
+}
 
+fn write_new_session(nick: &str, blob_text: &[u8]) -> Result<()> {
     let user_dir = Path::new(&args().state_dir).join(nick);
     if !user_dir.is_dir() {
         fs::DirBuilder::new()
@@ -140,24 +308,223 @@ pub fn create_user(
         .create_new(true)
         .open(user_dir.join("session"))
         .context("creating user session file failed")?;
-    file.write_all(&blob_text)
+    file.write_all(blob_text)
         .context("Writing to user session file failed")?;
     Ok(())
 }
 
+/// forget user after logout: remove session, matrix sdk stores and message
+/// cache. config.toml and logs are left alone.
+pub fn delete_user(nick: &str) -> Result<()> {
+    let user_dir = Path::new(&args().state_dir).join(nick);
+    fs::remove_file(user_dir.join("session")).context("Could not remove session file")?;
+    let store_dir = user_dir.join("sqlite_store");
+    if store_dir.is_dir() {
+        fs::remove_dir_all(store_dir).context("Could not remove matrix store")?;
+    }
+    let _ = fs::remove_file(user_dir.join("matrirc.sqlite3"));
+    info!("Deleted session for {}", nick);
+    Ok(())
+}
+
+/// decrypted session and crypto store, to move a user to another host
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SessionExport {
+    session: Session,
+    /// still encrypted with user password
+    #[serde(with = "Base64")]
+    crypto_store: Vec<u8>,
+}
+
+const CRYPTO_STORE: &str = "matrix-sdk-crypto.sqlite3";
+
+pub fn export_session(nick: &str, pass: &str) -> Result<SessionExport> {
+    let user_dir = Path::new(&args().state_dir).join(nick);
+    let (session, _) = check_pass(&user_dir.join("session"), pass)?;
+    let tmp_file = user_dir.join("crypto-export.sqlite3");
+    let _ = fs::remove_file(&tmp_file);
+    // VACUUM INTO gives a consistent copy even if matrirc is running
+    let conn = Connection::open(user_dir.join("sqlite_store").join(CRYPTO_STORE))
+        .context("Could not open crypto store")?;
+    conn.busy_timeout(Duration::from_secs(10))?;
+    conn.execute(
+        "VACUUM INTO ?1",
+        [tmp_file.to_str().context("non-utf8 state dir")?],
+    )
+    .context("Could not copy crypto store")?;
+    let crypto_store = fs::read(&tmp_file);
+    let _ = fs::remove_file(&tmp_file);
+    Ok(SessionExport {
+        session,
+        crypto_store: crypto_store?,
+    })
+}
+
+/// create user from exported session. The password must be the one used
+/// when exporting, as the crypto store is still encrypted with it.
+pub fn import_session(nick: &str, pass: &str, export: SessionExport) -> Result<()> {
+    let user_dir = Path::new(&args().state_dir).join(nick);
+    if user_dir.join("session").exists() {
+        return Err(Error::msg(format!("user {} already exists", nick)));
+    }
+    let store_dir = user_dir.join("sqlite_store");
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .recursive(true)
+        .create(&store_dir)
+        .context("mkdir of store dir failed")?;
+    let store_path = store_dir.join(CRYPTO_STORE);
+    fs::OpenOptions::new()
+        .mode(0o600)
+        .write(true)
+        .create_new(true)
+        .open(&store_path)
+        .context("creating crypto store failed (leftover from previous user?)")?
+        .write_all(&export.crypto_store)?;
+    let check = Connection::open(&store_path)
+        .map_err(Error::from)
+        .and_then(|conn| store_cipher_reencrypt(&conn, pass, pass));
+    if let Err(e) = check {
+        let _ = fs::remove_file(&store_path);
+        return Err(e.context("Could not decrypt crypto store: wrong password?"));
+    }
+    let blob_text = encrypt_session(pass, &export.session, &KdfParams::from_args())?;
+    write_new_session(nick, &blob_text)?;
+    info!("Imported session for {}", nick);
+    Ok(())
+}
+
+/// matrix-sdk sqlite stores keep their encryption key in their kv table,
+/// encrypted with the passphrase we gave (user password).
+/// Returns old and new encrypted key.
+fn store_cipher_reencrypt(
+    conn: &Connection,
+    old_pass: &str,
+    new_pass: &str,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let encrypted: Vec<u8> =
+        conn.query_row("SELECT value FROM kv WHERE key = 'cipher'", [], |row| {
+            row.get(0)
+        })?;
+    let cipher = StoreCipher::import(old_pass, &encrypted)
+        .map_err(|e| Error::msg(format!("Could not decrypt store key: {}", e)))?;
+    let reencrypted = cipher
+        .export(new_pass)
+        .map_err(|e| Error::msg(format!("Could not encrypt store key: {}", e)))?;
+    Ok((encrypted, reencrypted))
+}
+
+fn store_cipher_set(conn: &Connection, cipher: &[u8]) -> Result<()> {
+    conn.execute("UPDATE kv SET value = ?1 WHERE key = 'cipher'", [cipher])?;
+    Ok(())
+}
+
+/// change user password: re-encrypt session file and matrix stores keys.
+/// Everything is decrypted first so a bad old password doesn't change anything,
+/// and the session file is only replaced once all stores have been updated.
+/// session_key is updated to keep saving refreshed tokens.
+pub fn change_password(
+    nick: &str,
+    old_pass: &str,
+    new_pass: &str,
+    session_key: &SessionKey,
+) -> Result<()> {
+    let user_dir = Path::new(&args().state_dir).join(nick);
+    let session_file = user_dir.join("session");
+    let (session, _) = check_pass(&session_file, old_pass)?;
+    let new_key = BlobKey::new(new_pass, &KdfParams::from_args())?;
+    let blob_text = new_key.encrypt(&session)?;
+
+    let mut stores = vec![];
+    let mut paths = vec![];
+    for entry in fs::read_dir(user_dir.join("sqlite_store")).context("Could not list stores")? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "sqlite3") {
+            continue;
+        }
+        paths.push(path);
+    }
+    // our own store key, see store::cipher
+    let message_store = user_dir.join("matrirc.sqlite3");
+    if message_store.is_file() {
+        paths.push(message_store);
+    }
+    for path in paths {
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        let (old_cipher, new_cipher) = store_cipher_reencrypt(&conn, old_pass, new_pass)
+            .with_context(|| format!("Could not re-encrypt {}", path.display()))?;
+        stores.push((conn, old_cipher, new_cipher));
+    }
+
+    let restore = |stores: &[(Connection, Vec<u8>, Vec<u8>)]| {
+        // put back what we changed so old password keeps working
+        for (conn, old_cipher, _) in stores {
+            if let Err(e) = store_cipher_set(conn, old_cipher) {
+                warn!("Could not restore store key: {}", e);
+            }
+        }
+    };
+    for (i, (conn, _, new_cipher)) in stores.iter().enumerate() {
+        if let Err(e) = store_cipher_set(conn, new_cipher) {
+            restore(&stores[..i]);
+            return Err(e.context("Could not update store key"));
+        }
+    }
+    if let Err(e) = replace_session_file(&session_file, &blob_text) {
+        restore(&stores);
+        return Err(e);
+    }
+    session_key.lock().key = new_key;
+    info!("Changed password for {}", nick);
+    Ok(())
+}
+
 /// Initial "log in": if user exists validate its password,
-/// otherwise just let it through iff we allow new users
-pub fn login(nick: &str, pass: &str) -> Result<Option<Session>> {
+/// otherwise just let it through iff we allow new users.
+/// Key derivation is slow on purpose, call from a blocking task.
+pub fn login(nick: &str, pass: &str) -> Result<Option<(Session, SessionKey)>> {
     let session_file = Path::new(&args().state_dir).join(nick).join("session");
     if session_file.is_file() {
-        Ok(Some(check_pass(session_file, pass)?))
+        let (session, key) = check_pass(&session_file, pass)?;
+        let session_key = SessionKey::new(nick, &session.homeserver, key);
+        Ok(Some((session, session_key)))
     } else if args().allow_register {
+        register_pass(nick, pass)?;
         Ok(None)
     } else {
         Err(Error::msg(format!("unknown user {}", nick)))
     }
 }
 
+/// check new user is allowed to register, and return its password without
+/// the registration token suffix if any
+pub fn register_pass<'a>(nick: &str, pass: &'a str) -> Result<&'a str> {
+    register_allowed(&args().register_allow, &args().register_token, nick, pass)
+        .with_context(|| format!("{} is not allowed to register", nick))
+}
+
+fn register_allowed<'a>(
+    allow: &[String],
+    tokens: &[String],
+    nick: &str,
+    pass: &'a str,
+) -> Option<&'a str> {
+    if allow.is_empty() && tokens.is_empty() {
+        return Some(pass);
+    }
+    if let Some((pass, token)) = pass.rsplit_once(':') {
+        if tokens.iter().any(|t| t == token) {
+            return Some(pass);
+        }
+    }
+    allow
+        .iter()
+        .any(|pattern| glob_match(pattern, nick))
+        .then_some(pass)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,10 +548,17 @@ mod tests {
             },
         });
         // can serialize/encrypt
-        let blob_string = &encrypt_blob("pass", "domain.tld", session)?;
+        let kdf = KdfParams {
+            m_cost: 1024,
+            ..KdfParams::default()
+        };
+        let blob_string = &encrypt_blob("pass", "domain.tld", session, &kdf)?;
 
-        // can decrypt what we just encrypted
-        let session = decrypt_blob("pass", blob_string)?;
+        // can decrypt what we just encrypted, and got parameters back
+        let (session, blob_key) = decrypt_blob("pass", blob_string)?;
+        let blob_kdf = blob_key.kdf;
+        assert_eq!(blob_kdf, kdf);
+        assert!(blob_kdf.weaker_than(&KdfParams::default()));
         assert_eq!(session.homeserver, "domain.tld");
         assert_eq!(session.matrix_session.user_id, "@test:domain.tld");
         assert_eq!(session.matrix_session.device_id, "ABCDEFGHIJ");
@@ -196,9 +570,36 @@ mod tests {
 
         // can decrypt something we encrypted ages ago (format stability check)
         let old_blob = r#"{"version":"argon2+chacha20poly1305","ciphertext":"jTMm0N+nAl9jTD6sdppn+9w5B93QpGzng7YNyR+oDcFdHs3EEAUYKKBPTQlkJovthypQ+eDSrS9Vd9WJAdsa9NqGgyx+XoijMPL4LG+K88CnlKE/0GbNbGLH4r1QqGif5aimVJOmgI5rTgRAb+ZhfEGx5nmk1CNmCW5nCzLmWfdvjHJssMJt4JJFN82hJoVn2RHNwFY3q+MQ08E0zTvG1CA=","salt":"c9fUuFFl0Q1bzaBKAyvOcy+x1alIJ2mr/eZow4ut+58=","nonce":"QgY2eb3OGc7VCzw76t4b9kSPWx4pmZCG"}"#;
-        let old_session = decrypt_blob("pass", old_blob.as_bytes())?;
+        let (old_session, old_key) = decrypt_blob("pass", old_blob.as_bytes())?;
         assert_eq!(session, old_session);
+        assert_eq!(old_key.kdf, KdfParams::default());
 
         Ok(())
     }
+
+    #[test]
+    fn check_register_allowed() {
+        let allow = ["alice".to_string(), "friend*".to_string()];
+        let tokens = ["s3cret".to_string()];
+        assert_eq!(register_allowed(&[], &[], "anyone", "a:b"), Some("a:b"));
+        assert_eq!(register_allowed(&allow, &[], "alice", "pass"), Some("pass"));
+        assert_eq!(
+            register_allowed(&allow, &[], "friendly", "pass"),
+            Some("pass")
+        );
+        assert_eq!(register_allowed(&allow, &[], "mallory", "pass"), None);
+        assert_eq!(
+            register_allowed(&allow, &tokens, "mallory", "pa:ss:s3cret"),
+            Some("pa:ss")
+        );
+        assert_eq!(
+            register_allowed(&[], &tokens, "mallory", "pass:wrong"),
+            None
+        );
+        // allowed nicks keep their password as is
+        assert_eq!(
+            register_allowed(&allow, &tokens, "alice", "pass:wrong"),
+            Some("pass:wrong")
+        );
+    }
 }
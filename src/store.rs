@@ -0,0 +1,719 @@
+use anyhow::{Context, Result};
+use log::debug;
+use matrix_sdk::ruma::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, RoomId};
+use matrix_sdk_store_encryption::StoreCipher;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::args::args;
+use crate::config::MessageCacheConfig;
+
+/// short ids are three lowercase letters: well above the default message
+/// cache size, so ids of cached messages rarely collide
+const SHORT_ID_LEN: u32 = 3;
+const SHORT_ID_COUNT: i64 = 26i64.pow(SHORT_ID_LEN);
+
+/// short id of a message, derived from its sequence number.
+/// ids wrap around so only the most recent message with a given id can be addressed.
+fn short_id(seq: i64) -> String {
+    let n = (seq - 1).rem_euclid(SHORT_ID_COUNT);
+    (0..SHORT_ID_LEN)
+        .rev()
+        .map(|i| (b'a' + (n / 26i64.pow(i) % 26) as u8) as char)
+        .collect()
+}
+
+fn short_id_index(id: &str) -> Option<i64> {
+    if id.len() != SHORT_ID_LEN as usize {
+        return None;
+    }
+    id.bytes().try_fold(0, |index, c| match c {
+        b'a'..=b'z' => Some(index * 26 + (c - b'a') as i64),
+        _ => None,
+    })
+}
+
+/// delivered event ids remembered per room to drop duplicates
+const DELIVERED_PER_ROOM: u32 = 200;
+
+/// looked up events (reaction/redaction targets) remembered per room
+const EVENTS_PER_ROOM: u32 = 500;
+
+/// schema upgrades, applied in order: user_version pragma is the index of the next
+/// migration to run. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE messages (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        event_id TEXT NOT NULL UNIQUE,
+        room_id TEXT NOT NULL,
+        message BLOB NOT NULL
+    );",
+    "CREATE INDEX messages_room ON messages (room_id, seq);",
+    "CREATE TABLE media (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        path TEXT NOT NULL UNIQUE,
+        size INTEGER NOT NULL
+    );",
+    "CREATE TABLE kv (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );",
+    "CREATE TABLE delivered (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        event_id TEXT NOT NULL UNIQUE,
+        room_id TEXT NOT NULL
+    );
+    CREATE INDEX delivered_room ON delivered (room_id, seq);",
+    "CREATE TABLE events (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        event_id TEXT NOT NULL UNIQUE,
+        room_id TEXT NOT NULL,
+        text BLOB NOT NULL
+    );
+    CREATE INDEX events_room ON events (room_id, seq);",
+    "CREATE TABLE pending (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        room_id TEXT NOT NULL,
+        notice INTEGER NOT NULL,
+        sender BLOB NOT NULL,
+        text BLOB NOT NULL,
+        msgid TEXT
+    );
+    CREATE INDEX pending_room ON pending (room_id, seq);",
+    "CREATE TABLE room_settings (
+        room_id TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (room_id, key)
+    );",
+];
+
+/// event we had to look up, formatted when read as it includes relative time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedEvent {
+    pub sender: String,
+    pub ts: MilliSecondsSinceUnixEpoch,
+    /// None for state events
+    pub message: Option<String>,
+}
+
+/// message that was queued for irc but not sent when the client left
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingMessage {
+    pub notice: bool,
+    pub from: String,
+    pub text: String,
+    pub msgid: Option<String>,
+}
+
+fn connect(nick: &str) -> Result<Connection> {
+    let path = Path::new(&args().state_dir)
+        .join(nick)
+        .join("matrirc.sqlite3");
+    debug!("Opening store {}", path.display());
+    let mut conn = Connection::open(path).context("Could not open store")?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    // don't leave old messages around in free pages
+    conn.pragma_update(None, "secure_delete", true)?;
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < MIGRATIONS.len() {
+        let tx = conn.transaction()?;
+        for migration in &MIGRATIONS[version..] {
+            tx.execute_batch(migration)
+                .context("Could not upgrade store")?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// key for message text in store, created on first use.
+/// Like matrix-sdk stores, it is kept in the kv table encrypted with the user
+/// password, so state::change_password also updates it.
+/// Key derivation is slow on purpose, call from a blocking task.
+pub fn cipher(nick: &str, pass: &str) -> Result<StoreCipher> {
+    let conn = connect(nick)?;
+    let encrypted: Option<Vec<u8>> = conn
+        .query_row("SELECT value FROM kv WHERE key = 'cipher'", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if let Some(encrypted) = encrypted {
+        return StoreCipher::import(pass, &encrypted).context("Could not decrypt store key");
+    }
+    let cipher = StoreCipher::new()?;
+    conn.execute(
+        "INSERT INTO kv (key, value) VALUES ('cipher', ?1)",
+        [cipher.export(pass)?],
+    )?;
+    Ok(cipher)
+}
+
+/// persistent per-user store for things we want to keep across restarts.
+/// Cheap to clone, queries should go through `blocking`.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+    /// encrypts message text, which can come from encrypted rooms
+    cipher: Arc<StoreCipher>,
+    message_cache: MessageCacheConfig,
+}
+
+impl Store {
+    pub fn open(
+        nick: &str,
+        cipher: StoreCipher,
+        message_cache: MessageCacheConfig,
+    ) -> Result<Store> {
+        Ok(Store {
+            conn: Arc::new(Mutex::new(connect(nick)?)),
+            cipher: Arc::new(cipher),
+            message_cache,
+        })
+    }
+
+    #[cfg(test)]
+    fn from_connection(mut conn: Connection, message_cache: MessageCacheConfig) -> Result<Store> {
+        migrate(&mut conn)?;
+        Ok(Store {
+            conn: Arc::new(Mutex::new(conn)),
+            cipher: Arc::new(StoreCipher::new()?),
+            message_cache,
+        })
+    }
+
+    /// run store operations in a blocking task, sqlite calls can block for a while
+    pub async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Store) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(&store)).await?
+    }
+
+    fn encrypt(&self, text: &str) -> Result<Vec<u8>> {
+        Ok(self.cipher.encrypt_value(&text)?)
+    }
+
+    fn decrypt(&self, data: Option<Vec<u8>>) -> Result<Option<String>> {
+        Ok(match data {
+            Some(data) => Some(self.cipher.decrypt_value(&data)?),
+            None => None,
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // a panic while holding the lock doesn't leave the connection in a bad state
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn message_get(&self, event_id: &EventId) -> Result<Option<String>> {
+        let data = self
+            .conn()
+            .query_row(
+                "SELECT message FROM messages WHERE event_id = ?1",
+                [event_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        self.decrypt(data)
+    }
+
+    /// store message and return its short id
+    pub fn message_put(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        message: &str,
+    ) -> Result<String> {
+        let message = self.encrypt(message)?;
+        let conn = self.conn();
+        conn.execute(
+            "INSERT OR REPLACE INTO messages (event_id, room_id, message) VALUES (?1, ?2, ?3)",
+            params![event_id.as_str(), room_id.as_str(), message],
+        )?;
+        let seq = conn.last_insert_rowid();
+        if self.message_cache.per_room {
+            conn.execute(
+                "DELETE FROM messages WHERE room_id = ?1 AND seq NOT IN
+                    (SELECT seq FROM messages WHERE room_id = ?1 ORDER BY seq DESC LIMIT ?2)",
+                params![room_id.as_str(), self.message_cache.size],
+            )?;
+        } else {
+            conn.execute(
+                "DELETE FROM messages WHERE seq <= (SELECT MAX(seq) FROM messages) - ?1",
+                [self.message_cache.size],
+            )?;
+        }
+        Ok(short_id(seq))
+    }
+
+    /// true if event was already delivered to irc
+    /// (sync replay after restart, overlap with backfill...)
+    pub fn is_delivered(&self, event_id: &EventId) -> Result<bool> {
+        let found = self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM delivered WHERE event_id = ?1",
+                [event_id.as_str()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// remember event was delivered to irc, returns false if it already was
+    pub fn mark_delivered(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        let conn = self.conn();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO delivered (event_id, room_id) VALUES (?1, ?2)",
+            [event_id.as_str(), room_id.as_str()],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        conn.execute(
+            "DELETE FROM delivered WHERE room_id = ?1 AND seq NOT IN
+                (SELECT seq FROM delivered WHERE room_id = ?1 ORDER BY seq DESC LIMIT ?2)",
+            params![room_id.as_str(), DELIVERED_PER_ROOM],
+        )?;
+        Ok(true)
+    }
+
+    /// text of an event we had to look up before
+    pub fn event_get(&self, event_id: &EventId) -> Result<Option<CachedEvent>> {
+        let data: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT text FROM events WHERE event_id = ?1",
+                [event_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match data {
+            Some(data) => Some(self.cipher.decrypt_value(&data)?),
+            None => None,
+        })
+    }
+
+    pub fn event_put(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        event: &CachedEvent,
+    ) -> Result<()> {
+        let text = self.cipher.encrypt_value(event)?;
+        let conn = self.conn();
+        conn.execute(
+            "INSERT OR REPLACE INTO events (event_id, room_id, text) VALUES (?1, ?2, ?3)",
+            params![event_id.as_str(), room_id.as_str(), text],
+        )?;
+        conn.execute(
+            "DELETE FROM events WHERE room_id = ?1 AND seq NOT IN
+                (SELECT seq FROM events WHERE room_id = ?1 ORDER BY seq DESC LIMIT ?2)",
+            params![room_id.as_str(), EVENTS_PER_ROOM],
+        )?;
+        Ok(())
+    }
+
+    pub fn pending_put(&self, room_id: &RoomId, message: &PendingMessage) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO pending (room_id, notice, sender, text, msgid) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room_id.as_str(),
+                message.notice,
+                self.encrypt(&message.from)?,
+                self.encrypt(&message.text)?,
+                message.msgid
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// rooms with pending messages
+    pub fn pending_rooms(&self) -> Result<Vec<OwnedRoomId>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT DISTINCT room_id FROM pending")?;
+        let rooms = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|room_id| room_id.ok()?.try_into().ok())
+            .collect();
+        Ok(rooms)
+    }
+
+    /// pending messages for room, in order, removing them from the store
+    pub fn pending_take(&self, room_id: &RoomId) -> Result<Vec<PendingMessage>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let messages = {
+            let mut stmt = tx.prepare(
+                "SELECT notice, sender, text, msgid FROM pending WHERE room_id = ?1 ORDER BY seq",
+            )?;
+            let rows = stmt.query_map([room_id.as_str()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<(bool, Vec<u8>, Vec<u8>, Option<String>)>>>()?
+        };
+        tx.execute("DELETE FROM pending WHERE room_id = ?1", [room_id.as_str()])?;
+        tx.commit()?;
+        messages
+            .into_iter()
+            .map(|(notice, from, text, msgid)| {
+                Ok(PendingMessage {
+                    notice,
+                    from: self.cipher.decrypt_value(&from)?,
+                    text: self.cipher.decrypt_value(&text)?,
+                    msgid,
+                })
+            })
+            .collect()
+    }
+
+    /// per-room preferences set by commands, e.g. `joins`, for all rooms
+    /// with one set for key
+    pub fn room_settings(&self, key: &str) -> Result<Vec<(OwnedRoomId, String)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT room_id, value FROM room_settings WHERE key = ?1")?;
+        let settings = stmt
+            .query_map([key], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+            .filter_map(|row| {
+                let (room_id, value) = row.ok()?;
+                Some((room_id.try_into().ok()?, value))
+            })
+            .collect();
+        Ok(settings)
+    }
+
+    /// set room preference, or reset it to default with None
+    pub fn room_setting_set(&self, room_id: &RoomId, key: &str, value: Option<&str>) -> Result<()> {
+        match value {
+            Some(value) => self.conn().execute(
+                "INSERT OR REPLACE INTO room_settings (room_id, key, value) VALUES (?1, ?2, ?3)",
+                [room_id.as_str(), key, value],
+            )?,
+            None => self.conn().execute(
+                "DELETE FROM room_settings WHERE room_id = ?1 AND key = ?2",
+                [room_id.as_str(), key],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// total size of media files we saved
+    pub fn media_usage(&self) -> Result<u64> {
+        Ok(self
+            .conn()
+            .query_row("SELECT COALESCE(SUM(size), 0) FROM media", [], |row| {
+                row.get(0)
+            })?)
+    }
+
+    /// remember a media file we saved, most recent last
+    pub fn media_add(&self, path: &str, size: u64) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO media (path, size) VALUES (?1, ?2)",
+            params![path, size],
+        )?;
+        Ok(())
+    }
+
+    pub fn media_oldest(&self) -> Result<Option<(String, u64)>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT path, size FROM media ORDER BY seq LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    pub fn media_remove(&self, path: &str) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM media WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    /// short id of message, if it is still the most recent one with that id
+    pub fn message_short_id(&self, event_id: &EventId) -> Result<Option<String>> {
+        let conn = self.conn();
+        let Some(seq): Option<i64> = conn
+            .query_row(
+                "SELECT seq FROM messages WHERE event_id = ?1",
+                [event_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let newer: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE seq > ?1 AND (seq - ?1) % ?2 = 0",
+            [seq, SHORT_ID_COUNT],
+            |row| row.get(0),
+        )?;
+        Ok((newer == 0).then(|| short_id(seq)))
+    }
+
+    /// find most recent message with given short id
+    pub fn message_lookup(&self, id: &str) -> Result<Option<(OwnedRoomId, OwnedEventId)>> {
+        let Some(index) = short_id_index(id) else {
+            return Ok(None);
+        };
+        let found: Option<(String, String)> = self
+            .conn()
+            .query_row(
+                "SELECT room_id, event_id FROM messages WHERE (seq - 1) % ?1 = ?2
+                    ORDER BY seq DESC LIMIT 1",
+                [SHORT_ID_COUNT, index],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(match found {
+            Some((room_id, event_id)) => Some((room_id.try_into()?, event_id.try_into()?)),
+            None => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{room_id, OwnedEventId};
+
+    #[test]
+    fn check_message_pruning() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        let room = room_id!("!room:domain.tld");
+        let event_id = |i| OwnedEventId::try_from(format!("$event{}", i)).unwrap();
+        const MAX_MESSAGES: i64 = 1000;
+        for i in 0..MAX_MESSAGES + 10 {
+            store.message_put(room, &event_id(i), &format!("message {}", i))?;
+        }
+        assert_eq!(store.message_get(&event_id(0))?, None);
+        assert_eq!(store.message_get(&event_id(9))?, None);
+        assert_eq!(
+            store.message_get(&event_id(10))?.as_deref(),
+            Some("message 10")
+        );
+        assert_eq!(
+            store.message_get(&event_id(MAX_MESSAGES + 9))?.as_deref(),
+            Some(format!("message {}", MAX_MESSAGES + 9).as_str())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_mark_delivered() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        let room = room_id!("!room:domain.tld");
+        let other = room_id!("!other:domain.tld");
+        let event_id = |i| OwnedEventId::try_from(format!("$event{}", i)).unwrap();
+        assert!(!store.is_delivered(&event_id(0))?);
+        assert!(store.mark_delivered(room, &event_id(0))?);
+        assert!(store.is_delivered(&event_id(0))?);
+        assert!(!store.mark_delivered(room, &event_id(0))?);
+        assert!(store.mark_delivered(other, &event_id(1))?);
+        for i in 2..DELIVERED_PER_ROOM + 2 {
+            store.mark_delivered(room, &event_id(i))?;
+        }
+        // pruned in busy room only
+        assert!(store.mark_delivered(room, &event_id(0))?);
+        assert!(!store.mark_delivered(other, &event_id(1))?);
+        Ok(())
+    }
+
+    #[test]
+    fn check_events() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        let room = room_id!("!room:domain.tld");
+        let event_id = |i| OwnedEventId::try_from(format!("$event{}", i)).unwrap();
+        let event = |i: u32| CachedEvent {
+            sender: "@alice:domain.tld".to_string(),
+            ts: MilliSecondsSinceUnixEpoch(i.into()),
+            message: Some(format!("event {}", i)),
+        };
+        for i in 0..EVENTS_PER_ROOM + 1 {
+            store.event_put(room, &event_id(i), &event(i))?;
+        }
+        assert_eq!(store.event_get(&event_id(0))?, None);
+        assert_eq!(store.event_get(&event_id(1))?, Some(event(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn check_text_encrypted() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        let room = room_id!("!room:domain.tld");
+        let event_id = OwnedEventId::try_from("$event")?;
+        store.message_put(room, &event_id, "secret message")?;
+        store.event_put(
+            room,
+            &event_id,
+            &CachedEvent {
+                sender: "@alice:domain.tld".to_string(),
+                ts: MilliSecondsSinceUnixEpoch::now(),
+                message: Some("secret event".to_string()),
+            },
+        )?;
+        let raw: Vec<u8> = store
+            .conn()
+            .query_row("SELECT message FROM messages", [], |row| row.get(0))?;
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+        let raw: Vec<u8> = store
+            .conn()
+            .query_row("SELECT text FROM events", [], |row| row.get(0))?;
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            store.message_get(&event_id)?.as_deref(),
+            Some("secret message")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_pending() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        let room = room_id!("!room:domain.tld");
+        let message = |text: &str| PendingMessage {
+            notice: false,
+            from: "alice".to_string(),
+            text: text.to_string(),
+            msgid: None,
+        };
+        store.pending_put(room, &message("first"))?;
+        store.pending_put(room, &message("second"))?;
+        let raw: Vec<u8> =
+            store
+                .conn()
+                .query_row("SELECT text FROM pending LIMIT 1", [], |row| row.get(0))?;
+        assert!(!raw.windows(5).any(|w| w == b"first"));
+        assert_eq!(store.pending_rooms()?, vec![room.to_owned()]);
+        assert_eq!(
+            store.pending_take(room)?,
+            vec![message("first"), message("second")]
+        );
+        assert_eq!(store.pending_take(room)?, vec![]);
+        assert!(store.pending_rooms()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_room_settings() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        let room: &RoomId = "!a:domain.tld".try_into()?;
+        let other: &RoomId = "!b:domain.tld".try_into()?;
+        assert_eq!(store.room_settings("joins")?, vec![]);
+        store.room_setting_set(room, "joins", Some("on"))?;
+        store.room_setting_set(room, "joins", Some("smart"))?;
+        store.room_setting_set(other, "other", Some("on"))?;
+        assert_eq!(
+            store.room_settings("joins")?,
+            vec![(room.to_owned(), "smart".to_string())]
+        );
+        store.room_setting_set(room, "joins", None)?;
+        assert_eq!(store.room_settings("joins")?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_media_usage() -> Result<()> {
+        let store =
+            Store::from_connection(Connection::open_in_memory()?, MessageCacheConfig::default())?;
+        assert_eq!(store.media_usage()?, 0);
+        store.media_add("/media/a", 10)?;
+        store.media_add("/media/b", 20)?;
+        // overwritten file only counts once
+        store.media_add("/media/a", 5)?;
+        assert_eq!(store.media_usage()?, 25);
+        assert_eq!(store.media_oldest()?, Some(("/media/b".to_string(), 20)));
+        store.media_remove("/media/b")?;
+        assert_eq!(store.media_usage()?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn check_message_pruning_per_room() -> Result<()> {
+        let store = Store::from_connection(
+            Connection::open_in_memory()?,
+            MessageCacheConfig {
+                size: 10,
+                per_room: true,
+            },
+        )?;
+        let busy = room_id!("!busy:domain.tld");
+        let quiet = room_id!("!quiet:domain.tld");
+        let event_id = |i| OwnedEventId::try_from(format!("$event{}", i)).unwrap();
+        store.message_put(quiet, &event_id(0), "quiet message")?;
+        for i in 1..100 {
+            store.message_put(busy, &event_id(i), "busy message")?;
+        }
+        assert_eq!(
+            store.message_get(&event_id(0))?.as_deref(),
+            Some("quiet message")
+        );
+        assert_eq!(store.message_get(&event_id(89))?, None);
+        assert!(store.message_get(&event_id(90))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn check_short_ids() -> Result<()> {
+        assert_eq!(short_id(1), "aaa");
+        assert_eq!(short_id(2), "aab");
+        assert_eq!(short_id(27), "aba");
+        assert_eq!(short_id(SHORT_ID_COUNT), "zzz");
+        assert_eq!(short_id(SHORT_ID_COUNT + 1), "aaa");
+        assert_eq!(short_id_index("zzz"), Some(SHORT_ID_COUNT - 1));
+        assert_eq!(short_id_index("aba"), Some(26));
+        assert_eq!(short_id_index("ab"), None);
+        assert_eq!(short_id_index("$ab"), None);
+
+        // the default cache never wraps ids
+        assert!(SHORT_ID_COUNT > MessageCacheConfig::default().size as i64);
+        let cache = MessageCacheConfig {
+            size: SHORT_ID_COUNT as u32 + 10,
+            per_room: false,
+        };
+        let store = Store::from_connection(Connection::open_in_memory()?, cache)?;
+        let room = room_id!("!room:domain.tld");
+        let event_id = |i| OwnedEventId::try_from(format!("$event{}", i)).unwrap();
+        for i in 0..SHORT_ID_COUNT + 1 {
+            let id = store.message_put(room, &event_id(i), "message")?;
+            assert_eq!(short_id_index(&id), Some(i % SHORT_ID_COUNT));
+        }
+        assert_eq!(
+            store
+                .message_short_id(&event_id(SHORT_ID_COUNT))?
+                .as_deref(),
+            Some("aaa")
+        );
+        // id was reused by a more recent message
+        assert_eq!(store.message_short_id(&event_id(0))?, None);
+        assert_eq!(store.message_short_id(&event_id(-1))?, None);
+        // most recent message wins
+        assert_eq!(
+            store.message_lookup("aaa")?,
+            Some((room.to_owned(), event_id(SHORT_ID_COUNT)))
+        );
+        assert_eq!(
+            store.message_lookup("aab")?,
+            Some((room.to_owned(), event_id(1)))
+        );
+        Ok(())
+    }
+}
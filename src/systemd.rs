@@ -0,0 +1,107 @@
+//! minimal systemd integration: socket activation and sd_notify, as described in
+//! sd_listen_fds(3) and sd_notify(3). Everything is a no-op when not run by systemd.
+//! listen_socket modifies the environment, so it must run before any thread is
+//! started (i.e. before the tokio runtime).
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::env;
+use std::os::fd::FromRawFd;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// first passed fd, see SD_LISTEN_FDS_START
+const LISTEN_FDS_START: i32 = 3;
+
+/// take listening socket passed by systemd, if any.
+/// Only the first socket is used.
+/// Not thread safe (environment changes): call before starting the runtime.
+pub fn listen_socket() -> Result<Option<std::net::TcpListener>> {
+    let Ok(pid) = env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        debug!("LISTEN_PID {} is not for us", pid);
+        return Ok(None);
+    }
+    let fds: i32 = env::var("LISTEN_FDS")
+        .context("LISTEN_PID set without LISTEN_FDS")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+    // don't pass the fds down to anything we'd spawn
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets, only using the first one", fds);
+    }
+    // safety: systemd passes us ownership of these fds, and we just
+    // cleared the environment so nothing else will claim this one
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+fn notify(state: &str) -> Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(anyhow::Error::msg(
+                "abstract NOTIFY_SOCKET is only supported on linux",
+            ))
+        }
+        None => SocketAddr::from_pathname(&*path)?,
+    };
+    UnixDatagram::unbound()?
+        .send_to_addr(state.as_bytes(), &addr)
+        .context("Could not notify systemd")?;
+    Ok(())
+}
+
+/// tell systemd we're ready, and start sending watchdog keepalives if requested.
+/// Keepalives are only sent while alive() holds, and only if a newly spawned
+/// task gets to check it in time, so a stuck runtime gets restarted.
+pub fn notify_ready(alive: fn() -> bool) {
+    if let Err(e) = notify("READY=1") {
+        warn!("{:?}", e);
+    }
+    let Some(usec) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+    else {
+        return;
+    };
+    if env::var("WATCHDOG_PID").is_ok_and(|pid| pid.parse::<u32>().ok() != Some(std::process::id()))
+    {
+        return;
+    }
+    // recommended to ping at half the interval
+    let interval = Duration::from_micros(usec / 2);
+    debug!("Sending watchdog notifications every {:?}", interval);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match tokio::time::timeout(interval, tokio::spawn(async move { alive() })).await {
+                Ok(Ok(true)) => (),
+                _ => {
+                    warn!("Liveness check failed, not sending watchdog keepalive");
+                    continue;
+                }
+            }
+            if let Err(e) = notify("WATCHDOG=1") {
+                warn!("{:?}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::args::Tool;
+use crate::state;
+
+fn read_password(nick: &str) -> Result<String> {
+    eprint!("Password for {}: ", nick);
+    io::stderr().flush()?;
+    let mut pass = String::new();
+    io::stdin()
+        .read_line(&mut pass)
+        .context("Could not read password")?;
+    Ok(pass.trim_end_matches(['\r', '\n']).to_string())
+}
+
+pub fn run(tool: &Tool) -> Result<()> {
+    match tool {
+        Tool::ExportSession { nick, file } => {
+            let pass = read_password(nick)?;
+            let export = state::export_session(nick, &pass)?;
+            fs::OpenOptions::new()
+                .mode(0o600)
+                .write(true)
+                .create_new(true)
+                .open(file)
+                .context("Could not create export file")?
+                .write_all(&serde_json::to_vec(&export)?)?;
+            eprintln!(
+                "Exported {} to {}: this file contains your access token, keep it safe",
+                nick, file
+            );
+        }
+        Tool::ImportSession { nick, file } => {
+            let export = serde_json::from_slice(&fs::read(file).context("Could not read export")?)
+                .context("Invalid export file")?;
+            let pass = read_password(nick)?;
+            state::import_session(nick, &pass, export)?;
+            eprintln!("Imported {}, you can now connect", nick);
+        }
+    }
+    Ok(())
+}
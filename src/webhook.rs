@@ -0,0 +1,245 @@
+//! optional http endpoint relaying text POSTed by scripts (cron, ci...) to
+//! a room of a user: POST /<nick>/<room> with
+//! `Authorization: Bearer <webhook_token from config.toml>`.
+//! Posts for users without a connected client are kept in memory and sent
+//! once the next client connected and rooms were synced.
+
+use anyhow::{Context, Error, Result};
+use axum::{
+    extract::{ConnectInfo, Path},
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+use crate::args::args;
+use crate::config::Config;
+use crate::ircd;
+use crate::ircd::proto::IrcMessageType;
+use crate::ircd::throttle;
+use crate::matrirc::Matrirc;
+use crate::matrix::MatrixMessageType;
+
+/// posts kept per user while no client is connected
+const QUEUE_MAX: usize = 100;
+/// posts accepted per user and RATE_WINDOW
+const RATE_MAX: u32 = 30;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// posts waiting for a client to connect
+struct Queued {
+    room: String,
+    text: String,
+}
+
+struct Rate {
+    start: Instant,
+    count: u32,
+}
+
+lazy_static! {
+    static ref QUEUED: Mutex<HashMap<String, VecDeque<Queued>>> = Mutex::new(HashMap::new());
+    static ref RATES: Mutex<HashMap<String, Rate>> = Mutex::new(HashMap::new());
+}
+
+fn queued() -> MutexGuard<'static, HashMap<String, VecDeque<Queued>>> {
+    QUEUED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// false if nick posted more than RATE_MAX times in the current window
+fn rate_allowed(nick: &str, now: Instant) -> bool {
+    let mut rates = RATES.lock().unwrap_or_else(|e| e.into_inner());
+    rates.retain(|_, rate| now.duration_since(rate.start) < RATE_WINDOW);
+    let rate = rates.entry(nick.to_string()).or_insert(Rate {
+        start: now,
+        count: 0,
+    });
+    rate.count += 1;
+    rate.count <= RATE_MAX
+}
+
+/// compare without returning early, so timing doesn't tell how much matched
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// nick is an existing user, and safe to use as a path
+fn is_user(nick: &str) -> bool {
+    !nick.is_empty()
+        && !nick.starts_with('.')
+        && !nick.contains(['/', '\\'])
+        && path::Path::new(&args().state_dir)
+            .join(nick)
+            .join("session")
+            .is_file()
+}
+
+async fn relay(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((nick, name)): Path<(String, String)>,
+    headers: HeaderMap,
+    text: String,
+) -> (StatusCode, &'static str) {
+    // same answer for unknown users, users without token and bad tokens,
+    // so posts cannot tell which users exist
+    const REFUSED: (StatusCode, &str) = (StatusCode::NOT_FOUND, "no such user or bad token\n");
+    if !is_user(&nick) {
+        return REFUSED;
+    }
+    let session = ircd::session(&nick);
+    // config is loaded at login, read it again while no client is connected
+    let expected = match &session {
+        Some(matrirc) => matrirc.config().webhook_token.clone(),
+        None => Config::load(&nick)
+            .map_err(|e| warn!("Could not load config of {}: {:?}", nick, e))
+            .ok()
+            .and_then(|config| config.webhook_token),
+    };
+    // webhooks are disabled for this user, nothing to guess
+    let Some(expected) = expected else {
+        return REFUSED;
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let check = async {
+        match given {
+            Some(given) if token_matches(given, &expected) => Ok(()),
+            _ => Err(Error::msg("bad webhook token")),
+        }
+    };
+    if throttle::throttled(throttle::Scope::Webhook, &nick, addr.ip(), check)
+        .await
+        .is_err()
+    {
+        return REFUSED;
+    }
+    if !rate_allowed(&nick, Instant::now()) {
+        return (StatusCode::TOO_MANY_REQUESTS, "too many messages\n");
+    }
+    let text = text.trim_end().to_string();
+    if text.is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty message\n");
+    }
+    let Some(matrirc) = session else {
+        let mut queued = queued();
+        let queue = queued.entry(nick).or_default();
+        if queue.len() >= QUEUE_MAX {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "user not connected, queue full\n",
+            );
+        }
+        queue.push_back(Queued { room: name, text });
+        return (StatusCode::ACCEPTED, "queued until user connects\n");
+    };
+    send(&matrirc, &name, text).await
+}
+
+async fn send(matrirc: &Matrirc, name: &str, text: String) -> (StatusCode, &'static str) {
+    let Some(room) = matrirc.mappings().room(name).await else {
+        return (StatusCode::NOT_FOUND, "no such room\n");
+    };
+    let Some(text) = matrirc.filters().outgoing(name, text).await else {
+        return (StatusCode::FORBIDDEN, "blocked by filter\n");
+    };
+    if let Err(e) = matrirc
+        .mappings()
+        .to_matrix(name, MatrixMessageType::Notice, text.clone())
+        .await
+    {
+        warn!("Could not relay webhook to {}: {:?}", name, e);
+        return (StatusCode::BAD_GATEWAY, "could not send message\n");
+    }
+    // our own messages are not sent back from sync
+    let target = matrirc.mappings().room_target(&room).await;
+    if let Err(e) = target
+        .send_text_to_irc(
+            matrirc.irc(),
+            IrcMessageType::Notice,
+            &matrirc.irc().nick,
+            format!("[webhook] {}", text),
+        )
+        .await
+    {
+        warn!("Could not echo webhook to irc: {:?}", e);
+    }
+    (StatusCode::NO_CONTENT, "")
+}
+
+/// send posts received while no client was connected, once rooms are synced
+pub async fn send_queued(matrirc: &Matrirc) -> Result<()> {
+    let Some(queue) = queued().remove(&matrirc.irc().nick) else {
+        return Ok(());
+    };
+    info!("Sending {} queued webhook messages", queue.len());
+    for queued in queue {
+        let (status, error) = send(matrirc, &queued.room, queued.text).await;
+        if status != StatusCode::NO_CONTENT {
+            matrirc
+                .mappings()
+                .matrirc_query(format!(
+                    "Could not send queued webhook message to {}: {}",
+                    queued.room,
+                    error.trim_end()
+                ))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// listen on --webhook-listen, if set
+pub async fn listen() -> Result<()> {
+    let Some(addr) = args().webhook_listen else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(addr).await.context("bind webhook port")?;
+    info!("accepting webhooks on {}", addr);
+    let app = Router::new().route("/:nick/:room", post(relay));
+    tokio::spawn(async move {
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("Webhook server stopped: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+
+    #[test]
+    fn check_rate_allowed() {
+        let now = Instant::now();
+        for _ in 0..RATE_MAX {
+            assert!(rate_allowed("rate", now));
+        }
+        assert!(!rate_allowed("rate", now));
+        assert!(rate_allowed("other", now));
+        assert!(rate_allowed("rate", now + RATE_WINDOW));
+    }
+}